@@ -0,0 +1,568 @@
+//! Grouping the labeled tokens of a sentence into entities, including the
+//! strategies of the token classification pipeline of Hugging Face
+//! Transformers, so that a model gives the same entities as it does there.
+
+use std::{collections::HashMap, str::FromStr};
+
+use tract_onnx::tract_hir::tract_ndarray::{ArrayView1, ArrayViewD};
+
+use crate::{Error, RawEntity, Result, ScoreAggregation};
+
+/// The label of tokens outside of any entity, to the strategies of
+/// Transformers.
+const OUTSIDE: &str = "O";
+
+/// How the labeled tokens of a sentence are grouped into entities.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum AggregationStrategy {
+    /// Runs of tokens with the same label, scored as configured by
+    /// [`Pipeline::with_score_aggregation`](crate::Pipeline::with_score_aggregation).
+    /// Labels keep any `B-` and `I-` prefixes.
+    #[default]
+    Label,
+    /// Every token on its own, labeled as predicted.
+    None,
+    /// Runs of tokens continuing the IOB2 tag of the first, scored by the
+    /// mean of their scores. A word may be split between entities.
+    Simple,
+    /// Like [`AggregationStrategy::Simple`], but of words labeled as their
+    /// first token.
+    First,
+    /// Like [`AggregationStrategy::Simple`], but of words labeled by the
+    /// mean of the scores of their tokens.
+    Average,
+    /// Like [`AggregationStrategy::Simple`], but of words labeled as their
+    /// most confident token.
+    Max,
+}
+
+impl AggregationStrategy {
+    /// How the scores of the tokens or words of an entity are combined.
+    pub(crate) fn score_aggregation(self, configured: ScoreAggregation) -> ScoreAggregation {
+        match self {
+            Self::Label => configured,
+            _ => ScoreAggregation::Mean,
+        }
+    }
+}
+
+impl FromStr for AggregationStrategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "label" => Ok(Self::Label),
+            "none" => Ok(Self::None),
+            "simple" => Ok(Self::Simple),
+            "first" => Ok(Self::First),
+            "average" => Ok(Self::Average),
+            "max" => Ok(Self::Max),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A token or a word, with the label it's most likely to have.
+struct Part {
+    label: i64,
+    score: f32,
+    start: usize,
+    end: usize,
+    word: u32,
+}
+
+/// Group the tokens of a sentence, given their offsets, words and logits,
+/// into entities in order. Entities outside of any label and without any
+/// text are left out.
+pub(crate) fn group(
+    strategy: AggregationStrategy,
+    aggregation: ScoreAggregation,
+    id2label: &HashMap<i64, String>,
+    offsets: &[(usize, usize)],
+    word_ids: &[Option<u32>],
+    logits: ArrayViewD<f32>,
+) -> Result<Vec<RawEntity>> {
+    let entities = match strategy {
+        AggregationStrategy::Label => by_label(aggregation, id2label, offsets, word_ids, logits)?,
+        AggregationStrategy::None => tokens(offsets, word_ids, logits)
+            .into_iter()
+            .map(|(part, _)| {
+                Ok(RawEntity {
+                    label: label(id2label, part.label)?.clone(),
+                    score: part.score,
+                    tokens: 1,
+                    start: part.start,
+                    end: part.end,
+                    words: Some((part.word, part.word)),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|e| e.label != OUTSIDE)
+            .collect(),
+        AggregationStrategy::Simple => by_tag(
+            id2label,
+            tokens(offsets, word_ids, logits)
+                .into_iter()
+                .map(|(part, _)| part),
+        )?,
+        AggregationStrategy::First | AggregationStrategy::Average | AggregationStrategy::Max => {
+            by_tag(id2label, words(strategy, offsets, word_ids, logits))?
+        }
+    };
+
+    Ok(entities.into_iter().filter(|e| e.end > e.start).collect())
+}
+
+/// The label of the id, which the model may predict even if the
+/// configuration has no such label.
+pub(crate) fn label(id2label: &HashMap<i64, String>, id: i64) -> Result<&String> {
+    id2label.get(&id).ok_or(Error::UnknownLabel(id))
+}
+
+/// Runs of tokens with the same label, special tokens included.
+fn by_label(
+    aggregation: ScoreAggregation,
+    id2label: &HashMap<i64, String>,
+    offsets: &[(usize, usize)],
+    word_ids: &[Option<u32>],
+    logits: ArrayViewD<f32>,
+) -> Result<Vec<RawEntity>> {
+    let mut entities: Vec<(i64, RawEntity)> = vec![];
+
+    for ((scores, &(start, end)), &word) in logits.rows().into_iter().zip(offsets).zip(word_ids) {
+        let (label, max) = argmax(scores.iter().copied());
+        // shifted by the largest logit, which can't overflow
+        let score = 1. / scores.iter().map(|z| (z - max).exp()).sum::<f32>();
+        let words = word.map(|word| (word, word));
+
+        match entities.last_mut() {
+            Some((prev_label, prev)) if *prev_label == label => {
+                prev.score = aggregation.merge(prev.score, score);
+                prev.tokens += 1;
+                prev.start = prev.start.min(start);
+                prev.end = prev.end.max(end);
+                prev.words = match (prev.words, words) {
+                    (Some((first, last)), Some((word, _))) => {
+                        Some((first.min(word), last.max(word)))
+                    }
+                    (prev, words) => prev.or(words),
+                };
+            }
+            _ => entities.push((
+                label,
+                RawEntity {
+                    label: String::new(),
+                    score,
+                    tokens: 1,
+                    start,
+                    end,
+                    words,
+                },
+            )),
+        }
+    }
+
+    entities
+        .into_iter()
+        .filter(|&(label, _)| label != 0)
+        .map(|(id, entity)| {
+            Ok(RawEntity {
+                label: label(id2label, id)?.clone(),
+                ..entity
+            })
+        })
+        .collect()
+}
+
+/// The tokens of the words, labeled, along with the probabilities of every
+/// label. Special tokens are left out.
+fn tokens(
+    offsets: &[(usize, usize)],
+    word_ids: &[Option<u32>],
+    logits: ArrayViewD<f32>,
+) -> Vec<(Part, Vec<f32>)> {
+    logits
+        .rows()
+        .into_iter()
+        .zip(offsets)
+        .zip(word_ids)
+        .filter_map(|((scores, &(start, end)), &word)| {
+            let probabilities = softmax(scores);
+            let (label, score) = argmax(probabilities.iter().copied());
+            let part = Part {
+                label,
+                score,
+                start,
+                end,
+                word: word?,
+            };
+            Some((part, probabilities))
+        })
+        .collect()
+}
+
+/// The words of the sentence, each labeled as the strategy has it.
+fn words(
+    strategy: AggregationStrategy,
+    offsets: &[(usize, usize)],
+    word_ids: &[Option<u32>],
+    logits: ArrayViewD<f32>,
+) -> Vec<Part> {
+    let mut words: Vec<Vec<(Part, Vec<f32>)>> = vec![];
+    for token in tokens(offsets, word_ids, logits) {
+        match words.last_mut() {
+            Some(word) if word[0].0.word == token.0.word => word.push(token),
+            _ => words.push(vec![token]),
+        }
+    }
+
+    words
+        .into_iter()
+        .map(|tokens| {
+            let start = tokens.iter().map(|(token, _)| token.start).min();
+            let end = tokens.iter().map(|(token, _)| token.end).max();
+            let (label, score) = match strategy {
+                AggregationStrategy::First => (tokens[0].0.label, tokens[0].0.score),
+                AggregationStrategy::Max => {
+                    // the first of the most confident tokens wins, as in
+                    // Transformers
+                    let mut best = &tokens[0].0;
+                    for (token, _) in &tokens[1..] {
+                        if token.score > best.score {
+                            best = token;
+                        }
+                    }
+                    (best.label, best.score)
+                }
+                _ => {
+                    let mut mean = vec![0.; tokens[0].1.len()];
+                    for (_, probabilities) in &tokens {
+                        for (mean, p) in mean.iter_mut().zip(probabilities) {
+                            *mean += p / tokens.len() as f32;
+                        }
+                    }
+                    argmax(mean)
+                }
+            };
+            Part {
+                label,
+                score,
+                start: start.unwrap_or_default(),
+                end: end.unwrap_or_default(),
+                word: tokens[0].0.word,
+            }
+        })
+        .collect()
+}
+
+/// Runs of parts whose IOB2 tags continue that of the first, labeled
+/// without the prefix and scored by the sum of their scores, to be divided
+/// by their number. This is how Transformers groups entities.
+fn by_tag(
+    id2label: &HashMap<i64, String>,
+    parts: impl IntoIterator<Item = Part>,
+) -> Result<Vec<RawEntity>> {
+    let mut entities: Vec<(&str, RawEntity)> = vec![];
+
+    for part in parts {
+        let label = label(id2label, part.label)?.as_str();
+        let (inside, tag) = match label.split_once('-') {
+            Some(("B", tag)) => (false, tag),
+            Some(("I", tag)) => (true, tag),
+            _ => (true, label),
+        };
+
+        match entities.last_mut() {
+            Some((prev_tag, prev)) if *prev_tag == tag && inside => {
+                prev.score += part.score;
+                prev.tokens += 1;
+                prev.end = prev.end.max(part.end);
+                prev.words = prev.words.map(|(first, _)| (first, part.word));
+            }
+            _ => entities.push((
+                tag,
+                RawEntity {
+                    // the prefix of the first is stripped, whatever it is
+                    label: label
+                        .split_once('-')
+                        .map_or(label, |(_, label)| label)
+                        .to_owned(),
+                    score: part.score,
+                    tokens: 1,
+                    start: part.start,
+                    end: part.end,
+                    words: Some((part.word, part.word)),
+                },
+            )),
+        }
+    }
+
+    Ok(entities
+        .into_iter()
+        .map(|(_, entity)| entity)
+        .filter(|e| e.label != OUTSIDE)
+        .collect())
+}
+
+/// The index of the largest value and the value, the first of equal ones so
+/// that ties are always broken the same way.
+pub(crate) fn argmax(values: impl IntoIterator<Item = f32>) -> (i64, f32) {
+    let mut max = f32::NEG_INFINITY;
+    let mut index = 0;
+    for (i, value) in values.into_iter().enumerate() {
+        if value > max {
+            max = value;
+            index = i as _;
+        }
+    }
+    (index, max)
+}
+
+/// The probabilities of the labels, given their logits.
+pub(crate) fn softmax(logits: ArrayView1<f32>) -> Vec<f32> {
+    // shifted by the largest logit, which can't overflow
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exp = logits.iter().map(|z| (z - max).exp()).collect::<Vec<_>>();
+    let sum = exp.iter().sum::<f32>();
+    exp.into_iter().map(|z| z / sum).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tract_onnx::tract_hir::tract_ndarray::Array2;
+
+    use super::*;
+
+    /// "Enzo works at UN", as tokenized by a WordPiece tokenizer.
+    const OFFSETS: [(usize, usize); 7] =
+        [(0, 0), (0, 2), (2, 4), (5, 10), (11, 13), (14, 16), (0, 0)];
+    const WORD_IDS: [Option<u32>; 7] = [None, Some(0), Some(0), Some(1), Some(2), Some(3), None];
+    const LABELS: [&str; 5] = ["O", "B-PER", "I-PER", "B-ORG", "I-ORG"];
+
+    /// Logits that make the label all but certain.
+    fn certain(label: usize) -> [f32; 5] {
+        let mut logits = [0.; 5];
+        logits[label] = 20.;
+        logits
+    }
+
+    /// Logits of the given probabilities, of which the rest are all but
+    /// impossible.
+    fn probable(probabilities: &[(usize, f32)]) -> [f32; 5] {
+        let mut logits = [-40.; 5];
+        for &(label, p) in probabilities {
+            logits[label] = p.ln();
+        }
+        logits
+    }
+
+    struct Case {
+        name: &'static str,
+        strategy: AggregationStrategy,
+        logits: [[f32; 5]; 7],
+        /// Label, offsets and mean score of every entity, as the pipeline of
+        /// Transformers gives them.
+        expected: &'static [(&'static str, usize, usize, f32)],
+    }
+
+    fn cases() -> Vec<Case> {
+        let [o, b_per, i_per, b_org, i_org] = [0, 1, 2, 3, 4].map(certain);
+        // a word whose first token is likely a person, and whose second is
+        // less likely an organization
+        let torn = [
+            o,
+            probable(&[(1, 0.9), (3, 0.1)]),
+            probable(&[(0, 0.15), (3, 0.85)]),
+            o,
+            o,
+            o,
+            o,
+        ];
+
+        vec![
+            Case {
+                name: "simple merges sub-words",
+                strategy: AggregationStrategy::Simple,
+                logits: [o, b_per, i_per, o, o, b_org, o],
+                expected: &[("PER", 0, 4, 1.), ("ORG", 14, 16, 1.)],
+            },
+            Case {
+                name: "simple splits words between entities",
+                strategy: AggregationStrategy::Simple,
+                logits: [o, b_per, b_org, o, o, b_org, o],
+                expected: &[("PER", 0, 2, 1.), ("ORG", 2, 4, 1.), ("ORG", 14, 16, 1.)],
+            },
+            Case {
+                name: "B- starts an entity",
+                strategy: AggregationStrategy::Simple,
+                logits: [o, b_per, i_per, b_per, o, o, o],
+                expected: &[("PER", 0, 4, 1.), ("PER", 5, 10, 1.)],
+            },
+            Case {
+                name: "I- after O starts an entity",
+                strategy: AggregationStrategy::Simple,
+                logits: [o, o, o, i_per, i_per, o, o],
+                expected: &[("PER", 5, 13, 1.)],
+            },
+            Case {
+                name: "I- of another tag starts an entity",
+                strategy: AggregationStrategy::Simple,
+                logits: [o, b_per, i_org, i_org, o, o, o],
+                expected: &[("PER", 0, 2, 1.), ("ORG", 2, 10, 1.)],
+            },
+            Case {
+                name: "first labels words as their first token",
+                strategy: AggregationStrategy::First,
+                logits: [o, b_per, b_org, o, o, b_org, o],
+                expected: &[("PER", 0, 4, 1.), ("ORG", 14, 16, 1.)],
+            },
+            Case {
+                name: "first of a torn word",
+                strategy: AggregationStrategy::First,
+                logits: torn,
+                expected: &[("PER", 0, 4, 0.9)],
+            },
+            Case {
+                name: "max of a torn word",
+                strategy: AggregationStrategy::Max,
+                logits: torn,
+                expected: &[("PER", 0, 4, 0.9)],
+            },
+            Case {
+                name: "average of a torn word",
+                strategy: AggregationStrategy::Average,
+                logits: torn,
+                expected: &[("ORG", 0, 4, 0.475)],
+            },
+            Case {
+                name: "simple of a torn word",
+                strategy: AggregationStrategy::Simple,
+                logits: torn,
+                expected: &[("PER", 0, 2, 0.9), ("ORG", 2, 4, 0.85)],
+            },
+            Case {
+                // the tokens of the first word are as likely B-PER as
+                // I-PER, and the first of equals wins
+                name: "average of words continuing entities",
+                strategy: AggregationStrategy::Average,
+                logits: [o, b_per, i_per, i_per, o, i_org, o],
+                expected: &[("PER", 0, 10, 0.75), ("ORG", 14, 16, 1.)],
+            },
+            Case {
+                name: "none keeps every token",
+                strategy: AggregationStrategy::None,
+                logits: [o, b_per, i_per, o, o, b_org, o],
+                expected: &[
+                    ("B-PER", 0, 2, 1.),
+                    ("I-PER", 2, 4, 1.),
+                    ("B-ORG", 14, 16, 1.),
+                ],
+            },
+            Case {
+                name: "label groups runs of the same label",
+                strategy: AggregationStrategy::Label,
+                logits: [o, b_per, b_per, i_per, o, b_org, o],
+                expected: &[
+                    ("B-PER", 0, 4, 1.),
+                    ("I-PER", 5, 10, 1.),
+                    ("B-ORG", 14, 16, 1.),
+                ],
+            },
+        ]
+    }
+
+    #[test]
+    fn strategies_group_as_transformers() {
+        let id2label = LABELS
+            .iter()
+            .enumerate()
+            .map(|(i, label)| (i as i64, label.to_string()))
+            .collect::<HashMap<_, _>>();
+
+        for case in cases() {
+            let logits = Array2::from_shape_vec((7, 5), case.logits.concat())
+                .unwrap()
+                .into_dyn();
+            let aggregation = case.strategy.score_aggregation(ScoreAggregation::Mean);
+            let entities = group(
+                case.strategy,
+                aggregation,
+                &id2label,
+                &OFFSETS,
+                &WORD_IDS,
+                logits.view(),
+            )
+            .unwrap();
+
+            let found = entities
+                .iter()
+                .map(|e| (e.label.as_str(), e.start, e.end))
+                .collect::<Vec<_>>();
+            let expected = case
+                .expected
+                .iter()
+                .map(|&(label, start, end, _)| (label, start, end))
+                .collect::<Vec<_>>();
+            assert_eq!(found, expected, "{}", case.name);
+
+            for (entity, &(.., score)) in entities.iter().zip(case.expected) {
+                let mean = aggregation.finish(entity.score, entity.tokens);
+                assert!(
+                    (mean - score).abs() < 1e-3,
+                    "{}: {mean} != {score}",
+                    case.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn argmax_breaks_ties_by_the_first() {
+        assert_eq!(argmax([0.2, 0.4, 0.4, 0.1]), (1, 0.4));
+        assert_eq!(argmax([0.5, 0.5]), (0, 0.5));
+    }
+
+    #[test]
+    fn equal_logits_give_the_same_entities() {
+        let id2label = LABELS
+            .iter()
+            .enumerate()
+            .map(|(i, label)| (i as i64, label.to_string()))
+            .collect::<HashMap<_, _>>();
+        // every token as likely a person as an organization
+        let tie = probable(&[(1, 0.5), (3, 0.5)]);
+        let logits = Array2::from_shape_vec((7, 5), [tie; 7].concat())
+            .unwrap()
+            .into_dyn();
+        let group = || {
+            group(
+                AggregationStrategy::Simple,
+                ScoreAggregation::Mean,
+                &id2label,
+                &OFFSETS,
+                &WORD_IDS,
+                logits.view(),
+            )
+            .unwrap()
+        };
+
+        let spans = |entities: Vec<RawEntity>| {
+            entities
+                .into_iter()
+                .map(|e| (e.label, e.start, e.end, e.score.to_bits()))
+                .collect::<Vec<_>>()
+        };
+
+        let entities = spans(group());
+        assert!(entities.iter().all(|(label, ..)| label == "PER"));
+        for _ in 0..10 {
+            assert_eq!(spans(group()), entities);
+        }
+    }
+}
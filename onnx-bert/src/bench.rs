@@ -0,0 +1,180 @@
+//! Measuring how long every stage of the pipeline takes on synthetic
+//! sentences, so that capacity can be checked without a corpus.
+
+use std::time::{Duration, Instant};
+
+use crate::{Pipeline, Result};
+
+/// Words that the synthetic sentences are made up of.
+const WORDS: &[&str] = &[
+    "Idag",
+    "släpper",
+    "KB",
+    "tre",
+    "nya",
+    "språkmodeller",
+    "och",
+    "Kalle",
+    "Anka",
+    "bor",
+    "i",
+    "Ankeborg",
+    "med",
+    "sina",
+    "brorsöner",
+    "Knatte",
+    "Fnatte",
+    "Tjatte",
+];
+
+/// A sentence of `words` words.
+fn sentence(words: usize) -> String {
+    WORDS
+        .iter()
+        .cycle()
+        .take(words.max(1))
+        .copied()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// What [`Pipeline::benchmark`] measures.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenchOptions {
+    /// Unmeasured iterations run before every measurement.
+    pub warmup: usize,
+    pub iterations: usize,
+    /// Lengths of the sentences, in words.
+    pub lengths: Vec<usize>,
+    pub batch_sizes: Vec<usize>,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self {
+            warmup: 5,
+            iterations: 50,
+            lengths: vec![8, 32, 128],
+            batch_sizes: vec![1, 8, 32],
+        }
+    }
+}
+
+/// The distribution of a number of measurements.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BenchStats {
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl BenchStats {
+    /// Sort the samples and summarize them.
+    pub fn from_samples(samples: &mut [Duration]) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+
+        samples.sort();
+        let percentile = |p: f64| {
+            let i = ((samples.len() as f64 * p).ceil() as usize).clamp(1, samples.len());
+            samples[i - 1]
+        };
+        Self {
+            mean: samples.iter().sum::<Duration>() / samples.len() as u32,
+            p50: percentile(0.5),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+            max: samples[samples.len() - 1],
+        }
+    }
+}
+
+/// The measurements of a single sentence length and batch size.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BenchRun {
+    pub words: usize,
+    pub batch_size: usize,
+    pub tokenize: BenchStats,
+    pub infer: BenchStats,
+    pub postprocess: BenchStats,
+    pub total: BenchStats,
+    pub sentences_per_sec: f64,
+}
+
+/// The measurements of every combination of sentence length and batch size,
+/// in the order given.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BenchReport {
+    pub runs: Vec<BenchRun>,
+}
+
+impl Pipeline {
+    /// Measure the latency of tokenization, inference and postprocessing,
+    /// and the throughput, for every combination of sentence length and
+    /// batch size. Sentences are tokenized every time, bypassing the
+    /// encoding cache. This blocks for as long as it takes.
+    pub fn benchmark(&self, options: &BenchOptions) -> Result<BenchReport> {
+        let mut runs = Vec::with_capacity(options.lengths.len() * options.batch_sizes.len());
+
+        for &words in &options.lengths {
+            for &batch_size in &options.batch_sizes {
+                let batch = vec![sentence(words); batch_size.max(1)];
+
+                for _ in 0..options.warmup {
+                    self.time(&batch)?;
+                }
+
+                let iterations = options.iterations.max(1);
+                let mut tokenize = Vec::with_capacity(iterations);
+                let mut infer = Vec::with_capacity(iterations);
+                let mut postprocess = Vec::with_capacity(iterations);
+                let mut total = Vec::with_capacity(iterations);
+                let started = Instant::now();
+                for _ in 0..iterations {
+                    let [t, i, p] = self.time(&batch)?;
+                    tokenize.push(t);
+                    infer.push(i);
+                    postprocess.push(p);
+                    total.push(t + i + p);
+                }
+                let elapsed = started.elapsed();
+
+                runs.push(BenchRun {
+                    words,
+                    batch_size: batch.len(),
+                    tokenize: BenchStats::from_samples(&mut tokenize),
+                    infer: BenchStats::from_samples(&mut infer),
+                    postprocess: BenchStats::from_samples(&mut postprocess),
+                    total: BenchStats::from_samples(&mut total),
+                    sentences_per_sec: (iterations * batch.len()) as f64 / elapsed.as_secs_f64(),
+                });
+            }
+        }
+
+        Ok(BenchReport { runs })
+    }
+
+    /// Run the batch through the pipeline, returning how long tokenization,
+    /// inference and postprocessing took.
+    fn time(&self, batch: &[String]) -> Result<[Duration; 3]> {
+        let started = Instant::now();
+        let (encodings, lengths) = self.tokenize_with(batch, None)?;
+        let tokenized = Instant::now();
+        let outputs = self.infer(&encodings)?;
+        let inferred = Instant::now();
+        let logits = outputs[self.logits].to_array_view::<f32>()?;
+        self.postprocess(batch, &encodings, &lengths, logits)?;
+
+        Ok([
+            tokenized - started,
+            inferred - tokenized,
+            inferred.elapsed(),
+        ])
+    }
+}
@@ -0,0 +1,336 @@
+//! Configuring a [`Pipeline`] in one place before it's loaded.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    AggregationStrategy, EncodingCache, Error, KnowledgeBase, Limits, Padding, Pipeline,
+    PretrainedFiles, Resident, Result, ScoreAggregation, TokenizerOptions, TruncationStrategy,
+    WeightPrecision, WordSegmentation, README_FILE, TOKENIZER_CONFIG_FILES,
+};
+
+/// Where the files of the model are taken from.
+#[derive(Debug, Clone)]
+enum Source {
+    Files(PretrainedFiles),
+    #[cfg(feature = "remote")]
+    Pretrained {
+        model: String,
+        revision: String,
+    },
+}
+
+/// Builds a [`Pipeline`] from the files of a model, with the options of the
+/// tokenizer, loading and decoding. Everything not set is as the model was
+/// trained with, or as [`Pipeline`] defaults to.
+///
+/// The model is given either as a whole, by [`PipelineBuilder::files`] or
+/// [`PipelineBuilder::pretrained`], or file by file. Files given one by one
+/// take precedence over those of the whole.
+#[derive(Debug, Clone, Default)]
+pub struct PipelineBuilder {
+    source: Option<Source>,
+    config: Option<PathBuf>,
+    labels: Option<Vec<String>>,
+    tokenizer: Option<PathBuf>,
+    model: Option<PathBuf>,
+    tokenizer_options: TokenizerOptions,
+    weight_precision: WeightPrecision,
+    limits: Limits,
+    aggregation_strategy: AggregationStrategy,
+    score_aggregation: ScoreAggregation,
+    min_score: f32,
+    label_thresholds: HashMap<String, f32>,
+    snap_to_words: bool,
+    word_segmentation: WordSegmentation,
+    chunking: bool,
+    output: Option<String>,
+    /// The output of the classification head and its labels.
+    classifier: Option<(String, Vec<String>)>,
+    knowledge_base: Option<Arc<KnowledgeBase>>,
+    encoding_cache: Option<Arc<EncodingCache>>,
+}
+
+impl PipelineBuilder {
+    /// Take the files of the model from where they are.
+    pub fn files(self, files: PretrainedFiles) -> Self {
+        Self {
+            source: Some(Source::Files(files)),
+            ..self
+        }
+    }
+
+    /// Download the model from the Hugging Face Hub when the pipeline is
+    /// built, or reuse it if it's already cached.
+    #[cfg(feature = "remote")]
+    pub fn pretrained(self, model: impl Into<String>, revision: impl Into<String>) -> Self {
+        Self {
+            source: Some(Source::Pretrained {
+                model: model.into(),
+                revision: revision.into(),
+            }),
+            ..self
+        }
+    }
+
+    /// The `config.json` of the model. A `README.md` next to it is read as
+    /// the model card.
+    pub fn config(self, config: impl AsRef<Path>) -> Self {
+        Self {
+            config: Some(config.as_ref().to_owned()),
+            ..self
+        }
+    }
+
+    /// The labels of the model by index, instead of the `id2label` of its
+    /// `config.json`, which then isn't read. Without the serde feature the
+    /// `config.json` can't be read, so the labels have to be given.
+    pub fn labels(self, labels: Vec<String>) -> Self {
+        Self {
+            labels: Some(labels),
+            ..self
+        }
+    }
+
+    /// A `tokenizer.json`, or a WordPiece `vocab.txt` or SentencePiece
+    /// `.model` to assemble the tokenizer from. The files configuring the
+    /// tokenizer are read from next to it, with the serde feature.
+    pub fn tokenizer(self, tokenizer: impl AsRef<Path>) -> Self {
+        Self {
+            tokenizer: Some(tokenizer.as_ref().to_owned()),
+            ..self
+        }
+    }
+
+    /// The ONNX model.
+    pub fn model(self, model: impl AsRef<Path>) -> Self {
+        Self {
+            model: Some(model.as_ref().to_owned()),
+            ..self
+        }
+    }
+
+    /// Override how the tokenizer normalizes, truncates and pads text.
+    pub fn tokenizer_options(self, tokenizer_options: TokenizerOptions) -> Self {
+        Self {
+            tokenizer_options,
+            ..self
+        }
+    }
+
+    /// Number of tokens, including special tokens, that input is truncated
+    /// to, or split into windows of with [`PipelineBuilder::chunking`].
+    pub fn max_length(mut self, max_length: usize) -> Self {
+        self.tokenizer_options.max_length = Some(max_length);
+        self
+    }
+
+    pub fn truncation_strategy(mut self, truncation_strategy: TruncationStrategy) -> Self {
+        self.tokenizer_options.truncation_strategy = Some(truncation_strategy);
+        self
+    }
+
+    /// Number of tokens that the windows of long input overlap by.
+    pub fn stride(mut self, stride: usize) -> Self {
+        self.tokenizer_options.stride = Some(stride);
+        self
+    }
+
+    pub fn padding(mut self, padding: Padding) -> Self {
+        self.tokenizer_options.padding = Some(padding);
+        self
+    }
+
+    /// See [`Resident::with_weight_precision`].
+    pub fn weight_precision(self, weight_precision: WeightPrecision) -> Self {
+        Self {
+            weight_precision,
+            ..self
+        }
+    }
+
+    /// See [`Pipeline::with_limits`].
+    pub fn limits(self, limits: Limits) -> Self {
+        Self { limits, ..self }
+    }
+
+    /// See [`Pipeline::with_aggregation_strategy`].
+    pub fn aggregation(self, aggregation_strategy: AggregationStrategy) -> Self {
+        Self {
+            aggregation_strategy,
+            ..self
+        }
+    }
+
+    /// See [`Pipeline::with_score_aggregation`].
+    pub fn score_aggregation(self, score_aggregation: ScoreAggregation) -> Self {
+        Self {
+            score_aggregation,
+            ..self
+        }
+    }
+
+    /// See [`Pipeline::with_min_score`].
+    pub fn min_score(self, min_score: f32) -> Self {
+        Self { min_score, ..self }
+    }
+
+    /// See [`Pipeline::with_label_thresholds`].
+    pub fn label_thresholds(self, label_thresholds: HashMap<String, f32>) -> Self {
+        Self {
+            label_thresholds,
+            ..self
+        }
+    }
+
+    /// See [`Pipeline::with_snap_to_words`].
+    pub fn snap_to_words(self, snap_to_words: bool) -> Self {
+        Self {
+            snap_to_words,
+            ..self
+        }
+    }
+
+    /// See [`Pipeline::with_word_segmentation`].
+    pub fn word_segmentation(self, word_segmentation: WordSegmentation) -> Self {
+        Self {
+            word_segmentation,
+            ..self
+        }
+    }
+
+    /// See [`Pipeline::with_chunking`].
+    pub fn chunking(self, chunking: bool) -> Self {
+        Self { chunking, ..self }
+    }
+
+    /// See [`Pipeline::with_output`].
+    pub fn output(self, name: impl Into<String>) -> Self {
+        Self {
+            output: Some(name.into()),
+            ..self
+        }
+    }
+
+    /// See [`Pipeline::with_classifier`].
+    pub fn classifier(self, output: impl Into<String>, labels: Vec<String>) -> Self {
+        Self {
+            classifier: Some((output.into(), labels)),
+            ..self
+        }
+    }
+
+    /// See [`Pipeline::with_knowledge_base`].
+    pub fn knowledge_base(self, knowledge_base: Arc<KnowledgeBase>) -> Self {
+        Self {
+            knowledge_base: Some(knowledge_base),
+            ..self
+        }
+    }
+
+    /// See [`Pipeline::with_encoding_cache`].
+    pub fn encoding_cache(self, encoding_cache: Arc<EncodingCache>) -> Self {
+        Self {
+            encoding_cache: Some(encoding_cache),
+            ..self
+        }
+    }
+
+    /// The files of the model, downloading them if it's on the Hub.
+    fn pretrained_files(&self) -> Result<PretrainedFiles> {
+        let files = match &self.source {
+            Some(Source::Files(files)) => Some(files.clone()),
+            #[cfg(feature = "remote")]
+            Some(Source::Pretrained { model, revision }) => {
+                Some(crate::download_pretrained(model, revision)?)
+            }
+            None => None,
+        };
+
+        let Some(mut files) = files else {
+            let tokenizer = self
+                .tokenizer
+                .clone()
+                .ok_or(Error::MissingFile("tokenizer"))?;
+            // the config isn't read if the labels are given
+            let config = match (&self.config, &self.labels) {
+                (Some(config), _) => config.clone(),
+                (None, Some(_)) => tokenizer.with_file_name("config.json"),
+                (None, None) => return Err(Error::MissingFile("config")),
+            };
+            let model = self.model.clone().ok_or(Error::MissingFile("model"))?;
+            let optional = |file: &str| {
+                Some(tokenizer.with_file_name(file))
+                    .filter(|p| cfg!(feature = "serde") && p.exists())
+            };
+
+            return Ok(PretrainedFiles {
+                tokenizer_config: optional(TOKENIZER_CONFIG_FILES[0]),
+                special_tokens_map: optional(TOKENIZER_CONFIG_FILES[1]),
+                added_tokens: optional(TOKENIZER_CONFIG_FILES[2]),
+                readme: Some(config.with_file_name(README_FILE)).filter(|p| p.exists()),
+                config,
+                tokenizer,
+                model,
+            });
+        };
+
+        if let Some(config) = &self.config {
+            files.config = config.clone();
+        }
+        if let Some(tokenizer) = &self.tokenizer {
+            files.tokenizer = tokenizer.clone();
+        }
+        if let Some(model) = &self.model {
+            files.model = model.clone();
+        }
+        Ok(files)
+    }
+
+    /// Load the pipeline.
+    pub fn build(self) -> Result<Pipeline> {
+        let files = self.pretrained_files()?;
+        let resident = match self.labels {
+            Some(labels) => Resident::from_labels(&files, &self.tokenizer_options, labels)?,
+            #[cfg(feature = "serde")]
+            None => Resident::from_pretrained_files(&files, &self.tokenizer_options)?,
+            #[cfg(not(feature = "serde"))]
+            None => {
+                return Err(Error::Unsupported(
+                    "the labels have to be given without the serde feature".to_owned(),
+                ))
+            }
+        };
+        let pipeline = resident
+            .with_weight_precision(self.weight_precision)
+            .load_weights(&files.model)?
+            .with_limits(self.limits)
+            .with_aggregation_strategy(self.aggregation_strategy)
+            .with_score_aggregation(self.score_aggregation)
+            .with_min_score(self.min_score)
+            .with_label_thresholds(self.label_thresholds)
+            .with_snap_to_words(self.snap_to_words)
+            .with_word_segmentation(self.word_segmentation)
+            .with_chunking(self.chunking);
+        let pipeline = match self.output {
+            Some(output) => pipeline.with_output(output)?,
+            None => pipeline,
+        };
+        let pipeline = match self.classifier {
+            Some((output, labels)) => pipeline.with_classifier(output, labels)?,
+            None => pipeline,
+        };
+        let pipeline = match self.knowledge_base {
+            Some(knowledge_base) => pipeline.with_knowledge_base(knowledge_base),
+            None => pipeline,
+        };
+        let pipeline = match self.encoding_cache {
+            Some(encoding_cache) => pipeline.with_encoding_cache(encoding_cache)?,
+            None => pipeline,
+        };
+        Ok(pipeline)
+    }
+}
@@ -0,0 +1,51 @@
+//! A cache of encodings, which can be shared by pipelines so that sentences
+//! that are predicted again, by the same or another model, aren't tokenized
+//! again.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+
+use lru::LruCache;
+use tokenizers::{Encoding, Tokenizer};
+
+use crate::Result;
+
+/// LRU cache of encodings, keyed on the tokenizer and the sentence. The
+/// sentence is used as is, since the offsets of the encoding refer to it.
+#[derive(Debug)]
+pub struct EncodingCache {
+    inner: Mutex<LruCache<(u64, String), Encoding>>,
+}
+
+impl EncodingCache {
+    /// Create a cache holding up to `capacity` encodings.
+    pub fn new(capacity: NonZeroUsize) -> Arc<Self> {
+        Arc::new(Self {
+            inner: Mutex::new(LruCache::new(capacity)),
+        })
+    }
+
+    pub(crate) fn get(&self, tokenizer: u64, sentence: &str) -> Option<Encoding> {
+        let mut cache = self.inner.lock().unwrap();
+        cache.get(&(tokenizer, sentence.to_owned())).cloned()
+    }
+
+    pub(crate) fn put(&self, tokenizer: u64, sentence: &str, encoding: Encoding) {
+        self.inner
+            .lock()
+            .unwrap()
+            .put((tokenizer, sentence.to_owned()), encoding);
+    }
+}
+
+/// Hash of everything that determines how the tokenizer encodes a sentence,
+/// so that pipelines with identical tokenizers share their encodings.
+pub(crate) fn tokenizer_hash(tokenizer: &Tokenizer) -> Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    tokenizer.to_string(false)?.hash(&mut hasher);
+    Ok(hasher.finish())
+}
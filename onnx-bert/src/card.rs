@@ -0,0 +1,124 @@
+//! The metadata of a model card: the YAML front matter of the `README.md` of
+//! a repository on the Hugging Face Hub, and the same keys in `config.json`
+//! for models that don't ship a card.
+
+use std::collections::HashMap;
+
+#[cfg(feature = "serde")]
+use serde_json::Value;
+
+/// What a model is declared to be trained on and for, so that what is
+/// deployed can be audited. Everything is empty if the model doesn't say.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ModelCard {
+    /// Language codes, such as `en`.
+    pub languages: Vec<String>,
+    pub license: Option<String>,
+    /// Ids of the datasets on the Hugging Face Hub.
+    pub datasets: Vec<String>,
+    pub metrics: Vec<String>,
+}
+
+impl ModelCard {
+    /// Read the card from the `README.md` and fill in whatever it lacks from
+    /// the values of the keys of the `config.json`. Neither has to declare
+    /// anything.
+    pub(crate) fn parse(
+        readme: Option<&str>,
+        config: impl Fn(&str) -> Option<Vec<String>>,
+    ) -> Self {
+        let front_matter = readme.map(front_matter).unwrap_or_default();
+        let get = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|key| front_matter.get(*key).filter(|values| !values.is_empty()))
+                .cloned()
+                .or_else(|| keys.iter().find_map(|key| config(key)))
+                .unwrap_or_default()
+        };
+
+        Self {
+            languages: get(&["language", "languages"]),
+            license: get(&["license"]).into_iter().next(),
+            datasets: get(&["datasets", "dataset"]),
+            metrics: get(&["metrics"]),
+        }
+    }
+}
+
+/// A string or an array of strings.
+#[cfg(feature = "serde")]
+pub(crate) fn strings(value: &Value) -> Option<Vec<String>> {
+    match value {
+        Value::String(s) => Some(vec![s.clone()]),
+        Value::Array(values) => values
+            .iter()
+            .map(|value| value.as_str().map(ToOwned::to_owned))
+            .collect(),
+        _ => None,
+    }
+}
+
+/// The top-level keys of the front matter with their scalar or list values.
+/// Only as much YAML as model cards use is understood, and nested mappings
+/// such as `model-index` are skipped.
+fn front_matter(readme: &str) -> HashMap<String, Vec<String>> {
+    let mut keys = HashMap::new();
+    let mut lines = readme.lines();
+    if lines.next().map(str::trim_end) != Some("---") {
+        return keys;
+    }
+
+    let mut current: Option<&mut Vec<String>> = None;
+    for line in lines {
+        if line.trim_end() == "---" {
+            break;
+        }
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        if let Some(item) = trimmed.strip_prefix("- ") {
+            if let Some(values) = current.as_mut() {
+                values.push(unquote(item));
+            }
+            continue;
+        }
+        if line.starts_with(char::is_whitespace) {
+            // a nested mapping, which ends the list of the last key
+            current = None;
+            continue;
+        }
+
+        current = None;
+        let Some((key, value)) = trimmed.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        let values = keys.entry(key.trim().to_owned()).or_insert_with(Vec::new);
+        if let Some(flow) = value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+            values.extend(
+                flow.split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(unquote),
+            );
+        } else if !value.is_empty() {
+            values.push(unquote(value));
+        } else {
+            current = Some(values);
+        }
+    }
+
+    keys
+}
+
+fn unquote(s: &str) -> String {
+    let s = s.trim();
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .or_else(|| s.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')))
+        .unwrap_or(s)
+        .to_owned()
+}
@@ -0,0 +1,174 @@
+//! Merging the entities of the overlapping windows that sentences longer
+//! than the model takes are predicted in.
+
+use crate::Entity;
+
+/// Merge the entities of the windows of a sentence, keeping the longest of
+/// those that overlap, or the most confident of equally long ones, as
+/// Transformers does. Entities found by several windows are thereby kept
+/// once, and those cut off by the end of a window give way to the whole.
+pub(crate) fn merge(mut entities: Vec<Entity>) -> Vec<Entity> {
+    entities.sort_by(|a, b| (a.start, a.end, &a.label).cmp(&(b.start, b.end, &b.label)));
+
+    let mut merged: Vec<Entity> = Vec::with_capacity(entities.len());
+    for entity in entities {
+        match merged.last_mut() {
+            Some(prev) if entity.start < prev.end => {
+                let (len, prev_len) = (entity.end - entity.start, prev.end - prev.start);
+                if len > prev_len || (len == prev_len && entity.score > prev.score) {
+                    *prev = entity;
+                }
+            }
+            _ => merged.push(entity),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use tokenizers::Encoding;
+
+    use super::*;
+    use crate::{fixture, TokenizerOptions};
+
+    fn entity(label: &str, start: usize, end: usize, score: f32) -> Entity {
+        Entity {
+            label: label.to_owned(),
+            score,
+            word: String::new(),
+            start,
+            end,
+            start_word: 0,
+            end_word: 0,
+            candidates: vec![],
+        }
+    }
+
+    fn spans(entities: &[Entity]) -> Vec<(&str, usize, usize)> {
+        entities
+            .iter()
+            .map(|e| (e.label.as_str(), e.start, e.end))
+            .collect()
+    }
+
+    #[test]
+    fn entity_straddling_windows_is_kept_whole() {
+        // "Ada Lovelace wrote …", where the first window ends after "Ada"
+        // and the second starts before it
+        let merged = merge(vec![
+            entity("PER", 0, 3, 0.99),
+            entity("PER", 0, 12, 0.95),
+            entity("MISC", 23, 28, 0.7),
+        ]);
+        assert_eq!(spans(&merged), [("PER", 0, 12), ("MISC", 23, 28)]);
+    }
+
+    #[test]
+    fn entity_of_both_windows_is_kept_once() {
+        let merged = merge(vec![
+            entity("PER", 0, 12, 0.9),
+            entity("LOC", 40, 46, 0.8),
+            entity("PER", 0, 12, 0.95),
+            entity("LOC", 40, 46, 0.85),
+        ]);
+        assert_eq!(spans(&merged), [("PER", 0, 12), ("LOC", 40, 46)]);
+        assert_eq!(merged[0].score, 0.95);
+        assert_eq!(merged[1].score, 0.85);
+    }
+
+    #[test]
+    fn entity_of_final_window_is_kept() {
+        let merged = merge(vec![
+            entity("PER", 0, 12, 0.9),
+            entity("DATE", 40, 44, 0.9),
+            entity("PER", 4, 12, 0.6),
+        ]);
+        assert_eq!(spans(&merged), [("PER", 0, 12), ("DATE", 40, 44)]);
+    }
+
+    #[test]
+    fn merged_entities_are_the_same_in_any_order() {
+        let entities = [
+            entity("ORG", 20, 26, 0.8),
+            entity("LOC", 20, 26, 0.8),
+            entity("PER", 0, 12, 0.9),
+            entity("PER", 4, 12, 0.9),
+            entity("MISC", 30, 34, 0.5),
+        ];
+        let expected = [("PER", 0, 12), ("LOC", 20, 26), ("MISC", 30, 34)];
+        for shift in 0..entities.len() {
+            let mut entities = entities.to_vec();
+            entities.rotate_left(shift);
+            entities.reverse();
+            // equally long and confident entities are told apart by label
+            assert_eq!(spans(&merge(entities)), expected);
+        }
+    }
+
+    /// The windows of the sentence, as a WordPiece tokenizer taking six
+    /// tokens splits it.
+    fn windows(sentence: &str, stride: usize) -> Vec<Encoding> {
+        let tokenizer = fixture::tokenizer(&TokenizerOptions {
+            max_length: Some(6),
+            stride: Some(stride),
+            ..Default::default()
+        });
+        let mut encoding = tokenizer.encode(sentence, true).unwrap();
+        let overflowing = encoding.take_overflowing();
+        [encoding].into_iter().chain(overflowing).collect()
+    }
+
+    #[test]
+    fn offsets_of_final_window_are_into_the_sentence() {
+        let sentence = "Ada Lovelace wrote the first program in 1843.";
+        let windows = windows(sentence, 2);
+        assert!(windows.len() > 1);
+
+        for window in &windows {
+            assert!(window.len() <= 6);
+            for ((token, &(start, end)), word) in window
+                .get_tokens()
+                .iter()
+                .zip(window.get_offsets())
+                .zip(window.get_word_ids())
+            {
+                if word.is_some() {
+                    let text = sentence[start..end].to_lowercase();
+                    assert_eq!(text, token.trim_start_matches("##"));
+                }
+            }
+        }
+
+        let last = windows.last().unwrap();
+        let words = last
+            .get_offsets()
+            .iter()
+            .zip(last.get_word_ids())
+            .filter(|(_, word)| word.is_some())
+            .map(|(&(start, end), _)| &sentence[start..end])
+            .collect::<Vec<_>>();
+        assert_eq!(words.last(), Some(&"."));
+        assert!(words.contains(&"1843"));
+    }
+
+    #[test]
+    fn windows_overlap_by_the_stride() {
+        let sentence = "Ada Lovelace wrote the first program in 1843.";
+        let windows = windows(sentence, 2);
+        let content = |window: &Encoding| {
+            window
+                .get_offsets()
+                .iter()
+                .zip(window.get_word_ids())
+                .filter(|(_, word)| word.is_some())
+                .map(|(&offsets, _)| offsets)
+                .collect::<Vec<_>>()
+        };
+
+        for pair in windows.windows(2) {
+            let (prev, next) = (content(&pair[0]), content(&pair[1]));
+            assert_eq!(prev[prev.len() - 2..], next[..2]);
+        }
+    }
+}
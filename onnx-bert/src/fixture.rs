@@ -0,0 +1,27 @@
+//! The tokenizer that the tests share, assembled from a small WordPiece
+//! vocabulary of the words they tokenize.
+
+use std::path::Path;
+
+use tokenizers::Tokenizer;
+
+use crate::{tokenizer, PretrainedFiles, TokenizerOptions};
+
+/// The files of a model that only has a vocabulary.
+pub(crate) fn files() -> PretrainedFiles {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    PretrainedFiles {
+        config: dir.join("config.json"),
+        tokenizer: dir.join("vocab.txt"),
+        tokenizer_config: None,
+        special_tokens_map: None,
+        added_tokens: None,
+        readme: None,
+        model: dir.join("model.onnx"),
+    }
+}
+
+/// The tokenizer of the vocabulary, configured by the options.
+pub(crate) fn tokenizer(options: &TokenizerOptions) -> Tokenizer {
+    tokenizer::load(&files(), options).unwrap()
+}
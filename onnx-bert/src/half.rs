@@ -0,0 +1,115 @@
+//! Converting a model to half-precision floats, keeping its inputs and
+//! outputs as they were. tract only partly supports this: its own translator
+//! leaves the constants and casts of models that take token ids as 32-bit
+//! floats, and some operations, such as the `Erf` of GELU, can't run on
+//! half-precision floats at all.
+
+use std::collections::HashMap;
+
+use tract_onnx::{
+    prelude::{
+        f16, tensor0, tvec, DatumType, IntoTValue, OutletId, TVec, Tensor, TractResult, TypedFact,
+        TypedModel, TypedNode, TypedOp,
+    },
+    tract_core::{
+        half::HalfTranslator,
+        model::{translator::Translate, SpecialOps},
+        ops::{
+            cast::{cast, Cast},
+            element_wise::ElementWiseOp,
+            konst::Const,
+            source::TypedSource,
+            EvalOp,
+        },
+    },
+};
+
+/// The largest finite half-precision float.
+const F16_MAX: f32 = 65504.;
+
+#[derive(Debug)]
+struct Half;
+
+impl Translate<TypedFact, Box<dyn TypedOp>, TypedFact, Box<dyn TypedOp>> for Half {
+    fn translate_node(
+        &self,
+        source: &TypedModel,
+        node: &TypedNode,
+        target: &mut TypedModel,
+        mapping: &HashMap<OutletId, OutletId>,
+    ) -> TractResult<TVec<OutletId>> {
+        let inputs: TVec<OutletId> = node.inputs.iter().map(|i| mapping[i]).collect();
+
+        if let Some(op) = node.op_as::<TypedSource>() {
+            if op.fact.datum_type == DatumType::F32 {
+                // the inputs are still given as 32-bit floats
+                let wire = target.wire_node(&node.name, op.clone(), &[])?;
+                let name = &node.name;
+                return target.wire_node(format!("{name}.f16"), cast(DatumType::F16), &wire);
+            }
+        } else if let Some(Const(tensor)) = node.op_as::<Const>() {
+            if tensor.datum_type() == DatumType::F32 {
+                return target.wire_node(&node.name, Const(saturate(tensor)?.into()), &[]);
+            }
+        } else if let Some(op) = node.op_as::<Cast>() {
+            if op.to == DatumType::F32 {
+                return target.wire_node(&node.name, cast(DatumType::F16), &inputs);
+            }
+        } else if let Some(op) = node.op_as::<ElementWiseOp>() {
+            let probe = tvec!(tensor0(f16::from_f32(0.)).into_tvalue());
+            let unsupported = op.eval(probe).is_err();
+            if unsupported && target.outlet_fact(inputs[0])?.datum_type == DatumType::F16 {
+                let name = &node.name;
+                let wire =
+                    target.wire_node(format!("{name}.f32"), cast(DatumType::F32), &inputs)?;
+                let wire = target.wire_node(name, node.op.clone(), &wire)?;
+                return target.wire_node(format!("{name}.f16"), cast(DatumType::F16), &wire);
+            }
+        }
+
+        HalfTranslator.translate_node(source, node, target, mapping)
+    }
+}
+
+/// Convert the tensor to half precision, clamping values out of range, such
+/// as the smallest float that attention masks are often filled with, rather
+/// than letting them overflow to infinity.
+fn saturate(tensor: &Tensor) -> TractResult<Tensor> {
+    let mut tensor = tensor.clone();
+    for x in tensor.as_slice_mut::<f32>()? {
+        *x = x.clamp(-F16_MAX, F16_MAX);
+    }
+    Ok(tensor.cast_to::<f16>()?.into_owned())
+}
+
+/// Convert the weights and computations of the decluttered model to half
+/// precision, casting the outputs back so that they can be read like those
+/// of any other model.
+pub(crate) fn convert(model: &TypedModel) -> TractResult<TypedModel> {
+    let mut model = Half.translate_model(model)?;
+    // the inputs were mapped to the casts that follow them
+    let inputs: Vec<OutletId> = model
+        .input_outlets()?
+        .iter()
+        .map(|&input| match model.node(input.node).op_is::<Cast>() {
+            true => model.node(input.node).inputs[0],
+            false => input,
+        })
+        .collect();
+    model.set_input_outlets(&inputs)?;
+
+    let mut outputs = model.output_outlets()?.to_vec();
+    for output in &mut outputs {
+        if model.outlet_fact(*output)?.datum_type != DatumType::F16 {
+            continue;
+        }
+        let name = model
+            .outlet_label(*output)
+            .unwrap_or(&model.node(output.node).name)
+            .to_owned();
+        *output = model.wire_node(format!("{name}.f32"), cast(DatumType::F32), &[*output])?[0];
+        model.set_outlet_label(*output, name)?;
+    }
+    model.set_output_outlets(&outputs)?;
+    Ok(model)
+}
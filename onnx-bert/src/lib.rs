@@ -1,70 +1,789 @@
-use std::{collections::HashMap, fmt::Debug, fs::File, io::BufReader, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    fs::{self, File},
+    io::Read,
+    mem,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+#[cfg(feature = "serde")]
+use std::io::BufReader;
 
-use serde::{Deserialize, Serialize};
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde_json::Value;
 use thiserror::Error;
-use tokenizers::{EncodeInput, Tokenizer};
+use tokenizers::{
+    utils::{
+        padding::{pad_encodings, PaddingParams, PaddingStrategy},
+        parallelism::MaybeParallelIterator,
+    },
+    EncodeInput, Encoding, Tokenizer,
+};
 #[cfg(feature = "tracing")]
 use tracing::{debug, instrument};
 use tract_onnx::{
-    prelude::{tvec, Framework, Graph, InferenceModelExt, SimplePlan, Tensor, TypedFact, TypedOp},
-    tract_hir::tract_ndarray::{Array2, ShapeError},
+    prelude::{
+        tvec, DatumType, Framework, Graph, InferenceModelExt, SimplePlan, TValue, TVec, Tensor,
+        TypedFact, TypedOp,
+    },
+    tract_hir::tract_ndarray::{Array2, ArrayViewD, Axis, ShapeError},
 };
 
+mod aggregate;
+mod bench;
+mod builder;
+mod cache;
+mod card;
+mod chunk;
+#[cfg(test)]
+mod fixture;
+mod half;
+mod link;
+mod output;
+mod pool;
 #[cfg(feature = "remote")]
 mod remote;
+mod segment;
+mod sentencepiece;
+mod snap;
+mod tags;
+mod tokenizer;
+#[cfg(feature = "serde")]
+mod validate;
+
+pub use aggregate::AggregationStrategy;
+pub use bench::{BenchOptions, BenchReport, BenchRun, BenchStats};
+pub use builder::PipelineBuilder;
+pub use cache::EncodingCache;
+pub use card::ModelCard;
+pub use link::{Candidate, KnowledgeBase};
+#[cfg(feature = "remote")]
+pub use remote::CachedFile;
+pub use segment::WordSegmentation;
+pub use tags::{iob_tags, TaggedWord};
+pub use tokenizer::{Padding, TokenizerOptions, TruncationStrategy, UnicodeNormalization};
+#[cfg(feature = "serde")]
+pub use validate::{validate, Check};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Entity {
     pub label: String,
     pub score: f32,
     pub word: String,
     pub start: usize,
     pub end: usize,
+    /// Index of the first word of the entity, as configured by
+    /// [`Pipeline::with_word_segmentation`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub start_word: usize,
+    /// Index of the word after the last one of the entity.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub end_word: usize,
+    /// What the entity may refer to in the knowledge base configured by
+    /// [`Pipeline::with_knowledge_base`], most likely first.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub candidates: Vec<Candidate>,
+}
+
+/// A token of a sentence and how it's classified, before the tokens are
+/// grouped into entities.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TokenPrediction {
+    pub token: String,
+    /// The most likely label of the token.
+    pub label: String,
+    pub score: f32,
+    /// The probability of every label, in the order of [`Pipeline::labels`].
+    pub scores: Vec<f32>,
+    pub start: usize,
+    pub end: usize,
+    /// Index of the word of the token, as split by the pre-tokenizer, unless
+    /// it's a special token.
+    pub word: Option<usize>,
+}
+
+/// The entities of a sentence predicted by
+/// [`Pipeline::predict_batch_bucketed`], and how it was run through the
+/// model.
+#[derive(Debug, Clone, Default)]
+pub struct Predicted {
+    pub entities: Vec<Entity>,
+    /// Number of tokens, special tokens included, that the sentence was run
+    /// through the model as. In windows, the tokens of every window count.
+    pub tokens: usize,
+    /// Whether the end of the sentence was truncated away, and has no
+    /// entities because the model never saw it. In windows, that's when its
+    /// last windows don't fit the [`Limits`] of the batch.
+    pub truncated: bool,
+    /// Number of words that the model saw, as configured by
+    /// [`Pipeline::with_word_segmentation`], which is what the word indices
+    /// of the entities count up to.
+    pub words: usize,
+}
+
+/// A class that a whole sentence is assigned by a classification head.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Class {
+    pub label: String,
+    pub score: f32,
+}
+
+/// Optional files configuring the tokenizer, whichever file it is loaded
+/// from.
+const TOKENIZER_CONFIG_FILES: [&str; 3] = [
+    "tokenizer_config.json",
+    "special_tokens_map.json",
+    "added_tokens.json",
+];
+
+/// The files that the tokenizer can be loaded from, in order of preference.
+const TOKENIZER_FILES: [&str; 4] = [
+    "tokenizer.json",
+    "vocab.txt",
+    "sentencepiece.bpe.model",
+    "spm.model",
+];
+
+/// The model card of a repository on the Hugging Face Hub.
+const README_FILE: &str = "README.md";
+
+/// Local paths of the files making up a pretrained model.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PretrainedFiles {
+    pub config: PathBuf,
+    /// A `tokenizer.json`, or a WordPiece `vocab.txt` or SentencePiece
+    /// `.model` to assemble the tokenizer from.
+    pub tokenizer: PathBuf,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tokenizer_config: Option<PathBuf>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub special_tokens_map: Option<PathBuf>,
+    /// Tokens added to the vocabulary during fine-tuning, by id.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub added_tokens: Option<PathBuf>,
+    /// The model card, whose front matter describes the model.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub readme: Option<PathBuf>,
+    pub model: PathBuf,
+}
+
+impl PretrainedFiles {
+    /// The files of a model in a local directory, laid out like a repository
+    /// on the Hugging Face Hub.
+    pub fn from_dir(dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref();
+        let optional = |file: &str| Some(dir.join(file)).filter(|path| path.exists());
+
+        Self {
+            config: dir.join("config.json"),
+            tokenizer: TOKENIZER_FILES
+                .iter()
+                .map(|file| dir.join(file))
+                .find(|path| path.exists())
+                .unwrap_or_else(|| dir.join(TOKENIZER_FILES[0])),
+            tokenizer_config: optional(TOKENIZER_CONFIG_FILES[0]),
+            special_tokens_map: optional(TOKENIZER_CONFIG_FILES[1]),
+            added_tokens: optional(TOKENIZER_CONFIG_FILES[2]),
+            readme: optional(README_FILE),
+            model: dir.join("model.onnx"),
+        }
+    }
+}
+
+/// Download the files of a model on the Hugging Face Hub, or reuse them if
+/// they are already cached.
+#[cfg(feature = "remote")]
+#[cfg_attr(feature = "tracing", instrument)]
+pub fn download_pretrained(model: &str, revision: &str) -> Result<PretrainedFiles> {
+    let download_file = |file: &str| {
+        #[cfg(feature = "tracing")]
+        debug!(%file, "downloading file");
+        remote::download(format!(
+            "https://huggingface.co/{model}/resolve/{revision}/{file}"
+        ))
+    };
+
+    // older repositories only have the vocabulary or SentencePiece model
+    let tokenizer = match download_file(TOKENIZER_FILES[0]) {
+        Ok(tokenizer) => tokenizer,
+        Err(e) => TOKENIZER_FILES[1..]
+            .iter()
+            .find_map(|file| download_file(file).ok())
+            .ok_or(e)?,
+    };
+
+    Ok(PretrainedFiles {
+        config: download_file("config.json")?,
+        tokenizer,
+        tokenizer_config: download_file(TOKENIZER_CONFIG_FILES[0]).ok(),
+        special_tokens_map: download_file(TOKENIZER_CONFIG_FILES[1]).ok(),
+        added_tokens: download_file(TOKENIZER_CONFIG_FILES[2]).ok(),
+        readme: download_file(README_FILE).ok(),
+        model: download_file("model.onnx")?,
+    })
+}
+
+/// Resolve a branch or tag of a model on the Hugging Face Hub to the hash of
+/// the commit it currently points to.
+#[cfg(feature = "remote")]
+pub fn resolve_revision(model: &str, revision: &str) -> Result<String> {
+    remote::resolve_revision(model, revision)
 }
 
+/// The files that have been downloaded to the local cache.
+#[cfg(feature = "remote")]
+pub fn cached_files() -> Result<Vec<CachedFile>> {
+    remote::cached_files()
+}
+
+/// An [`EncodingCache`] along with the hash of the tokenizer that it's used
+/// with.
+type SharedCache = (Arc<EncodingCache>, u64);
+
+/// Clones share the weights of the model.
+#[derive(Clone)]
 pub struct Pipeline {
-    tokenizer: Tokenizer,
+    tokenizer: Arc<Tokenizer>,
     config: Config,
+    /// Always loaded, unless the pipeline is wrapped in a [`Resident`].
+    weights: Option<Arc<Weights>>,
+    encoding_cache: Option<SharedCache>,
+    /// Name of the output to take the logits from, instead of guessing.
+    output: Option<String>,
+    /// Index of the output with the logits, as of when the weights were
+    /// last loaded.
+    logits: usize,
+    /// The classification head, if any, and the index of its output.
+    classifier: Option<(Classifier, usize)>,
+    limits: Limits,
+    aggregation_strategy: AggregationStrategy,
+    score_aggregation: ScoreAggregation,
+    weight_precision: WeightPrecision,
+    knowledge_base: Option<Arc<KnowledgeBase>>,
+    /// Whether the spans of entities are snapped to whole words.
+    snap_to_words: bool,
+    /// Minimum score of the entities of every label.
+    label_thresholds: HashMap<String, f32>,
+    /// Minimum score of the entities of labels without a threshold.
+    min_score: f32,
+    /// Whether the truncated parts of long sentences are predicted too.
+    chunking: bool,
+    /// Whether the tokenizer composes characters, so that the offsets of
+    /// tokens are extended over the marks that it composes away.
+    align_combining_marks: bool,
+    word_segmentation: WordSegmentation,
+    model_card: ModelCard,
+}
+
+/// Hard limits on the input of a single call, so that a mistake can't build
+/// tensors large enough to run the process out of memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum number of sentences in a batch, or of windows with
+    /// [`Pipeline::with_chunking`].
+    pub max_batch_size: Option<usize>,
+    /// Maximum number of tokens in a batch, padding included.
+    pub max_tokens: Option<usize>,
+}
+
+impl Limits {
+    fn check(what: &'static str, actual: usize, limit: Option<usize>) -> Result<()> {
+        match limit {
+            Some(limit) if actual > limit => Err(Error::LimitExceeded {
+                what,
+                actual,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How the scores of the tokens of an entity are combined into its score.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum ScoreAggregation {
+    /// The score of the most confident token, which overstates the
+    /// confidence in long entities.
+    #[default]
+    Max,
+    Mean,
+    Min,
+    /// The product of the scores, approximating the probability that every
+    /// token is labeled correctly.
+    Product,
+}
+
+impl ScoreAggregation {
+    fn merge(self, acc: f32, score: f32) -> f32 {
+        match self {
+            Self::Max => acc.max(score),
+            Self::Mean => acc + score,
+            Self::Min => acc.min(score),
+            Self::Product => acc * score,
+        }
+    }
+
+    fn finish(self, acc: f32, tokens: usize) -> f32 {
+        match self {
+            Self::Mean => acc / tokens as f32,
+            Self::Max | Self::Min | Self::Product => acc,
+        }
+    }
+}
+
+impl FromStr for ScoreAggregation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "max" => Ok(Self::Max),
+            "mean" => Ok(Self::Mean),
+            "min" => Ok(Self::Min),
+            "product" => Ok(Self::Product),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What the weights of the model are stored as once loaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum WeightPrecision {
+    /// As exported, which is almost always 32-bit floats.
+    #[default]
+    F32,
+    /// Half-precision floats, which take half the memory at the cost of a
+    /// slower load and slightly less accurate logits. The outputs are still
+    /// 32-bit floats. tract can't compress weights to 8-bit integers, so
+    /// models have to be quantized when exported for that.
+    F16,
+}
+
+impl FromStr for WeightPrecision {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "f32" => Ok(Self::F32),
+            "f16" => Ok(Self::F16),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A classification head of a multi-head model, served along with the token
+/// classification.
+#[derive(Debug, Clone)]
+struct Classifier {
+    output: String,
+    labels: Vec<String>,
+}
+
+/// The model weights, which a [`Resident`] lets go of.
+struct Weights {
     model: Model,
+    size: u64,
+    /// What runs of the model leave behind for the next.
+    states: pool::StatePool,
+}
+
+/// The parts of a [`Pipeline`] that are cheap to keep in memory: everything
+/// but the model weights.
+#[derive(Clone)]
+pub struct Resident(Pipeline);
+
+impl Resident {
+    /// Load everything but the weights of the pipeline, overriding how the
+    /// tokenizer normalizes and truncates text.
+    #[cfg(feature = "serde")]
+    pub fn from_pretrained_files(
+        files: &PretrainedFiles,
+        options: &TokenizerOptions,
+    ) -> Result<Self> {
+        #[cfg(feature = "tracing")]
+        debug!("constructing model");
+        let config: Value = serde_json::from_reader(BufReader::new(File::open(&files.config)?))?;
+        let readme = read_readme(files)?;
+        let model_card = ModelCard::parse(readme.as_deref(), |key| card::strings(config.get(key)?));
+        let config = Config::deserialize(config)?;
+        Self::new(files, options, config, model_card)
+    }
+
+    /// Like [`Resident::from_pretrained_files`], but with the labels of the
+    /// model given by index instead of read from its `config.json`, which
+    /// isn't read at all.
+    pub fn from_labels(
+        files: &PretrainedFiles,
+        options: &TokenizerOptions,
+        labels: Vec<String>,
+    ) -> Result<Self> {
+        let readme = read_readme(files)?;
+        let model_card = ModelCard::parse(readme.as_deref(), |_| None);
+        let config = Config {
+            id2label: (0..).zip(labels).collect(),
+        };
+        Self::new(files, options, config, model_card)
+    }
+
+    fn new(
+        files: &PretrainedFiles,
+        options: &TokenizerOptions,
+        config: Config,
+        model_card: ModelCard,
+    ) -> Result<Self> {
+        let tokenizer = Arc::new(tokenizer::load(files, options)?);
+
+        Ok(Self(Pipeline {
+            tokenizer,
+            config,
+            weights: None,
+            encoding_cache: None,
+            output: None,
+            logits: 0,
+            classifier: None,
+            limits: Limits::default(),
+            aggregation_strategy: AggregationStrategy::default(),
+            score_aggregation: ScoreAggregation::default(),
+            weight_precision: WeightPrecision::default(),
+            knowledge_base: None,
+            snap_to_words: false,
+            label_thresholds: HashMap::new(),
+            min_score: 0.,
+            chunking: false,
+            align_combining_marks: options.unicode_normalization.is_some(),
+            word_segmentation: WordSegmentation::default(),
+            model_card,
+        }))
+    }
+
+    /// Convert the weights to the precision whenever they are loaded.
+    pub fn with_weight_precision(self, weight_precision: WeightPrecision) -> Self {
+        Self(Pipeline {
+            weight_precision,
+            ..self.0
+        })
+    }
+
+    /// Load the weights again, turning this back into a [`Pipeline`].
+    pub fn load_weights(self, model: impl AsRef<Path>) -> Result<Pipeline> {
+        let pipeline = self.0;
+        let (model, size) = load_model(model, pipeline.weight_precision)?;
+        let logits = output::logits(
+            model.model(),
+            pipeline.output.as_deref(),
+            Some(pipeline.config.id2label.len()),
+        )?;
+        let classifier = pipeline
+            .classifier
+            .map(|(classifier, _)| {
+                let i =
+                    output::classes(model.model(), &classifier.output, classifier.labels.len())?;
+                Ok::<_, Error>((classifier, i))
+            })
+            .transpose()?;
+        Ok(Pipeline {
+            weights: Some(Arc::new(Weights {
+                model,
+                size,
+                states: pool::StatePool::new(),
+            })),
+            logits,
+            classifier,
+            ..pipeline
+        })
+    }
+}
+
+fn read_readme(files: &PretrainedFiles) -> Result<Option<String>> {
+    match &files.readme {
+        Some(readme) => Ok(Some(fs::read_to_string(readme)?)),
+        None => Ok(None),
+    }
 }
 
 type Model = SimplePlan<TypedFact, Box<dyn TypedOp>, Graph<TypedFact, Box<dyn TypedOp>>>;
 
-#[derive(Debug, Deserialize)]
+/// File identifier of ONNX Runtime's own format, which tract can't read.
+const ORT_IDENTIFIER: &[u8; 4] = b"ORTM";
+
+/// Fail early, with a clear error, on models that tract can't read.
+fn check_format(model: &Path) -> Result<()> {
+    // the identifier of a flatbuffer follows the offset of its root table
+    let mut header = [0; 8];
+    let read = File::open(model).and_then(|mut file| file.read_exact(&mut header));
+    if read.is_ok() && &header[4..] == ORT_IDENTIFIER {
+        return Err(Error::Unsupported(
+            "models in the ORT format aren't supported, convert them to ONNX".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+fn load_model(model: impl AsRef<Path>, precision: WeightPrecision) -> Result<(Model, u64)> {
+    let model_size = std::fs::metadata(model.as_ref())?.len();
+    check_format(model.as_ref())?;
+    let mut model = tract_onnx::onnx().model_for_path(model)?.into_typed()?;
+    let model_size = match precision {
+        WeightPrecision::F32 => model_size,
+        WeightPrecision::F16 => {
+            model = half::convert(&model.into_decluttered()?)?;
+            model_size / 2
+        }
+    };
+    let model = model.into_optimized()?.into_runnable()?;
+    Ok((model, model_size))
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
 struct Config {
     id2label: HashMap<i64, String>,
 }
 
 #[derive(Debug)]
 struct RawEntity {
-    label: i64,
+    label: String,
+    /// The scores of the tokens, or words, combined so far.
     score: f32,
+    /// Number of tokens, or words, whose scores are combined.
+    tokens: usize,
     start: usize,
     end: usize,
+    /// First and last word of the entity, unless it's only special tokens.
+    words: Option<(u32, u32)>,
 }
 
 impl Pipeline {
+    /// Configure the pipeline to load, including how text is tokenized and
+    /// how entities are decoded.
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder::default()
+    }
+
+    /// Load the pipeline from a `tokenizer.json`, `vocab.txt` or
+    /// SentencePiece model, along with the `tokenizer_config.json`,
+    /// `special_tokens_map.json` and `added_tokens.json` next to it, and the
+    /// `README.md` next to the config, if any.
+    #[cfg(feature = "serde")]
     pub fn from_files(
         config: impl AsRef<Path>,
         tokenizer: impl AsRef<Path>,
         model: impl AsRef<Path>,
     ) -> Result<Self> {
-        #[cfg(feature = "tracing")]
-        debug!("constructing model");
-        let config: Config = serde_json::from_reader(BufReader::new(File::open(config)?))?;
-        let tokenizer = Tokenizer::from_file(tokenizer)?;
-        let model = tract_onnx::onnx()
-            .model_for_path(model)?
-            .into_optimized()?
-            .into_runnable()?;
+        Self::builder()
+            .config(config)
+            .tokenizer(tokenizer)
+            .model(model)
+            .build()
+    }
+
+    /// Drop the model weights, which make up most of the memory used by the
+    /// pipeline, but keep the tokenizer and configuration.
+    pub fn unload_weights(self) -> Resident {
+        Resident(Self {
+            weights: None,
+            ..self
+        })
+    }
+
+    fn weights(&self) -> &Weights {
+        self.weights
+            .as_ref()
+            .expect("the weights of a pipeline are loaded")
+    }
+
+    /// Size of the ONNX model weights in bytes, halved if they are stored as
+    /// half-precision floats.
+    pub fn model_size(&self) -> u64 {
+        self.weights().size
+    }
+
+    /// Approximate number of bytes occupied by the tokenizer vocabulary.
+    pub fn tokenizer_size(&self) -> u64 {
+        // every token is stored twice: once in the vocab and once in the reverse lookup
+        let entry = 2 * std::mem::size_of::<(String, u32)>();
+        self.tokenizer
+            .get_vocab(true)
+            .keys()
+            .map(|token| (entry + 2 * token.len()) as u64)
+            .sum()
+    }
 
+    /// Look up the encodings of sentences in the cache, which may be shared
+    /// with other pipelines, before tokenizing them.
+    pub fn with_encoding_cache(self, cache: Arc<EncodingCache>) -> Result<Self> {
+        let hash = cache::tokenizer_hash(&self.tokenizer)?;
         Ok(Self {
-            tokenizer,
-            config,
-            model,
+            encoding_cache: Some((cache, hash)),
+            ..self
+        })
+    }
+
+    /// Take the logits from the output with the name, rather than from the
+    /// one named `logits` or shaped like logits. The output must have as
+    /// many labels as the config.
+    pub fn with_output(self, name: impl Into<String>) -> Result<Self> {
+        let name = name.into();
+        let logits = output::logits(
+            self.weights().model.model(),
+            Some(&name),
+            Some(self.config.id2label.len()),
+        )?;
+        Ok(Self {
+            output: Some(name),
+            logits,
+            ..self
+        })
+    }
+
+    /// Also classify whole sentences, using the logits of the output with
+    /// the name, over the labels in order. This lets a single multi-head
+    /// model serve both tasks.
+    pub fn with_classifier(self, output: impl Into<String>, labels: Vec<String>) -> Result<Self> {
+        let classifier = Classifier {
+            output: output.into(),
+            labels,
+        };
+        let i = output::classes(
+            self.weights().model.model(),
+            &classifier.output,
+            classifier.labels.len(),
+        )?;
+        Ok(Self {
+            classifier: Some((classifier, i)),
+            ..self
         })
     }
 
+    /// Reject calls whose input exceeds the limits with
+    /// [`Error::LimitExceeded`].
+    pub fn with_limits(self, limits: Limits) -> Self {
+        Self { limits, ..self }
+    }
+
+    /// Group the tokens into entities as given, rather than by runs of the
+    /// same label.
+    pub fn with_aggregation_strategy(self, aggregation_strategy: AggregationStrategy) -> Self {
+        Self {
+            aggregation_strategy,
+            ..self
+        }
+    }
+
+    /// Combine the scores of the tokens of every entity as given, rather
+    /// than taking the highest. This only applies to
+    /// [`AggregationStrategy::Label`], as the others take the mean.
+    pub fn with_score_aggregation(self, score_aggregation: ScoreAggregation) -> Self {
+        Self {
+            score_aggregation,
+            ..self
+        }
+    }
+
+    /// Link the entities to the entries of the knowledge base that their
+    /// words are aliases of.
+    pub fn with_knowledge_base(self, knowledge_base: Arc<KnowledgeBase>) -> Self {
+        Self {
+            knowledge_base: Some(knowledge_base),
+            ..self
+        }
+    }
+
+    /// Expand the spans of entities that start or end within a word to the
+    /// whole word, and trim whitespace and punctuation from their ends, so
+    /// that they can be highlighted. Entities that end up overlapping are
+    /// merged if they have the same label, and otherwise the more confident
+    /// one is kept.
+    pub fn with_snap_to_words(self, snap_to_words: bool) -> Self {
+        Self {
+            snap_to_words,
+            ..self
+        }
+    }
+
+    /// Drop the entities scoring below the threshold of their label, after
+    /// the scores of their tokens are combined. Labels without a threshold
+    /// are subject to [`Pipeline::with_min_score`].
+    pub fn with_label_thresholds(self, label_thresholds: HashMap<String, f32>) -> Self {
+        Self {
+            label_thresholds,
+            ..self
+        }
+    }
+
+    /// Drop the entities scoring below the minimum, unless their label has a
+    /// threshold of its own. Entities are filtered once they are grouped,
+    /// and snapped if configured, so it's the score of the whole entity
+    /// that counts.
+    pub fn with_min_score(self, min_score: f32) -> Self {
+        Self { min_score, ..self }
+    }
+
+    /// Predict sentences longer than the tokenizer truncates to in windows
+    /// overlapping by the stride of the tokenizer, rather than dropping
+    /// what doesn't fit in the first. The entities that windows share are
+    /// merged. Without a stride, [`TokenizerOptions::stride`], entities may
+    /// be split where one window ends and the next begins.
+    pub fn with_chunking(self, chunking: bool) -> Self {
+        Self { chunking, ..self }
+    }
+
+    /// Index the words of entities, and tag them, as split by the
+    /// segmentation rather than the pre-tokenizer.
+    pub fn with_word_segmentation(self, word_segmentation: WordSegmentation) -> Self {
+        Self {
+            word_segmentation,
+            ..self
+        }
+    }
+
+    /// The metadata declared by the model card.
+    pub fn model_card(&self) -> &ModelCard {
+        &self.model_card
+    }
+
+    /// Names of the outputs of the model.
+    pub fn outputs(&self) -> Result<Vec<String>> {
+        let names = output::names(self.weights().model.model())?;
+        Ok(names.into_iter().map(ToOwned::to_owned).collect())
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn from_pretrained_files(files: &PretrainedFiles) -> Result<Self> {
+        Self::builder().files(files.clone()).build()
+    }
+
+    /// Load the pipeline, overriding how the tokenizer normalizes and
+    /// truncates text.
+    #[cfg(feature = "serde")]
+    pub fn from_pretrained_files_with(
+        files: &PretrainedFiles,
+        options: &TokenizerOptions,
+    ) -> Result<Self> {
+        Self::builder()
+            .files(files.clone())
+            .tokenizer_options(*options)
+            .build()
+    }
+
     #[cfg(feature = "remote")]
     #[cfg_attr(feature = "tracing", instrument(skip_all, fields(model)))]
     pub fn from_pretrained(model: impl AsRef<str>) -> Result<Self> {
@@ -73,21 +792,11 @@ impl Pipeline {
         #[cfg(feature = "tracing")]
         tracing::Span::current().record("model", model);
 
-        let download_file = |file: &str| {
-            #[cfg(feature = "tracing")]
-            debug!(%file, "downloading file");
-            remote::download(format!(
-                "https://huggingface.co/{model}/resolve/main/{file}"
-            ))
-        };
-
-        Self::from_files(
-            download_file("config.json")?,
-            download_file("tokenizer.json")?,
-            download_file("model.onnx")?,
-        )
+        Self::builder().pretrained(model, "main").build()
     }
 
+    /// Recognize the entities in the sentence, ordered by their offsets.
+    /// The same sentence always gives the same entities.
     #[cfg_attr(feature = "tracing", instrument(skip_all, fields(sentence)))]
     pub fn predict(&self, sentence: impl AsRef<str>) -> Result<Vec<Entity>> {
         let sentence = sentence.as_ref();
@@ -95,95 +804,676 @@ impl Pipeline {
         #[cfg(feature = "tracing")]
         tracing::Span::current().record("sentence", sentence);
 
-        let input = self
-            .tokenizer
-            .encode(EncodeInput::Single(sentence.into()), true)?;
-
-        let input_ids: Tensor = Array2::from_shape_vec(
-            (1, input.len()),
-            input.get_ids().iter().map(|&x| x as i64).collect(),
-        )?
-        .into();
-        let attention_mask: Tensor = Array2::from_shape_vec(
-            (1, input.len()),
-            input
-                .get_attention_mask()
+        let mut entities = self.predict_batch(&[sentence])?;
+        Ok(entities.pop().unwrap_or_default())
+    }
+
+    /// Classify every token of the sentence, special tokens included,
+    /// without grouping them into entities. Tokens truncated away are left
+    /// out, even with [`Pipeline::with_chunking`]: the windows overlap, so
+    /// the tokens they share would be classified once per window, and
+    /// there's no telling which of the classifications is right.
+    pub fn predict_tokens(&self, sentence: impl AsRef<str>) -> Result<Vec<TokenPrediction>> {
+        let sentence = sentence.as_ref();
+        let (encodings, lengths) = self.tokenize(&[sentence])?;
+        let outputs = self.infer(&encodings)?;
+        let logits = outputs[self.logits].to_array_view::<f32>()?;
+
+        let len = lengths[0];
+        let encoding = &encodings[0];
+        logits
+            .index_axis(Axis(0), 0)
+            .rows()
+            .into_iter()
+            .zip(&encoding.get_tokens()[..len])
+            .zip(&encoding.get_offsets()[..len])
+            .zip(&encoding.get_word_ids()[..len])
+            .map(|(((logits, token), &(start, end)), &word)| {
+                let scores = aggregate::softmax(logits);
+                let (label, score) = aggregate::argmax(scores.iter().copied());
+                Ok(TokenPrediction {
+                    token: token.clone(),
+                    label: aggregate::label(&self.config.id2label, label)?.clone(),
+                    score,
+                    scores,
+                    start,
+                    end,
+                    word: word.map(|word| word as usize),
+                })
+            })
+            .collect()
+    }
+
+    /// The labels that tokens are classified as, ordered by their ids.
+    pub fn labels(&self) -> Vec<&str> {
+        let mut labels = self.config.id2label.iter().collect::<Vec<_>>();
+        labels.sort_by_key(|&(id, _)| id);
+        labels
+            .into_iter()
+            .map(|(_, label)| label.as_str())
+            .collect()
+    }
+
+    /// Tag every word of the sentence, as configured by
+    /// [`Pipeline::with_word_segmentation`], with the IOB2 tag of the entity
+    /// it's part of, if any. Words truncated away are left out, unless the
+    /// sentence is predicted in windows with [`Pipeline::with_chunking`].
+    pub fn predict_tags(&self, sentence: impl AsRef<str>) -> Result<Vec<TaggedWord>> {
+        let sentence = sentence.as_ref();
+        let mut predicted = self.predict_batch_bucketed(&[sentence], &[])?;
+        let predicted = predicted.pop().unwrap_or_default();
+
+        let encoding = self
+            .encode_cached(&[sentence], self.encoding_cache.as_ref())?
+            .pop()
+            .ok_or(Error::Tokenizer)?;
+        // the windows overlap, but a word is the same in every window. If
+        // some were left out, only the words of the first are tagged, as the
+        // sentence would be without windows
+        let windows = if self.chunking && !predicted.truncated {
+            encoding.get_overflowing().as_slice()
+        } else {
+            &[]
+        };
+        let (offsets, word_ids): (Vec<_>, Vec<_>) = std::iter::once(&encoding)
+            .chain(windows)
+            .flat_map(|window| window.get_offsets().iter().zip(window.get_word_ids()))
+            .map(|(&offsets, &word)| (offsets, word))
+            .unzip();
+        let words = match self.word_segmentation {
+            WordSegmentation::PreTokenizer => tags::words(&offsets, &word_ids),
+            WordSegmentation::Whitespace => {
+                let end = offsets.iter().map(|&(_, end)| end).max().unwrap_or(0);
+                let mut words = segment::whitespace(sentence);
+                words.retain(|&(start, _)| start < end);
+                words
+            }
+        };
+        let tags = iob_tags(&predicted.entities, words.len());
+        Ok(words
+            .into_iter()
+            .zip(tags)
+            .map(|((start, end), tag)| TaggedWord {
+                word: sentence[start..end].to_owned(),
+                start,
+                end,
+                tag,
+            })
+            .collect())
+    }
+
+    /// Recognize entities in several sentences using a single forward pass.
+    ///
+    /// The sentences are padded to the length of the longest one. With
+    /// [`Pipeline::with_chunking`], every window of a long sentence is a
+    /// sentence of its own to the forward pass.
+    #[cfg_attr(feature = "tracing", instrument(skip_all, fields(batch_size = sentences.len())))]
+    pub fn predict_batch(&self, sentences: &[impl AsRef<str>]) -> Result<Vec<Vec<Entity>>> {
+        let predicted = self.predict_batch_bucketed(sentences, &[])?;
+        Ok(predicted.into_iter().map(|p| p.entities).collect())
+    }
+
+    /// Like [`Pipeline::predict_batch`], but in a forward pass per group of
+    /// sentences whose token counts are within the same of the ascending
+    /// upper bounds of `buckets`, so that a single long sentence doesn't
+    /// inflate the padding of the others. Along with the entities, this
+    /// tells how every sentence was run through the model.
+    ///
+    /// With [`Pipeline::with_chunking`], the windows of the sentences are
+    /// run in a single forward pass.
+    #[cfg_attr(feature = "tracing", instrument(skip_all, fields(batch_size = sentences.len())))]
+    pub fn predict_batch_bucketed(
+        &self,
+        sentences: &[impl AsRef<str>],
+        buckets: &[usize],
+    ) -> Result<Vec<Predicted>> {
+        if sentences.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let predicted = if self.chunking {
+            self.predict_windows(sentences)?
+        } else {
+            self.predict_buckets(sentences, buckets)?
+        };
+
+        #[cfg(feature = "tracing")]
+        debug!(
+            "recognized {} entities",
+            predicted.iter().map(|p| p.entities.len()).sum::<usize>()
+        );
+
+        Ok(predicted)
+    }
+
+    /// Like [`Pipeline::predict_batch_bucketed`], but always in windows, as
+    /// with [`Pipeline::with_chunking`], so that no sentence is truncated.
+    #[cfg_attr(feature = "tracing", instrument(skip_all, fields(batch_size = sentences.len())))]
+    pub fn predict_batch_windowed(&self, sentences: &[impl AsRef<str>]) -> Result<Vec<Predicted>> {
+        if sentences.is_empty() {
+            return Ok(vec![]);
+        }
+        self.predict_windows(sentences)
+    }
+
+    /// Predict the sentences in a forward pass per bucket, tokenizing each
+    /// only once.
+    fn predict_buckets(
+        &self,
+        sentences: &[impl AsRef<str>],
+        buckets: &[usize],
+    ) -> Result<Vec<Predicted>> {
+        Limits::check("batch size", sentences.len(), self.limits.max_batch_size)?;
+        let sentences = sentences.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+        let mut encodings = self.encode_cached(&sentences, self.encoding_cache.as_ref())?;
+        let mut predicted = encodings
+            .iter()
+            .zip(&sentences)
+            .map(|(encoding, sentence)| Predicted {
+                entities: vec![],
+                tokens: encoding.len(),
+                // the tokenizer keeps what it truncates away
+                truncated: !encoding.get_overflowing().is_empty(),
+                words: self.count_words(sentence, [encoding]),
+            })
+            .collect::<Vec<_>>();
+
+        let mut groups = BTreeMap::<usize, Vec<usize>>::new();
+        for (i, predicted) in predicted.iter().enumerate() {
+            let bucket = buckets.partition_point(|&bound| bound < predicted.tokens);
+            groups.entry(bucket).or_default().push(i);
+        }
+
+        for indices in groups.into_values() {
+            let mut group = indices
                 .iter()
-                .map(|&x| x as i64)
-                .collect(),
-        )?
-        .into();
-        let token_type_ids: Tensor = Array2::from_shape_vec(
-            (1, input.len()),
-            input.get_type_ids().iter().map(|&x| x as i64).collect(),
-        )?
-        .into();
-
-        let outputs = self.model.run(tvec![
-            input_ids.into(),
-            attention_mask.into(),
-            token_type_ids.into()
-        ])?;
-
-        let mut entities: Vec<RawEntity> = vec![];
-
-        let logits = outputs[0].to_array_view::<f32>()?;
-
-        for (i, scores) in logits.rows().into_iter().enumerate() {
-            let mut sum = 0.;
-            let mut max = f32::MIN;
-            let mut label = 0;
-
-            for (i, z) in scores.iter().enumerate() {
-                let z = z.exp();
-                sum += z;
-                if z > max {
-                    max = z;
-                    label = i as _;
+                .map(|&i| mem::take(&mut encodings[i]))
+                .collect::<Vec<_>>();
+            let lengths = self.pad(&mut group)?;
+            let outputs = self.infer(&group)?;
+            let logits = outputs[self.logits].to_array_view::<f32>()?;
+            let texts = indices.iter().map(|&i| sentences[i]).collect::<Vec<_>>();
+            let entities = self.postprocess(&texts, &group, &lengths, logits)?;
+            for (i, entities) in indices.into_iter().zip(entities) {
+                predicted[i].entities = entities;
+            }
+        }
+
+        Ok(predicted)
+    }
+
+    /// Predict the windows that the tokenizer splits the sentences into,
+    /// merging the entities of the windows of every sentence.
+    ///
+    /// The limits count windows rather than sentences. Every sentence gets
+    /// its first window, and the rest are taken a window of every sentence
+    /// at a time for as long as they fit, so that the sentences whose last
+    /// windows don't are truncated rather than the whole batch rejected.
+    fn predict_windows(&self, sentences: &[impl AsRef<str>]) -> Result<Vec<Predicted>> {
+        Limits::check("batch size", sentences.len(), self.limits.max_batch_size)?;
+        let sentences = sentences.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+
+        let mut encoded = self.encode_cached(&sentences, self.encoding_cache.as_ref())?;
+        let mut overflowing = encoded
+            .iter_mut()
+            .map(|encoding| encoding.take_overflowing().into_iter())
+            .collect::<Vec<_>>();
+
+        // the first window of a sentence is its longest
+        let longest = encoded.iter().map(Encoding::len).max().unwrap_or_default();
+        let length = self.padded_length(longest);
+        let budget = [
+            self.limits.max_batch_size,
+            self.limits.max_tokens.map(|max| max / length.max(1)),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .unwrap_or(usize::MAX);
+
+        let mut kept = encoded.into_iter().map(|e| vec![e]).collect::<Vec<_>>();
+        let mut spare = budget.saturating_sub(sentences.len());
+        let mut more = true;
+        while spare > 0 && more {
+            more = false;
+            for (kept, overflowing) in kept.iter_mut().zip(&mut overflowing) {
+                if spare == 0 {
+                    break;
+                }
+                if let Some(window) = overflowing.next() {
+                    kept.push(window);
+                    spare -= 1;
+                    more = true;
                 }
             }
+        }
+        let truncated = overflowing
+            .into_iter()
+            .map(|mut overflowing| overflowing.next().is_some())
+            .collect::<Vec<_>>();
+
+        let words = kept
+            .iter()
+            .zip(&sentences)
+            .map(|(kept, sentence)| self.count_words(sentence, kept))
+            .collect::<Vec<_>>();
 
-            let score = max / sum;
-            let (start, end) = input.get_offsets()[i];
+        // the index of the sentence of every window
+        let mut windows = vec![];
+        let mut encodings = vec![];
+        for (i, kept) in kept.into_iter().enumerate() {
+            windows.resize(windows.len() + kept.len(), i);
+            encodings.extend(kept);
+        }
 
-            match entities.last_mut() {
-                Some(prev) if prev.label == label => {
-                    prev.score = prev.score.max(score);
-                    prev.start = prev.start.min(start);
-                    prev.end = prev.end.max(end);
+        self.pad(&mut encodings)?;
+        // the tokenizer pads the last window of a sentence to the length of
+        // the others, so that the lengths before padding count some of it
+        let lengths = encodings
+            .iter()
+            .map(|encoding| {
+                let mask = encoding.get_attention_mask();
+                mask.iter().filter(|&&mask| mask == 1).count()
+            })
+            .collect::<Vec<_>>();
+        let outputs = self.infer(&encodings)?;
+        let logits = outputs[self.logits].to_array_view::<f32>()?;
+        let texts = windows.iter().map(|&i| sentences[i]).collect::<Vec<_>>();
+        let entities = self.postprocess(&texts, &encodings, &lengths, logits)?;
+
+        let mut merged = vec![vec![]; sentences.len()];
+        let mut tokens = vec![0; sentences.len()];
+        for ((i, entities), len) in windows.into_iter().zip(entities).zip(lengths) {
+            merged[i].extend(entities);
+            tokens[i] += len;
+        }
+        let predicted = merged
+            .into_iter()
+            .zip(tokens)
+            .zip(truncated)
+            .zip(words)
+            .map(|(((entities, tokens), truncated), words)| Predicted {
+                entities: chunk::merge(entities),
+                tokens,
+                truncated,
+                words,
+            })
+            .collect();
+        Ok(predicted)
+    }
+
+    /// Number of words of the sentence, as configured by
+    /// [`Pipeline::with_word_segmentation`], in the windows of it that the
+    /// model sees.
+    fn count_words<'a>(
+        &self,
+        sentence: &str,
+        windows: impl IntoIterator<Item = &'a Encoding>,
+    ) -> usize {
+        let mut end = 0;
+        let mut last = None;
+        for window in windows {
+            for (&(_, token_end), &word) in window.get_offsets().iter().zip(window.get_word_ids()) {
+                if word.is_some() {
+                    end = end.max(token_end);
+                    last = last.max(word);
                 }
-                _ => entities.push(RawEntity {
-                    label,
-                    score,
-                    start,
-                    end,
-                }),
             }
         }
+        match self.word_segmentation {
+            WordSegmentation::PreTokenizer => last.map_or(0, |word| word as usize + 1),
+            WordSegmentation::Whitespace => segment::whitespace(sentence)
+                .into_iter()
+                .filter(|&(start, _)| start < end)
+                .count(),
+        }
+    }
+
+    /// Classify the sentence with the head configured by
+    /// [`Pipeline::with_classifier`]. The classes are sorted by descending
+    /// score.
+    pub fn classify(&self, sentence: impl AsRef<str>) -> Result<Vec<Class>> {
+        let mut classes = self.classify_batch(&[sentence.as_ref()])?;
+        Ok(classes.pop().unwrap_or_default())
+    }
+
+    /// Classify several sentences using a single forward pass.
+    #[cfg_attr(feature = "tracing", instrument(skip_all, fields(batch_size = sentences.len())))]
+    pub fn classify_batch(&self, sentences: &[impl AsRef<str>]) -> Result<Vec<Vec<Class>>> {
+        let (classifier, output) = self
+            .classifier
+            .as_ref()
+            .ok_or_else(|| Error::Output("no classification head is configured".to_owned()))?;
+        if sentences.is_empty() {
+            return Ok(vec![]);
+        }
 
-        let entities = entities
+        let (encodings, _) = self.tokenize(sentences)?;
+        let outputs = self.infer(&encodings)?;
+        let logits = outputs[*output].to_array_view::<f32>()?;
+
+        let classes = logits
+            .rows()
             .into_iter()
-            .filter(|e| e.label != 0 && e.end > e.start)
-            .map(
+            .map(|logits| {
+                let exp = logits.iter().map(|z| z.exp()).collect::<Vec<_>>();
+                let sum = exp.iter().sum::<f32>();
+                let mut classes = classifier
+                    .labels
+                    .iter()
+                    .zip(exp)
+                    .map(|(label, z)| Class {
+                        label: label.clone(),
+                        score: z / sum,
+                    })
+                    .collect::<Vec<_>>();
+                classes.sort_by(|a, b| b.score.total_cmp(&a.score));
+                classes
+            })
+            .collect();
+
+        Ok(classes)
+    }
+
+    /// Encode the sentences and pad them as configured, returning the
+    /// encodings along with their unpadded lengths.
+    ///
+    /// The sentences of a batch are encoded in parallel on the Rayon thread
+    /// pool that the pipeline is run on, unless `TOKENIZERS_PARALLELISM` is
+    /// disabled. Those in the encoding cache, if any, aren't encoded again.
+    fn tokenize(&self, sentences: &[impl AsRef<str>]) -> Result<(Vec<Encoding>, Vec<usize>)> {
+        self.tokenize_with(sentences, self.encoding_cache.as_ref())
+    }
+
+    /// Like [`Pipeline::tokenize`], but with the given cache, if any.
+    #[cfg_attr(feature = "tracing", instrument(skip_all))]
+    fn tokenize_with(
+        &self,
+        sentences: &[impl AsRef<str>],
+        cache: Option<&SharedCache>,
+    ) -> Result<(Vec<Encoding>, Vec<usize>)> {
+        Limits::check("batch size", sentences.len(), self.limits.max_batch_size)?;
+        let sentences = sentences.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+        let mut encodings = self.encode_cached(&sentences, cache)?;
+        let lengths = self.pad(&mut encodings)?;
+        Ok((encodings, lengths))
+    }
+
+    /// Encode the sentences, taking those in the cache, if any, from it.
+    fn encode_cached(
+        &self,
+        sentences: &[&str],
+        cache: Option<&SharedCache>,
+    ) -> Result<Vec<Encoding>> {
+        let encodings = match cache {
+            Some((cache, hash)) => {
+                let cached = sentences
+                    .iter()
+                    .map(|sentence| cache.get(*hash, sentence))
+                    .collect::<Vec<_>>();
+                let missing = sentences
+                    .iter()
+                    .zip(&cached)
+                    .filter(|(_, encoding)| encoding.is_none())
+                    .map(|(&sentence, _)| sentence)
+                    .collect();
+                let mut encoded = self.encode(missing)?.into_iter();
+
+                let mut encodings = Vec::with_capacity(sentences.len());
+                for (sentence, encoding) in sentences.iter().zip(cached) {
+                    let encoding = match encoding {
+                        Some(encoding) => encoding,
+                        None => {
+                            let encoding = encoded.next().ok_or(Error::Tokenizer)?;
+                            cache.put(*hash, sentence, encoding.clone());
+                            encoding
+                        }
+                    };
+                    encodings.push(encoding);
+                }
+                encodings
+            }
+            None => self.encode(sentences.to_vec())?,
+        };
+        Ok(encodings)
+    }
+
+    /// Pad the encodings as configured, returning their unpadded lengths.
+    fn pad(&self, encodings: &mut [Encoding]) -> Result<Vec<usize>> {
+        let lengths = encodings.iter().map(Encoding::len).collect::<Vec<_>>();
+        let longest = lengths.iter().copied().max().unwrap_or_default();
+        let length = self.padded_length(longest);
+        Limits::check(
+            "token count",
+            length.saturating_mul(encodings.len()),
+            self.limits.max_tokens,
+        )?;
+        pad_encodings(
+            encodings,
+            &PaddingParams {
+                strategy: PaddingStrategy::Fixed(length),
+                pad_to_multiple_of: None,
+                ..self.padding_params()
+            },
+        )?;
+
+        Ok(lengths)
+    }
+
+    /// The length that a batch whose longest encoding is `longest` tokens
+    /// is padded to. Every sentence is padded to the same length, even if a
+    /// fixed one is shorter than the longest sentence.
+    fn padded_length(&self, longest: usize) -> usize {
+        let params = self.padding_params();
+        let length = match params.strategy {
+            PaddingStrategy::BatchLongest => longest,
+            PaddingStrategy::Fixed(length) => length.max(longest),
+        };
+        match params.pad_to_multiple_of {
+            Some(n) if n > 0 => length + (n - length % n) % n,
+            _ => length,
+        }
+    }
+
+    /// Run the model on the padded encodings.
+    #[cfg_attr(feature = "tracing", instrument(skip_all))]
+    fn infer(&self, encodings: &[Encoding]) -> Result<TVec<TValue>> {
+        let shape = (encodings.len(), encodings[0].len());
+        // of the type that the model declares, as some exports take `int32`
+        let tensor = |input: usize, f: fn(&Encoding) -> &[u32]| -> Result<Tensor> {
+            let data = encodings.iter().flat_map(|encoding| f(encoding).iter());
+            let tensor = match self.weights().model.model().input_fact(input)?.datum_type {
+                DatumType::I32 => {
+                    let data = data.map(|&x| x as i32).collect();
+                    Array2::<i32>::from_shape_vec(shape, data)?.into()
+                }
+                _ => {
+                    let data = data.map(|&x| x as i64).collect();
+                    Array2::<i64>::from_shape_vec(shape, data)?.into()
+                }
+            };
+            Ok(tensor)
+        };
+
+        let input_ids = tensor(0, Encoding::get_ids)?;
+        let attention_mask = tensor(1, Encoding::get_attention_mask)?;
+        let token_type_ids = tensor(2, Encoding::get_type_ids)?;
+
+        let weights = self.weights();
+        let outputs = weights.states.run(
+            &weights.model,
+            tvec![
+                input_ids.into(),
+                attention_mask.into(),
+                token_type_ids.into()
+            ],
+        )?;
+
+        Ok(outputs)
+    }
+
+    /// Decode the logits of every sentence into entities.
+    #[cfg_attr(feature = "tracing", instrument(skip_all))]
+    fn postprocess(
+        &self,
+        sentences: &[impl AsRef<str>],
+        encodings: &[Encoding],
+        lengths: &[usize],
+        logits: ArrayViewD<f32>,
+    ) -> Result<Vec<Vec<Entity>>> {
+        sentences
+            .iter()
+            .zip(encodings)
+            .zip(lengths)
+            .enumerate()
+            .map(|(i, ((sentence, encoding), &len))| {
+                self.decode(
+                    sentence.as_ref(),
+                    &encoding.get_offsets()[..len],
+                    &encoding.get_word_ids()[..len],
+                    logits.index_axis(Axis(0), i),
+                )
+            })
+            .collect()
+    }
+
+    /// Encode the sentences, in parallel if there are several.
+    fn encode(&self, sentences: Vec<&str>) -> Result<Vec<Encoding>> {
+        let parallel = sentences.len() > 1;
+        let encodings = sentences
+            .into_maybe_par_iter_cond(parallel)
+            .map(|sentence| {
+                let mut encoding = self
+                    .tokenizer
+                    .encode(EncodeInput::Single(sentence.into()), true)?;
+                if self.align_combining_marks {
+                    tokenizer::align_combining_marks(sentence, &mut encoding);
+                }
+                Ok(encoding)
+            })
+            .collect::<tokenizers::Result<Vec<_>>>()?;
+        Ok(encodings)
+    }
+
+    /// Number of tokens the sentence is encoded into, including special tokens.
+    pub fn token_count(&self, sentence: impl AsRef<str>) -> Result<usize> {
+        let encoding = self
+            .tokenizer
+            .encode(EncodeInput::Single(sentence.as_ref().into()), true)?;
+        Ok(encoding.len())
+    }
+
+    fn padding_params(&self) -> PaddingParams {
+        match self.tokenizer.get_padding() {
+            Some(params) => params.clone(),
+            None => PaddingParams {
+                pad_id: self.tokenizer.token_to_id("[PAD]").unwrap_or(0),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Merge the token classifications of a single sentence into entities,
+    /// ordered by start, then end, then label.
+    fn decode(
+        &self,
+        sentence: &str,
+        offsets: &[(usize, usize)],
+        word_ids: &[Option<u32>],
+        logits: ArrayViewD<f32>,
+    ) -> Result<Vec<Entity>> {
+        let mut entities = aggregate::group(
+            self.aggregation_strategy,
+            self.score_aggregation(),
+            &self.config.id2label,
+            offsets,
+            word_ids,
+            logits,
+        )?;
+        if self.snap_to_words {
+            entities = self.snap(sentence, entities);
+        }
+        let whitespace_words = (self.word_segmentation == WordSegmentation::Whitespace)
+            .then(|| segment::whitespace(sentence));
+
+        let mut entities = entities
+            .into_iter()
+            .filter_map(
                 |RawEntity {
                      label,
                      score,
+                     tokens,
                      start,
                      end,
-                 }| Entity {
-                    label: self.config.id2label[&label].clone(),
-                    score,
-                    word: sentence[start..end].to_owned(),
-                    start,
-                    end,
+                     words,
+                 }| {
+                    let score = self.score_aggregation().finish(score, tokens);
+                    let min = self.label_thresholds.get(&label).unwrap_or(&self.min_score);
+                    if score < *min {
+                        return None;
+                    }
+
+                    let (start_word, end_word) = match &whitespace_words {
+                        Some(words) => segment::span(words, start, end),
+                        None => {
+                            let (first, last) = words.unwrap_or_default();
+                            (first as usize, last as usize + usize::from(words.is_some()))
+                        }
+                    };
+                    let word = &sentence[start..end];
+                    Some(Entity {
+                        score,
+                        word: word.to_owned(),
+                        start,
+                        end,
+                        start_word,
+                        end_word,
+                        candidates: self
+                            .knowledge_base
+                            .as_ref()
+                            .map(|knowledge_base| knowledge_base.link(&label, word))
+                            .unwrap_or_default(),
+                        label,
+                    })
                 },
             )
-            .collect::<Vec<Entity>>();
+            .collect::<Vec<_>>();
+        entities.sort_by(|a, b| (a.start, a.end, &a.label).cmp(&(b.start, b.end, &b.label)));
+        Ok(entities)
+    }
 
-        #[cfg(feature = "tracing")]
-        debug!("recognized {} entities", entities.len());
+    /// How the scores of the tokens, or words, of entities are combined.
+    fn score_aggregation(&self) -> ScoreAggregation {
+        self.aggregation_strategy
+            .score_aggregation(self.score_aggregation)
+    }
 
-        Ok(entities)
+    /// Snap the entities, which are in order, to the words of the sentence.
+    fn snap(&self, sentence: &str, entities: Vec<RawEntity>) -> Vec<RawEntity> {
+        let aggregation = self.score_aggregation();
+        let mut snapped: Vec<RawEntity> = Vec::with_capacity(entities.len());
+        for mut entity in entities {
+            (entity.start, entity.end) = snap::snap(sentence, entity.start, entity.end);
+            match snapped.last_mut() {
+                Some(prev) if prev.end > entity.start && prev.label == entity.label => {
+                    prev.score = aggregation.merge(prev.score, entity.score);
+                    prev.tokens += entity.tokens;
+                    prev.end = prev.end.max(entity.end);
+                    prev.words = match (prev.words, entity.words) {
+                        (Some((a, b)), Some((c, d))) => Some((a.min(c), b.max(d))),
+                        (prev, words) => prev.or(words),
+                    };
+                }
+                Some(prev) if prev.end > entity.start => {
+                    let score = |e: &RawEntity| aggregation.finish(e.score, e.tokens);
+                    if score(&entity) > score(prev) {
+                        *prev = entity;
+                    }
+                }
+                _ => snapped.push(entity),
+            }
+        }
+        snapped
     }
 }
 
@@ -194,7 +1484,11 @@ pub enum Error {
     #[cfg(feature = "remote")]
     #[cfg_attr(feature = "remote", error("{0}"))]
     Download(#[from] cached_path::Error),
-    #[error("{0}")]
+    #[cfg(feature = "remote")]
+    #[cfg_attr(feature = "remote", error("{0}"))]
+    Http(#[from] reqwest::Error),
+    #[cfg(feature = "serde")]
+    #[cfg_attr(feature = "serde", error("{0}"))]
     Serde(#[from] serde_json::Error),
     #[error("{0}")]
     Onnx(#[from] tract_onnx::tract_core::anyhow::Error),
@@ -202,6 +1496,58 @@ pub enum Error {
     Tokenizer,
     #[error("shape error: {0}")]
     Shape(#[from] ShapeError),
+    #[error("{0}")]
+    Output(String),
+    #[error("{0}")]
+    Unsupported(String),
+    #[error("{what} of {actual} exceeds the limit of {limit}")]
+    LimitExceeded {
+        what: &'static str,
+        actual: usize,
+        limit: usize,
+    },
+    #[error("invalid knowledge base: {0}")]
+    KnowledgeBase(String),
+    #[error("no {0} file is given")]
+    MissingFile(&'static str),
+    #[error("the model predicted label {0}, which id2label doesn't have")]
+    UnknownLabel(i64),
+    /// The forward pass panicked, which tract does when it fails to allocate
+    /// a tensor.
+    #[error("inference panicked: {0}")]
+    Panicked(String),
+    #[error("added token {token:?} has id {actual:?} rather than {expected}")]
+    AddedToken {
+        token: String,
+        expected: u32,
+        actual: Option<u32>,
+    },
+}
+
+impl Error {
+    /// Whether the operation might succeed if it's retried, as opposed to
+    /// failing because of the input or the model files. Errors of inference
+    /// aren't: the same input fails the same way again. A panic is, as it's
+    /// most likely a failure to allocate under memory pressure.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Io(_) | Self::Panicked(_) => true,
+            #[cfg(feature = "remote")]
+            Self::Download(_) | Self::Http(_) => true,
+            #[cfg(feature = "serde")]
+            Self::Serde(_) => false,
+            Self::Onnx(_)
+            | Self::Tokenizer
+            | Self::Shape(_)
+            | Self::Output(_)
+            | Self::Unsupported(_)
+            | Self::LimitExceeded { .. }
+            | Self::KnowledgeBase(_)
+            | Self::MissingFile(_)
+            | Self::UnknownLabel(_)
+            | Self::AddedToken { .. } => false,
+        }
+    }
 }
 
 impl From<Box<dyn std::error::Error + Send + Sync>> for Error {
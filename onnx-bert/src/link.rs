@@ -0,0 +1,239 @@
+//! Linking recognized entities to the identifiers of a knowledge base, such
+//! as Wikidata, by looking up their words in a table of aliases.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use crate::{Error, Result};
+
+/// An entry of the knowledge base that an entity may refer to.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Candidate {
+    /// Identifier in the knowledge base, such as `Q34`.
+    pub id: String,
+    /// Share of the prior of the entries with the same alias, summing to at
+    /// most 1 over the candidates of an entity.
+    pub score: f32,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    id: String,
+    prior: f32,
+    /// Labels of the entities that may refer to this entry, any if empty.
+    labels: Vec<String>,
+}
+
+/// A table of aliases of the entries of a knowledge base.
+#[derive(Debug, Clone)]
+pub struct KnowledgeBase {
+    aliases: HashMap<String, Vec<Entry>>,
+    max_candidates: usize,
+}
+
+impl KnowledgeBase {
+    /// Read the aliases from a tab-separated file with a line per alias and
+    /// entry: `alias`, `id`, and optionally the prior of the entry, 1 if
+    /// empty, and the labels of the entities that may refer to it, separated
+    /// by commas. Lines starting with `#` are skipped.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_reader(reader: impl BufRead) -> Result<Self> {
+        let mut aliases: HashMap<_, Vec<_>> = HashMap::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid =
+                |message: &str| Error::KnowledgeBase(format!("line {}: {message}", i + 1));
+            let mut columns = line.split('\t');
+            let alias = normalize(columns.next().unwrap_or_default());
+            let id = columns.next().map(str::trim).unwrap_or_default();
+            if alias.is_empty() || id.is_empty() {
+                return Err(invalid("expected an alias and an id"));
+            }
+            let prior = match columns.next().map(str::trim) {
+                None | Some("") => 1.,
+                Some(prior) => prior
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|prior| prior.is_finite() && *prior >= 0.)
+                    .ok_or_else(|| invalid("the prior isn't a non-negative number"))?,
+            };
+            let labels = columns
+                .next()
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|label| !label.is_empty())
+                .map(ToOwned::to_owned)
+                .collect();
+
+            aliases.entry(alias).or_default().push(Entry {
+                id: id.to_owned(),
+                prior,
+                labels,
+            });
+        }
+
+        Ok(Self {
+            aliases,
+            max_candidates: 5,
+        })
+    }
+
+    /// Keep only the most likely candidates of every entity.
+    pub fn with_max_candidates(self, max_candidates: usize) -> Self {
+        Self {
+            max_candidates,
+            ..self
+        }
+    }
+
+    /// Number of aliases in the table.
+    pub fn len(&self) -> usize {
+        self.aliases.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aliases.is_empty()
+    }
+
+    /// The entries that an entity with the label and words may refer to,
+    /// most likely first. Aliases are matched regardless of case and
+    /// whitespace.
+    pub fn link(&self, label: &str, word: &str) -> Vec<Candidate> {
+        let Some(entries) = self.aliases.get(&normalize(word)) else {
+            return vec![];
+        };
+
+        let entries = entries
+            .iter()
+            .filter(|entry| entry.labels.is_empty() || entry.labels.iter().any(|l| l == label))
+            .collect::<Vec<_>>();
+        let total = entries.iter().map(|entry| entry.prior).sum::<f32>();
+        let mut candidates = entries
+            .into_iter()
+            .map(|entry| Candidate {
+                id: entry.id.clone(),
+                score: if total > 0. { entry.prior / total } else { 0. },
+            })
+            .collect::<Vec<_>>();
+        // ties are broken by id, so that the same entity is always linked
+        // the same way
+        candidates.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.id.cmp(&b.id)));
+        candidates.truncate(self.max_candidates);
+        candidates
+    }
+}
+
+/// Lowercase the alias and collapse its whitespace.
+fn normalize(alias: &str) -> String {
+    alias
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn knowledge_base(table: &str) -> KnowledgeBase {
+        KnowledgeBase::from_reader(table.as_bytes()).unwrap()
+    }
+
+    fn ids(candidates: &[Candidate]) -> Vec<&str> {
+        candidates.iter().map(|c| c.id.as_str()).collect()
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let kb = knowledge_base("# alias\tid\n\nAda Lovelace\tQ7259\n  \n");
+        assert_eq!(kb.len(), 1);
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected_with_their_number() {
+        for table in [
+            "Ada\tQ7259\nLovelace\n",
+            "Ada\tQ7259\n\tQ7259\n",
+            "Ada\tQ7259\nLovelace\tQ7259\t-1\n",
+            "Ada\tQ7259\nLovelace\tQ7259\tNaN\n",
+            "Ada\tQ7259\nLovelace\tQ7259\thigh\n",
+        ] {
+            match KnowledgeBase::from_reader(table.as_bytes()) {
+                Err(Error::KnowledgeBase(message)) => assert!(message.starts_with("line 2:")),
+                result => panic!("{table:?} gave {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn aliases_match_regardless_of_case_and_whitespace() {
+        let kb = knowledge_base("Ada  Lovelace\tQ7259\n");
+        assert_eq!(ids(&kb.link("PER", "ada\nLOVELACE ")), ["Q7259"]);
+        assert!(kb.link("PER", "Ada").is_empty());
+    }
+
+    #[test]
+    fn entries_are_filtered_by_label() {
+        let kb = knowledge_base(
+            "Paris\tQ90\t\tLOC\n\
+             Paris\tQ167646\t\tPER, MISC\n\
+             Paris\tQ2\n",
+        );
+        assert_eq!(ids(&kb.link("LOC", "Paris")), ["Q2", "Q90"]);
+        assert_eq!(ids(&kb.link("PER", "Paris")), ["Q167646", "Q2"]);
+        assert_eq!(ids(&kb.link("ORG", "Paris")), ["Q2"]);
+    }
+
+    #[test]
+    fn priors_are_normalized_over_the_candidates() {
+        let kb = knowledge_base(
+            "Paris\tQ90\t3\tLOC\n\
+             Paris\tQ167646\t5\tPER\n\
+             Paris\tQ830149\t1\n",
+        );
+        let candidates = kb.link("LOC", "Paris");
+        assert_eq!(ids(&candidates), ["Q90", "Q830149"]);
+        assert_eq!(candidates[0].score, 0.75);
+        assert_eq!(candidates[1].score, 0.25);
+    }
+
+    #[test]
+    fn ties_are_broken_by_id() {
+        let kb = knowledge_base("Java\tQ251\nJava\tQ3757\nJava\tQ1122\t0\n");
+        let candidates = kb.link("MISC", "Java");
+        assert_eq!(ids(&candidates), ["Q251", "Q3757", "Q1122"]);
+        assert_eq!(candidates[2].score, 0.);
+    }
+
+    #[test]
+    fn zero_priors_score_zero() {
+        let kb = knowledge_base("Java\tQ251\t0\nJava\tQ3757\t0\n");
+        let scores = kb
+            .link("MISC", "Java")
+            .iter()
+            .map(|c| c.score)
+            .collect::<Vec<_>>();
+        assert_eq!(scores, [0., 0.]);
+    }
+
+    #[test]
+    fn only_the_most_likely_candidates_are_kept() {
+        let kb = knowledge_base("Java\tQ251\t1\nJava\tQ3757\t3\nJava\tQ1122\t2\n")
+            .with_max_candidates(2);
+        assert_eq!(ids(&kb.link("MISC", "Java")), ["Q3757", "Q1122"]);
+    }
+}
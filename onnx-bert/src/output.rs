@@ -0,0 +1,111 @@
+//! Finding the outputs of a model that the tasks of a pipeline take their
+//! logits from. Exports from `optimum` don't always put the token logits
+//! first or name them `logits`, and multi-head models have several.
+
+use tract_onnx::prelude::{DatumType, TypedModel};
+
+use crate::{Error, Result};
+
+/// What `transformers` names the token logits.
+const LOGITS: &str = "logits";
+
+/// Names of the outputs of the model, in order.
+pub(crate) fn names(model: &TypedModel) -> Result<Vec<&str>> {
+    let names = model
+        .output_outlets()?
+        .iter()
+        .map(|&outlet| {
+            model
+                .outlet_label(outlet)
+                .unwrap_or(&model.node(outlet.node).name)
+        })
+        .collect();
+    Ok(names)
+}
+
+/// Index of the output with the name.
+pub(crate) fn named(model: &TypedModel, name: &str) -> Result<usize> {
+    let names = names(model)?;
+    names
+        .iter()
+        .position(|&output| output == name)
+        .ok_or_else(|| Error::Output(format!("no output named {name:?} in {names:?}")))
+}
+
+/// Index of the output with the name, which must hold sentence logits over
+/// `labels` labels.
+pub(crate) fn classes(model: &TypedModel, name: &str, labels: usize) -> Result<usize> {
+    let i = named(model, name)?;
+    let fact = model.output_fact(i)?;
+    let dim = match &*fact.shape {
+        [_, dim] if fact.datum_type == DatumType::F32 => dim.to_i64().ok(),
+        _ => {
+            return Err(Error::Output(format!(
+                "{name} is {:?} [{:?}], expected F32 [batch, labels]",
+                fact.datum_type, fact.shape
+            )))
+        }
+    };
+    match dim {
+        Some(dim) if dim as usize != labels => Err(Error::Output(format!(
+            "{name} has {dim} labels, but {labels} were given"
+        ))),
+        _ => Ok(i),
+    }
+}
+
+/// Index of the output with the token logits: the one called `name` if
+/// given, or else the one called `logits`, or else the first that is shaped
+/// like logits over `labels` labels. An output picked by its name must hold
+/// token logits over `labels` labels too.
+pub(crate) fn logits(
+    model: &TypedModel,
+    name: Option<&str>,
+    labels: Option<usize>,
+) -> Result<usize> {
+    if let Some(name) = name {
+        let i = named(model, name)?;
+        return token_logits(model, i, name, labels);
+    }
+    let names = names(model)?;
+    if let Some(i) = names.iter().position(|&output| output == LOGITS) {
+        return token_logits(model, i, LOGITS, labels);
+    }
+
+    for i in 0..names.len() {
+        let fact = model.output_fact(i)?;
+        if fact.datum_type != DatumType::F32 || fact.rank() != 3 {
+            continue;
+        }
+        let dim = fact.shape[2].to_i64().ok().map(|dim| dim as usize);
+        if matches!((dim, labels), (Some(dim), Some(labels)) if dim != labels) {
+            continue;
+        }
+        return Ok(i);
+    }
+
+    Err(Error::Output(format!(
+        "none of the outputs {names:?} look like token logits"
+    )))
+}
+
+/// The index of the output called `name` if it holds token logits over
+/// `labels` labels, which are taken from the labels of the config.
+fn token_logits(model: &TypedModel, i: usize, name: &str, labels: Option<usize>) -> Result<usize> {
+    let fact = model.output_fact(i)?;
+    let dim = match &*fact.shape {
+        [_, _, dim] if fact.datum_type == DatumType::F32 => dim.to_i64().ok(),
+        _ => {
+            return Err(Error::Output(format!(
+                "{name} is {:?} [{:?}], expected F32 [batch, sequence, labels]",
+                fact.datum_type, fact.shape
+            )))
+        }
+    };
+    match (dim, labels) {
+        (Some(dim), Some(labels)) if dim as usize != labels => Err(Error::Output(format!(
+            "{name} has {dim} labels, but the config has {labels}"
+        ))),
+        _ => Ok(i),
+    }
+}
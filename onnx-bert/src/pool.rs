@@ -0,0 +1,58 @@
+//! Reusing what inference allocates across calls. tract builds the state of
+//! a plan anew for every run, and with it the scratch space of the matrix
+//! multiplications, which is the part worth keeping. The rest of the state
+//! can't be sent between threads, and is cheap to build.
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::Mutex,
+    thread,
+};
+
+use tract_onnx::prelude::{tract_linalg::mmm::ScratchSpace, SimpleState, TValue, TVec};
+
+use crate::{Error, Model, Result};
+
+/// Scratch spaces checked out by one run at a time, so that concurrent
+/// calls don't share any.
+pub(crate) struct StatePool {
+    scratch: Mutex<Vec<Box<dyn ScratchSpace>>>,
+    /// Maximum number of idle scratch spaces kept.
+    capacity: usize,
+}
+
+impl StatePool {
+    pub fn new() -> Self {
+        Self {
+            scratch: Mutex::default(),
+            capacity: thread::available_parallelism().map_or(1, Into::into),
+        }
+    }
+
+    /// Run the model, reusing a scratch space left by an earlier run.
+    pub fn run(&self, model: &Model, inputs: TVec<TValue>) -> Result<TVec<TValue>> {
+        let mut state = SimpleState::new(model)?;
+        state.session_state.cached_mmm_scratch_space = self.scratch.lock().unwrap().pop();
+
+        // tract asserts that the tensors it allocates aren't null, so that
+        // running out of memory for the activations panics
+        let outputs =
+            panic::catch_unwind(AssertUnwindSafe(|| state.run(inputs))).map_err(|panic| {
+                let message = match panic.downcast::<String>() {
+                    Ok(message) => *message,
+                    Err(panic) => panic
+                        .downcast::<&str>()
+                        .map_or_else(|_| "unknown panic".to_owned(), |m| (*m).to_owned()),
+                };
+                Error::Panicked(message)
+            })??;
+
+        if let Some(scratch) = state.session_state.cached_mmm_scratch_space.take() {
+            let mut idle = self.scratch.lock().unwrap();
+            if idle.len() < self.capacity {
+                idle.push(scratch);
+            }
+        }
+        Ok(outputs)
+    }
+}
@@ -1,6 +1,7 @@
-use std::path::PathBuf;
+use std::{fs, path::PathBuf};
 
 use cached_path::Cache;
+use serde::Deserialize;
 
 use crate::Result;
 
@@ -18,3 +19,96 @@ pub fn download(url: impl AsRef<str>) -> Result<PathBuf> {
 
     Ok(cache.cached_path(url)?)
 }
+
+/// The part of the metadata that `cached_path` keeps next to every file that
+/// we need.
+#[derive(Debug, Deserialize)]
+struct Meta {
+    resource: String,
+    resource_path: PathBuf,
+    meta_path: PathBuf,
+}
+
+/// A downloaded file in the cache.
+#[derive(Debug, Clone)]
+pub struct CachedFile {
+    /// Hugging Face model id, if the file belongs to a model.
+    pub model: Option<String>,
+    pub revision: Option<String>,
+    /// Name of the file in the model repository, or its URL.
+    pub name: String,
+    pub path: PathBuf,
+    pub size: u64,
+    meta_path: PathBuf,
+}
+
+impl CachedFile {
+    fn new(meta: Meta) -> Self {
+        let parsed = meta
+            .resource
+            .strip_prefix("https://huggingface.co/")
+            .and_then(|rest| rest.split_once("/resolve/"))
+            .and_then(|(model, rest)| Some((model, rest.split_once('/')?)));
+        let (model, revision, name) = match parsed {
+            Some((model, (revision, name))) => (
+                Some(model.to_owned()),
+                Some(revision.to_owned()),
+                name.to_owned(),
+            ),
+            None => (None, None, meta.resource),
+        };
+
+        Self {
+            model,
+            revision,
+            name,
+            size: fs::metadata(&meta.resource_path).map_or(0, |m| m.len()),
+            path: meta.resource_path,
+            meta_path: meta.meta_path,
+        }
+    }
+
+    /// Delete the file, so that it's downloaded again when needed.
+    pub fn remove(&self) -> std::io::Result<()> {
+        fs::remove_file(&self.path)?;
+        match fs::remove_file(&self.meta_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn cached_files() -> Result<Vec<CachedFile>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(ensure_cache_dir()?)? {
+        let path = entry?.path();
+        if !matches!(path.extension(), Some(ext) if ext == "meta") {
+            continue;
+        }
+        // anything else in the directory isn't ours to judge
+        let Ok(meta) = serde_json::from_slice::<Meta>(&fs::read(&path)?) else {
+            continue;
+        };
+        if meta.resource_path.exists() {
+            files.push(CachedFile::new(meta));
+        }
+    }
+
+    Ok(files)
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelInfo {
+    sha: String,
+}
+
+pub fn resolve_revision(model: &str, revision: &str) -> Result<String> {
+    let body = reqwest::blocking::get(format!(
+        "https://huggingface.co/api/models/{model}/revision/{revision}"
+    ))?
+    .error_for_status()?
+    .text()?;
+    let info: ModelInfo = serde_json::from_str(&body)?;
+
+    Ok(info.sha)
+}
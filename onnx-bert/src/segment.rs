@@ -0,0 +1,101 @@
+//! Splitting the text into words, for the word indices of entities.
+
+use std::str::FromStr;
+
+/// What the words that entities are indexed by are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum WordSegmentation {
+    /// The words split by the pre-tokenizer of the model, which usually
+    /// splits off punctuation too.
+    #[default]
+    PreTokenizer,
+    /// Runs of characters separated by whitespace.
+    Whitespace,
+}
+
+impl FromStr for WordSegmentation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pre-tokenizer" => Ok(Self::PreTokenizer),
+            "whitespace" => Ok(Self::Whitespace),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The offsets of the words of the text that are separated by whitespace.
+pub(crate) fn whitespace(text: &str) -> Vec<(usize, usize)> {
+    let mut words = vec![];
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match (start, c.is_whitespace()) {
+            (None, false) => start = Some(i),
+            (Some(s), true) => {
+                words.push((s, i));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        words.push((s, text.len()));
+    }
+    words
+}
+
+/// Indices of the first word that the span overlaps and of the word after
+/// the last one, given the offsets of the words in order.
+pub(crate) fn span(words: &[(usize, usize)], start: usize, end: usize) -> (usize, usize) {
+    let first = words.partition_point(|&(_, word_end)| word_end <= start);
+    let last = words.partition_point(|&(word_start, _)| word_start < end);
+    (first, last.max(first))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_are_separated_by_any_whitespace() {
+        let text = "  Ada\tLovelace,\n 1843 ";
+        let words = whitespace(text)
+            .into_iter()
+            .map(|(start, end)| &text[start..end])
+            .collect::<Vec<_>>();
+        assert_eq!(words, ["Ada", "Lovelace,", "1843"]);
+    }
+
+    #[test]
+    fn offsets_are_in_bytes() {
+        assert_eq!(
+            whitespace("hon bor i malmö\u{a0}nu"),
+            [(0, 3), (4, 7), (8, 9), (10, 16), (18, 20)]
+        );
+        assert!(whitespace(" \u{3000} ").is_empty());
+    }
+
+    #[test]
+    fn spans_cover_the_words_they_overlap() {
+        let words = whitespace("Ada Lovelace wrote programs");
+        assert_eq!(span(&words, 0, 12), (0, 2));
+        // partial words count
+        assert_eq!(span(&words, 5, 15), (1, 3));
+        assert_eq!(span(&words, 13, 27), (2, 4));
+    }
+
+    #[test]
+    fn empty_spans_are_empty() {
+        let words = whitespace("Ada Lovelace");
+        // between words, and at the end
+        assert_eq!(span(&words, 3, 4), (1, 1));
+        assert_eq!(span(&words, 12, 12), (2, 2));
+        assert_eq!(span(&[], 0, 5), (0, 0));
+    }
+}
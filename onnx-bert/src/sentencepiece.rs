@@ -0,0 +1,226 @@
+//! Assembling a tokenizer from a SentencePiece model, as shipped by XLM-R and
+//! mDeBERTa style checkpoints without a `tokenizer.json`. Only the parts of
+//! the SentencePiece protobuf that the tokenizer needs are decoded.
+
+use std::{fs, path::Path};
+
+use prost::Message;
+use tokenizers::{
+    decoders::metaspace::Metaspace,
+    models::unigram::Unigram,
+    normalizers::{
+        precompiled::Precompiled,
+        replace::{Replace, ReplacePattern},
+        utils::Sequence,
+    },
+    processors::bert::BertProcessing,
+    utils::padding::PaddingParams,
+    AddedToken, NormalizerWrapper, Tokenizer,
+};
+
+use crate::{tokenizer::SpecialTokens, Error, Result};
+
+/// `TrainerSpec.ModelType.UNIGRAM`, the default.
+const UNIGRAM: i32 = 1;
+/// `SentencePiece.Type.UNKNOWN`.
+const UNKNOWN: i32 = 2;
+
+#[derive(Clone, PartialEq, Message)]
+struct ModelProto {
+    #[prost(message, repeated, tag = "1")]
+    pieces: Vec<SentencePiece>,
+    #[prost(message, optional, tag = "2")]
+    trainer_spec: Option<TrainerSpec>,
+    #[prost(message, optional, tag = "3")]
+    normalizer_spec: Option<NormalizerSpec>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct SentencePiece {
+    #[prost(string, optional, tag = "1")]
+    piece: Option<String>,
+    #[prost(float, optional, tag = "2")]
+    score: Option<f32>,
+    #[prost(int32, optional, tag = "3")]
+    r#type: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TrainerSpec {
+    #[prost(int32, optional, tag = "3")]
+    model_type: Option<i32>,
+    #[prost(int32, optional, tag = "40")]
+    unk_id: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct NormalizerSpec {
+    #[prost(bytes = "vec", optional, tag = "2")]
+    precompiled_charsmap: Option<Vec<u8>>,
+}
+
+/// Assemble the tokenizer that the `transformers` converters would from a
+/// Unigram SentencePiece model. With `fairseq`, the vocabulary is laid out
+/// like XLM-R's: shifted by one to make room for its special tokens.
+/// Special tokens that aren't configured are guessed from the vocabulary.
+pub(crate) fn from_model(path: &Path, fairseq: bool, tokens: &SpecialTokens) -> Result<Tokenizer> {
+    let model = ModelProto::decode(fs::read(path)?.as_slice())
+        .map_err(|e| Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))?;
+
+    let trainer = model.trainer_spec.unwrap_or_default();
+    let model_type = trainer.model_type.unwrap_or(UNIGRAM);
+    // BPE, word and character models tokenize differently
+    if model_type != UNIGRAM {
+        return Err(Error::Unsupported(format!(
+            "SentencePiece model type {model_type}, only Unigram ({UNIGRAM}) is supported"
+        )));
+    }
+
+    let pieces = model.pieces.iter().map(|piece| {
+        (
+            piece.piece.clone().unwrap_or_default(),
+            f64::from(piece.score.unwrap_or_default()),
+        )
+    });
+    let id_of = |vocab: &[(String, f64)], token: &str| {
+        let id = vocab.iter().position(|(piece, _)| piece == token)?;
+        u32::try_from(id).ok()
+    };
+    let (vocab, unk_id) = if fairseq {
+        let special = ["<s>", "<pad>", "</s>", "<unk>"].map(|token| (token.to_owned(), 0.));
+        let vocab = special
+            .into_iter()
+            .chain(pieces.skip(3))
+            .chain([("<mask>".to_owned(), 0.)])
+            .collect::<Vec<_>>();
+        (vocab, 3)
+    } else {
+        let vocab = pieces.collect::<Vec<_>>();
+        let unk_id = match &tokens.unk_token {
+            Some(_) => id_of(&vocab, tokens.unk("")).map(|id| id as usize),
+            None => trainer.unk_id.map(|id| id as usize).or_else(|| {
+                model
+                    .pieces
+                    .iter()
+                    .position(|piece| piece.r#type == Some(UNKNOWN))
+            }),
+        };
+        (vocab, unk_id.unwrap_or(0))
+    };
+
+    let id = |token: &str| id_of(&vocab, token);
+    let (cls, sep) = [
+        (tokens.cls("<s>"), tokens.sep("</s>")),
+        (tokens.cls("[CLS]"), tokens.sep("[SEP]")),
+    ]
+    .into_iter()
+    .find_map(|(cls, sep)| Some(((cls.to_owned(), id(cls)?), (sep.to_owned(), id(sep)?))))
+    .ok_or(Error::Tokenizer)?;
+    let pad = [tokens.pad("<pad>"), tokens.pad("[PAD]")]
+        .into_iter()
+        .find_map(|pad| Some((pad, id(pad)?)));
+    let special_tokens = [
+        tokens.cls("<s>"),
+        tokens.sep("</s>"),
+        tokens.pad("<pad>"),
+        tokens.unk("<unk>"),
+        tokens.mask("<mask>"),
+        tokens.cls("[CLS]"),
+        tokens.sep("[SEP]"),
+        tokens.pad("[PAD]"),
+        tokens.unk("[UNK]"),
+        tokens.mask("[MASK]"),
+    ]
+    .into_iter()
+    .filter(|token| id(token).is_some())
+    .map(|token| AddedToken::from(token, true))
+    .collect::<Vec<_>>();
+
+    let mut normalizers = vec![];
+    if let Some(charsmap) = model
+        .normalizer_spec
+        .and_then(|spec| spec.precompiled_charsmap)
+        .filter(|charsmap| !charsmap.is_empty())
+    {
+        let precompiled = Precompiled::from(&charsmap).map_err(|_| Error::Tokenizer)?;
+        normalizers.push(NormalizerWrapper::from(precompiled));
+    }
+    normalizers.push(Replace::new(ReplacePattern::Regex(" {2,}".to_owned()), " ")?.into());
+
+    let unigram = Unigram::from(vocab, Some(unk_id))?;
+    let mut tokenizer = Tokenizer::new(unigram);
+    tokenizer
+        .with_normalizer(Sequence::new(normalizers))
+        .with_pre_tokenizer(Metaspace::new('▁', true))
+        .with_post_processor(BertProcessing::new(sep, cls))
+        .with_decoder(Metaspace::new('▁', true));
+    if let Some((pad_token, pad_id)) = pad {
+        tokenizer.with_padding(Some(PaddingParams {
+            pad_id,
+            pad_token: pad_token.to_owned(),
+            ..Default::default()
+        }));
+    }
+    tokenizer.add_special_tokens(&special_tokens);
+
+    Ok(tokenizer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// A Unigram model of the pieces `<unk> <s> </s> ▁Ada ▁Love lace ▁`,
+    /// followed by the characters of "Ada Lovelace".
+    fn fixture() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sentencepiece.model")
+    }
+
+    fn ids(tokenizer: &Tokenizer, text: &str) -> Vec<u32> {
+        let encoding = tokenizer.encode(text, true).unwrap();
+        encoding.get_ids().to_vec()
+    }
+
+    #[test]
+    fn pieces_keep_their_ids() {
+        let tokenizer = from_model(&fixture(), false, &SpecialTokens::default()).unwrap();
+        assert_eq!(tokenizer.token_to_id("▁Ada"), Some(3));
+        assert_eq!(ids(&tokenizer, "Ada Lovelace"), [1, 3, 4, 5, 2]);
+    }
+
+    #[test]
+    fn fairseq_shifts_the_pieces_for_its_special_tokens() {
+        let tokenizer = from_model(&fixture(), true, &SpecialTokens::default()).unwrap();
+        let special = ["<s>", "<pad>", "</s>", "<unk>"].map(|token| tokenizer.token_to_id(token));
+        assert_eq!(special, [Some(0), Some(1), Some(2), Some(3)]);
+        assert_eq!(tokenizer.token_to_id("▁Ada"), Some(4));
+        assert_eq!(tokenizer.token_to_id("<mask>"), Some(17));
+        assert_eq!(ids(&tokenizer, "Ada Lovelace"), [0, 4, 5, 6, 2]);
+        assert_eq!(ids(&tokenizer, "€"), [0, 7, 3, 2]);
+    }
+
+    #[test]
+    fn unknown_is_the_piece_of_that_type() {
+        let tokenizer = from_model(&fixture(), false, &SpecialTokens::default()).unwrap();
+        assert_eq!(ids(&tokenizer, "€"), [1, 6, 0, 2]);
+    }
+
+    #[test]
+    fn only_unigram_models_are_supported() {
+        let bpe = ModelProto {
+            pieces: vec![],
+            trainer_spec: Some(TrainerSpec {
+                model_type: Some(2),
+                unk_id: None,
+            }),
+            normalizer_spec: None,
+        };
+        let path = std::env::temp_dir().join(format!("onnx-bert-bpe-{}.model", std::process::id()));
+        fs::write(&path, bpe.encode_to_vec()).unwrap();
+        let result = from_model(&path, false, &SpecialTokens::default());
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(Error::Unsupported(_))));
+    }
+}
@@ -0,0 +1,75 @@
+//! Snapping the spans of entities to the words of the text, as the offsets
+//! of subword tokens can end in the middle of a word, such as "ungliga" in
+//! "Kungliga biblioteket".
+
+/// Whether the character is part of a word, rather than whitespace or
+/// punctuation.
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric()
+}
+
+/// Expand the span to the boundaries of the words it touches, then contract
+/// it to start and end within a word. Spans without any word are kept.
+pub(crate) fn snap(text: &str, start: usize, end: usize) -> (usize, usize) {
+    let Some(span) = text.get(start..end) else {
+        return (start, end);
+    };
+    let Some(first) = span.find(is_word) else {
+        return (start, end);
+    };
+    let last = span.trim_end_matches(|c| !is_word(c)).len();
+    let (start, end) = (start + first, start + last);
+
+    let start = text[..start]
+        .char_indices()
+        .rev()
+        .take_while(|&(_, c)| is_word(c))
+        .last()
+        .map_or(start, |(i, _)| i);
+    let end = text[end..]
+        .char_indices()
+        .find(|&(_, c)| !is_word(c))
+        .map_or(text.len(), |(i, _)| end + i);
+    (start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapped<'a>(text: &'a str, span: &str) -> &'a str {
+        let start = text.find(span).unwrap();
+        let (start, end) = snap(text, start, start + span.len());
+        &text[start..end]
+    }
+
+    #[test]
+    fn expands_to_whole_words() {
+        let text = "Kungliga biblioteket ligger i Stockholm.";
+        assert_eq!(snapped(text, "ungliga biblioteket"), "Kungliga biblioteket");
+        assert_eq!(snapped(text, "Kungliga bibl"), "Kungliga biblioteket");
+        assert_eq!(snapped(text, "tockhol"), "Stockholm");
+    }
+
+    #[test]
+    fn contracts_past_whitespace_and_punctuation() {
+        let text = "Hon bor i (Malmö), Sverige.";
+        assert_eq!(snapped(text, " (Malmö), "), "Malmö");
+        assert_eq!(snapped(text, "Sverige."), "Sverige");
+    }
+
+    #[test]
+    fn whole_words_are_kept() {
+        let text = "Åsa Öberg";
+        assert_eq!(snapped(text, "Åsa Öberg"), "Åsa Öberg");
+        assert_eq!(snapped(text, "Öberg"), "Öberg");
+    }
+
+    #[test]
+    fn spans_without_words_are_kept() {
+        let text = "a -- b";
+        assert_eq!(snap(text, 1, 5), (1, 5));
+        // not on a character boundary
+        assert_eq!(snap("Åsa", 1, 3), (1, 3));
+    }
+}
@@ -0,0 +1,155 @@
+//! Rendering the entities of a sentence as the IOB2 tags of its words, the
+//! format of CoNLL and most sequence labeling tools.
+
+use crate::Entity;
+
+/// What the words outside of any entity are tagged.
+const OUTSIDE: &str = "O";
+
+/// A word of a sentence and its tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TaggedWord {
+    pub word: String,
+    pub start: usize,
+    pub end: usize,
+    /// `O`, or the label prefixed by `B-` for the first word of an entity
+    /// and `I-` for the rest.
+    pub tag: String,
+}
+
+/// The tags of the first `words` words of the sentence that the entities,
+/// ordered by their offsets, were recognized in. Labels prefixed by the
+/// model, such as `I-PER`, are tagged as the entity they continue.
+pub fn iob_tags(entities: &[Entity], words: usize) -> Vec<String> {
+    let mut tags: Vec<Option<String>> = vec![None; words];
+
+    for entity in entities {
+        // models trained on BIO or BIOES tags label the tokens with them
+        let (continues, label) = match entity.label.split_once('-') {
+            Some(("B" | "S", label)) => (false, label),
+            Some(("I" | "E", label)) => (true, label),
+            _ => (false, entity.label.as_str()),
+        };
+
+        let mut first = true;
+        for i in entity.start_word..entity.end_word.min(words) {
+            // overlapping entities don't retag words
+            if tags[i].is_some() {
+                continue;
+            }
+            let inside = !first
+                || (continues
+                    && i > 0
+                    && matches!(&tags[i - 1], Some(tag) if tag.get(2..) == Some(label)));
+            let prefix = if inside { 'I' } else { 'B' };
+            tags[i] = Some(format!("{prefix}-{label}"));
+            first = false;
+        }
+    }
+
+    tags.into_iter()
+        .map(|tag| tag.unwrap_or_else(|| OUTSIDE.to_owned()))
+        .collect()
+}
+
+/// The offsets of the words of the sentence, given those of its tokens and
+/// the words they belong to.
+pub(crate) fn words(offsets: &[(usize, usize)], word_ids: &[Option<u32>]) -> Vec<(usize, usize)> {
+    let mut words: Vec<(usize, usize)> = vec![];
+    for (&(start, end), word) in offsets.iter().zip(word_ids) {
+        let Some(word) = word.map(|word| word as usize) else {
+            continue;
+        };
+        if word >= words.len() {
+            words.resize(word + 1, (start, end));
+        }
+        let (first, last) = &mut words[word];
+        *first = (*first).min(start);
+        *last = (*last).max(end);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity(label: &str, start_word: usize, end_word: usize) -> Entity {
+        Entity {
+            label: label.to_owned(),
+            score: 1.,
+            word: String::new(),
+            start: 0,
+            end: 0,
+            start_word,
+            end_word,
+            candidates: vec![],
+        }
+    }
+
+    #[test]
+    fn entities_are_tagged_by_word() {
+        // "Ada Lovelace wrote in London"
+        let entities = [entity("PER", 0, 2), entity("LOC", 4, 5)];
+        let tags = iob_tags(&entities, 5);
+        assert_eq!(tags, ["B-PER", "I-PER", "O", "O", "B-LOC"]);
+    }
+
+    #[test]
+    fn adjacent_entities_of_a_label_are_apart() {
+        let entities = [entity("PER", 0, 1), entity("PER", 1, 2)];
+        assert_eq!(iob_tags(&entities, 2), ["B-PER", "B-PER"]);
+    }
+
+    #[test]
+    fn bio_labels_continue_the_entity_before() {
+        let entities = [
+            entity("B-PER", 0, 1),
+            entity("I-PER", 1, 2),
+            entity("B-PER", 2, 3),
+        ];
+        assert_eq!(iob_tags(&entities, 3), ["B-PER", "I-PER", "B-PER"]);
+    }
+
+    #[test]
+    fn bioes_labels_continue_the_entity_before() {
+        let entities = [
+            entity("B-ORG", 0, 1),
+            entity("I-ORG", 1, 2),
+            entity("E-ORG", 2, 3),
+            entity("S-LOC", 3, 4),
+        ];
+        assert_eq!(iob_tags(&entities, 4), ["B-ORG", "I-ORG", "I-ORG", "B-LOC"]);
+    }
+
+    #[test]
+    fn continuations_of_other_labels_begin_entities() {
+        let entities = [
+            entity("B-PER", 0, 1),
+            entity("I-LOC", 1, 2),
+            entity("I-LOC", 3, 4),
+        ];
+        assert_eq!(iob_tags(&entities, 4), ["B-PER", "B-LOC", "O", "B-LOC"]);
+    }
+
+    #[test]
+    fn overlapping_entities_keep_the_first_tags() {
+        let entities = [entity("PER", 0, 2), entity("ORG", 1, 3)];
+        assert_eq!(iob_tags(&entities, 3), ["B-PER", "I-PER", "B-ORG"]);
+    }
+
+    #[test]
+    fn words_beyond_the_count_are_left_out() {
+        let entities = [entity("PER", 1, 3), entity("LOC", 4, 5)];
+        assert_eq!(iob_tags(&entities, 2), ["O", "B-PER"]);
+    }
+
+    #[test]
+    fn words_span_their_tokens() {
+        // [CLS] Ada Love ##lace , [SEP]
+        let offsets = [(0, 0), (0, 3), (4, 8), (8, 12), (12, 13), (0, 0)];
+        let word_ids = [None, Some(0), Some(1), Some(1), Some(2), None];
+        assert_eq!(words(&offsets, &word_ids), [(0, 3), (4, 12), (12, 13)]);
+    }
+}
@@ -0,0 +1,692 @@
+//! Loading the tokenizer of a model, which some repositories only ship as a
+//! WordPiece vocabulary or a SentencePiece model rather than a
+//! `tokenizer.json`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    str::FromStr,
+};
+#[cfg(feature = "serde")]
+use std::{fs::File, io::BufReader};
+
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Deserialize};
+#[cfg(feature = "serde")]
+use serde_json::{json, Value};
+#[cfg(feature = "serde")]
+use tokenizers::NormalizerWrapper;
+use tokenizers::{
+    decoders::wordpiece::WordPiece as WordPieceDecoder,
+    models::wordpiece::WordPiece,
+    normalizers::bert::BertNormalizer,
+    pre_tokenizers::bert::BertPreTokenizer,
+    processors::bert::BertProcessing,
+    utils::{
+        padding::{PaddingParams, PaddingStrategy},
+        truncation::{self, TruncationParams},
+    },
+    AddedToken, Encoding, Model, Tokenizer,
+};
+
+use unicode_normalization::char::is_combining_mark;
+
+use crate::{sentencepiece, Error, PretrainedFiles, Result};
+
+/// `transformers` writes a huge `model_max_length` for models without a
+/// limit, so anything above this is taken to mean none.
+const MAX_MODEL_MAX_LENGTH: f64 = 1e9;
+
+/// Which of a pair of sequences is truncated when it's too long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TruncationStrategy {
+    /// Remove a token from the longer sequence at a time.
+    LongestFirst,
+    OnlyFirst,
+    /// Only truncate the second sequence, such as the context of a question.
+    OnlySecond,
+}
+
+impl From<TruncationStrategy> for truncation::TruncationStrategy {
+    fn from(strategy: TruncationStrategy) -> Self {
+        match strategy {
+            TruncationStrategy::LongestFirst => Self::LongestFirst,
+            TruncationStrategy::OnlyFirst => Self::OnlyFirst,
+            TruncationStrategy::OnlySecond => Self::OnlySecond,
+        }
+    }
+}
+
+/// How far the sentences of a batch are padded. Batches are always padded to
+/// at least their longest sentence, as the model takes rectangular input,
+/// so a sentence run on its own is only padded by the fixed strategies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum Padding {
+    /// To the longest sentence of the batch.
+    Longest,
+    /// To a fixed number of tokens, or the longest sentence if it's longer.
+    Fixed(usize),
+    /// To the longest sentence, rounded up to a multiple of the number of
+    /// tokens.
+    MultipleOf(usize),
+}
+
+impl FromStr for Padding {
+    type Err = ();
+
+    /// Parse `longest`, `fixed:N` or `multiple-of:N`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        let (strategy, n) = s.split_once(':').unwrap_or((&s, ""));
+        let tokens = || n.parse().map_err(|_| ());
+        match strategy {
+            "longest" if n.is_empty() => Ok(Self::Longest),
+            "fixed" => Ok(Self::Fixed(tokens()?)),
+            "multiple-of" => Ok(Self::MultipleOf(tokens()?)),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A Unicode normalization form that text is brought into before anything
+/// else, so that composed and decomposed characters are tokenized alike.
+/// Offsets still refer to the text as given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum UnicodeNormalization {
+    Nfc,
+    Nfkc,
+}
+
+impl FromStr for UnicodeNormalization {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nfc" => Ok(Self::Nfc),
+            "nfkc" => Ok(Self::Nfkc),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Overrides of how text is tokenized, taking precedence over what the
+/// model was trained with. `None` leaves the setting of the model as is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenizerOptions {
+    pub lowercase: Option<bool>,
+    pub strip_accents: Option<bool>,
+    pub unicode_normalization: Option<UnicodeNormalization>,
+    /// Number of tokens, including special tokens, that input is truncated
+    /// to.
+    pub max_length: Option<usize>,
+    pub truncation_strategy: Option<TruncationStrategy>,
+    /// Number of tokens that the overflowing parts of truncated input
+    /// overlap by.
+    pub stride: Option<usize>,
+    pub padding: Option<Padding>,
+}
+
+/// A special token, written either as a string or as a serialized
+/// `AddedToken`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(untagged))]
+// only ever read from the files
+#[cfg_attr(not(feature = "serde"), allow(dead_code))]
+pub(crate) enum SpecialToken {
+    Content(String),
+    Added { content: String },
+}
+
+impl SpecialToken {
+    fn content(&self) -> &str {
+        match self {
+            Self::Content(content) | Self::Added { content } => content,
+        }
+    }
+}
+
+/// The special tokens of `tokenizer_config.json` and
+/// `special_tokens_map.json`.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub(crate) struct SpecialTokens {
+    pub(crate) unk_token: Option<SpecialToken>,
+    pub(crate) sep_token: Option<SpecialToken>,
+    pub(crate) pad_token: Option<SpecialToken>,
+    pub(crate) cls_token: Option<SpecialToken>,
+    pub(crate) mask_token: Option<SpecialToken>,
+}
+
+impl SpecialTokens {
+    /// Tokens set in `other` take precedence.
+    fn merge(self, other: Self) -> Self {
+        Self {
+            unk_token: other.unk_token.or(self.unk_token),
+            sep_token: other.sep_token.or(self.sep_token),
+            pad_token: other.pad_token.or(self.pad_token),
+            cls_token: other.cls_token.or(self.cls_token),
+            mask_token: other.mask_token.or(self.mask_token),
+        }
+    }
+
+    pub(crate) fn unk<'a>(&'a self, default: &'a str) -> &'a str {
+        self.unk_token
+            .as_ref()
+            .map_or(default, SpecialToken::content)
+    }
+
+    pub(crate) fn sep<'a>(&'a self, default: &'a str) -> &'a str {
+        self.sep_token
+            .as_ref()
+            .map_or(default, SpecialToken::content)
+    }
+
+    pub(crate) fn pad<'a>(&'a self, default: &'a str) -> &'a str {
+        self.pad_token
+            .as_ref()
+            .map_or(default, SpecialToken::content)
+    }
+
+    pub(crate) fn cls<'a>(&'a self, default: &'a str) -> &'a str {
+        self.cls_token
+            .as_ref()
+            .map_or(default, SpecialToken::content)
+    }
+
+    pub(crate) fn mask<'a>(&'a self, default: &'a str) -> &'a str {
+        self.mask_token
+            .as_ref()
+            .map_or(default, SpecialToken::content)
+    }
+
+    /// The tokens that are set, by the name of their setting.
+    fn set(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("unk_token", &self.unk_token),
+            ("sep_token", &self.sep_token),
+            ("pad_token", &self.pad_token),
+            ("cls_token", &self.cls_token),
+            ("mask_token", &self.mask_token),
+        ]
+        .into_iter()
+        .filter_map(|(name, token)| Some((name, token.as_ref()?.content())))
+    }
+}
+
+/// The settings of `tokenizer_config.json` that the tokenizer depends on.
+/// Missing ones take the defaults of `BertTokenizer`.
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+struct TokenizerConfig {
+    tokenizer_class: Option<String>,
+    do_lower_case: Option<bool>,
+    strip_accents: Option<bool>,
+    tokenize_chinese_chars: Option<bool>,
+    model_max_length: Option<f64>,
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    special_tokens: SpecialTokens,
+}
+
+#[cfg(feature = "serde")]
+fn read_json<T: DeserializeOwned + Default>(path: Option<&Path>) -> Result<T> {
+    match path {
+        Some(path) => Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?),
+        None => Ok(T::default()),
+    }
+}
+
+#[cfg(not(feature = "serde"))]
+fn read_json<T: Default>(path: Option<&Path>) -> Result<T> {
+    match path {
+        Some(path) => Err(Error::Unsupported(format!(
+            "{} can't be read without the serde feature",
+            path.display()
+        ))),
+        None => Ok(T::default()),
+    }
+}
+
+/// Load the tokenizer from a `tokenizer.json`, or assemble it from a
+/// `vocab.txt` or SentencePiece `.model`, configured by the optional
+/// `tokenizer_config.json` and `special_tokens_map.json` and extended with
+/// the tokens of `added_tokens.json`.
+pub(crate) fn load(files: &PretrainedFiles, options: &TokenizerOptions) -> Result<Tokenizer> {
+    let mut config: TokenizerConfig = read_json(files.tokenizer_config.as_deref())?;
+    let special_tokens_map = read_json(files.special_tokens_map.as_deref())?;
+    config.special_tokens = config.special_tokens.merge(special_tokens_map);
+
+    let mut tokenizer = match files.tokenizer.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => {
+            let mut tokenizer = Tokenizer::from_file(&files.tokenizer)?;
+            with_special_tokens(&mut tokenizer, &config.special_tokens)?;
+            tokenizer
+        }
+        Some("model") => {
+            let fairseq =
+                matches!(&config.tokenizer_class, Some(class) if class.starts_with("XLMRoberta"));
+            sentencepiece::from_model(&files.tokenizer, fairseq, &config.special_tokens)?
+        }
+        _ => from_vocab(&files.tokenizer, &config)?,
+    };
+    override_normalizer(&mut tokenizer, options)?;
+
+    // the ids must match the embeddings the model was fine-tuned with, so
+    // the tokens are added in order and checked to have ended up there
+    let added_tokens: HashMap<String, u32> = read_json(files.added_tokens.as_deref())?;
+    let added_tokens = added_tokens
+        .into_iter()
+        .map(|(token, id)| (id, token))
+        .collect::<BTreeMap<_, _>>();
+    for (id, token) in added_tokens {
+        tokenizer.add_tokens(&[AddedToken::from(token.as_str(), false)]);
+        let actual = tokenizer.token_to_id(&token);
+        if actual != Some(id) {
+            return Err(Error::AddedToken {
+                token,
+                expected: id,
+                actual,
+            });
+        }
+    }
+
+    // a `tokenizer.json` may already say how to pad and truncate
+    if tokenizer.get_padding().is_none() {
+        let pad_token = config.special_tokens.pad("[PAD]");
+        if let Some(pad_id) = tokenizer.token_to_id(pad_token) {
+            tokenizer.with_padding(Some(PaddingParams {
+                pad_id,
+                pad_token: pad_token.to_owned(),
+                ..Default::default()
+            }));
+        }
+    }
+    if tokenizer.get_truncation().is_none() {
+        if let Some(max_length) = config
+            .model_max_length
+            .filter(|len| (1. ..=MAX_MODEL_MAX_LENGTH).contains(len))
+        {
+            tokenizer.with_truncation(Some(TruncationParams {
+                max_length: max_length as usize,
+                ..Default::default()
+            }));
+        }
+    }
+    override_truncation(&mut tokenizer, options);
+    if let Some(padding) = options.padding {
+        let mut params = tokenizer.get_padding().cloned().unwrap_or_default();
+        (params.strategy, params.pad_to_multiple_of) = match padding {
+            Padding::Longest => (PaddingStrategy::BatchLongest, None),
+            Padding::Fixed(length) => (PaddingStrategy::Fixed(length), None),
+            Padding::MultipleOf(n) => (PaddingStrategy::BatchLongest, Some(n)),
+        };
+        tokenizer.with_padding(Some(params));
+    }
+
+    Ok(tokenizer)
+}
+
+/// Make the configured special tokens special to a `tokenizer.json`, so
+/// that they're never split, as Transformers does. They can't be added: a
+/// token that the vocabulary doesn't have has no embedding.
+fn with_special_tokens(tokenizer: &mut Tokenizer, tokens: &SpecialTokens) -> Result<()> {
+    let mut special = vec![];
+    for (name, token) in tokens.set() {
+        if tokenizer.token_to_id(token).is_none() {
+            return Err(Error::Unsupported(format!(
+                "the {name} {token:?} of the tokenizer configuration isn't in tokenizer.json"
+            )));
+        }
+        special.push(AddedToken::from(token, true));
+    }
+    tokenizer.add_special_tokens(&special);
+    Ok(())
+}
+
+/// Extend the tokens of the encoding, and of its overflowing windows, over
+/// the combining marks that follow them in the text. A character composed
+/// with its marks by [`UnicodeNormalization`] is aligned with its first
+/// character alone, which would otherwise leave the offsets of decomposed
+/// text ending before the marks. Marks that a token of their own starts
+/// at, such as the `##ि` of mBERT, stay with that token.
+pub(crate) fn align_combining_marks(text: &str, encoding: &mut Encoding) {
+    let offsets = encoding.get_offsets_mut();
+    for i in 0..offsets.len() {
+        let (start, end) = offsets[i];
+        // special tokens take up no text
+        if start == end {
+            continue;
+        }
+        let next = offsets[i + 1..]
+            .iter()
+            .find(|&&(start, end)| start < end)
+            .map_or(text.len(), |&(start, _)| start.max(end));
+        let marks = text
+            .get(end..)
+            .unwrap_or_default()
+            .chars()
+            .take_while(|&c| is_combining_mark(c))
+            .map(char::len_utf8)
+            .sum::<usize>();
+        offsets[i].1 = (end + marks).min(next);
+    }
+    for window in encoding.get_overflowing_mut() {
+        align_combining_marks(text, window);
+    }
+}
+
+/// Apply the overrides to the truncation of the tokenizer, which is enabled
+/// by overriding the maximum length of a tokenizer that doesn't truncate.
+fn override_truncation(tokenizer: &mut Tokenizer, options: &TokenizerOptions) {
+    let params = match (tokenizer.get_truncation(), options.max_length) {
+        (Some(params), max_length) => TruncationParams {
+            max_length: max_length.unwrap_or(params.max_length),
+            ..params.clone()
+        },
+        (None, Some(max_length)) => TruncationParams {
+            max_length,
+            ..Default::default()
+        },
+        (None, None) => return,
+    };
+
+    tokenizer.with_truncation(Some(TruncationParams {
+        strategy: options
+            .truncation_strategy
+            .map_or(params.strategy, Into::into),
+        stride: options.stride.unwrap_or(params.stride),
+        ..params
+    }));
+}
+
+/// Apply the overrides to the normalizer of the tokenizer. The settings of
+/// a `BertNormalizer` are changed in place; otherwise any `Lowercase` and
+/// `StripAccents` steps are removed or appended. The Unicode normalization,
+/// if any, comes first.
+#[cfg(feature = "serde")]
+fn override_normalizer(tokenizer: &mut Tokenizer, options: &TokenizerOptions) -> Result<()> {
+    if options.lowercase.is_none()
+        && options.strip_accents.is_none()
+        && options.unicode_normalization.is_none()
+    {
+        return Ok(());
+    }
+
+    // the steps of a `Sequence` are private, so they're edited as JSON
+    let mut normalizers = vec![];
+    if let Some(normalizer) = tokenizer.get_normalizer() {
+        normalizers.push(serde_json::to_value(normalizer)?);
+    }
+    let mut sequence = json!({ "type": "Sequence", "normalizers": normalizers });
+    if !override_json(&mut sequence, options) {
+        let normalizers = sequence["normalizers"]
+            .as_array_mut()
+            .ok_or(Error::Tokenizer)?;
+        if options.strip_accents == Some(true) {
+            normalizers.push(json!({ "type": "NFD" }));
+            normalizers.push(json!({ "type": "StripAccents" }));
+        }
+        if options.lowercase == Some(true) {
+            normalizers.push(json!({ "type": "Lowercase" }));
+        }
+    }
+    if let Some(form) = options.unicode_normalization {
+        let form = match form {
+            UnicodeNormalization::Nfc => "NFC",
+            UnicodeNormalization::Nfkc => "NFKC",
+        };
+        sequence["normalizers"]
+            .as_array_mut()
+            .ok_or(Error::Tokenizer)?
+            .insert(0, json!({ "type": form }));
+    }
+
+    let normalizer: NormalizerWrapper = serde_json::from_value(sequence)?;
+    tokenizer.with_normalizer(normalizer);
+    Ok(())
+}
+
+/// The normalizer is edited as JSON, which takes the serde feature.
+#[cfg(not(feature = "serde"))]
+fn override_normalizer(_: &mut Tokenizer, options: &TokenizerOptions) -> Result<()> {
+    if options.lowercase.is_none()
+        && options.strip_accents.is_none()
+        && options.unicode_normalization.is_none()
+    {
+        return Ok(());
+    }
+    Err(Error::Unsupported(
+        "the normalizer can't be overridden without the serde feature".to_owned(),
+    ))
+}
+
+/// Override the settings of the serialized normalizer, returning whether it
+/// is or contains a `BertNormalizer`, which takes care of both.
+#[cfg(feature = "serde")]
+fn override_json(normalizer: &mut Value, options: &TokenizerOptions) -> bool {
+    match normalizer["type"].as_str() {
+        Some("BertNormalizer") => {
+            if let Some(lowercase) = options.lowercase {
+                normalizer["lowercase"] = lowercase.into();
+            }
+            if let Some(strip_accents) = options.strip_accents {
+                normalizer["strip_accents"] = strip_accents.into();
+            }
+            true
+        }
+        Some("Sequence") => {
+            let Some(normalizers) = normalizer["normalizers"].as_array_mut() else {
+                return false;
+            };
+            normalizers.retain(|normalizer| match normalizer["type"].as_str() {
+                Some("Lowercase") => options.lowercase != Some(false),
+                Some("StripAccents") => options.strip_accents != Some(false),
+                _ => true,
+            });
+            // every step is visited, unlike with `any`
+            let mut bert = false;
+            for normalizer in normalizers {
+                bert |= override_json(normalizer, options);
+            }
+            bert
+        }
+        _ => false,
+    }
+}
+
+/// Assemble the tokenizer that `BertTokenizerFast` would from a WordPiece
+/// vocabulary.
+fn from_vocab(vocab: &Path, config: &TokenizerConfig) -> Result<Tokenizer> {
+    let tokens = &config.special_tokens;
+    let vocab = vocab.to_str().ok_or(Error::Tokenizer)?;
+    let wordpiece = WordPiece::from_file(vocab)
+        .unk_token(tokens.unk("[UNK]").to_owned())
+        .build()?;
+    let special = |token: &str| {
+        let id = wordpiece.token_to_id(token).ok_or(Error::Tokenizer)?;
+        Ok::<_, Error>((token.to_owned(), id))
+    };
+    let processor =
+        BertProcessing::new(special(tokens.sep("[SEP]"))?, special(tokens.cls("[CLS]"))?);
+    let special_tokens = [
+        tokens.pad("[PAD]"),
+        tokens.unk("[UNK]"),
+        tokens.cls("[CLS]"),
+        tokens.sep("[SEP]"),
+        tokens.mask("[MASK]"),
+    ]
+    .into_iter()
+    .filter(|token| wordpiece.token_to_id(token).is_some())
+    .map(|token| AddedToken::from(token, true))
+    .collect::<Vec<_>>();
+
+    let mut tokenizer = Tokenizer::new(wordpiece);
+    tokenizer
+        .with_normalizer(BertNormalizer::new(
+            true,
+            config.tokenize_chinese_chars.unwrap_or(true),
+            config.strip_accents,
+            config.do_lower_case.unwrap_or(true),
+        ))
+        .with_pre_tokenizer(BertPreTokenizer)
+        .with_post_processor(processor)
+        .with_decoder(WordPieceDecoder::default());
+    tokenizer.add_special_tokens(&special_tokens);
+
+    Ok(tokenizer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+
+    /// The tokens of the text and the text they're at, by their offsets.
+    #[cfg(feature = "serde")]
+    fn tokens(tokenizer: &Tokenizer, text: &str) -> Vec<(String, String)> {
+        let mut encoding = tokenizer.encode(text, false).unwrap();
+        align_combining_marks(text, &mut encoding);
+        encoding
+            .get_tokens()
+            .iter()
+            .zip(encoding.get_offsets())
+            .map(|(token, &(start, end))| (token.clone(), text[start..end].to_owned()))
+            .collect()
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn decomposed_text_is_tokenized_as_composed() {
+        let tokenizer = fixture::tokenizer(&TokenizerOptions {
+            strip_accents: Some(false),
+            unicode_normalization: Some(UnicodeNormalization::Nfc),
+            ..Default::default()
+        });
+        let composed = tokens(&tokenizer, "Hon bor i Malm\u{f6}.");
+        let decomposed = tokens(&tokenizer, "Hon bor i Malmo\u{308}.");
+        assert_eq!(composed[3], ("malmö".to_owned(), "Malm\u{f6}".to_owned()));
+        // the offsets are into the text as given
+        assert_eq!(
+            decomposed[3],
+            ("malmö".to_owned(), "Malmo\u{308}".to_owned())
+        );
+        assert_eq!(decomposed.len(), composed.len());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn decomposed_text_is_unknown_without_normalization() {
+        let tokenizer = fixture::tokenizer(&TokenizerOptions {
+            strip_accents: Some(false),
+            ..Default::default()
+        });
+        let decomposed = tokens(&tokenizer, "Hon bor i Malmo\u{308}.");
+        assert_eq!(decomposed[3].0, "[UNK]");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn subwords_starting_with_a_mark_keep_it() {
+        let tokenizer = fixture::tokenizer(&TokenizerOptions {
+            strip_accents: Some(false),
+            unicode_normalization: Some(UnicodeNormalization::Nfc),
+            ..Default::default()
+        });
+        let tokens = tokens(&tokenizer, "\u{915}\u{93f}");
+        assert_eq!(
+            tokens,
+            [
+                ("\u{915}".to_owned(), "\u{915}".to_owned()),
+                ("##\u{93f}".to_owned(), "\u{93f}".to_owned()),
+            ]
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn nfkc_folds_compatibility_characters() {
+        let tokenizer = fixture::tokenizer(&TokenizerOptions {
+            unicode_normalization: Some(UnicodeNormalization::Nfkc),
+            ..Default::default()
+        });
+        let tokens = tokens(&tokenizer, "the \u{fb01}rst program");
+        assert_eq!(tokens[1], ("first".to_owned(), "\u{fb01}rst".to_owned()));
+    }
+
+    /// Load the fixture extended with the `added_tokens.json`.
+    fn with_added_tokens(name: &str, json: &str) -> Result<Tokenizer> {
+        let path = std::env::temp_dir().join(format!(
+            "onnx-bert-{name}-{}-added_tokens.json",
+            std::process::id()
+        ));
+        std::fs::write(&path, json).unwrap();
+        let files = PretrainedFiles {
+            added_tokens: Some(path.clone()),
+            ..fixture::files()
+        };
+        let result = load(&files, &TokenizerOptions::default());
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn added_tokens_are_added_in_id_order() {
+        let tokenizer = with_added_tokens("order", r#"{"turing": 22, "hopper": 21}"#).unwrap();
+        assert_eq!(tokenizer.token_to_id("hopper"), Some(21));
+        assert_eq!(tokenizer.token_to_id("turing"), Some(22));
+        let encoding = tokenizer.encode("Hopper wrote", false).unwrap();
+        assert_eq!(encoding.get_tokens(), ["hopper", "wrote"]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn added_tokens_must_continue_the_vocabulary() {
+        let result = with_added_tokens("gap", r#"{"hopper": 30}"#);
+        assert!(matches!(
+            result,
+            Err(Error::AddedToken { token, expected: 30, actual: Some(21) }) if token == "hopper"
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn added_tokens_already_in_the_vocabulary_keep_their_id() {
+        let result = with_added_tokens("existing", r#"{"love": 21}"#);
+        assert!(matches!(
+            result,
+            Err(Error::AddedToken {
+                expected: 21,
+                actual: Some(6),
+                ..
+            })
+        ));
+    }
+
+    #[cfg(not(feature = "serde"))]
+    #[test]
+    fn added_tokens_take_serde() {
+        let result = with_added_tokens("serde", r#"{"hopper": 21}"#);
+        assert!(matches!(result, Err(Error::Unsupported(_))));
+    }
+
+    #[cfg(not(feature = "serde"))]
+    #[test]
+    fn normalization_takes_serde() {
+        let options = TokenizerOptions {
+            unicode_normalization: Some(UnicodeNormalization::Nfc),
+            ..Default::default()
+        };
+        let result = load(&fixture::files(), &options);
+        assert!(matches!(result, Err(Error::Unsupported(_))));
+    }
+}
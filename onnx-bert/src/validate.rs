@@ -0,0 +1,157 @@
+use std::{fs::File, io::BufReader};
+
+use tract_onnx::prelude::{DatumType, Framework, InferenceModelExt, TypedModel};
+
+use crate::{output, tokenizer, Config, Pipeline, PretrainedFiles, Result, TokenizerOptions};
+
+/// The inputs fed to the model by [`Pipeline`], in order.
+const INPUTS: [&str; 3] = ["input_ids", "attention_mask", "token_type_ids"];
+
+/// The outcome of one of the checks made by [`validate`].
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<String, String>) -> Self {
+        let (passed, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        Self {
+            name,
+            passed,
+            detail,
+        }
+    }
+}
+
+/// Check that the files make up a model that [`Pipeline`] can run: that the
+/// ONNX signature is the expected one, that `id2label` covers the outputs and
+/// that a sentence can be run through it. Every check is made even if an
+/// earlier one fails, unless it depends on it.
+pub fn validate(files: &PretrainedFiles) -> Vec<Check> {
+    let mut checks = vec![];
+
+    let config = File::open(&files.config)
+        .map_err(crate::Error::from)
+        .and_then(|file| Ok(serde_json::from_reader::<_, Config>(BufReader::new(file))?));
+    checks.push(Check::new(
+        "config",
+        match &config {
+            Ok(config) => check_labels(config),
+            Err(e) => Err(e.to_string()),
+        },
+    ));
+
+    checks.push(Check::new(
+        "tokenizer",
+        tokenizer::load(files, &TokenizerOptions::default())
+            .map(|tokenizer| format!("{} tokens", tokenizer.get_vocab_size(true)))
+            .map_err(|e| e.to_string()),
+    ));
+
+    let model = crate::check_format(&files.model)
+        .map_err(|e| e.to_string())
+        .and_then(|()| {
+            tract_onnx::onnx()
+                .model_for_path(&files.model)
+                .and_then(|model| model.into_typed())
+                .map_err(|e| e.to_string())
+        });
+    let model = match model {
+        Ok(model) => model,
+        Err(e) => {
+            checks.push(Check::new("model", Err(e)));
+            return checks;
+        }
+    };
+    checks.push(Check::new("inputs", check_inputs(&model)));
+
+    let labels = config.as_ref().ok().map(|config| config.id2label.len());
+    checks.push(Check::new("outputs", check_outputs(&model, labels)));
+
+    if checks.iter().all(|check| check.passed) {
+        let result = Pipeline::from_pretrained_files(files)
+            .and_then(|pipeline| pipeline.predict("Kalle Anka bor i Ankeborg."))
+            .map(|entities| format!("{} entities found", entities.len()))
+            .map_err(|e| e.to_string());
+        checks.push(Check::new("inference", result));
+    }
+
+    checks
+}
+
+/// The labels must be numbered from zero without gaps, with the first being
+/// the "outside" label that is skipped.
+fn check_labels(config: &Config) -> Result<String, String> {
+    let n = config.id2label.len();
+    if n == 0 {
+        return Err("id2label is empty".to_owned());
+    }
+    if let Some(id) = (0..n as i64).find(|id| !config.id2label.contains_key(id)) {
+        return Err(format!("id2label has {n} labels, but none with id {id}"));
+    }
+
+    Ok(format!(
+        "{n} labels, {:?} treated as outside",
+        config.id2label[&0]
+    ))
+}
+
+fn check_inputs(model: &TypedModel) -> Result<String, String> {
+    let outlets = model.input_outlets().map_err(|e| e.to_string())?;
+    let names = outlets
+        .iter()
+        .map(|outlet| model.node(outlet.node).name.as_str())
+        .collect::<Vec<_>>();
+    if names != INPUTS {
+        return Err(format!("expected inputs {INPUTS:?}, found {names:?}"));
+    }
+
+    for (i, name) in names.iter().enumerate() {
+        let fact = model.input_fact(i).map_err(|e| e.to_string())?;
+        if !matches!(fact.datum_type, DatumType::I64 | DatumType::I32) {
+            return Err(format!(
+                "{name} is {:?}, expected I64 or I32",
+                fact.datum_type
+            ));
+        }
+        if fact.rank() != 2 {
+            return Err(format!(
+                "{name} has shape [{:?}], expected [batch, sequence]",
+                fact.shape
+            ));
+        }
+    }
+
+    Ok(names.join(", "))
+}
+
+fn check_outputs(model: &TypedModel, labels: Option<usize>) -> Result<String, String> {
+    let i = output::logits(model, None, labels).map_err(|e| e.to_string())?;
+    let fact = model.output_fact(i).map_err(|e| e.to_string())?;
+    let names = output::names(model).map_err(|e| e.to_string())?;
+    let name = names[i];
+    if fact.datum_type != DatumType::F32 {
+        return Err(format!("logits are {:?}, expected F32", fact.datum_type));
+    }
+    if fact.rank() != 3 {
+        return Err(format!(
+            "logits have shape [{:?}], expected [batch, sequence, labels]",
+            fact.shape
+        ));
+    }
+
+    let dim = fact.shape[2].to_i64().ok().map(|dim| dim as usize);
+    match (dim, labels) {
+        (Some(dim), Some(labels)) if dim != labels => Err(format!(
+            "logits have {dim} labels, but id2label has {labels}"
+        )),
+        (Some(dim), _) => Ok(format!("{name} [{:?}], {dim} labels", fact.shape)),
+        (None, _) => Ok(format!("{name} [{:?}]", fact.shape)),
+    }
+}
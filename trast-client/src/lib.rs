@@ -0,0 +1,442 @@
+//! Typed async client for trast servers.
+//!
+//! ```no_run
+//! # async fn example() -> trast_client::Result<()> {
+//! let client = trast_client::Client::builder()
+//!     .endpoint("http://trast:8000")
+//!     .client_id("my-service")
+//!     .build()?;
+//!
+//! for entity in client.ner("Kalle Anka bor i Ankeborg.").await? {
+//!     println!("{} {}", entity.label, entity.word);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use thiserror::Error;
+use tokio::time;
+use tonic::{
+    metadata::{AsciiMetadataValue, MetadataValue},
+    transport::{Channel, Endpoint},
+    Code, Request, Response, Status,
+};
+pub use trast_proto::{self as proto, Entity, ModelInfo, NerOutput, Priority, WarmedModel};
+use trast_proto::{
+    trast_client::TrastClient, GetConfigRequest, GetModelInfoRequest, ListModelsRequest, NerInput,
+    WarmupRequest,
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Transport(#[from] tonic::transport::Error),
+    #[error("invalid client id")]
+    ClientId,
+    #[error("no endpoints")]
+    NoEndpoints,
+    /// The server replied with an error, after any retries.
+    #[error("{0}")]
+    Status(Box<Status>),
+}
+
+impl Error {
+    /// The status returned by the server, if it was reached.
+    pub fn status(&self) -> Option<&Status> {
+        match self {
+            Self::Status(status) => Some(status),
+            Self::Transport(_) | Self::ClientId | Self::NoEndpoints => None,
+        }
+    }
+}
+
+impl From<Status> for Error {
+    fn from(status: Status) -> Self {
+        Self::Status(Box::new(status))
+    }
+}
+
+pub type Result<T, E = Error> = core::result::Result<T, E>;
+
+/// Configures a [`Client`].
+#[derive(Debug, Clone)]
+pub struct Builder {
+    endpoints: Vec<String>,
+    connections: usize,
+    connect_timeout: Duration,
+    timeout: Option<Duration>,
+    retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    client_id: Option<String>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            endpoints: vec![],
+            connections: 1,
+            connect_timeout: Duration::from_secs(5),
+            timeout: None,
+            retries: 3,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            client_id: None,
+        }
+    }
+}
+
+impl Builder {
+    /// Add a server to send requests to. Requests are balanced across all
+    /// of them.
+    pub fn endpoint(mut self, url: impl Into<String>) -> Self {
+        self.endpoints.push(url.into());
+        self
+    }
+
+    /// Number of connections to open to every endpoint. A single HTTP/2
+    /// connection multiplexes requests, but more spread the load over the
+    /// server's connection handling.
+    pub fn connections(mut self, connections: usize) -> Self {
+        self.connections = connections.max(1);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Default timeout of every attempt at a request.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Number of times to retry a request that failed with `UNAVAILABLE`.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Delay before the first retry, doubled for every following one up to
+    /// the maximum.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Sent as `x-client-id`, which the server uses for quotas and metrics.
+    pub fn client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id = Some(client_id.into());
+        self
+    }
+
+    /// Create the client. Connections are opened lazily, on the first
+    /// request.
+    pub fn build(self) -> Result<Client> {
+        // a balanced channel over nothing waits forever for an endpoint
+        if self.endpoints.is_empty() {
+            return Err(Error::NoEndpoints);
+        }
+        let endpoints = self
+            .endpoints
+            .iter()
+            .map(
+                |url| Ok(Endpoint::from_shared(url.clone())?.connect_timeout(self.connect_timeout)),
+            )
+            .collect::<Result<Vec<_>>>()?;
+
+        let channel = match endpoints.as_slice() {
+            [endpoint] if self.connections == 1 => endpoint.connect_lazy(),
+            _ => Channel::balance_list(
+                endpoints
+                    .iter()
+                    .flat_map(|endpoint| (0..self.connections).map(move |_| endpoint.clone())),
+            ),
+        };
+
+        let client_id = self
+            .client_id
+            .map(|id| MetadataValue::try_from(id).map_err(|_| Error::ClientId))
+            .transpose()?;
+
+        Ok(Client {
+            inner: TrastClient::new(channel),
+            client_id,
+            timeout: self.timeout,
+            deadline: None,
+            retries: self.retries,
+            backoff: self.backoff,
+            max_backoff: self.max_backoff,
+        })
+    }
+}
+
+/// A client for one or more trast servers. Clones share the connections and
+/// are cheap.
+#[derive(Debug, Clone)]
+pub struct Client {
+    inner: TrastClient<Channel>,
+    client_id: Option<AsciiMetadataValue>,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Client {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// A client for a single server, with the default settings.
+    pub fn new(url: impl Into<String>) -> Result<Self> {
+        Self::builder().endpoint(url).build()
+    }
+
+    /// A copy of the client whose attempts time out after the duration.
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self.clone()
+        }
+    }
+
+    /// A copy of the client whose requests, including retries, must finish
+    /// before the instant.
+    pub fn with_deadline(&self, deadline: Instant) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..self.clone()
+        }
+    }
+
+    /// The generated client, for the calls that aren't wrapped.
+    pub fn raw(&self) -> TrastClient<Channel> {
+        self.inner.clone()
+    }
+
+    /// How long the next attempt may take.
+    fn attempt_timeout(&self) -> Result<Option<Duration>> {
+        let Some(deadline) = self.deadline else {
+            return Ok(self.timeout);
+        };
+        let remaining = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+            .ok_or_else(|| Status::deadline_exceeded("deadline exceeded"))?;
+
+        Ok(Some(match self.timeout {
+            Some(timeout) => timeout.min(remaining),
+            None => remaining,
+        }))
+    }
+
+    /// Make the call, retrying with backoff while the server is unavailable.
+    async fn call<T, U, F, Fut>(&self, message: T, f: F) -> Result<U>
+    where
+        T: Clone,
+        F: Fn(TrastClient<Channel>, Request<T>) -> Fut,
+        Fut: Future<Output = Result<Response<U>, Status>>,
+    {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+
+        loop {
+            let timeout = self.attempt_timeout()?;
+            let mut request = Request::new(message.clone());
+            if let Some(client_id) = &self.client_id {
+                request
+                    .metadata_mut()
+                    .insert("x-client-id", client_id.clone());
+            }
+
+            let result = match timeout {
+                Some(timeout) => {
+                    request.set_timeout(timeout);
+                    match time::timeout(timeout, f(self.inner.clone(), request)).await {
+                        Ok(result) => result,
+                        Err(_) => Err(Status::deadline_exceeded("request timed out")),
+                    }
+                }
+                None => f(self.inner.clone(), request).await,
+            };
+
+            match result {
+                Err(status) if status.code() == Code::Unavailable && attempt < self.retries => {
+                    // jitter, so that clients don't retry in lockstep
+                    let delay = backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+                    if matches!(self.deadline, Some(deadline) if Instant::now() + delay >= deadline)
+                    {
+                        return Err(status.into());
+                    }
+                    time::sleep(delay).await;
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    attempt += 1;
+                }
+                result => return Ok(result?.into_inner()),
+            }
+        }
+    }
+
+    /// Recognize the entities in the sentence.
+    pub async fn ner(&self, sentence: &str) -> Result<Vec<Entity>> {
+        let output = self
+            .ner_with_priority(sentence, Priority::Interactive)
+            .await?;
+        Ok(output.entities)
+    }
+
+    /// Recognize the entities in the sentence, along with the model that
+    /// found them.
+    pub async fn ner_with_priority(&self, sentence: &str, priority: Priority) -> Result<NerOutput> {
+        let input = NerInput {
+            sentence: sentence.to_owned(),
+            priority: priority as i32,
+            ..Default::default()
+        };
+        self.call(input, |mut client, request| async move {
+            client.ner(request).await
+        })
+        .await
+    }
+
+    pub async fn list_models(&self) -> Result<Vec<ModelInfo>> {
+        let response = self
+            .call(ListModelsRequest {}, |mut client, request| async move {
+                client.list_models(request).await
+            })
+            .await?;
+        Ok(response.models)
+    }
+
+    /// The state of the model and what its model card declares.
+    pub async fn model_info(&self, id: &str) -> Result<ModelInfo> {
+        let request = GetModelInfoRequest { id: id.to_owned() };
+        self.call(request, |mut client, request| async move {
+            client.get_model_info(request).await
+        })
+        .await
+    }
+
+    /// The effective configuration of the server as JSON, with secrets
+    /// redacted.
+    pub async fn config(&self) -> Result<String> {
+        let response = self
+            .call(GetConfigRequest {}, |mut client, request| async move {
+                client.get_config(request).await
+            })
+            .await?;
+        Ok(response.config)
+    }
+
+    /// Have the server warm up the model, or all models that it serves.
+    pub async fn warmup(&self, model: Option<&str>) -> Result<Vec<WarmedModel>> {
+        let request = WarmupRequest {
+            model: model.unwrap_or_default().to_owned(),
+        };
+        let response = self
+            .call(request, |mut client, request| async move {
+                client.warmup(request).await
+            })
+            .await?;
+        Ok(response.models)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    fn client() -> Client {
+        Client::new("http://127.0.0.1:1").unwrap()
+    }
+
+    #[test]
+    fn build_without_endpoints_fails() {
+        let result = Client::builder().build();
+        assert!(matches!(result, Err(Error::NoEndpoints)));
+    }
+
+    #[tokio::test]
+    async fn attempts_time_out_after_the_timeout() {
+        let client = client();
+        assert_eq!(client.attempt_timeout().unwrap(), None);
+
+        let timeout = Duration::from_secs(1);
+        let client = client.with_timeout(timeout);
+        assert_eq!(client.attempt_timeout().unwrap(), Some(timeout));
+
+        // a distant deadline leaves the timeout as is
+        let client = client.with_deadline(Instant::now() + Duration::from_secs(60));
+        assert_eq!(client.attempt_timeout().unwrap(), Some(timeout));
+    }
+
+    #[tokio::test]
+    async fn attempts_are_clamped_to_the_deadline() {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        for client in [client(), client().with_timeout(Duration::from_secs(60))] {
+            let timeout = client.with_deadline(deadline).attempt_timeout().unwrap();
+            assert!(timeout.unwrap() <= Duration::from_secs(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn passed_deadlines_fail_without_an_attempt() {
+        let client = client().with_deadline(Instant::now());
+        let error = client.attempt_timeout().unwrap_err();
+        assert_eq!(error.status().unwrap().code(), Code::DeadlineExceeded);
+    }
+
+    #[tokio::test]
+    async fn unavailable_is_retried() {
+        let client = Client {
+            backoff: Duration::from_millis(1),
+            ..client()
+        };
+        let attempts = AtomicU32::new(0);
+        let result = client
+            .call((), |_, _| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err::<Response<()>, _>(Status::unavailable("down"))
+            })
+            .await;
+
+        assert_eq!(
+            result.unwrap_err().status().unwrap().code(),
+            Code::Unavailable
+        );
+        assert_eq!(attempts.load(Ordering::SeqCst), client.retries + 1);
+    }
+
+    #[tokio::test]
+    async fn retries_stop_at_the_deadline() {
+        let client = Client {
+            backoff: Duration::from_secs(60),
+            max_backoff: Duration::from_secs(60),
+            ..client()
+        }
+        .with_deadline(Instant::now() + Duration::from_secs(1));
+        let attempts = AtomicU32::new(0);
+        let result = client
+            .call((), |_, _| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err::<Response<()>, _>(Status::unavailable("down"))
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,4 +1,13 @@
 fn main() {
-    tonic_build::compile_protos("proto/trast.proto")
+    let mut builder = tonic_build::configure();
+    // the replies that are also written as JSON, such as by the Redis worker
+    for message in ["trast.NerOutput", "trast.Entity", "trast.Candidate"] {
+        builder = builder.type_attribute(
+            message,
+            r#"#[cfg_attr(feature = "serde", derive(serde::Serialize))]"#,
+        );
+    }
+    builder
+        .compile(&["proto/trast.proto"], &["proto"])
         .unwrap_or_else(|e| panic!("Failed to compile protos {e:?}"));
 }
@@ -0,0 +1,1021 @@
+use std::{
+    future::Future,
+    ops::RangeInclusive,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::{future, stream::FuturesUnordered, FutureExt, StreamExt};
+use onnx_bert::{Entity, Pipeline, Predicted, Resident};
+use opentelemetry::KeyValue;
+use tokio::{
+    select,
+    sync::{mpsc, oneshot, Notify},
+    task::{spawn_blocking, JoinHandle},
+    time::{self, interval, sleep_until},
+};
+use tokio_rayon::{
+    rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder},
+    AsyncThreadPool,
+};
+use tonic::Status;
+use tracing::{debug, error, info, instrument, warn, Instrument, Span};
+
+use trast_proto::Priority;
+
+use crate::{
+    affinity,
+    breaker::CircuitBreaker,
+    config::{Config, UnloadPolicy},
+    mailbox::{self, Inbox, Mailbox, Queued},
+    metrics::Metrics,
+    queue::FairQueue,
+    registry::Registry,
+    trace, Error, Result,
+};
+
+const AUTOSCALE_INTERVAL: Duration = Duration::from_secs(5);
+/// Delay before the first retry of a failed inference, growing linearly with
+/// every attempt.
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone)]
+pub struct Prediction {
+    pub entities: Vec<Entity>,
+    /// Number of tokens the sentence was encoded into.
+    pub tokens: usize,
+    /// Whether the sentence is longer than the model takes, and was cut off.
+    pub truncated: bool,
+    /// Number of words in what the model saw of the sentence.
+    pub words: usize,
+    /// The model that made the prediction.
+    pub model: String,
+    /// Number of sentences in the batch that the sentence was part of.
+    pub batch_size: usize,
+}
+
+#[derive(Debug)]
+pub struct Message {
+    pub sentence: String,
+    pub tx: oneshot::Sender<Result<Prediction, Status>>,
+    pub priority: Priority,
+    /// Identity of the client that sent the request.
+    pub client: String,
+    pub span: Span,
+    /// Attributes taken from the baggage of the request.
+    pub baggage: Vec<KeyValue>,
+    pub received: Instant,
+    /// Whether the sentence is run in windows of what the model takes,
+    /// rather than truncated, whether or not the pipeline chunks.
+    pub windowed: bool,
+}
+
+type Handles = FuturesUnordered<JoinHandle<()>>;
+
+/// Run the sentences in separate forward passes grouped by token length, so
+/// that a single long sentence doesn't inflate the padding of the others.
+/// Those to be windowed are run in windows of their own forward pass.
+fn predict(
+    pipeline: &Pipeline,
+    sentences: &[(String, bool)],
+    buckets: &[usize],
+    model: &str,
+) -> onnx_bert::Result<Vec<Prediction>> {
+    let (windowed, bucketed): (Vec<_>, Vec<_>) =
+        (0..sentences.len()).partition(|&i| sentences[i].1);
+    let texts = |indices: &[usize]| {
+        indices
+            .iter()
+            .map(|&i| sentences[i].0.as_str())
+            .collect::<Vec<_>>()
+    };
+
+    let mut predicted = vec![Predicted::default(); sentences.len()];
+    let windows = pipeline.predict_batch_windowed(&texts(&windowed))?;
+    let buckets = pipeline.predict_batch_bucketed(&texts(&bucketed), buckets)?;
+    for (i, p) in windowed.into_iter().zip(windows) {
+        predicted[i] = p;
+    }
+    for (i, p) in bucketed.into_iter().zip(buckets) {
+        predicted[i] = p;
+    }
+
+    let predictions = predicted
+        .into_iter()
+        .map(|predicted| Prediction {
+            entities: predicted.entities,
+            tokens: predicted.tokens,
+            truncated: predicted.truncated,
+            words: predicted.words,
+            model: model.to_owned(),
+            batch_size: sentences.len(),
+        })
+        .collect();
+    Ok(predictions)
+}
+
+/// Run the inference until it succeeds, fails for good or has been retried
+/// `retries` times, backing off linearly. Returns the last outcome along
+/// with the number of retries.
+async fn retry<T, R, Fut>(
+    retries: u32,
+    mut run: impl FnMut() -> Fut,
+) -> ((onnx_bert::Result<T>, R), u32)
+where
+    Fut: Future<Output = (onnx_bert::Result<T>, R)>,
+{
+    let mut attempt = 0;
+    loop {
+        let outcome = run().await;
+        match &outcome.0 {
+            Err(e) if e.is_transient() && attempt < retries => {
+                attempt += 1;
+                warn!(?e, attempt, "retrying inference");
+                time::sleep(RETRY_BACKOFF * attempt).await;
+            }
+            _ => return (outcome, attempt),
+        }
+    }
+}
+
+/// Load the pipeline, on the thread pool of the NUMA node that it's bound to
+/// if any, so that its weights are allocated there.
+#[instrument(skip(registry, resident, threadpool))]
+async fn get_pipeline(
+    registry: Arc<Registry>,
+    model: String,
+    resident: Option<Resident>,
+    threadpool: Option<Arc<ThreadPool>>,
+) -> Result<Pipeline> {
+    let span = Span::current();
+    let load = move || span.in_scope(|| registry.load(&model, resident));
+    let pipeline = match threadpool {
+        Some(threadpool) => threadpool.spawn_async(load).await?,
+        None => spawn_blocking(load).await??,
+    };
+    Ok(pipeline)
+}
+
+#[instrument(skip(registry, resident, replica_pools))]
+async fn get_replicas(
+    registry: &Arc<Registry>,
+    model: &str,
+    replicas: usize,
+    resident: Option<Resident>,
+    replica_pools: &[Arc<ThreadPool>],
+) -> Result<Vec<Arc<Pipeline>>> {
+    let pipelines = future::try_join_all((0..replicas.max(1)).map(|replica| {
+        get_pipeline(
+            Arc::clone(registry),
+            model.to_owned(),
+            resident.clone(),
+            replica_pool(replica_pools, replica),
+        )
+    }))
+    .await?;
+    Ok(pipelines.into_iter().map(Arc::new).collect())
+}
+
+/// The thread pool of the replica, if it has one of its own.
+fn replica_pool(replica_pools: &[Arc<ThreadPool>], replica: usize) -> Option<Arc<ThreadPool>> {
+    match replica_pools.len() {
+        0 => None,
+        len => Some(Arc::clone(&replica_pools[replica % len])),
+    }
+}
+
+/// A thread pool for every replica that may be loaded, so that replicas run
+/// side by side instead of taking turns on the same threads. Replicas bound
+/// to the same NUMA node split its cores, and replicas that aren't bound to
+/// any split the threads of the model. A single replica that isn't bound to
+/// a node runs on the thread pool of the model.
+fn replica_pools(
+    config: &Config,
+    threadpool: &ThreadPool,
+) -> Result<Vec<Arc<ThreadPool>>, ThreadPoolBuildError> {
+    let replicas = config.max_pipeline_replicas.max(1);
+    let nodes = &config.replica_numa_nodes;
+    if replicas == 1 && nodes.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    (0..replicas)
+        .map(|replica| {
+            let node = (!nodes.is_empty()).then(|| nodes[replica % nodes.len()]);
+            let (threads, sharing) = match node {
+                // a thread per core of the node, unless configured otherwise
+                Some(node) => (
+                    config
+                        .model_threads
+                        .get(&config.model)
+                        .copied()
+                        .or_else(|| Some(affinity::node_cores(node).ok()?.len()))
+                        .unwrap_or_else(|| threadpool.current_num_threads()),
+                    (replica % nodes.len()..replicas)
+                        .step_by(nodes.len())
+                        .count(),
+                ),
+                None => (threadpool.current_num_threads(), replicas),
+            };
+
+            let model = config.model.clone();
+            let cores = config.inference_cores.clone();
+            let threadpool = ThreadPoolBuilder::new()
+                .num_threads((threads / sharing).max(1))
+                .thread_name(move |i| match node {
+                    Some(node) => format!("{model}-node{node}-replica{replica}-{i}"),
+                    None => format!("{model}-replica{replica}-{i}"),
+                })
+                .start_handler(move |_| match (node, &cores) {
+                    (Some(node), _) => {
+                        if let Err(e) = affinity::bind(node) {
+                            warn!(?e, node, "failed to bind inference thread to NUMA node");
+                        }
+                    }
+                    (None, Some(cores)) => {
+                        if let Err(e) = affinity::pin(cores) {
+                            warn!(?e, ?cores, "failed to pin inference thread");
+                        }
+                    }
+                    (None, None) => {}
+                })
+                .build()?;
+            Ok(Arc::new(threadpool))
+        })
+        .collect()
+}
+
+/// Whether to add or remove a replica.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scale {
+    Out,
+    In,
+    Keep,
+}
+
+/// How to scale `replicas` loaded replicas, given the number of batches in
+/// flight per replica and the average latency. Replicas that failed to load
+/// are added again, and the last one is never removed.
+fn scale(
+    replicas: usize,
+    load: f64,
+    latency: Duration,
+    bounds: RangeInclusive<usize>,
+    target: Option<Duration>,
+) -> Scale {
+    let min = (*bounds.start()).max(1);
+
+    if replicas < min
+        || (load > 1. || matches!(target, Some(t) if latency > t)) && replicas < *bounds.end()
+    {
+        Scale::Out
+    } else if load < 0.5 && !matches!(target, Some(t) if latency >= t / 2) && replicas > min {
+        Scale::In
+    } else {
+        Scale::Keep
+    }
+}
+
+struct Actor {
+    /// Identical copies of the pipeline, empty if it isn't loaded.
+    replicas: Vec<Arc<Pipeline>>,
+    /// What is left of the pipeline after its weights were unloaded.
+    resident: Option<Resident>,
+    next_replica: usize,
+    /// The fallback pipeline, if it has been needed since it was last
+    /// unloaded.
+    fallback: Option<Arc<Pipeline>>,
+    breaker: Arc<CircuitBreaker>,
+    fallback_breaker: Arc<CircuitBreaker>,
+    eviction: Arc<Notify>,
+    fallback_eviction: Arc<Notify>,
+    /// Whether the registry asked for the pipelines to be evicted, which
+    /// happens once they may be unloaded.
+    evicting: bool,
+    fallback_evicting: bool,
+    /// Whether an additional replica is currently being loaded.
+    scaling: bool,
+    /// Moving average of the request latency in microseconds.
+    latency: Arc<AtomicU64>,
+    last_active: time::Instant,
+    /// Interactive messages waiting to be dispatched.
+    interactive: FairQueue<Queued>,
+    /// When the pending interactive batch must be dispatched even if it
+    /// isn't full.
+    deadline: time::Instant,
+    /// Batch priority messages, dispatched only when workers are idle.
+    background: FairQueue<Queued>,
+    threadpool: Arc<ThreadPool>,
+    /// Thread pools of the replicas, by replica, or empty if the single
+    /// replica runs on [`Actor::threadpool`].
+    replica_pools: Vec<Arc<ThreadPool>>,
+    metrics: Arc<Metrics>,
+    registry: Arc<Registry>,
+    config: Config,
+    handles: Handles,
+}
+
+impl Actor {
+    /// Batching only pays off once every worker is busy; until then it just
+    /// adds latency.
+    fn has_idle_workers(&self) -> bool {
+        self.handles.len() < self.workers()
+    }
+
+    /// Whether more batches may be queued in the thread pool. Anything beyond
+    /// that stays in the fair queues of the actor.
+    fn has_capacity(&self) -> bool {
+        self.handles.len() < 2 * self.workers()
+    }
+
+    /// Number of threads that batches of the primary model run on, which
+    /// grows and shrinks with the replicas.
+    fn workers(&self) -> usize {
+        if self.replica_pools.is_empty() {
+            return self.threadpool.current_num_threads();
+        }
+        let replicas = self.replicas.len().clamp(1, self.replica_pools.len());
+        self.replica_pools[..replicas]
+            .iter()
+            .map(|pool| pool.current_num_threads())
+            .sum()
+    }
+
+    async fn enqueue(&mut self, queued: Queued, overflow: usize) {
+        if self.is_unavailable() {
+            let status = Status::from(Error::Unavailable(self.config.model.clone()));
+            let _ = queued.into_message().tx.send(Err(status));
+            return;
+        }
+
+        let client = queued.message.client.clone();
+        match queued.message.priority {
+            Priority::Interactive => {
+                if self.interactive.is_empty() {
+                    self.deadline = time::Instant::now() + self.config.batch_max_delay;
+                }
+                self.interactive.push(client, queued);
+            }
+            Priority::Batch => self.background.push(client, queued),
+        }
+
+        // background messages go first, then the oldest interactive ones
+        for _ in 0..overflow {
+            let received = |queued: &Queued| queued.message.received;
+            match self.background.pop_min_by_key(received) {
+                Some(queued) => queued.shed(),
+                None => match self.interactive.pop_min_by_key(received) {
+                    Some(queued) => queued.shed(),
+                    None => break,
+                },
+            }
+        }
+
+        self.dispatch().await;
+    }
+
+    /// Take the next batch out of the queue, freeing the places of its
+    /// messages in the mailbox.
+    fn take_batch(queue: &mut FairQueue<Queued>, n: usize) -> Vec<Message> {
+        queue
+            .take(n)
+            .into_iter()
+            .map(Queued::into_message)
+            .collect()
+    }
+
+    /// Hand queued work to the workers. Background work is only dispatched
+    /// to idle workers, as long as no interactive request is waiting.
+    async fn dispatch(&mut self) {
+        while !self.interactive.is_empty() {
+            let full = self.interactive.len() >= self.config.batch_max_size;
+            let expired = time::Instant::now() >= self.deadline;
+            if !(self.has_idle_workers() || self.has_capacity() && (full || expired)) {
+                return;
+            }
+
+            let batch = Self::take_batch(&mut self.interactive, self.config.batch_max_size);
+            self.spawn_batch(batch).await;
+            self.deadline = time::Instant::now() + self.config.batch_max_delay;
+        }
+
+        while !self.background.is_empty() && self.has_idle_workers() {
+            let batch = Self::take_batch(&mut self.background, self.config.batch_max_size);
+            self.spawn_batch(batch).await;
+        }
+    }
+
+    /// Whether no model is able to serve requests right now, in which case
+    /// they are rejected instead of being queued.
+    fn is_unavailable(&self) -> bool {
+        self.breaker.is_open()
+            && (self.config.fallback_model.is_none() || self.fallback_breaker.is_open())
+    }
+
+    /// Load the primary pipeline if needed, returning the next replica and the
+    /// thread pool to run it on.
+    async fn load_primary(
+        &mut self,
+        batch_size: usize,
+    ) -> Result<(Arc<Pipeline>, Arc<ThreadPool>)> {
+        if let Some(pipeline) = self.registry.take_staged(&self.config.model) {
+            // batches in flight keep the old revision alive until they finish
+            info!("swapping in new model revision");
+            let count = self.replicas.len().max(self.config.pipeline_replicas);
+            let others = future::try_join_all((1..count).map(|replica| {
+                get_pipeline(
+                    Arc::clone(&self.registry),
+                    self.config.model.clone(),
+                    None,
+                    replica_pool(&self.replica_pools, replica),
+                )
+            }))
+            .await;
+
+            let mut replicas = vec![pipeline];
+            match others {
+                Ok(others) => replicas.extend(others.into_iter().map(Arc::new)),
+                // autoscaling adds the missing replicas later
+                Err(e) => error!(?e, "failed to load replicas of new model revision"),
+            }
+            self.metrics
+                .set_replicas(&self.config.model, &replicas[0], replicas.len());
+            self.replicas = replicas;
+            self.resident = None;
+            self.track_memory();
+        }
+
+        if self.replicas.is_empty() {
+            for _ in 0..batch_size {
+                self.metrics.cold_request(&self.config.model);
+            }
+            debug!("initializing pipeline");
+
+            let replicas = get_replicas(
+                &self.registry,
+                &self.config.model,
+                self.config.pipeline_replicas,
+                self.resident.clone(),
+                &self.replica_pools,
+            )
+            .await?;
+            self.metrics
+                .pipeline_loaded(&self.config.model, &replicas[0], replicas.len());
+            self.replicas = replicas;
+            self.resident = None;
+            self.track_memory();
+
+            debug!("initialized pipeline");
+        }
+
+        self.next_replica = (self.next_replica + 1) % self.replicas.len();
+        let threadpool = replica_pool(&self.replica_pools, self.next_replica)
+            .unwrap_or_else(|| Arc::clone(&self.threadpool));
+        Ok((Arc::clone(&self.replicas[self.next_replica]), threadpool))
+    }
+
+    async fn load_fallback(&mut self, model: &str, batch_size: usize) -> Result<Arc<Pipeline>> {
+        if let Some(pipeline) = &self.fallback {
+            return Ok(Arc::clone(pipeline));
+        }
+
+        for _ in 0..batch_size {
+            self.metrics.cold_request(model);
+        }
+        debug!("initializing fallback pipeline");
+
+        let pipeline =
+            Arc::new(get_pipeline(Arc::clone(&self.registry), model.to_owned(), None, None).await?);
+        self.metrics.pipeline_loaded(model, &pipeline, 1);
+        self.registry
+            .set_memory(model, pipeline.model_size() + pipeline.tokenizer_size());
+        self.fallback = Some(Arc::clone(&pipeline));
+
+        Ok(pipeline)
+    }
+
+    /// Pick the model, pipeline and thread pool to run the next batch on,
+    /// falling back to the fallback model while the circuit breaker of the
+    /// primary is open.
+    async fn select_pipeline(
+        &mut self,
+        batch_size: usize,
+    ) -> Result<(String, Arc<Pipeline>, Arc<ThreadPool>)> {
+        if self.breaker.allow() {
+            match self.load_primary(batch_size).await {
+                Ok((pipeline, threadpool)) => {
+                    return Ok((self.config.model.clone(), pipeline, threadpool))
+                }
+                Err(e) => {
+                    error!(?e, "failed to load primary model");
+                    self.breaker.trip();
+                    if self.config.fallback_model.is_none() {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        let Some(model) = self.config.fallback_model.clone() else {
+            return Err(Error::Unavailable(self.config.model.clone()));
+        };
+        if !self.fallback_breaker.allow() {
+            return Err(Error::Unavailable(model));
+        }
+
+        match self.load_fallback(&model, batch_size).await {
+            Ok(pipeline) => Ok((model, pipeline, Arc::clone(&self.threadpool))),
+            Err(e) => {
+                self.fallback_breaker.trip();
+                Err(e)
+            }
+        }
+    }
+
+    fn autoscale(&mut self, tx: &mpsc::Sender<Result<Arc<Pipeline>>>) {
+        if self.replicas.is_empty() || self.scaling {
+            return;
+        }
+
+        let load = self.handles.len() as f64 / self.replicas.len() as f64;
+        let latency = Duration::from_micros(self.latency.load(Ordering::Relaxed));
+
+        let bounds = self.config.pipeline_replicas..=self.config.max_pipeline_replicas;
+        match scale(
+            self.replicas.len(),
+            load,
+            latency,
+            bounds,
+            self.config.target_latency,
+        ) {
+            Scale::Out => {
+                info!(load, ?latency, "adding replica");
+                self.scaling = true;
+                let tx = tx.clone();
+                let registry = Arc::clone(&self.registry);
+                let model = self.config.model.clone();
+                let threadpool = replica_pool(&self.replica_pools, self.replicas.len());
+                tokio::spawn(async move {
+                    let pipeline = get_pipeline(registry, model, None, threadpool).await;
+                    let _ = tx.send(pipeline.map(Arc::new)).await;
+                });
+            }
+            Scale::In => {
+                info!(load, ?latency, "removing replica");
+                self.replicas.pop();
+                if let Some(first) = self.replicas.first() {
+                    self.metrics
+                        .set_replicas(&self.config.model, first, self.replicas.len());
+                }
+                self.track_memory();
+            }
+            Scale::Keep => {}
+        }
+    }
+
+    fn add_replica(&mut self, result: Result<Arc<Pipeline>>) {
+        self.scaling = false;
+
+        match result {
+            // the pipeline may have been dropped in the meantime
+            Ok(pipeline) if !self.replicas.is_empty() => {
+                self.replicas.push(pipeline);
+                self.metrics.set_replicas(
+                    &self.config.model,
+                    &self.replicas[0],
+                    self.replicas.len(),
+                );
+                self.track_memory();
+            }
+            Ok(_) => {}
+            Err(e) => error!(?e, "failed to add replica"),
+        }
+    }
+
+    /// Load the pipeline before the first request arrives, so that it
+    /// doesn't have to pay for the cold start.
+    async fn prefetch(&mut self) {
+        info!(model = self.config.model, "prefetching pipeline");
+        if let Err(e) = self.select_pipeline(0).await {
+            // requests will retry loading the pipeline
+            error!(?e, "failed to prefetch pipeline");
+        }
+        self.last_active = time::Instant::now();
+    }
+
+    /// Report the memory used by the replicas to the registry, which
+    /// enforces the memory budget.
+    fn track_memory(&self) {
+        let bytes = self.replicas.iter().fold(0, |bytes, pipeline| {
+            bytes + pipeline.model_size() + pipeline.tokenizer_size()
+        });
+        self.registry.set_memory(&self.config.model, bytes);
+    }
+
+    /// Whether the idle pipeline of the model may be unloaded.
+    fn may_unload(&self, model: &str) -> bool {
+        self.config.unload_policy != UnloadPolicy::Never
+            && !self.config.is_pinned(model)
+            && self.handles.is_empty()
+    }
+
+    /// Drop the pipeline, keeping as much of it as the unload policy allows.
+    fn unload_primary(&mut self) {
+        self.evicting = false;
+        if self.replicas.is_empty() {
+            return;
+        }
+
+        let replicas = std::mem::take(&mut self.replicas);
+        if self.config.unload_policy == UnloadPolicy::Weights {
+            // batches in flight keep their replica alive, so only an unused
+            // one can be taken apart
+            self.resident = replicas
+                .into_iter()
+                .find_map(|pipeline| Arc::try_unwrap(pipeline).ok())
+                .map(Pipeline::unload_weights);
+        }
+
+        self.registry.unloaded(&self.config.model);
+        self.track_memory();
+        if self.resident.is_some() {
+            self.metrics.weights_unloaded(&self.config.model);
+            info!("dropped model weights");
+        } else {
+            self.metrics.pipeline_unloaded(&self.config.model);
+            info!("dropped pipeline");
+        }
+    }
+
+    fn unload_fallback(&mut self) {
+        self.fallback_evicting = false;
+        if self.fallback.take().is_some() {
+            let model = self.config.fallback_model.as_deref().unwrap_or_default();
+            self.metrics.pipeline_unloaded(model);
+            self.registry.unloaded(model);
+            self.registry.set_memory(model, 0);
+            info!("dropped fallback pipeline");
+        }
+    }
+
+    #[instrument(skip_all, fields(batch_size = batch.len(), cold, model))]
+    async fn spawn_batch(&mut self, batch: Vec<Message>) {
+        let span = Span::current();
+        span.record("cold", self.replicas.is_empty());
+        for message in &batch {
+            span.follows_from(&message.span);
+        }
+        // only what the requests of the batch have in common
+        if let Some((first, rest)) = batch.split_first() {
+            let shared = first
+                .baggage
+                .iter()
+                .filter(|attribute| rest.iter().all(|m| m.baggage.contains(attribute)))
+                .cloned()
+                .collect::<Vec<_>>();
+            trace::set_attributes(&span, &shared);
+        }
+
+        let (model, pipeline, threadpool) = match self.select_pipeline(batch.len()).await {
+            Ok(selected) => selected,
+            Err(e) => {
+                let status = Status::from(e);
+                for message in batch {
+                    let _ = message.tx.send(Err(status.clone()));
+                }
+                return;
+            }
+        };
+        span.record("model", &model);
+        self.registry.record_requests(&model, batch.len() as u64);
+
+        let is_primary = model == self.config.model;
+        let breaker = if is_primary {
+            Arc::clone(&self.breaker)
+        } else {
+            Arc::clone(&self.fallback_breaker)
+        };
+        let slow_request_threshold = self.config.slow_request_threshold;
+        let buckets = self.config.batch_buckets.clone();
+        let latency = Arc::clone(&self.latency);
+        let metrics = Arc::clone(&self.metrics);
+        let retries = self.config.inference_retries;
+
+        debug!("recognizing entities");
+
+        let handle = tokio::spawn(
+            async move {
+                let batch_size = batch.len();
+                let sentences = Arc::new(
+                    batch
+                        .iter()
+                        .map(|m| (m.sentence.clone(), m.windowed))
+                        .collect::<Vec<_>>(),
+                );
+                let buckets = Arc::new(buckets);
+
+                let ((result, (started, inference_time)), attempt) = retry(retries, || {
+                    let span = Span::current();
+                    let pipeline = Arc::clone(&pipeline);
+                    let sentences = Arc::clone(&sentences);
+                    let buckets = Arc::clone(&buckets);
+                    let model = model.clone();
+                    let threadpool = Arc::clone(&threadpool);
+                    async move {
+                        threadpool
+                            .spawn_fifo_async(move || {
+                                let started = Instant::now();
+                                let result = span
+                                    .in_scope(|| predict(&pipeline, &sentences, &buckets, &model));
+                                (result, (started, started.elapsed()))
+                            })
+                            .await
+                    }
+                })
+                .await;
+
+                let max_latency = batch
+                    .iter()
+                    .map(|m| started.saturating_duration_since(m.received) + inference_time)
+                    .max()
+                    .unwrap_or_default()
+                    .as_micros() as u64;
+                let average = latency.load(Ordering::Relaxed);
+                latency.store((average * 4 + max_latency) / 5, Ordering::Relaxed);
+
+                if let Some(threshold) = slow_request_threshold {
+                    for message in &batch {
+                        let queue_wait = started.saturating_duration_since(message.received);
+                        if queue_wait + inference_time > threshold {
+                            warn!(
+                                sentence_len = message.sentence.len(),
+                                model,
+                                batch_size,
+                                ?queue_wait,
+                                ?inference_time,
+                                "slow request"
+                            );
+                        }
+                    }
+                }
+
+                match result {
+                    Ok(_) => breaker.record_success(),
+                    Err(_) => breaker.record_failure(),
+                }
+
+                match result {
+                    Ok(predictions) => {
+                        for (message, prediction) in batch.into_iter().zip(predictions) {
+                            let _ = message.tx.send(Ok(prediction));
+                        }
+                    }
+                    Err(e) => {
+                        error!(?e);
+                        // keep failed inputs around for offline analysis
+                        let reason = if e.is_transient() {
+                            "retries_exhausted"
+                        } else {
+                            "non_retryable"
+                        };
+                        metrics.dead_letter(&model, reason, batch_size);
+                        for message in &batch {
+                            error!(
+                                target: "trast::dead_letter",
+                                model,
+                                reason,
+                                attempts = attempt + 1,
+                                client = message.client,
+                                sentence = message.sentence,
+                                error = %e,
+                            );
+                        }
+
+                        let status = Status::from(crate::Error::from(e));
+                        for message in batch {
+                            let _ = message.tx.send(Err(status.clone()));
+                        }
+                    }
+                };
+            }
+            .in_current_span(),
+        );
+
+        self.handles.push(handle);
+        self.last_active = time::Instant::now();
+    }
+}
+
+impl Actor {
+    fn new(
+        threadpool: Arc<ThreadPool>,
+        replica_pools: Vec<Arc<ThreadPool>>,
+        metrics: Arc<Metrics>,
+        registry: Arc<Registry>,
+        config: Config,
+    ) -> Self {
+        Self {
+            replicas: Vec::new(),
+            resident: None,
+            next_replica: 0,
+            fallback: None,
+            breaker: Arc::new(CircuitBreaker::new(
+                &config.model,
+                config.breaker_threshold,
+                config.breaker_cooldown,
+            )),
+            fallback_breaker: Arc::new(CircuitBreaker::new(
+                config.fallback_model.as_deref().unwrap_or_default(),
+                config.breaker_threshold,
+                config.breaker_cooldown,
+            )),
+            eviction: registry.eviction(&config.model),
+            fallback_eviction: registry
+                .eviction(config.fallback_model.as_deref().unwrap_or_default()),
+            evicting: false,
+            fallback_evicting: false,
+            scaling: false,
+            latency: Arc::default(),
+            last_active: time::Instant::now(),
+            interactive: FairQueue::default(),
+            deadline: time::Instant::now(),
+            background: FairQueue::default(),
+            threadpool,
+            replica_pools,
+            metrics,
+            registry,
+            config,
+            handles: FuturesUnordered::new(),
+        }
+    }
+
+    async fn run(&mut self, inbox: &mut Inbox, prefetched: Option<oneshot::Sender<()>>) {
+        let (scale_tx, mut scale_rx) = mpsc::channel(1);
+        let mut autoscale = interval(AUTOSCALE_INTERVAL);
+
+        if let Some(prefetched) = prefetched {
+            self.prefetch().await;
+            let _ = prefetched.send(());
+        }
+
+        loop {
+            select! {
+                message = inbox.recv() => match message {
+                    Some(queued) => self.enqueue(queued, inbox.overflow()).await,
+                    None => return,
+                },
+                _ = sleep_until(self.deadline), if !self.interactive.is_empty() && self.has_capacity() => {
+                    self.dispatch().await;
+                }
+                Some(_) = self.handles.next(), if !self.handles.is_empty() => {
+                    self.last_active = time::Instant::now();
+                    self.dispatch().await;
+                }
+                _ = autoscale.tick() => self.autoscale(&scale_tx),
+                Some(result) = scale_rx.recv() => self.add_replica(result),
+                _ = sleep_until(self.last_active + self.config.ttl(&self.config.model)), if !self.replicas.is_empty() && self.may_unload(&self.config.model) => {
+                    self.unload_primary();
+                }
+                _ = sleep_until(self.last_active + self.config.ttl(self.config.fallback_model.as_deref().unwrap_or_default())), if self.fallback.is_some() && self.may_unload(self.config.fallback_model.as_deref().unwrap_or_default()) => {
+                    self.unload_fallback();
+                }
+                _ = self.eviction.notified() => self.evicting = true,
+                _ = self.fallback_eviction.notified() => self.fallback_evicting = true,
+                _ = future::ready(()), if self.evicting && self.may_unload(&self.config.model) => {
+                    self.unload_primary();
+                }
+                _ = future::ready(()), if self.fallback_evicting && self.may_unload(self.config.fallback_model.as_deref().unwrap_or_default()) => {
+                    self.unload_fallback();
+                }
+            }
+        }
+    }
+}
+
+/// Spawn an actor serving the model in `config`. The actor is restarted if
+/// it panics, failing the requests it had queued. This fails if the thread
+/// pools of its replicas can't be built.
+pub fn act(
+    threadpool: Arc<ThreadPool>,
+    metrics: Arc<Metrics>,
+    registry: Arc<Registry>,
+    config: Config,
+    mut prefetched: Option<oneshot::Sender<()>>,
+) -> Result<Mailbox, ThreadPoolBuildError> {
+    let (mailbox, mut inbox) = mailbox::mailbox(
+        config.model.clone(),
+        config.actor_queue_capacity,
+        config.actor_overflow_policy,
+        Arc::clone(&metrics),
+    );
+    let replica_pools = replica_pools(&config, &threadpool)?;
+
+    tokio::spawn(async move {
+        loop {
+            let mut actor = Actor::new(
+                Arc::clone(&threadpool),
+                replica_pools.clone(),
+                Arc::clone(&metrics),
+                Arc::clone(&registry),
+                config.clone(),
+            );
+            let result = AssertUnwindSafe(actor.run(&mut inbox, prefetched.take()))
+                .catch_unwind()
+                .await;
+
+            match result {
+                Ok(()) => return,
+                // dropping the actor drops the senders of the queued requests
+                Err(_) => error!(model = config.model, "actor panicked, restarting"),
+            }
+        }
+    });
+
+    Ok(mailbox)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scale from `replicas` for as long as the load calls for it.
+    fn settle(mut replicas: usize, load: f64, bounds: RangeInclusive<usize>) -> usize {
+        loop {
+            match scale(replicas, load, Duration::ZERO, bounds.clone(), None) {
+                Scale::Out => replicas += 1,
+                Scale::In => replicas -= 1,
+                Scale::Keep => return replicas,
+            }
+        }
+    }
+
+    #[test]
+    fn scales_out_under_load_and_back_in() {
+        let replicas = settle(1, 2., 1..=4);
+        assert_eq!(replicas, 4);
+        assert_eq!(settle(replicas, 0., 1..=4), 1);
+    }
+
+    #[test]
+    fn scales_out_above_target_latency() {
+        let target = Some(Duration::from_millis(100));
+        let slow = Duration::from_millis(150);
+        assert_eq!(scale(1, 0.5, slow, 1..=2, target), Scale::Out);
+        assert_eq!(scale(2, 0.5, slow, 1..=2, target), Scale::Keep);
+        // not until the latency is well below the target
+        let fine = Duration::from_millis(60);
+        assert_eq!(scale(2, 0., fine, 1..=2, target), Scale::Keep);
+        assert_eq!(scale(2, 0., fine / 2, 1..=2, target), Scale::In);
+    }
+
+    #[test]
+    fn replaces_missing_replicas() {
+        assert_eq!(scale(1, 0., Duration::ZERO, 3..=3, None), Scale::Out);
+    }
+
+    #[test]
+    fn keeps_the_last_replica() {
+        assert_eq!(scale(1, 0., Duration::ZERO, 0..=4, None), Scale::Keep);
+        assert_eq!(settle(4, 0., 0..=4), 1);
+    }
+
+    /// An inference that fails with the errors in order, then succeeds.
+    fn failing(
+        errors: Vec<onnx_bert::Error>,
+    ) -> impl FnMut() -> future::Ready<(onnx_bert::Result<()>, ())> {
+        let mut errors = errors.into_iter();
+        move || future::ready((errors.next().map_or(Ok(()), Err), ()))
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        let panicked = || onnx_bert::Error::Panicked("out of memory".to_owned());
+        let ((result, ()), retries) = retry(2, failing(vec![panicked(), panicked()])).await;
+        assert!(result.is_ok());
+        assert_eq!(retries, 2);
+    }
+
+    #[tokio::test]
+    async fn retries_run_out() {
+        let panicked = || onnx_bert::Error::Panicked("out of memory".to_owned());
+        let ((result, ()), retries) = retry(1, failing(vec![panicked(), panicked()])).await;
+        assert!(matches!(result, Err(onnx_bert::Error::Panicked(_))));
+        assert_eq!(retries, 1);
+    }
+
+    #[tokio::test]
+    async fn permanent_failures_are_not_retried() {
+        let limit = onnx_bert::Error::LimitExceeded {
+            what: "batch size",
+            actual: 2,
+            limit: 1,
+        };
+        let ((result, ()), retries) = retry(3, failing(vec![limit])).await;
+        assert!(result.is_err());
+        assert_eq!(retries, 0);
+    }
+}
@@ -0,0 +1,129 @@
+//! Pinning threads to cores, so that inference and IO don't compete for the
+//! same ones, and to NUMA nodes, so that inference doesn't reach across
+//! sockets for its memory. Only Linux is supported.
+
+use std::{fs, io};
+
+/// Restrict the calling thread to the cores. Threads spawned by it from now
+/// on inherit the restriction.
+#[cfg(target_os = "linux")]
+pub fn pin(cores: &[usize]) -> io::Result<()> {
+    // SAFETY: `cpu_set_t` is a plain bit set, for which zeroes are valid
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &core in cores {
+        if core >= libc::CPU_SETSIZE as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("core {core} is out of range"),
+            ));
+        }
+        // SAFETY: the core is within the set, as checked above
+        unsafe { libc::CPU_SET(core, &mut set) };
+    }
+
+    // SAFETY: the set is initialized and of the given size
+    let result = unsafe { libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The cores that the calling thread may run on.
+#[cfg(target_os = "linux")]
+pub fn allowed() -> io::Result<Vec<usize>> {
+    // SAFETY: as above
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::sched_getaffinity(0, std::mem::size_of_val(&set), &mut set) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let cores = (0..libc::CPU_SETSIZE as usize)
+        // SAFETY: the core is within the set
+        .filter(|&core| unsafe { libc::CPU_ISSET(core, &set) })
+        .collect();
+    Ok(cores)
+}
+
+/// Bind the calling thread to the cores of the NUMA node, and have it
+/// allocate its memory there. Memory is taken from other nodes once the node
+/// runs out, rather than failing.
+#[cfg(target_os = "linux")]
+pub fn bind(node: usize) -> io::Result<()> {
+    pin(&node_cores(node)?)?;
+
+    const BITS: usize = libc::c_ulong::BITS as usize;
+    let mut mask = [0 as libc::c_ulong; 16];
+    let word = mask.get_mut(node / BITS).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("node {node} is out of range"),
+        )
+    })?;
+    *word |= 1 << (node % BITS);
+
+    // SAFETY: the mask holds the number of bits given, plus one, as the
+    // kernel expects
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_set_mempolicy,
+            libc::MPOL_PREFERRED,
+            mask.as_ptr(),
+            mask.len() * BITS + 1,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The cores of the NUMA node.
+pub fn node_cores(node: usize) -> io::Result<Vec<usize>> {
+    let list = fs::read_to_string(format!("/sys/devices/system/node/node{node}/cpulist"))?;
+    parse_cores(&list).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid cores of node {node}: {list:?}"),
+        )
+    })
+}
+
+/// Parse a list of cores like `0-3,8,10-11`, as found in sysfs.
+fn parse_cores(list: &str) -> Option<Vec<usize>> {
+    let mut cores = vec![];
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        cores.extend(first.parse::<usize>().ok()?..=last.parse().ok()?);
+    }
+    Some(cores)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin(_cores: &[usize]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn allowed() -> io::Result<Vec<usize>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind(_node: usize) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Keep the calling thread, and the threads it goes on to spawn, off the
+/// cores reserved for inference. Nothing is done if no others are left.
+pub fn avoid(reserved: &[usize]) -> io::Result<()> {
+    let others = allowed()?
+        .into_iter()
+        .filter(|core| !reserved.contains(core))
+        .collect::<Vec<_>>();
+    if others.is_empty() {
+        return Ok(());
+    }
+    pin(&others)
+}
@@ -0,0 +1,135 @@
+use std::{
+    borrow::Cow,
+    fs::OpenOptions,
+    io::{LineWriter, Write},
+    path::PathBuf,
+    sync::mpsc,
+    thread,
+};
+
+use onnx_bert::Entity;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{
+    config::{AuditInput, AuditSink},
+    registry::unix_now,
+};
+
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    timestamp: u64,
+    request_id: &'a str,
+    client: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_sha256: Option<String>,
+    model: &'a str,
+    /// Commit of the model that made the prediction, if known.
+    revision: Option<String>,
+    entities: Cow<'a, [Entity]>,
+}
+
+#[derive(Debug)]
+enum Writer {
+    File(mpsc::Sender<Vec<u8>>),
+    Log,
+}
+
+/// Records every prediction returned to a client, for compliance review.
+#[derive(Debug)]
+pub struct Audit {
+    writer: Writer,
+    input: AuditInput,
+}
+
+impl Audit {
+    pub fn new(sink: &AuditSink, input: AuditInput) -> Self {
+        let writer = match sink {
+            AuditSink::File(path) => Writer::File(spawn_writer(path.clone())),
+            AuditSink::Log => Writer::Log,
+        };
+
+        Self { writer, input }
+    }
+
+    pub fn record(
+        &self,
+        request_id: &str,
+        client: &str,
+        sentence: &str,
+        model: &str,
+        revision: Option<String>,
+        entities: &[Entity],
+    ) {
+        let mut entities = Cow::Borrowed(entities);
+        if self.input != AuditInput::Text {
+            // the words are just as sensitive as the input they're taken
+            // from, and so is what they're linked to
+            for entity in entities.to_mut() {
+                entity.word.clear();
+                entity.candidates.clear();
+            }
+        }
+
+        let record = Record {
+            timestamp: unix_now(),
+            request_id,
+            client,
+            input: (self.input == AuditInput::Text).then_some(sentence),
+            input_sha256: (self.input == AuditInput::Hash)
+                .then(|| format!("{:x}", Sha256::digest(sentence))),
+            model,
+            revision,
+            entities,
+        };
+
+        let line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(?e, "failed to serialize audit record");
+                return;
+            }
+        };
+
+        match &self.writer {
+            Writer::File(tx) => {
+                if tx.send(line.into_bytes()).is_err() {
+                    warn!(request_id, "audit log writer is gone, dropping record");
+                }
+            }
+            Writer::Log => info!(target: "trast::audit", record = line),
+        }
+    }
+}
+
+/// Append lines to the file on a thread of its own, so that a slow disk
+/// doesn't hold up requests.
+fn spawn_writer(path: PathBuf) -> mpsc::Sender<Vec<u8>> {
+    let (tx, rx) = mpsc::channel::<Vec<u8>>();
+
+    thread::Builder::new()
+        .name("audit".to_owned())
+        .spawn(move || {
+            let file = match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!(?e, path = %path.display(), "failed to open audit log");
+                    return;
+                }
+            };
+            let mut file = LineWriter::new(file);
+
+            for mut line in rx {
+                line.push(b'\n');
+                if let Err(e) = file.write_all(&line) {
+                    warn!(?e, path = %path.display(), "failed to write audit record");
+                }
+            }
+        })
+        .unwrap();
+
+    tx
+}
@@ -0,0 +1,122 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+};
+
+use onnx_bert::Entity;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio_rayon::rayon::{prelude::*, ThreadPoolBuilder};
+
+use crate::{cli::BatchArgs, registry::Registry};
+
+#[derive(Debug, Deserialize)]
+struct Record {
+    id: Value,
+    text: String,
+}
+
+/// A line of the output, serialized straight from the results rather than
+/// copied into a `Value` first.
+#[derive(Debug, Serialize)]
+struct Output<'a> {
+    id: Option<&'a Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entities: Option<&'a [Entity]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+/// Run NER on the JSON lines of the input, writing a line with the entities
+/// of each to the output. Lines that can't be processed are answered with an
+/// error rather than stopping the run. This blocks.
+pub fn run(registry: &Registry, model: &str, args: BatchArgs) -> anyhow::Result<()> {
+    let pipeline = registry.load(model, None)?;
+    let threadpool = ThreadPoolBuilder::new()
+        .num_threads(args.parallelism)
+        .build()?;
+    let batch_size = args.batch_size.max(1);
+
+    let reader: Box<dyn BufRead> = match &args.input {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(io::stdin().lock()),
+    };
+    let mut writer: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+
+    // read enough lines at a time to keep every thread busy
+    let window = batch_size * threadpool.current_num_threads();
+    let mut lines = reader.lines();
+    let mut processed = 0;
+
+    loop {
+        let chunk = lines
+            .by_ref()
+            .take(window)
+            .collect::<io::Result<Vec<_>>>()?;
+        if chunk.is_empty() {
+            break;
+        }
+
+        let records = chunk
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str::<Record>(line))
+            .collect::<Vec<_>>();
+        let texts = records
+            .iter()
+            .filter_map(|record| record.as_ref().ok())
+            .map(|record| record.text.as_str())
+            .collect::<Vec<_>>();
+
+        let mut results = threadpool
+            .install(|| {
+                texts
+                    .par_chunks(batch_size)
+                    .flat_map_iter(|batch| match pipeline.predict_batch(batch) {
+                        Ok(entities) => entities.into_iter().map(Ok).collect::<Vec<_>>(),
+                        Err(e) => vec![Err(e.to_string()); batch.len()],
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .into_iter();
+
+        for record in records {
+            let (id, result) = match &record {
+                Ok(record) => match results.next() {
+                    Some(result) => (Some(&record.id), result),
+                    None => unreachable!("a result per record"),
+                },
+                Err(e) => (None, Err(e.to_string())),
+            };
+            let output = match &result {
+                Ok(entities) => Output {
+                    id,
+                    entities: Some(entities),
+                    error: None,
+                },
+                Err(e) => Output {
+                    id,
+                    entities: None,
+                    error: Some(e),
+                },
+            };
+            serde_json::to_writer(&mut writer, &output)?;
+            writeln!(writer)?;
+        }
+
+        processed += chunk.len();
+        if args.progress {
+            eprint!("\r{processed} lines processed");
+        }
+    }
+
+    writer.flush()?;
+    if args.progress {
+        eprintln!();
+    }
+
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use onnx_bert::BenchOptions;
+
+use crate::{cli::BenchArgs, registry::Registry};
+
+/// Measure the latency and throughput of the model for every combination of
+/// sentence length and batch size. This blocks.
+pub fn run(registry: &Registry, model: &str, args: BenchArgs) -> anyhow::Result<()> {
+    let pipeline = registry.load(model, None)?;
+    let report = pipeline.benchmark(&BenchOptions {
+        warmup: args.warmup,
+        iterations: args.iterations,
+        lengths: args.lengths,
+        batch_sizes: args.batch_sizes,
+    })?;
+
+    println!(
+        "{:>6} {:>6} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>12}",
+        "words",
+        "batch",
+        "tok ms",
+        "infer ms",
+        "post ms",
+        "p50 ms",
+        "p95 ms",
+        "p99 ms",
+        "sentences/s"
+    );
+
+    let ms = |d: Duration| d.as_secs_f64() * 1000.;
+    for run in report.runs {
+        println!(
+            "{:>6} {:>6} {:>10.2} {:>10.2} {:>10.2} {:>10.2} {:>10.2} {:>10.2} {:>12.1}",
+            run.words,
+            run.batch_size,
+            ms(run.tokenize.p50),
+            ms(run.infer.p50),
+            ms(run.postprocess.p50),
+            ms(run.total.p50),
+            ms(run.total.p95),
+            ms(run.total.p99),
+            run.sentences_per_sec,
+        );
+    }
+
+    Ok(())
+}
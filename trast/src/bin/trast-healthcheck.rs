@@ -0,0 +1,46 @@
+//! Probes the health of a trast server, exiting with 0 if it's serving and 1
+//! otherwise. Meant for Docker `HEALTHCHECK`s, in images without a shell.
+
+use std::{env, process, time::Duration};
+
+use tonic::transport::Endpoint;
+use tonic_health::proto::{
+    health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest,
+};
+
+const SERVICE: &str = "trast.Trast";
+
+async fn check(addr: String) -> Result<ServingStatus, Box<dyn std::error::Error>> {
+    let channel = Endpoint::from_shared(addr)?
+        .connect_timeout(Duration::from_secs(1))
+        .timeout(Duration::from_secs(1))
+        .connect()
+        .await?;
+
+    let response = HealthClient::new(channel)
+        .check(HealthCheckRequest {
+            service: SERVICE.to_owned(),
+        })
+        .await?;
+
+    Ok(response.into_inner().status())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let addr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "http://127.0.0.1:8000".to_owned());
+
+    match check(addr).await {
+        Ok(ServingStatus::Serving) => {}
+        Ok(status) => {
+            eprintln!("{SERVICE} is {status:?}");
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("health check failed: {e}");
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A single probe has been let through after the cooldown.
+    HalfOpen,
+}
+
+/// Stops sending work to a model that keeps failing, letting a single probe
+/// through every `cooldown` to detect recovery.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    model: String,
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(model: impl Into<String>, threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            model: model.into(),
+            threshold: threshold.max(1),
+            cooldown,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Whether requests should be rejected right away, without waiting for a
+    /// probe.
+    pub fn is_open(&self) -> bool {
+        match *self.state.lock().unwrap() {
+            State::Closed { .. } => false,
+            State::Open { until } => Instant::now() < until,
+            State::HalfOpen => true,
+        }
+    }
+
+    /// Whether a batch may be sent to the model. Once the cooldown has passed,
+    /// this returns `true` exactly once, for the probe.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if Instant::now() >= until => {
+                info!(model = self.model, "probing model");
+                *state = State::HalfOpen;
+                true
+            }
+            State::Open { .. } | State::HalfOpen => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, State::Closed { .. }) {
+            info!(model = self.model, "circuit breaker closed");
+        }
+        *state = State::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { failures } if failures + 1 < self.threshold => {
+                *state = State::Closed {
+                    failures: failures + 1,
+                };
+            }
+            _ => self.open(&mut state),
+        }
+    }
+
+    /// Open the breaker regardless of the number of failures, e.g. because
+    /// the model couldn't be loaded at all.
+    pub fn trip(&self) {
+        self.open(&mut self.state.lock().unwrap());
+    }
+
+    fn open(&self, state: &mut State) {
+        if !matches!(state, State::Open { .. }) {
+            warn!(model = self.model, cooldown = ?self.cooldown, "circuit breaker opened");
+        }
+        *state = State::Open {
+            until: Instant::now() + self.cooldown,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG: Duration = Duration::from_secs(3600);
+
+    #[test]
+    fn opens_after_threshold_failures() {
+        let breaker = CircuitBreaker::new("model", 3, LONG);
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(!breaker.is_open());
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert!(breaker.is_open());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn success_resets_the_failures() {
+        let breaker = CircuitBreaker::new("model", 2, LONG);
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn lets_a_single_probe_through_after_the_cooldown() {
+        let breaker = CircuitBreaker::new("model", 1, Duration::ZERO);
+        breaker.record_failure();
+        assert!(!breaker.is_open());
+        assert!(breaker.allow());
+        // the probe is in flight
+        assert!(breaker.is_open());
+        assert!(!breaker.allow());
+        breaker.record_success();
+        assert!(!breaker.is_open());
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn failed_probe_opens_again() {
+        let breaker = CircuitBreaker::new("model", 5, Duration::ZERO);
+        breaker.trip();
+        assert!(breaker.allow());
+        breaker.record_failure();
+        // a single failure is enough, whatever the threshold
+        assert!(matches!(*breaker.state.lock().unwrap(), State::Open { .. }));
+    }
+
+    #[test]
+    fn trip_opens_regardless_of_failures() {
+        let breaker = CircuitBreaker::new("model", 10, LONG);
+        breaker.trip();
+        assert!(breaker.is_open());
+        assert!(!breaker.allow());
+    }
+}
@@ -0,0 +1,83 @@
+use std::{num::NonZeroUsize, sync::Mutex};
+
+use lru::LruCache;
+
+use crate::actor::Prediction;
+
+/// The model, the commit of it that made the prediction, if known, and the
+/// sentence.
+type Key = (String, Option<String>, String);
+
+/// LRU cache of predictions, keyed on model revision and sentence, so that
+/// a new revision swapped in doesn't answer with the entities of the old.
+#[derive(Debug)]
+pub struct ResponseCache {
+    inner: Option<Mutex<LruCache<Key, Prediction>>>,
+}
+
+impl ResponseCache {
+    /// Create a cache holding up to `capacity` predictions. A capacity of zero
+    /// disables caching.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: NonZeroUsize::new(capacity).map(|capacity| Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    /// Only trailing whitespace is ignored, since anything else would shift the
+    /// offsets of the cached entities.
+    fn key(model: &str, sha: Option<&str>, sentence: &str) -> Key {
+        (
+            model.to_owned(),
+            sha.map(str::to_owned),
+            sentence.trim_end().to_owned(),
+        )
+    }
+
+    pub fn get(&self, model: &str, sha: Option<&str>, sentence: &str) -> Option<Prediction> {
+        let mut cache = self.inner.as_ref()?.lock().unwrap();
+        cache.get(&Self::key(model, sha, sentence)).cloned()
+    }
+
+    pub fn put(&self, model: &str, sha: Option<&str>, sentence: &str, prediction: Prediction) {
+        if let Some(cache) = &self.inner {
+            cache
+                .lock()
+                .unwrap()
+                .put(Self::key(model, sha, sentence), prediction);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prediction(tokens: usize) -> Prediction {
+        Prediction {
+            entities: vec![],
+            tokens,
+            truncated: false,
+            words: 0,
+            model: "model".to_owned(),
+            batch_size: 1,
+        }
+    }
+
+    #[test]
+    fn revisions_are_cached_apart() {
+        let cache = ResponseCache::new(8);
+        cache.put("model", Some("old"), "Hej", prediction(1));
+        assert!(cache.get("model", Some("new"), "Hej").is_none());
+        assert!(cache.get("model", None, "Hej").is_none());
+        let cached = cache.get("model", Some("old"), "Hej  ").unwrap();
+        assert_eq!(cached.tokens, 1);
+    }
+
+    #[test]
+    fn zero_capacity_caches_nothing() {
+        let cache = ResponseCache::new(0);
+        cache.put("model", None, "Hej", prediction(1));
+        assert!(cache.get("model", None, "Hej").is_none());
+    }
+}
@@ -0,0 +1,66 @@
+use sha2::{Digest, Sha256};
+
+use crate::mailbox::Mailbox;
+
+/// Routes a percentage of the requests to a new model version, so that it
+/// can be rolled out gradually.
+#[derive(Debug)]
+pub struct Canary {
+    pub model: String,
+    percent: f64,
+    pub tx: Mailbox,
+}
+
+impl Canary {
+    pub fn new(model: String, percent: f64, tx: Mailbox) -> Self {
+        Self { model, percent, tx }
+    }
+
+    /// Whether the sentence goes to the canary. Routing is sticky: the same
+    /// sentence always ends up on the same model version, on every replica
+    /// and across builds of the server.
+    pub fn routes(&self, sentence: &str) -> bool {
+        let digest = Sha256::digest(sentence);
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&digest[..8]);
+        ((u64::from_be_bytes(bytes) % 10_000) as f64) < self.percent * 100.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::OverflowPolicy, mailbox::mailbox, metrics::Metrics};
+
+    fn canary(percent: f64) -> Canary {
+        let metrics = Metrics::new().unwrap();
+        let (tx, _) = mailbox("canary".to_owned(), 1, OverflowPolicy::Block, metrics);
+        Canary::new("canary".to_owned(), percent, tx)
+    }
+
+    #[test]
+    fn routing_is_pinned_by_the_hash() {
+        // the first 8 bytes of the SHA-256 of "hello", big-endian, are 5342
+        // modulo 10 000, on every build
+        assert!(!canary(53.42).routes("hello"));
+        assert!(canary(53.43).routes("hello"));
+    }
+
+    #[test]
+    fn routes_the_share_of_sentences() {
+        let sentences = (0..10_000).map(|i| format!("sentence {i}"));
+        let canary = canary(10.);
+        let routed = sentences.filter(|s| canary.routes(s)).count();
+        assert!((800..1200).contains(&routed), "{routed}");
+    }
+
+    #[test]
+    fn routes_none_or_all() {
+        let (none, all) = (canary(0.), canary(100.));
+        for i in 0..1000 {
+            let sentence = format!("sentence {i}");
+            assert!(!none.routes(&sentence));
+            assert!(all.routes(&sentence));
+        }
+    }
+}
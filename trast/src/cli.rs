@@ -0,0 +1,423 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use onnx_bert::BenchOptions;
+
+pub const USAGE: &str = "\
+usage:
+  trast [--self-test]
+  trast batch [--model ID] [--input FILE] [--output FILE] [--parallelism N]
+              [--batch-size N] [--progress]
+  trast predict [--model ID] [--json | --tags] SENTENCE...
+  trast replay [--model ID] [--output FILE] [--batch-size N] AUDIT_LOG
+  trast bench [--model ID] [--warmup N] [--iterations N] [--lengths N,...]
+              [--batch-sizes N,...]
+  trast loadtest [--target URL] [--rate N] [--concurrency N] [--duration SECS]
+                 [--corpus FILE]
+  trast warmup [--target URL] [--model ID]
+  trast config [--target URL]
+  trast cache list|size|clear [--model ID]
+  trast validate-model [--revision REV] DIRECTORY|ID";
+
+/// What to do, as given by the command line.
+#[derive(Debug)]
+pub enum Command {
+    /// Run the server, or only smoke test the model.
+    Serve {
+        self_test: bool,
+    },
+    Batch(BatchArgs),
+    Predict(PredictArgs),
+    Replay(ReplayArgs),
+    Bench(BenchArgs),
+    Loadtest(LoadtestArgs),
+    Warmup(WarmupArgs),
+    Config(ConfigArgs),
+    Cache(CacheArgs),
+    ValidateModel(ValidateModelArgs),
+}
+
+impl Command {
+    /// The model to use instead of the configured one, if any.
+    pub fn model(&self) -> Option<&str> {
+        match self {
+            Self::Serve { .. }
+            | Self::Loadtest(_)
+            | Self::Warmup(_)
+            | Self::Config(_)
+            | Self::ValidateModel(_) => None,
+            Self::Batch(args) => args.model.as_deref(),
+            Self::Predict(args) => args.model.as_deref(),
+            Self::Replay(args) => args.model.as_deref(),
+            Self::Bench(args) => args.model.as_deref(),
+            Self::Cache(args) => args.model.as_deref(),
+        }
+    }
+}
+
+/// Run NER on JSON lines like `{"id": …, "text": …}`.
+#[derive(Debug)]
+pub struct BatchArgs {
+    /// Overrides the configured model.
+    pub model: Option<String>,
+    /// Read from stdin if not given.
+    pub input: Option<PathBuf>,
+    /// Written to stdout if not given.
+    pub output: Option<PathBuf>,
+    /// Number of threads, zero for one per core.
+    pub parallelism: usize,
+    pub batch_size: usize,
+    /// Report the number of processed lines on stderr.
+    pub progress: bool,
+}
+
+/// Print the entities found in the sentences.
+#[derive(Debug)]
+pub struct PredictArgs {
+    pub model: Option<String>,
+    pub json: bool,
+    /// Print the IOB2 tag of every word, in the CoNLL format.
+    pub tags: bool,
+    pub sentences: Vec<String>,
+}
+
+/// Run the inputs of an audit log through a model and compare the entities
+/// with those that were recorded.
+#[derive(Debug)]
+pub struct ReplayArgs {
+    pub model: Option<String>,
+    /// Audit log with the full text of the inputs.
+    pub input: PathBuf,
+    /// The differences are written to stdout if not given.
+    pub output: Option<PathBuf>,
+    pub batch_size: usize,
+}
+
+/// Measure latency and throughput across sentence lengths and batch sizes.
+#[derive(Debug)]
+pub struct BenchArgs {
+    pub model: Option<String>,
+    /// Unmeasured iterations run before every measurement.
+    pub warmup: usize,
+    pub iterations: usize,
+    /// Lengths of the sentences, in words.
+    pub lengths: Vec<usize>,
+    pub batch_sizes: Vec<usize>,
+}
+
+/// Send synthetic traffic to a server.
+#[derive(Debug)]
+pub struct LoadtestArgs {
+    pub target: String,
+    /// Requests per second, zero for as many as the concurrency allows.
+    pub rate: f64,
+    /// Maximum number of requests in flight.
+    pub concurrency: usize,
+    pub duration: Duration,
+    /// File with a sentence per line, sent in turn.
+    pub corpus: Option<PathBuf>,
+}
+
+/// Have a server warm up its models.
+#[derive(Debug)]
+pub struct WarmupArgs {
+    pub target: String,
+    /// Only this model, rather than all that the server routes to.
+    pub model: Option<String>,
+}
+
+/// Print the configuration of a server.
+#[derive(Debug)]
+pub struct ConfigArgs {
+    pub target: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheAction {
+    /// Print the downloaded files.
+    List,
+    /// Print the disk space used.
+    Size,
+    /// Delete the downloaded files.
+    Clear,
+}
+
+impl FromStr for CacheAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "list" => Ok(Self::List),
+            "size" => Ok(Self::Size),
+            "clear" => Ok(Self::Clear),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Manage the downloaded models.
+#[derive(Debug)]
+pub struct CacheArgs {
+    pub action: CacheAction,
+    /// Only the files of this model, rather than the whole cache.
+    pub model: Option<String>,
+}
+
+/// Check that a model can be served.
+#[derive(Debug)]
+pub struct ValidateModelArgs {
+    /// A directory with the model files, or the id of a model on the Hub.
+    pub model: String,
+    /// Revision to download, if the model is on the Hub.
+    pub revision: String,
+}
+
+/// The options following a command, as `--flag`, `--option value` or
+/// `--option=value`.
+#[derive(Debug, Default)]
+struct Options {
+    values: HashMap<String, String>,
+    flags: HashSet<String>,
+    positional: Vec<String>,
+}
+
+impl Options {
+    fn parse(
+        args: impl IntoIterator<Item = String>,
+        options: &[&str],
+        flags: &[&str],
+    ) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                parsed.positional.push(arg);
+                continue;
+            };
+
+            if let Some((name, value)) = name.split_once('=') {
+                if !options.contains(&name) {
+                    return Err(format!("unknown option --{name}"));
+                }
+                parsed.values.insert(name.to_owned(), value.to_owned());
+            } else if flags.contains(&name) {
+                parsed.flags.insert(name.to_owned());
+            } else if options.contains(&name) {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("--{name} needs a value"))?;
+                parsed.values.insert(name.to_owned(), value);
+            } else {
+                return Err(format!("unknown option --{name}"));
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    fn get<T: FromStr>(&self, name: &str) -> Result<Option<T>, String> {
+        self.values
+            .get(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| format!("invalid value {value:?} for --{name}"))
+            })
+            .transpose()
+    }
+
+    /// A comma-separated list.
+    fn get_list<T: FromStr>(&self, name: &str) -> Result<Option<Vec<T>>, String> {
+        self.values
+            .get(name)
+            .map(|values| {
+                values
+                    .split(',')
+                    .map(|value| {
+                        value
+                            .trim()
+                            .parse()
+                            .map_err(|_| format!("invalid value {value:?} for --{name}"))
+                    })
+                    .collect()
+            })
+            .transpose()
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.contains(name)
+    }
+
+    fn no_positional(&self) -> Result<(), String> {
+        match self.positional.first() {
+            Some(arg) => Err(format!("unexpected argument {arg:?}")),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Parse the command line arguments, without the name of the binary.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut args = args.into_iter().peekable();
+
+    match args.peek().map(String::as_str) {
+        Some("batch") => {
+            args.next();
+            let options = Options::parse(
+                args,
+                &["model", "input", "output", "parallelism", "batch-size"],
+                &["progress"],
+            )?;
+            options.no_positional()?;
+
+            Ok(Command::Batch(BatchArgs {
+                model: options.get("model")?,
+                input: options.get("input")?,
+                output: options.get("output")?,
+                parallelism: options.get("parallelism")?.unwrap_or(0),
+                batch_size: options.get("batch-size")?.unwrap_or(16),
+                progress: options.flag("progress"),
+            }))
+        }
+        Some("predict") => {
+            args.next();
+            let options = Options::parse(args, &["model"], &["json", "tags"])?;
+            if options.positional.is_empty() {
+                return Err("no sentence given".to_owned());
+            }
+            if options.flag("json") && options.flag("tags") {
+                return Err("--json and --tags can't be combined".to_owned());
+            }
+
+            Ok(Command::Predict(PredictArgs {
+                model: options.get("model")?,
+                json: options.flag("json"),
+                tags: options.flag("tags"),
+                sentences: options.positional,
+            }))
+        }
+        Some("replay") => {
+            args.next();
+            let options = Options::parse(args, &["model", "output", "batch-size"], &[])?;
+            let input = match options.positional.as_slice() {
+                [input] => input.into(),
+                [] => return Err("no audit log given".to_owned()),
+                [_, arg, ..] => return Err(format!("unexpected argument {arg:?}")),
+            };
+
+            Ok(Command::Replay(ReplayArgs {
+                model: options.get("model")?,
+                input,
+                output: options.get("output")?,
+                batch_size: options.get("batch-size")?.unwrap_or(16),
+            }))
+        }
+        Some("bench") => {
+            args.next();
+            let options = Options::parse(
+                args,
+                &["model", "warmup", "iterations", "lengths", "batch-sizes"],
+                &[],
+            )?;
+            options.no_positional()?;
+
+            let defaults = BenchOptions::default();
+            Ok(Command::Bench(BenchArgs {
+                model: options.get("model")?,
+                warmup: options.get("warmup")?.unwrap_or(defaults.warmup),
+                iterations: options.get("iterations")?.unwrap_or(defaults.iterations),
+                lengths: options.get_list("lengths")?.unwrap_or(defaults.lengths),
+                batch_sizes: options
+                    .get_list("batch-sizes")?
+                    .unwrap_or(defaults.batch_sizes),
+            }))
+        }
+        Some("loadtest") => {
+            args.next();
+            let options = Options::parse(
+                args,
+                &["target", "rate", "concurrency", "duration", "corpus"],
+                &[],
+            )?;
+            options.no_positional()?;
+
+            Ok(Command::Loadtest(LoadtestArgs {
+                target: options
+                    .get("target")?
+                    .unwrap_or_else(|| "http://127.0.0.1:8000".to_owned()),
+                rate: options.get("rate")?.unwrap_or(0.),
+                concurrency: options.get("concurrency")?.unwrap_or(16),
+                duration: Duration::from_secs(options.get("duration")?.unwrap_or(10)),
+                corpus: options.get("corpus")?,
+            }))
+        }
+        Some("warmup") => {
+            args.next();
+            let options = Options::parse(args, &["target", "model"], &[])?;
+            options.no_positional()?;
+
+            Ok(Command::Warmup(WarmupArgs {
+                target: options
+                    .get("target")?
+                    .unwrap_or_else(|| "http://127.0.0.1:8000".to_owned()),
+                model: options.get("model")?,
+            }))
+        }
+        Some("config") => {
+            args.next();
+            let options = Options::parse(args, &["target"], &[])?;
+            options.no_positional()?;
+
+            Ok(Command::Config(ConfigArgs {
+                target: options
+                    .get("target")?
+                    .unwrap_or_else(|| "http://127.0.0.1:8000".to_owned()),
+            }))
+        }
+        Some("cache") => {
+            args.next();
+            let options = Options::parse(args, &["model"], &[])?;
+            let action = match options.positional.as_slice() {
+                [action] => action
+                    .parse()
+                    .map_err(|_| format!("unknown cache action {action:?}"))?,
+                [] => return Err("no cache action given".to_owned()),
+                [_, arg, ..] => return Err(format!("unexpected argument {arg:?}")),
+            };
+
+            Ok(Command::Cache(CacheArgs {
+                action,
+                model: options.get("model")?,
+            }))
+        }
+        Some("validate-model") => {
+            args.next();
+            let options = Options::parse(args, &["revision"], &[])?;
+            let model = match options.positional.as_slice() {
+                [model] => model.clone(),
+                [] => return Err("no model given".to_owned()),
+                [_, arg, ..] => return Err(format!("unexpected argument {arg:?}")),
+            };
+
+            Ok(Command::ValidateModel(ValidateModelArgs {
+                model,
+                revision: options
+                    .get("revision")?
+                    .unwrap_or_else(|| "main".to_owned()),
+            }))
+        }
+        _ => {
+            let options = Options::parse(args, &[], &["self-test"])?;
+            options.no_positional()?;
+
+            Ok(Command::Serve {
+                self_test: options.flag("self-test"),
+            })
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! NER over whole documents, which are split into chunks no longer than a
+//! sentence may be, each run in windows of what the model takes so that
+//! none of it is truncated away. Entities are returned with offsets into
+//! the document, and the document counts as a single request to the quota
+//! of the client.
+
+use std::str::SplitInclusive;
+
+use futures::{stream, StreamExt, TryStreamExt};
+use tonic::Status;
+use trast_proto::{Entity, NerInput, Priority};
+
+use crate::{Error, TrastService};
+
+/// The entities of a document, and the models that found them.
+#[derive(Debug)]
+pub struct DocumentOutput {
+    pub entities: Vec<Entity>,
+    pub models: Vec<String>,
+}
+
+/// The non-empty lines of a text, further split at whitespace into chunks
+/// of at most `max_chars` characters, along with the byte offset of every
+/// chunk in the text. Chunks are split off as they're needed, so that only
+/// those in flight are copied out of the document.
+pub struct Chunks<'a> {
+    lines: SplitInclusive<'a, char>,
+    max_chars: usize,
+    /// What is left of the current line, and its offset.
+    rest: &'a str,
+    start: usize,
+    /// Offset of the next line.
+    line_start: usize,
+}
+
+pub fn chunks(text: &str, max_chars: usize) -> Chunks<'_> {
+    Chunks {
+        lines: text.split_inclusive('\n'),
+        max_chars: max_chars.max(1),
+        rest: "",
+        start: 0,
+        line_start: 0,
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = (usize, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let trimmed = self.rest.trim_start();
+            self.start += self.rest.len() - trimmed.len();
+            let rest = trimmed.trim_end();
+            if rest.is_empty() {
+                let line = self.lines.next()?;
+                self.start = self.line_start;
+                self.rest = line;
+                self.line_start += line.len();
+                continue;
+            }
+
+            // cut after the last whitespace within the limit, or at the
+            // limit if there is none
+            let end = match rest.char_indices().nth(self.max_chars) {
+                None => rest.len(),
+                Some((limit, c)) if c.is_whitespace() => limit,
+                Some((limit, _)) => rest[..limit]
+                    .rfind(char::is_whitespace)
+                    .filter(|&i| i > 0)
+                    .unwrap_or(limit),
+            };
+
+            let chunk = (self.start, rest[..end].trim_end());
+            self.start += end;
+            self.rest = &rest[end..];
+            return Some(chunk);
+        }
+    }
+}
+
+/// Recognize the entities in every chunk of the document, with up to
+/// `concurrency` chunks in flight. They're batched by the actor like any
+/// other sentences, and the batches spread over the inference threads, so
+/// that a long document isn't run a chunk at a time.
+pub async fn predict(
+    trast: &TrastService,
+    text: &str,
+    client: &str,
+    request_id: &str,
+    concurrency: usize,
+) -> Result<DocumentOutput, Status> {
+    trast.acquire(client)?;
+
+    let mut outputs = stream::iter(chunks(text, trast.max_input_chars).enumerate())
+        .map(|(i, (offset, chunk))| {
+            let input = NerInput {
+                sentence: chunk.to_owned(),
+                priority: Priority::Interactive as i32,
+                ..Default::default()
+            };
+            async move {
+                let route = trast.route(&input.language, &input.sentence);
+                let output = trast
+                    .recognize(input, route, client, &format!("{request_id}-{i}"), true)
+                    .await?;
+                Ok::<_, Status>((offset, output))
+            }
+        })
+        .buffered(concurrency.max(1))
+        // boxed, as the compiler otherwise fails to tell whether a lazy
+        // iterator over borrowed chunks is `Send`
+        .boxed();
+
+    // merged as they arrive, so that the outputs aren't all held at once.
+    // They arrive in order, so the words of the chunks before are counted
+    let mut document = DocumentOutput {
+        entities: vec![],
+        models: vec![],
+    };
+    let mut words = 0;
+    while let Some((offset, output)) = outputs.try_next().await? {
+        let shift = |offset: usize, i: u32| {
+            u32::try_from(offset)
+                .ok()
+                .and_then(|offset| offset.checked_add(i))
+                .ok_or_else(|| Error::TooLarge("document is too long".to_owned()))
+        };
+        for entity in output.entities {
+            document.entities.push(Entity {
+                start: shift(offset, entity.start)?,
+                end: shift(offset, entity.end)?,
+                start_word: shift(words, entity.start_word)?,
+                end_word: shift(words, entity.end_word)?,
+                ..entity
+            });
+        }
+        words += output.words as usize;
+        if !document.models.contains(&output.model) {
+            document.models.push(output.model);
+        }
+    }
+
+    Ok(document)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actor::Prediction;
+
+    #[test]
+    fn chunks_are_trimmed_lines() {
+        let text = "  foo bar\n\nbaz  \n";
+        let chunks = chunks(text, 100).collect::<Vec<_>>();
+        assert_eq!(chunks, [(2, "foo bar"), (11, "baz")]);
+    }
+
+    #[test]
+    fn long_lines_are_split_at_whitespace() {
+        let chunks = chunks("one two three", 8).collect::<Vec<_>>();
+        assert_eq!(chunks, [(0, "one two"), (8, "three")]);
+    }
+
+    #[test]
+    fn long_words_are_split_at_the_limit() {
+        let chunks = chunks("abcdefghij", 4).collect::<Vec<_>>();
+        assert_eq!(chunks, [(0, "abcd"), (4, "efgh"), (8, "ij")]);
+    }
+
+    #[test]
+    fn limit_is_in_characters() {
+        let chunks = chunks("åäö åäö", 4).collect::<Vec<_>>();
+        assert_eq!(chunks, [(0, "åäö"), (7, "åäö")]);
+    }
+
+    #[test]
+    fn offsets_point_into_the_text() {
+        let text = "Ada Lovelace\r\n  wrote the first program\n\n\tfor Babbage's \
+                    Analytical Engine, in 1843. Ünïcödé wörds tôo.\n";
+        for max_chars in 1..20 {
+            for (offset, chunk) in chunks(text, max_chars) {
+                assert_eq!(&text[offset..offset + chunk.len()], chunk);
+                assert!(chunk.chars().count() <= max_chars);
+                assert_eq!(chunk, chunk.trim());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn offsets_and_word_indices_are_into_the_document() {
+        let (trast, mut inbox) = crate::tests::service();
+        // an actor finding the last word of every chunk
+        tokio::spawn(async move {
+            while let Some(queued) = inbox.recv().await {
+                let message = queued.into_message();
+                let words = message.sentence.split_whitespace().count();
+                let start = message.sentence.rfind(' ').map_or(0, |i| i + 1);
+                let end = message.sentence.len();
+                let entity = onnx_bert::Entity {
+                    label: "PER".to_owned(),
+                    score: 1.,
+                    word: message.sentence[start..].to_owned(),
+                    start,
+                    end,
+                    start_word: words - 1,
+                    end_word: words,
+                    candidates: vec![],
+                };
+                let _ = message.tx.send(Ok(Prediction {
+                    entities: vec![entity],
+                    tokens: words,
+                    truncated: false,
+                    words,
+                    model: "ner".to_owned(),
+                    batch_size: 1,
+                }));
+            }
+        });
+
+        let text = "Hej Ada\n\nNär kommer Grace";
+        let output = predict(&trast, text, "client", "request", 1).await.unwrap();
+        let spans = output
+            .entities
+            .iter()
+            .map(|e| (e.start, e.end, e.start_word, e.end_word))
+            .collect::<Vec<_>>();
+        assert_eq!(spans, [(4, 7, 1, 2), (21, 26, 4, 5)]);
+        assert_eq!(&text[21..26], "Grace");
+    }
+}
@@ -0,0 +1,81 @@
+//! The `trast cache` command, for inspecting and reclaiming the disk space
+//! used by downloaded models.
+
+use std::io::{self, Write};
+
+use onnx_bert::CachedFile;
+
+use crate::{
+    cli::{CacheAction, CacheArgs},
+    registry::Registry,
+};
+
+/// Format a number of bytes for humans.
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024. && unit < UNITS.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{size:.1} {}", UNITS[unit]),
+    }
+}
+
+/// List, measure or delete the downloaded files, of every model or only the
+/// one given. Deleted models are downloaded again when they're next loaded.
+/// This blocks.
+pub fn run(registry: &Registry, args: CacheArgs) -> anyhow::Result<()> {
+    let mut files = onnx_bert::cached_files()?;
+    if let Some(model) = &args.model {
+        files.retain(|file| file.model.as_ref() == Some(model));
+    }
+    files.sort_by(|a, b| (&a.model, &a.revision, &a.name).cmp(&(&b.model, &b.revision, &b.name)));
+
+    let total = files.iter().map(|file| file.size).sum::<u64>();
+    let mut stdout = io::stdout().lock();
+
+    match args.action {
+        CacheAction::List => {
+            for CachedFile {
+                model,
+                revision,
+                name,
+                size,
+                ..
+            } in &files
+            {
+                let model = model.as_deref().unwrap_or("-");
+                let revision = revision.as_deref().map_or("-", |r| &r[..r.len().min(12)]);
+                writeln!(stdout, "{model}  {revision}  {name}  {}", human_size(*size))?;
+            }
+        }
+        CacheAction::Size => writeln!(stdout, "{}", human_size(total))?,
+        CacheAction::Clear => {
+            for file in &files {
+                file.remove()?;
+            }
+
+            for entry in registry.list() {
+                match &args.model {
+                    Some(model) if *model != entry.id => {}
+                    _ => registry.forget_download(&entry.id),
+                }
+            }
+
+            writeln!(
+                stdout,
+                "removed {} files, {}",
+                files.len(),
+                human_size(total)
+            )?;
+        }
+    }
+
+    Ok(())
+}
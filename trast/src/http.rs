@@ -0,0 +1,280 @@
+//! A REST gateway, for clients that would rather POST a whole document than
+//! speak gRPC. `POST /v1/documents` takes the document as `text/plain`, or as
+//! the file of a `multipart/form-data` upload, and answers with its entities:
+//!
+//! ```json
+//! {"models": ["…"], "entities": [{"label": "PER", "score": 0.99, "word": "…", "start": 0, "end": 10}]}
+//! ```
+//!
+//! Offsets are in bytes from the start of the document.
+
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use hyper::{
+    body::HttpBody,
+    header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE},
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde_json::{json, Value};
+use tonic::Code;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{document, trace, TrastService};
+
+/// Settings of the gateway.
+#[derive(Debug, Clone)]
+pub struct Gateway {
+    pub addr: SocketAddr,
+    /// Larger uploads are rejected.
+    pub max_upload_bytes: usize,
+    /// Chunks of a document that are run at the same time.
+    pub concurrency: usize,
+}
+
+/// A failed request, answered with `{"error": message}`.
+struct Failure(StatusCode, String);
+
+impl From<tonic::Status> for Failure {
+    fn from(status: tonic::Status) -> Self {
+        let code = match status.code() {
+            Code::InvalidArgument => StatusCode::BAD_REQUEST,
+            Code::NotFound => StatusCode::NOT_FOUND,
+            Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+            Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            Code::OutOfRange => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Self(code, status.message().to_owned())
+    }
+}
+
+fn reply(status: StatusCode, body: &Value, request_id: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    if let Ok(request_id) = HeaderValue::from_str(request_id) {
+        headers.insert(trace::REQUEST_ID, request_id);
+    }
+    response
+}
+
+/// Serve the gateway until the process exits.
+pub async fn serve(trast: Arc<TrastService>, gateway: Gateway) {
+    let addr = gateway.addr;
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let remote = conn.remote_addr();
+        let trast = Arc::clone(&trast);
+        let gateway = gateway.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let trast = Arc::clone(&trast);
+                let gateway = gateway.clone();
+                async move { Ok::<_, Infallible>(handle(&trast, &gateway, remote, request).await) }
+            }))
+        }
+    });
+
+    info!("gateway listening on {addr}");
+    if let Err(e) = Server::bind(&addr).serve(make_service).await {
+        warn!(?e, "gateway failed");
+    }
+}
+
+async fn handle(
+    trast: &TrastService,
+    gateway: &Gateway,
+    remote: SocketAddr,
+    request: Request<Body>,
+) -> Response<Body> {
+    let request_id = request
+        .headers()
+        .get(trace::REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .map_or_else(trace::generate_request_id, ToOwned::to_owned);
+    let client = request
+        .headers()
+        .get("x-client-id")
+        .and_then(|v| v.to_str().ok())
+        .map_or_else(|| remote.ip().to_string(), ToOwned::to_owned);
+
+    let span = info_span!("http", request_id, path = request.uri().path());
+    let result = match (request.method(), request.uri().path()) {
+        (&Method::POST, "/v1/documents") => {
+            ner_document(trast, gateway, &client, &request_id, request)
+                .instrument(span)
+                .await
+        }
+        (_, "/v1/documents") => Err(Failure(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "use POST".to_owned(),
+        )),
+        _ => Err(Failure(StatusCode::NOT_FOUND, "not found".to_owned())),
+    };
+
+    match result {
+        Ok(body) => reply(StatusCode::OK, &body, &request_id),
+        Err(Failure(status, message)) => reply(status, &json!({ "error": message }), &request_id),
+    }
+}
+
+async fn ner_document(
+    trast: &TrastService,
+    gateway: &Gateway,
+    client: &str,
+    request_id: &str,
+    request: Request<Body>,
+) -> Result<Value, Failure> {
+    let content_type = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("text/plain")
+        .to_owned();
+    let too_large = || {
+        Failure(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("uploads are limited to {} bytes", gateway.max_upload_bytes),
+        )
+    };
+
+    let declared = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+    if matches!(declared, Some(len) if len > gateway.max_upload_bytes) {
+        return Err(too_large());
+    }
+
+    // don't trust the declared length, or its absence
+    let mut body = request.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| Failure(StatusCode::BAD_REQUEST, e.to_string()))?;
+        if bytes.len() + chunk.len() > gateway.max_upload_bytes {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    let (mime, params) = content_type.split_once(';').unwrap_or((&content_type, ""));
+    let document = match mime.trim().to_ascii_lowercase().as_str() {
+        "text/plain" => bytes,
+        "multipart/form-data" => {
+            let boundary = params
+                .split(';')
+                .filter_map(|param| param.trim().split_once('='))
+                .find(|(key, _)| key.eq_ignore_ascii_case("boundary"))
+                .map(|(_, value)| value.trim_matches('"'))
+                .ok_or_else(|| {
+                    Failure(
+                        StatusCode::BAD_REQUEST,
+                        "missing multipart boundary".to_owned(),
+                    )
+                })?;
+            multipart_file(&bytes, boundary).ok_or_else(|| {
+                Failure(StatusCode::BAD_REQUEST, "no file in the upload".to_owned())
+            })?
+        }
+        other => {
+            return Err(Failure(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("unsupported content type {other:?}"),
+            ))
+        }
+    };
+    let text = String::from_utf8(document)
+        .map_err(|_| Failure(StatusCode::BAD_REQUEST, "document isn't UTF-8".to_owned()))?;
+
+    let output = document::predict(trast, &text, client, request_id, gateway.concurrency).await?;
+
+    Ok(json!({
+        "models": output.models,
+        "entities": output.entities,
+    }))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// The contents of the first file in a `multipart/form-data` body, or of the
+/// first part if none is a file.
+fn multipart_file(body: &[u8], boundary: &str) -> Option<Vec<u8>> {
+    let delimiter = format!("--{boundary}");
+    let mut rest = &body[find(body, delimiter.as_bytes())? + delimiter.len()..];
+    let mut first = None;
+
+    // every part ends at a CRLF followed by the delimiter
+    let delimiter = format!("\r\n{delimiter}");
+    while !rest.starts_with(b"--") {
+        let headers_end = find(rest, b"\r\n\r\n")?;
+        let headers = String::from_utf8_lossy(&rest[..headers_end]);
+        let content = &rest[headers_end + 4..];
+        let end = find(content, delimiter.as_bytes())?;
+
+        let is_file = headers.lines().any(|line| {
+            let line = line.to_ascii_lowercase();
+            line.starts_with("content-disposition:") && line.contains("filename=")
+        });
+        if is_file {
+            return Some(content[..end].to_vec());
+        }
+        first.get_or_insert_with(|| content[..end].to_vec());
+
+        rest = &content[end + delimiter.len()..];
+    }
+
+    first
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multipart_file_is_preferred() {
+        let body = b"preamble\r\n--xyz\r\n\
+            Content-Disposition: form-data; name=\"lang\"\r\n\r\n\
+            sv\r\n--xyz\r\n\
+            Content-Disposition: form-data; name=\"doc\"; filename=\"a.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            first line\r\nsecond line\r\n--xyz--\r\n";
+        let file = multipart_file(body, "xyz").unwrap();
+        assert_eq!(file, b"first line\r\nsecond line");
+    }
+
+    #[test]
+    fn multipart_without_file_is_first_part() {
+        let body = b"--xyz\r\n\
+            Content-Disposition: form-data; name=\"text\"\r\n\r\n\
+            hello\r\n--xyz\r\n\
+            Content-Disposition: form-data; name=\"other\"\r\n\r\n\
+            world\r\n--xyz--";
+        assert_eq!(multipart_file(body, "xyz").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn multipart_part_may_be_empty() {
+        let body = b"--xyz\r\n\
+            Content-Disposition: form-data; name=\"doc\"; filename=\"a.txt\"\r\n\r\n\
+            \r\n--xyz--";
+        assert_eq!(multipart_file(body, "xyz").unwrap(), b"");
+    }
+
+    #[test]
+    fn malformed_multipart_is_rejected() {
+        let unterminated = b"--xyz\r\n\
+            Content-Disposition: form-data; name=\"doc\"; filename=\"a.txt\"\r\n\r\n\
+            text";
+        assert_eq!(multipart_file(unterminated, "xyz"), None);
+        assert_eq!(multipart_file(b"--xyz\r\nno headers", "xyz"), None);
+        assert_eq!(multipart_file(b"text", "xyz"), None);
+        assert_eq!(multipart_file(b"--xyz--\r\n", "xyz"), None);
+    }
+}
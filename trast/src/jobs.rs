@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{future, stream, Stream, StreamExt};
+use tokio::sync::{mpsc, watch};
+use tracing::{info, info_span, warn, Instrument};
+use trast_proto::{JobResult, JobState, JobStatus, NerInput, Priority};
+
+use crate::{trace, TrastService};
+
+/// The sentences of a job, before any remote file has been read.
+#[derive(Debug)]
+pub struct Corpus {
+    sentences: Vec<String>,
+    url: Option<String>,
+}
+
+impl Corpus {
+    async fn read(self) -> reqwest::Result<Vec<String>> {
+        let mut sentences = self.sentences;
+        if let Some(url) = self.url {
+            let text = reqwest::get(url).await?.error_for_status()?.text().await?;
+            sentences.extend(
+                text.lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(ToOwned::to_owned),
+            );
+        }
+        Ok(sentences)
+    }
+}
+
+#[derive(Debug)]
+struct Progress {
+    state: JobState,
+    total: usize,
+    processed: usize,
+    failed: usize,
+    error: Option<String>,
+    results: Vec<Option<JobResult>>,
+    finished: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Job {
+    id: String,
+    /// The client that submitted the job, which its quota is charged to.
+    client: String,
+    progress: Mutex<Progress>,
+    /// Notified whenever a sentence has been processed.
+    updated: watch::Sender<()>,
+}
+
+impl Job {
+    fn update(&self, f: impl FnOnce(&mut Progress)) {
+        f(&mut self.progress.lock().unwrap());
+        self.updated.send_replace(());
+    }
+
+    pub fn status(&self) -> JobStatus {
+        let progress = self.progress.lock().unwrap();
+        JobStatus {
+            job_id: self.id.clone(),
+            state: progress.state as i32,
+            total: progress.total as u64,
+            processed: progress.processed as u64,
+            failed: progress.failed as u64,
+            error: progress.error.clone().unwrap_or_default(),
+        }
+    }
+
+    /// The results in order, waiting for those that aren't ready yet.
+    pub fn results(self: Arc<Self>) -> impl Stream<Item = JobResult> {
+        let rx = self.updated.subscribe();
+        stream::unfold((self, rx, 0), |(job, mut rx, next)| async move {
+            loop {
+                rx.borrow_and_update();
+                {
+                    let progress = job.progress.lock().unwrap();
+                    if let Some(Some(result)) = progress.results.get(next) {
+                        return Some((result.clone(), (Arc::clone(&job), rx, next + 1)));
+                    }
+                    if progress.finished.is_some() {
+                        return None;
+                    }
+                }
+                rx.changed().await.ok()?;
+            }
+        })
+    }
+}
+
+/// Background jobs, kept in memory for the retention period after they
+/// finish.
+#[derive(Debug)]
+pub struct Jobs {
+    jobs: Mutex<HashMap<String, Arc<Job>>>,
+    tx: mpsc::UnboundedSender<(Arc<Job>, Corpus)>,
+    retention: Duration,
+}
+
+pub type Queue = mpsc::UnboundedReceiver<(Arc<Job>, Corpus)>;
+
+impl Jobs {
+    pub fn new(retention: Duration) -> (Self, Queue) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let jobs = Self {
+            jobs: Mutex::default(),
+            tx,
+            retention,
+        };
+        (jobs, rx)
+    }
+
+    /// Queue the sentences, and those in the file at the URL, for
+    /// processing. Returns the id of the job.
+    pub fn submit(&self, client: &str, sentences: Vec<String>, url: Option<String>) -> String {
+        let job = Arc::new(Job {
+            id: trace::generate_request_id(),
+            client: client.to_owned(),
+            progress: Mutex::new(Progress {
+                state: JobState::Pending,
+                total: sentences.len(),
+                processed: 0,
+                failed: 0,
+                error: None,
+                results: vec![],
+                finished: None,
+            }),
+            updated: watch::channel(()).0,
+        });
+
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, job| match job.progress.lock().unwrap().finished {
+            Some(finished) => finished.elapsed() < self.retention,
+            None => true,
+        });
+        jobs.insert(job.id.clone(), Arc::clone(&job));
+
+        let id = job.id.clone();
+        let _ = self.tx.send((job, Corpus { sentences, url }));
+        id
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Job>> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+}
+
+/// Process the queued jobs one at a time, with up to `concurrency` sentences
+/// of each in flight so that they can be batched.
+pub async fn run(trast: Arc<TrastService>, mut queue: Queue, concurrency: usize) {
+    while let Some((job, corpus)) = queue.recv().await {
+        let span = info_span!("job", job = job.id);
+        process(&trast, &job, corpus, concurrency)
+            .instrument(span)
+            .await;
+    }
+}
+
+async fn process(trast: &TrastService, job: &Job, corpus: Corpus, concurrency: usize) {
+    job.update(|progress| progress.state = JobState::Running);
+
+    let sentences = match corpus.read().await {
+        Ok(sentences) => sentences,
+        Err(e) => {
+            warn!(?e, "failed to read job corpus");
+            job.update(|progress| {
+                progress.state = JobState::Failed;
+                progress.error = Some(e.to_string());
+                progress.finished = Some(Instant::now());
+            });
+            return;
+        }
+    };
+
+    let total = sentences.len();
+    job.update(|progress| {
+        progress.total = total;
+        progress.results = vec![None; total];
+    });
+
+    stream::iter(sentences.into_iter().enumerate())
+        .map(|(index, sentence)| async move {
+            let input = NerInput {
+                sentence,
+                priority: Priority::Batch as i32,
+                ..Default::default()
+            };
+            let request_id = format!("{}-{index}", job.id);
+            (index, trast.predict(input, &job.client, &request_id).await)
+        })
+        .buffer_unordered(concurrency.max(1))
+        .for_each(|(index, result)| {
+            let result = match result {
+                Ok(output) => JobResult {
+                    index: index as u64,
+                    output: Some(output),
+                    error: String::new(),
+                },
+                Err(status) => JobResult {
+                    index: index as u64,
+                    output: None,
+                    error: status.message().to_owned(),
+                },
+            };
+            job.update(|progress| {
+                progress.processed += 1;
+                if result.output.is_none() {
+                    progress.failed += 1;
+                }
+                progress.results[index] = Some(result);
+            });
+            future::ready(())
+        })
+        .await;
+
+    job.update(|progress| {
+        progress.state = JobState::Done;
+        progress.finished = Some(Instant::now());
+    });
+    info!(total, "job done");
+}
@@ -0,0 +1,172 @@
+//! Consuming sentences from a Kafka topic and producing their entities to
+//! another. Messages carry an encoded `NerInput` and are answered, under
+//! the same key, with an encoded `NerOutput`. Failures are answered with an
+//! empty message with the `Grpc-Status` and `Grpc-Message` headers set, as
+//! over NATS.
+//!
+//! The offset of a message is only committed once its answer has been
+//! acknowledged by the brokers, so that a message is answered at least once
+//! even if the instance dies. Messages are processed concurrently, so that
+//! the actor can batch them, but their offsets are stored in order.
+
+use std::{sync::Arc, time::Duration};
+
+use futures::{stream::FuturesOrdered, StreamExt};
+use prost::Message as _;
+use rdkafka::{
+    consumer::{Consumer, StreamConsumer},
+    error::{KafkaError, KafkaResult},
+    message::{Header, Headers, OwnedHeaders, OwnedMessage},
+    producer::{FutureProducer, FutureRecord},
+    util::Timeout,
+    ClientConfig, Message,
+};
+use tokio::time;
+use tonic::Status;
+use tracing::{info, info_span, warn, Instrument};
+use trast_proto::{NerInput, NerOutput};
+
+use crate::{trace, TrastService};
+
+/// How long to wait before connecting again after the consumer or the
+/// producer failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct ConsumerConfig {
+    /// Comma-separated `host:port` of the brokers.
+    pub brokers: String,
+    /// Consumer group that instances share the partitions within.
+    pub group_id: String,
+    pub input_topic: String,
+    pub output_topic: String,
+    /// Number of messages processed at once.
+    pub concurrency: usize,
+}
+
+/// Answer the messages of the input topic, connecting again whenever the
+/// consumer or the producer fails. Messages whose answers weren't
+/// acknowledged are consumed again.
+pub async fn consume(trast: Arc<TrastService>, config: ConsumerConfig) {
+    loop {
+        match consume_and_produce(&trast, &config).await {
+            Ok(()) => warn!(topic = config.input_topic, "kafka consumer ended"),
+            Err(e) => warn!(?e, topic = config.input_topic, "kafka consumer failed"),
+        }
+        time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn consume_and_produce(trast: &TrastService, config: &ConsumerConfig) -> KafkaResult<()> {
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &config.brokers)
+        .set("group.id", &config.group_id)
+        .set("client.id", env!("CARGO_PKG_NAME"))
+        // offsets are stored once answered, and committed in the background
+        .set("enable.auto.offset.store", "false")
+        .create()?;
+    let producer: FutureProducer = ClientConfig::new()
+        .set("bootstrap.servers", &config.brokers)
+        .set("client.id", env!("CARGO_PKG_NAME"))
+        .set("enable.idempotence", "true")
+        .create()?;
+    consumer.subscribe(&[&config.input_topic])?;
+    info!(
+        topic = config.input_topic,
+        group_id = config.group_id,
+        "subscribed to kafka topic"
+    );
+
+    let mut in_flight = FuturesOrdered::new();
+    loop {
+        tokio::select! {
+            msg = consumer.recv(), if in_flight.len() < config.concurrency.max(1) => {
+                let msg = msg?.detach();
+                in_flight.push_back(answer(trast, &producer, &config.output_topic, msg));
+            }
+            Some(answered) = in_flight.next() => {
+                let (partition, offset) = answered?;
+                // the partition may have been revoked since, in which case
+                // the message is answered again by its new consumer
+                if let Err(e) = consumer.store_offset(&config.input_topic, partition, offset + 1) {
+                    warn!(?e, partition, offset, "failed to store kafka offset");
+                }
+            }
+        }
+    }
+}
+
+/// Predict the entities of the message and produce them, returning the
+/// partition and offset of the message once the brokers have them.
+async fn answer(
+    trast: &TrastService,
+    producer: &FutureProducer,
+    topic: &str,
+    msg: OwnedMessage,
+) -> KafkaResult<(i32, i64)> {
+    let request_id = header(&msg, "x-request-id")
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(trace::generate_request_id);
+    let span = info_span!(
+        "kafka",
+        partition = msg.partition(),
+        offset = msg.offset(),
+        request_id
+    );
+
+    async {
+        let client_id = header(&msg, "x-client-id").unwrap_or("kafka");
+        let result = match NerInput::decode(msg.payload().unwrap_or_default()) {
+            Ok(input) => trast.predict(input, client_id, &request_id).await,
+            Err(e) => Err(Status::invalid_argument(e.to_string())),
+        };
+
+        let (payload, headers) = reply(result, &request_id);
+        let mut record = FutureRecord::to(topic).payload(&payload).headers(headers);
+        if let Some(key) = msg.key() {
+            record = record.key(key);
+        }
+        producer
+            .send(record, Timeout::Never)
+            .await
+            .map_err(|(e, _): (KafkaError, _)| e)?;
+
+        Ok((msg.partition(), msg.offset()))
+    }
+    .instrument(span)
+    .await
+}
+
+/// The value of the header of the message, whatever the case of its name.
+fn header<'a>(msg: &'a OwnedMessage, key: &str) -> Option<&'a str> {
+    msg.headers()?
+        .iter()
+        .find(|header| header.key.eq_ignore_ascii_case(key))
+        .and_then(|header| std::str::from_utf8(header.value?).ok())
+}
+
+/// The payload and headers of the answer.
+fn reply(result: Result<NerOutput, Status>, request_id: &str) -> (Vec<u8>, OwnedHeaders) {
+    let headers = OwnedHeaders::new().insert(Header {
+        key: "x-request-id",
+        value: Some(request_id),
+    });
+
+    match result {
+        Ok(output) => (output.encode_to_vec(), headers),
+        Err(status) => {
+            let code = (status.code() as i32).to_string();
+            let message = status.message().replace(['\r', '\n'], " ");
+            let headers = headers
+                .insert(Header {
+                    key: "Grpc-Status",
+                    value: Some(&code),
+                })
+                .insert(Header {
+                    key: "Grpc-Message",
+                    value: Some(&message),
+                });
+            (Vec::new(), headers)
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Routing sentences to the model configured for their language, which is
+//! detected unless the client says what it is.
+
+use std::collections::HashMap;
+
+use crate::mailbox::Mailbox;
+
+#[derive(Debug)]
+pub struct LanguageRouter {
+    /// The model of every language, by lowercase code.
+    languages: HashMap<String, String>,
+    /// The actors of the models, except the primary one.
+    actors: HashMap<String, Mailbox>,
+}
+
+impl LanguageRouter {
+    pub fn new(languages: &HashMap<String, String>, actors: HashMap<String, Mailbox>) -> Self {
+        let languages = languages
+            .iter()
+            .map(|(language, model)| (language.to_ascii_lowercase(), model.clone()))
+            .collect();
+        Self { languages, actors }
+    }
+
+    /// The language of the sentence: the hint if there is one, otherwise
+    /// the ISO 639-3 code of the detected language, such as `eng`, if the
+    /// detection is reliable.
+    pub fn language(&self, hint: &str, sentence: &str) -> Option<String> {
+        if !hint.is_empty() {
+            return Some(hint.to_ascii_lowercase());
+        }
+
+        whatlang::detect(sentence)
+            .filter(whatlang::Info::is_reliable)
+            .map(|info| info.lang().code().to_owned())
+    }
+
+    /// The model configured for the language, and its actor, unless the
+    /// language is served by the primary model.
+    pub fn route(&self, language: &str) -> Option<(&String, &Mailbox)> {
+        let model = self.languages.get(language)?;
+        self.actors.get_key_value(model)
+    }
+
+    pub fn models(&self) -> impl Iterator<Item = (&String, &Mailbox)> {
+        self.actors.iter()
+    }
+}
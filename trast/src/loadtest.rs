@@ -0,0 +1,99 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use onnx_bert::BenchStats;
+use tokio::{
+    sync::{mpsc, Semaphore},
+    time::{self, MissedTickBehavior},
+};
+use tonic::{transport::Endpoint, Code};
+use trast_proto::{trast_client::TrastClient, NerInput};
+
+use crate::{cli::LoadtestArgs, registry::WARMUP_SENTENCE};
+
+/// Send requests to the target for the duration of the test, then report the
+/// latencies and errors.
+pub async fn run(args: LoadtestArgs) -> anyhow::Result<()> {
+    let corpus = match &args.corpus {
+        Some(path) => fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(ToOwned::to_owned)
+            .collect(),
+        None => vec![WARMUP_SENTENCE.to_owned()],
+    };
+    anyhow::ensure!(!corpus.is_empty(), "the corpus is empty");
+
+    let channel = Endpoint::from_shared(args.target.clone())?
+        .connect()
+        .await?;
+    let client = TrastClient::new(channel);
+
+    let semaphore = Arc::new(Semaphore::new(args.concurrency.max(1)));
+    let (tx, mut rx) = mpsc::unbounded_channel::<(Duration, Result<(), Code>)>();
+    let mut interval = (args.rate > 0.).then(|| {
+        let mut interval = time::interval(Duration::from_secs_f64(1. / args.rate));
+        // a saturated server shouldn't be hit by a burst once it recovers
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        interval
+    });
+
+    let started = Instant::now();
+    for sentence in corpus.iter().cycle() {
+        if started.elapsed() >= args.duration {
+            break;
+        }
+        if let Some(interval) = &mut interval {
+            interval.tick().await;
+        }
+
+        let permit = Arc::clone(&semaphore).acquire_owned().await?;
+        let mut client = client.clone();
+        let input = NerInput {
+            sentence: sentence.clone(),
+            ..Default::default()
+        };
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let start = Instant::now();
+            let result = client.ner(input).await;
+            let _ = tx.send((start.elapsed(), result.map(drop).map_err(|s| s.code())));
+            drop(permit);
+        });
+    }
+    drop(tx);
+
+    let mut latencies = vec![];
+    let mut errors = BTreeMap::<String, usize>::new();
+    while let Some((latency, result)) = rx.recv().await {
+        match result {
+            Ok(()) => latencies.push(latency),
+            Err(code) => *errors.entry(format!("{code:?}")).or_default() += 1,
+        }
+    }
+    let elapsed = started.elapsed();
+
+    let failed = errors.values().sum::<usize>();
+    let total = latencies.len() + failed;
+    println!("requests:   {total}");
+    println!("rate:       {:.1}/s", total as f64 / elapsed.as_secs_f64());
+    println!(
+        "errors:     {failed} ({:.2}%)",
+        100. * failed as f64 / total.max(1) as f64
+    );
+    for (code, n) in errors {
+        println!("  {code}: {n}");
+    }
+    if !latencies.is_empty() {
+        let stats = BenchStats::from_samples(&mut latencies);
+        for (name, p) in [("p50", stats.p50), ("p95", stats.p95), ("p99", stats.p99)] {
+            println!("{name}:        {:.2} ms", p.as_secs_f64() * 1000.);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,416 @@
+//! The bounded queue of messages in front of an actor. A message holds its
+//! place until it leaves the queues of the actor for a batch, so the queue
+//! fills up while the actor has more work than its workers can take, and
+//! what happens to messages beyond its capacity depends on the
+//! [`OverflowPolicy`].
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::{Notify, Semaphore, TryAcquireError};
+use tonic::Status;
+use trast_proto::Priority;
+
+use crate::{actor::Message, config::OverflowPolicy, metrics::Metrics, Error};
+
+#[derive(Debug)]
+struct State {
+    model: String,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Free places in the queue, unless messages are shed.
+    permits: Semaphore,
+    /// Messages sent but not yet taken into a batch.
+    queued: AtomicUsize,
+    /// Messages sent but not yet received by the actor.
+    channel: Mutex<Channel>,
+    /// Wakes the actor when messages are sent or the senders are gone.
+    notify: Notify,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Debug, Default)]
+struct Channel {
+    messages: VecDeque<Message>,
+    /// Whether every mailbox has been dropped.
+    tx_closed: bool,
+    /// Whether the inbox has been dropped.
+    rx_closed: bool,
+}
+
+impl State {
+    fn overflowed(&self) {
+        self.metrics.queue_overflowed(&self.model, self.policy);
+    }
+
+    /// Answer the message with an error, to make room for newer ones.
+    fn shed(&self, message: Message) {
+        self.overflowed();
+        let status = Status::from(Error::Overloaded(self.model.clone()));
+        let _ = message.tx.send(Err(status));
+    }
+}
+
+/// Why a message couldn't be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The actor is gone.
+    Closed,
+    /// The queue is full and the policy is to reject new messages.
+    Full,
+}
+
+/// The sending half of the mailbox, shared by everything that sends the
+/// actor messages.
+#[derive(Debug, Clone)]
+pub struct Mailbox {
+    senders: Arc<Senders>,
+}
+
+/// Closes the mailbox once the last sender is dropped.
+#[derive(Debug)]
+struct Senders(Arc<State>);
+
+impl Drop for Senders {
+    fn drop(&mut self) {
+        self.0.channel.lock().unwrap().tx_closed = true;
+        self.0.notify.notify_one();
+    }
+}
+
+/// The receiving half of the mailbox, owned by the actor.
+#[derive(Debug)]
+pub struct Inbox {
+    state: Arc<State>,
+}
+
+impl Drop for Inbox {
+    fn drop(&mut self) {
+        let state = &self.state;
+        let messages = {
+            let mut channel = state.channel.lock().unwrap();
+            channel.rx_closed = true;
+            std::mem::take(&mut channel.messages)
+        };
+        state.queued.fetch_sub(messages.len(), Ordering::SeqCst);
+        // wake blocked senders to fail
+        state.permits.close();
+    }
+}
+
+/// A received message, holding its place in the queue until it's taken
+/// into a batch or dropped.
+#[derive(Debug)]
+pub struct Queued {
+    pub message: Message,
+    slot: Slot,
+}
+
+#[derive(Debug)]
+struct Slot(Arc<State>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.queued.fetch_sub(1, Ordering::SeqCst);
+        if self.0.policy != OverflowPolicy::ShedOldest {
+            self.0.permits.add_permits(1);
+        }
+    }
+}
+
+impl Queued {
+    /// Free the place of the message in the queue.
+    pub fn into_message(self) -> Message {
+        self.message
+    }
+
+    /// Answer the message with an error, to make room for newer ones.
+    pub fn shed(self) {
+        let Self { message, slot } = self;
+        slot.0.shed(message);
+    }
+}
+
+/// Create the mailbox of the actor serving the model.
+pub fn mailbox(
+    model: String,
+    capacity: usize,
+    policy: OverflowPolicy,
+    metrics: Arc<Metrics>,
+) -> (Mailbox, Inbox) {
+    let capacity = capacity.max(1);
+    let state = Arc::new(State {
+        model,
+        capacity,
+        policy,
+        permits: Semaphore::new(capacity),
+        queued: AtomicUsize::new(0),
+        channel: Mutex::default(),
+        notify: Notify::new(),
+        metrics,
+    });
+
+    (
+        Mailbox {
+            senders: Arc::new(Senders(Arc::clone(&state))),
+        },
+        Inbox { state },
+    )
+}
+
+impl Mailbox {
+    /// Queue the message, waiting for room if the policy is to block, or
+    /// shedding the oldest message not yet received by the actor if the
+    /// policy is to shed.
+    pub async fn send(&self, message: Message) -> Result<(), SendError> {
+        let state = &self.senders.0;
+        match state.policy {
+            OverflowPolicy::Block => {
+                let permit = state.permits.acquire().await;
+                permit.map_err(|_| SendError::Closed)?.forget();
+            }
+            OverflowPolicy::RejectNew => match state.permits.try_acquire() {
+                Ok(permit) => permit.forget(),
+                Err(TryAcquireError::NoPermits) => {
+                    state.overflowed();
+                    return Err(SendError::Full);
+                }
+                Err(TryAcquireError::Closed) => return Err(SendError::Closed),
+            },
+            OverflowPolicy::ShedOldest => {}
+        }
+
+        let shed = {
+            let mut channel = state.channel.lock().unwrap();
+            if channel.rx_closed {
+                return Err(SendError::Closed);
+            }
+            // what the actor has already received is shed by the actor
+            let shed = if state.policy == OverflowPolicy::ShedOldest
+                && state.queued.load(Ordering::SeqCst) >= state.capacity
+            {
+                Self::oldest(&mut channel.messages)
+            } else {
+                None
+            };
+            if shed.is_none() {
+                state.queued.fetch_add(1, Ordering::SeqCst);
+            }
+            channel.messages.push_back(message);
+            shed
+        };
+        state.notify.notify_one();
+
+        if let Some(shed) = shed {
+            state.shed(shed);
+        }
+        Ok(())
+    }
+
+    /// Take the oldest message of batch priority out of the queue, or else
+    /// the oldest one.
+    fn oldest(messages: &mut VecDeque<Message>) -> Option<Message> {
+        let i = messages
+            .iter()
+            .position(|message| message.priority == Priority::Batch)
+            .unwrap_or(0);
+        messages.remove(i)
+    }
+}
+
+impl Inbox {
+    /// Receive the next message, or `None` once every mailbox is dropped.
+    /// This is cancel safe.
+    pub async fn recv(&mut self) -> Option<Queued> {
+        let state = &self.state;
+        loop {
+            {
+                let mut channel = state.channel.lock().unwrap();
+                if let Some(message) = channel.messages.pop_front() {
+                    return Some(Queued {
+                        message,
+                        slot: Slot(Arc::clone(state)),
+                    });
+                }
+                if channel.tx_closed {
+                    return None;
+                }
+            }
+            // a notification sent in between is kept for this
+            state.notify.notified().await;
+        }
+    }
+
+    /// Number of queued messages beyond the capacity, which are to be shed
+    /// if the policy is to shed the oldest. Senders only shed what the
+    /// actor hasn't received, so this is what was sent while the rest of the
+    /// queue was waiting in the actor.
+    pub fn overflow(&self) -> usize {
+        let state = &self.state;
+        match state.policy {
+            OverflowPolicy::ShedOldest => state
+                .queued
+                .load(Ordering::SeqCst)
+                .saturating_sub(state.capacity),
+            OverflowPolicy::Block | OverflowPolicy::RejectNew => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use tokio::{sync::oneshot, time::timeout};
+    use tracing::Span;
+    use trast_proto::Priority;
+
+    use super::*;
+    use crate::actor::Prediction;
+
+    fn mailbox(capacity: usize, policy: OverflowPolicy) -> (Mailbox, Inbox) {
+        super::mailbox(
+            "model".to_owned(),
+            capacity,
+            policy,
+            Metrics::new().unwrap(),
+        )
+    }
+
+    fn message(sentence: &str) -> (Message, oneshot::Receiver<Result<Prediction, Status>>) {
+        let (tx, rx) = oneshot::channel();
+        let message = Message {
+            sentence: sentence.to_owned(),
+            tx,
+            priority: Priority::Interactive,
+            client: "client".to_owned(),
+            span: Span::none(),
+            baggage: vec![],
+            received: Instant::now(),
+            windowed: false,
+        };
+        (message, rx)
+    }
+
+    #[tokio::test]
+    async fn messages_are_received_in_order() {
+        let (mailbox, mut inbox) = mailbox(4, OverflowPolicy::Block);
+        for sentence in ["a", "b", "c"] {
+            mailbox.send(message(sentence).0).await.unwrap();
+        }
+        for sentence in ["a", "b", "c"] {
+            let queued = inbox.recv().await.unwrap();
+            assert_eq!(queued.into_message().sentence, sentence);
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_new_rejects_beyond_capacity() {
+        let (mailbox, mut inbox) = mailbox(1, OverflowPolicy::RejectNew);
+        mailbox.send(message("a").0).await.unwrap();
+        assert_eq!(mailbox.send(message("b").0).await, Err(SendError::Full));
+
+        // received messages hold their place until they're taken
+        let queued = inbox.recv().await.unwrap();
+        assert_eq!(mailbox.send(message("b").0).await, Err(SendError::Full));
+        queued.into_message();
+        assert_eq!(mailbox.send(message("b").0).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (mailbox, mut inbox) = mailbox(1, OverflowPolicy::Block);
+        mailbox.send(message("a").0).await.unwrap();
+        let blocked = timeout(Duration::from_millis(10), mailbox.send(message("b").0));
+        assert!(blocked.await.is_err());
+
+        inbox.recv().await.unwrap().into_message();
+        mailbox.send(message("b").0).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shed_oldest_sheds_when_sending() {
+        let (mailbox, mut inbox) = mailbox(2, OverflowPolicy::ShedOldest);
+        let mut answers = vec![];
+        for sentence in ["a", "b", "c"] {
+            let (message, rx) = message(sentence);
+            mailbox.send(message).await.unwrap();
+            answers.push(rx);
+        }
+        // the queue stays bounded while the actor isn't receiving
+        assert_eq!(inbox.overflow(), 0);
+        let status = answers.remove(0).await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+
+        for sentence in ["b", "c"] {
+            let queued = inbox.recv().await.unwrap();
+            assert_eq!(queued.into_message().sentence, sentence);
+        }
+    }
+
+    #[tokio::test]
+    async fn shed_oldest_sheds_batch_priority_first() {
+        let (mailbox, mut inbox) = mailbox(2, OverflowPolicy::ShedOldest);
+        let (a, _a) = message("a");
+        let (mut b, b_rx) = message("b");
+        b.priority = Priority::Batch;
+        for message in [a, b, message("c").0] {
+            mailbox.send(message).await.unwrap();
+        }
+
+        assert!(b_rx.await.unwrap().is_err());
+        for sentence in ["a", "c"] {
+            let queued = inbox.recv().await.unwrap();
+            assert_eq!(queued.into_message().sentence, sentence);
+        }
+    }
+
+    #[tokio::test]
+    async fn shed_oldest_leaves_received_messages_to_the_actor() {
+        let (mailbox, mut inbox) = mailbox(1, OverflowPolicy::ShedOldest);
+        let (a, rx) = message("a");
+        mailbox.send(a).await.unwrap();
+        let queued = inbox.recv().await.unwrap();
+        mailbox.send(message("b").0).await.unwrap();
+        assert_eq!(inbox.overflow(), 1);
+
+        queued.shed();
+        assert_eq!(inbox.overflow(), 0);
+        let status = rx.await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+    }
+
+    #[tokio::test]
+    async fn dropped_mailboxes_end_the_inbox() {
+        let (mailbox, mut inbox) = mailbox(1, OverflowPolicy::Block);
+        mailbox.send(message("a").0).await.unwrap();
+        drop(mailbox);
+        assert!(inbox.recv().await.is_some());
+        assert!(inbox.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn blocked_senders_fail_once_the_inbox_is_dropped() {
+        let (mailbox, inbox) = mailbox(1, OverflowPolicy::Block);
+        mailbox.send(message("a").0).await.unwrap();
+        let blocked = tokio::spawn({
+            let mailbox = mailbox.clone();
+            async move { mailbox.send(message("b").0).await }
+        });
+        tokio::task::yield_now().await;
+        drop(inbox);
+        assert_eq!(blocked.await.unwrap(), Err(SendError::Closed));
+    }
+
+    #[tokio::test]
+    async fn closed_inbox_fails_sends() {
+        let (mailbox, inbox) = mailbox(1, OverflowPolicy::Block);
+        drop(inbox);
+        assert_eq!(mailbox.send(message("a").0).await, Err(SendError::Closed));
+    }
+}
@@ -1,89 +1,644 @@
-use std::{env, sync::Arc, time::Duration};
-
-use futures::{stream::FuturesUnordered, StreamExt};
-use onnx_bert::{Entity, Pipeline};
-use opentelemetry::{
-    sdk::{propagation::TraceContextPropagator, trace::Sampler, Resource},
-    KeyValue,
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    process,
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use opentelemetry_otlp::WithExportConfig;
+
+use anyhow::Context;
+use futures::{future, stream::BoxStream, StreamExt};
+use rand::Rng;
 use tokio::{
-    select,
-    sync::{mpsc, oneshot},
-    task::{spawn_blocking, JoinError, JoinHandle},
-    time::sleep,
-};
-use tokio_rayon::{
-    rayon::{ThreadPool, ThreadPoolBuilder},
-    AsyncThreadPool,
+    runtime, signal,
+    sync::{oneshot, watch},
+    task::{self, JoinError},
+    time,
 };
+use tokio_rayon::rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
 use tonic::{transport::Server, Request, Response, Status};
-use tracing::{debug, error, info, instrument, metadata::LevelFilter, Instrument, Span};
-use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use tonic_health::server::HealthReporter;
+use tracing::{debug, info, warn, Span};
 use trast_proto::{
     trast_server::{Trast, TrastServer},
-    NerInput, NerOutput,
+    DrainRequest, DrainResponse, GetConfigRequest, GetConfigResponse, GetJobResultsRequest,
+    GetJobStatusRequest, GetModelInfoRequest, GetStatsRequest, GetStatsResponse, JobResult,
+    JobStatus, ListModelsRequest, ListModelsResponse, ModelInfo, NerInput, NerOutput, Priority,
+    SubmitJobRequest, SubmitJobResponse, WarmedModel, WarmupRequest, WarmupResponse,
 };
 
-use crate::trace::TraceLayer;
+use crate::{
+    actor::{act, Message, Prediction},
+    audit::Audit,
+    cache::ResponseCache,
+    canary::Canary,
+    cli::Command,
+    config::Config,
+    jobs::{Job, Jobs},
+    language::LanguageRouter,
+    mailbox::{Mailbox, SendError},
+    metrics::Metrics,
+    quota::Quotas,
+    registry::{Registry, WARMUP_SENTENCE},
+    shadow::Shadow,
+    trace::{BaggageAttributes, TraceLayer},
+};
 
+mod actor;
+mod affinity;
+mod audit;
+mod batch;
+mod bench;
+mod breaker;
+mod cache;
+mod canary;
+mod cli;
+mod config;
+mod document;
+mod downloads;
+mod http;
+mod jobs;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod language;
+mod loadtest;
+mod mailbox;
+mod metrics;
+#[cfg(feature = "nats")]
+mod nats;
+mod otlp;
+mod predict;
+mod queue;
+mod quota;
+mod redact;
+#[cfg(feature = "redis")]
+mod redis;
+mod registry;
+mod replay;
+mod sampling;
+mod self_test;
+mod shadow;
+mod telemetry;
 mod trace;
+mod validate_model;
+mod warmup;
+
+/// Identify the client by the `x-client-id` metadata, falling back to its IP
+/// address.
+fn client_id<T>(request: &Request<T>) -> String {
+    request
+        .metadata()
+        .get("x-client-id")
+        .and_then(|v| v.to_str().ok())
+        .map(ToOwned::to_owned)
+        .or_else(|| request.remote_addr().map(|addr| addr.ip().to_string()))
+        .unwrap_or_default()
+}
+
+/// Pin the threads of the pool to the inference cores, if any are configured.
+fn pinned(builder: ThreadPoolBuilder, config: &Config) -> ThreadPoolBuilder {
+    let Some(cores) = config.inference_cores.clone() else {
+        return builder;
+    };
+    builder.start_handler(move |_| {
+        if let Err(e) = affinity::pin(&cores) {
+            warn!(?e, ?cores, "failed to pin inference thread");
+        }
+    })
+}
 
-const PIPELINE_TTL: Duration = Duration::from_secs(60);
+/// The thread pool to run the model on: its own if it's configured with a
+/// thread count, so that it can't starve the other models, or else the shared
+/// one.
+fn threadpool_for(
+    config: &Config,
+    model: &str,
+    shared: &Arc<ThreadPool>,
+) -> Result<Arc<ThreadPool>, ThreadPoolBuildError> {
+    match config.model_threads.get(model) {
+        Some(&threads) => {
+            let model = model.to_owned();
+            let threadpool = pinned(ThreadPoolBuilder::new(), config)
+                .num_threads(threads)
+                .thread_name(move |i| format!("{model}-{i}"))
+                .build()?;
+            Ok(Arc::new(threadpool))
+        }
+        None => Ok(Arc::clone(shared)),
+    }
+}
+
+/// Where a sentence is sent: the model that it's routed to along with its
+/// actor, and its language if languages are routed.
+struct Route<'a> {
+    language: Option<String>,
+    model: &'a String,
+    actor_tx: &'a Mailbox,
+}
 
 struct TrastService {
-    actor_tx: mpsc::Sender<Message>,
+    actor_tx: Mailbox,
+    /// The primary model, serving everything not routed to the canary.
+    model: String,
+    quotas: Quotas,
+    metrics: Arc<Metrics>,
+    cache: ResponseCache,
+    registry: Arc<Registry>,
+    canary: Option<Canary>,
+    /// Routes sentences to the models of their languages, if any are
+    /// configured.
+    languages: Option<LanguageRouter>,
+    shadow: Option<Shadow>,
+    audit: Option<Audit>,
+    request_log_sample_rate: f64,
+    max_input_chars: usize,
+    /// Set once the server starts draining.
+    draining: Arc<watch::Sender<bool>>,
+    drain_grace_period: Duration,
+    jobs: Jobs,
+    /// The redacted configuration, as reported by `GetConfig`.
+    config: String,
 }
 
-#[tonic::async_trait]
-impl Trast for TrastService {
-    async fn ner(&self, request: Request<NerInput>) -> Result<Response<NerOutput>, Status> {
-        let NerInput { sentence } = request.into_inner();
+impl TrastService {
+    /// Check the input before it takes up a place in the queue of a model,
+    /// and route it to the model. This runs on the runtime, so only the
+    /// length in characters is checked, and the actor rejects what the
+    /// model would truncate once it has been tokenized for the batch.
+    fn validate(&self, input: &NerInput) -> Result<Route<'_>> {
+        let sentence = &input.sentence;
+        if sentence.trim().is_empty() {
+            return Err(Error::InvalidArgument("sentence is empty".to_owned()));
+        }
+
+        let chars = sentence.chars().count();
+        if chars > self.max_input_chars {
+            return Err(Error::InvalidArgument(format!(
+                "sentence is {chars} characters long, the limit is {}",
+                self.max_input_chars
+            )));
+        }
+
+        Ok(self.route(&input.language, sentence))
+    }
+
+    /// Route the sentence to the model of its language, if languages are
+    /// routed, or else to the canary or the primary model.
+    fn route(&self, language: &str, sentence: &str) -> Route<'_> {
+        let language = match &self.languages {
+            Some(languages) => languages.language(language, sentence),
+            None => None,
+        };
+        let routed = match (&self.languages, &language) {
+            (Some(languages), Some(language)) => languages.route(language),
+            _ => None,
+        };
+        let (model, actor_tx) = match (routed, &self.canary) {
+            (Some(routed), _) => routed,
+            (None, Some(canary)) if canary.routes(sentence) => (&canary.model, &canary.tx),
+            _ => (&self.model, &self.actor_tx),
+        };
+        Route {
+            language,
+            model,
+            actor_tx,
+        }
+    }
 
+    fn job(&self, id: &str) -> Result<Arc<Job>> {
+        self.jobs
+            .get(id)
+            .ok_or_else(|| Error::NotFound(format!("job {id}")))
+    }
+
+    /// The models that requests are routed to, along with their actors.
+    fn served_models(&self) -> impl Iterator<Item = (&String, &Mailbox)> {
+        let canary = self
+            .canary
+            .as_ref()
+            .map(|canary| (&canary.model, &canary.tx));
+        let languages = self.languages.iter().flat_map(LanguageRouter::models);
+        [(&self.model, &self.actor_tx)]
+            .into_iter()
+            .chain(canary)
+            .chain(languages)
+    }
+
+    /// Run the sentence through the actor of the model.
+    async fn infer(
+        actor_tx: &Mailbox,
+        model: &str,
+        sentence: String,
+        priority: Priority,
+        client: &str,
+        windowed: bool,
+    ) -> Result<Prediction, Status> {
         let (tx, rx) = oneshot::channel();
-        self.actor_tx
+        actor_tx
             .send(Message {
                 sentence,
                 tx,
+                priority,
+                client: client.to_owned(),
                 span: Span::current(),
+                baggage: BaggageAttributes::current().0,
+                received: Instant::now(),
+                windowed,
             })
             .await
-            .unwrap();
-
-        let entities = rx.await.unwrap()?.into_iter().map(
-            |onnx_bert::Entity {
-                 label,
-                 score,
-                 word,
-                 start,
-                 end,
-             }| trast_proto::Entity {
+            .map_err(|e| match e {
+                SendError::Closed => Error::Unavailable(model.to_owned()),
+                SendError::Full => Error::Overloaded(model.to_owned()),
+            })?;
+
+        // the sender is dropped without a reply if the actor panics
+        match rx.await {
+            Ok(result) => result,
+            Err(_) => Err(Status::internal(format!(
+                "model {model} failed without replying"
+            ))),
+        }
+    }
+
+    /// Recognize the entities in the input on behalf of the client. This is
+    /// shared by all transports, so that they're all subject to the same
+    /// validation, quotas, routing and caching.
+    pub async fn predict(
+        &self,
+        input: NerInput,
+        client: &str,
+        request_id: &str,
+    ) -> Result<NerOutput, Status> {
+        let route = self.validate(&input)?;
+        self.acquire(client)?;
+        self.recognize(input, route, client, request_id, false)
+            .await
+    }
+
+    /// Count a request of the client against its quota.
+    fn acquire(&self, client: &str) -> Result<()> {
+        let acquired = self.quotas.acquire(client);
+        if acquired.is_err() {
+            self.metrics.quota_rejected(client);
+        }
+        acquired
+    }
+
+    /// Recognize the entities in a valid input that the client has been
+    /// granted a request for. Windowed sentences are run in windows of what
+    /// the model takes rather than truncated, and bypass the cache, which
+    /// holds the predictions of sentences as they're truncated.
+    async fn recognize(
+        &self,
+        input: NerInput,
+        route: Route<'_>,
+        client: &str,
+        request_id: &str,
+        windowed: bool,
+    ) -> Result<NerOutput, Status> {
+        let priority = input.priority();
+        let sentence = input.sentence;
+        let Route {
+            language,
+            model,
+            actor_tx,
+        } = route;
+
+        let span = Span::current();
+        span.record("language", language.as_deref());
+
+        let BaggageAttributes(baggage) = BaggageAttributes::current();
+        let sha = self.registry.sha(model);
+        let cached = if windowed {
+            None
+        } else {
+            self.cache.get(model, sha.as_deref(), &sentence)
+        };
+        let prediction = match cached {
+            Some(prediction) => {
+                span.record("cache_hit", true);
+                prediction
+            }
+            None => {
+                span.record("cache_hit", false);
+                let started = Instant::now();
+                let result = Self::infer(
+                    actor_tx,
+                    model,
+                    sentence.clone(),
+                    priority,
+                    client,
+                    windowed,
+                )
+                .await;
+                self.metrics
+                    .model_request(model, started.elapsed(), result.is_ok(), &baggage);
+                self.registry
+                    .record_reply(model, started.elapsed(), result.is_ok());
+
+                let prediction = result?;
+                span.record("batch_size", prediction.batch_size);
+                if &prediction.model == model && !windowed {
+                    self.cache
+                        .put(model, sha.as_deref(), &sentence, prediction.clone());
+                }
+                prediction
+            }
+        };
+
+        // entities past the end of what the model takes would be missing
+        if prediction.truncated {
+            return Err(Error::InvalidArgument(format!(
+                "sentence is longer than the {} tokens that model {} takes",
+                prediction.tokens, prediction.model
+            ))
+            .into());
+        }
+
+        // only compare against the primary model, not its fallback
+        if let Some(shadow) = &self.shadow {
+            if prediction.model == self.model {
+                shadow.mirror(&sentence, client, &prediction);
+            }
+        }
+
+        let revision = self.registry.sha(&prediction.model);
+        span.record("model", &prediction.model);
+        span.record("model_revision", revision.as_deref());
+        span.record("tokens", prediction.tokens);
+        span.record("entities", prediction.entities.len());
+        self.metrics
+            .entities_returned(&prediction.model, &prediction.entities, &baggage);
+
+        if let Some(audit) = &self.audit {
+            audit.record(
+                request_id,
+                client,
+                &sentence,
+                &prediction.model,
+                revision,
+                &prediction.entities,
+            );
+        }
+
+        if rand::thread_rng().gen::<f64>() < self.request_log_sample_rate {
+            debug!(
+                request_id,
+                client,
+                model = prediction.model,
+                sentence = redact::redact(&sentence, &prediction.entities),
+                entities = ?redact::redact_entities(&prediction.entities),
+                "sampled request"
+            );
+        }
+
+        let Prediction {
+            entities,
+            tokens,
+            words,
+            model,
+            ..
+        } = prediction;
+        self.quotas.record_tokens(client, tokens as u64);
+        self.metrics.client_usage(client, tokens as u64);
+
+        let offset = |i: usize| {
+            u32::try_from(i).map_err(|_| Error::TooLarge(format!("offset {i} is out of range")))
+        };
+        // the strings are moved rather than cloned, and the reply is sized
+        // up front, as collecting into a `Result` can't be
+        let mut converted = Vec::with_capacity(entities.len());
+        for onnx_bert::Entity {
+            label,
+            score,
+            word,
+            start,
+            end,
+            start_word,
+            end_word,
+            candidates,
+        } in entities
+        {
+            converted.push(trast_proto::Entity {
                 label,
                 score,
                 word,
-                start: start.try_into().unwrap(),
-                end: end.try_into().unwrap(),
-            },
-        );
+                start: offset(start)?,
+                end: offset(end)?,
+                start_word: offset(start_word)?,
+                end_word: offset(end_word)?,
+                candidates: candidates
+                    .into_iter()
+                    .map(|onnx_bert::Candidate { id, score }| trast_proto::Candidate { id, score })
+                    .collect(),
+            });
+        }
+
+        Ok(NerOutput {
+            entities: converted,
+            model,
+            language: language.unwrap_or_default(),
+            words: offset(words)?,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Trast for TrastService {
+    async fn ner(&self, request: Request<NerInput>) -> Result<Response<NerOutput>, Status> {
+        let client = client_id(&request);
+        let request_id = request
+            .metadata()
+            .get("x-request-id")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+
+        self.predict(request.into_inner(), &client, &request_id)
+            .await
+            .map(Response::new)
+    }
+
+    async fn list_models(
+        &self,
+        _: Request<ListModelsRequest>,
+    ) -> Result<Response<ListModelsResponse>, Status> {
+        Ok(Response::new(ListModelsResponse {
+            models: self.registry.list().into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn get_model_info(
+        &self,
+        request: Request<GetModelInfoRequest>,
+    ) -> Result<Response<ModelInfo>, Status> {
+        let GetModelInfoRequest { id } = request.into_inner();
+        let entry = self
+            .registry
+            .get(&id)
+            .ok_or_else(|| Error::NotFound(format!("model {id}")))?;
+        Ok(Response::new(entry.into()))
+    }
+
+    async fn get_stats(
+        &self,
+        _: Request<GetStatsRequest>,
+    ) -> Result<Response<GetStatsResponse>, Status> {
+        Ok(Response::new(GetStatsResponse {
+            models: self.registry.list().into_iter().map(Into::into).collect(),
+        }))
+    }
 
-        Ok(Response::new(NerOutput {
-            entities: entities.into_iter().map(Into::into).collect(),
+    async fn submit_job(
+        &self,
+        request: Request<SubmitJobRequest>,
+    ) -> Result<Response<SubmitJobResponse>, Status> {
+        let client = client_id(&request);
+        let SubmitJobRequest { sentences, url } = request.into_inner();
+        if sentences.is_empty() && url.is_empty() {
+            return Err(Error::InvalidArgument("job has no sentences".to_owned()).into());
+        }
+
+        let url = (!url.is_empty()).then_some(url);
+        let job_id = self.jobs.submit(&client, sentences, url);
+
+        Ok(Response::new(SubmitJobResponse { job_id }))
+    }
+
+    async fn get_job_status(
+        &self,
+        request: Request<GetJobStatusRequest>,
+    ) -> Result<Response<JobStatus>, Status> {
+        let job = self.job(&request.get_ref().job_id)?;
+        Ok(Response::new(job.status()))
+    }
+
+    type GetJobResultsStream = BoxStream<'static, Result<JobResult, Status>>;
+
+    async fn get_job_results(
+        &self,
+        request: Request<GetJobResultsRequest>,
+    ) -> Result<Response<Self::GetJobResultsStream>, Status> {
+        let job = self.job(&request.get_ref().job_id)?;
+        Ok(Response::new(job.results().map(Ok).boxed()))
+    }
+
+    async fn drain(&self, _: Request<DrainRequest>) -> Result<Response<DrainResponse>, Status> {
+        self.draining.send_replace(true);
+
+        Ok(Response::new(DrainResponse {
+            grace_period_secs: self.drain_grace_period.as_secs(),
+        }))
+    }
+
+    async fn get_config(
+        &self,
+        _: Request<GetConfigRequest>,
+    ) -> Result<Response<GetConfigResponse>, Status> {
+        Ok(Response::new(GetConfigResponse {
+            config: self.config.clone(),
+        }))
+    }
+
+    async fn warmup(
+        &self,
+        request: Request<WarmupRequest>,
+    ) -> Result<Response<WarmupResponse>, Status> {
+        let client = client_id(&request);
+        let wanted = request.into_inner().model;
+        let models = self
+            .served_models()
+            .filter(|(model, _)| wanted.is_empty() || **model == wanted)
+            .collect::<Vec<_>>();
+        if models.is_empty() {
+            return Err(Error::NotFound(format!("served model {wanted}")).into());
+        }
+
+        // bypass the cache and quotas, so that the model really is run
+        let client = &client;
+        let warmed = models.into_iter().map(|(model, actor_tx)| async move {
+            let started = Instant::now();
+            let sentence = WARMUP_SENTENCE.to_owned();
+            Self::infer(
+                actor_tx,
+                model,
+                sentence,
+                Priority::Interactive,
+                client,
+                false,
+            )
+            .await?;
+            info!(model, "warmed up");
+
+            Ok::<_, Status>(WarmedModel {
+                id: model.clone(),
+                latency_ms: started.elapsed().as_secs_f64() * 1000.,
+            })
+        });
+
+        Ok(Response::new(WarmupResponse {
+            models: future::try_join_all(warmed).await?,
         }))
     }
 }
 
-#[derive(Debug)]
-struct Message {
-    sentence: String,
-    tx: oneshot::Sender<Result<Vec<Entity>>>,
-    span: Span,
+/// Resolves when the server is terminated.
+async fn terminated() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = sigterm.recv() => {}
+            _ = signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await.unwrap();
+}
+
+/// Resolves once the server has drained: the grace period after a drain is
+/// requested through the RPC or a signal, during which it reports
+/// `NOT_SERVING` so that no new traffic is routed to it.
+async fn drained(
+    draining: Arc<watch::Sender<bool>>,
+    mut health_reporter: HealthReporter,
+    grace_period: Duration,
+) {
+    let mut rx = draining.subscribe();
+    let requested = async {
+        while !*rx.borrow_and_update() {
+            let _ = rx.changed().await;
+        }
+    };
+
+    tokio::select! {
+        _ = requested => {}
+        _ = terminated() => {
+            draining.send_replace(true);
+        }
+    }
+
+    info!(?grace_period, "draining");
+    health_reporter
+        .set_not_serving::<TrastServer<TrastService>>()
+        .await;
+    time::sleep(grace_period).await;
 }
 
 type Result<T, E = Error> = core::result::Result<T, E>;
 
 impl From<Error> for Status {
     fn from(value: Error) -> Self {
-        Self::internal(value.to_string())
+        match value {
+            Error::QuotaExceeded { .. } => Self::resource_exhausted(value.to_string()),
+            // clients back off and retry either
+            Error::Unavailable(_) | Error::Overloaded(_) => Self::unavailable(value.to_string()),
+            Error::InvalidArgument(_) => Self::invalid_argument(value.to_string()),
+            Error::NotFound(_) => Self::not_found(value.to_string()),
+            Error::TooLarge(_) => Self::out_of_range(value.to_string()),
+            _ => Self::internal(value.to_string()),
+        }
     }
 }
 
@@ -93,179 +648,452 @@ enum Error {
     Join(#[from] JoinError),
     #[error("{0}")]
     Bert(#[from] onnx_bert::Error),
+    #[error("{kind} quota of {max} exceeded, resets in {}s", resets_in.as_secs())]
+    QuotaExceeded {
+        kind: &'static str,
+        max: u64,
+        resets_in: Duration,
+    },
+    #[error("model {0} is unavailable")]
+    Unavailable(String),
+    #[error("model {0} is overloaded")]
+    Overloaded(String),
+    #[error("{0}")]
+    InvalidArgument(String),
+    #[error("{0} not found")]
+    NotFound(String),
+    /// The input or output is too large to be represented in a reply.
+    #[error("{0}")]
+    TooLarge(String),
 }
 
-type Handles = FuturesUnordered<JoinHandle<()>>;
+fn main() -> anyhow::Result<()> {
+    let _ = dotenv::dotenv();
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {e}");
+            process::exit(2);
+        }
+    };
+
+    // threads inherit the affinity of the thread that spawns them, so the
+    // runtime is kept off the inference cores by starting it from here
+    let runtime_affinity = config.inference_cores.as_deref().map(affinity::avoid);
+    runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(config, runtime_affinity))
+}
 
-#[instrument]
-async fn get_pipeline() -> Result<Pipeline> {
-    let span = Span::current();
-    let pipeline = spawn_blocking(move || {
-        span.in_scope(|| Pipeline::from_pretrained("amcoff/bert-based-swedish-cased-ner"))
-    })
-    .await??;
-    Ok(pipeline)
+/// Run an offline command on the blocking threads, with the model it names
+/// or else the configured one.
+async fn offline(
+    config: &Config,
+    model: Option<&str>,
+    command: impl FnOnce(&Registry, &str) -> anyhow::Result<()> + Send + 'static,
+) -> anyhow::Result<()> {
+    telemetry::init_cli();
+    let registry = Registry::open(config);
+    let model = model.map_or_else(|| config.model.clone(), ToOwned::to_owned);
+    task::spawn_blocking(move || command(&registry, &model)).await?
 }
 
-#[instrument(skip_all, fields(cold))]
-async fn spawn_ner_task(
-    sentence: String,
-    cb: oneshot::Sender<Result<Vec<Entity>>>,
-    pipeline: &mut Option<Arc<Pipeline>>,
-    threadpool: &Arc<ThreadPool>,
-) -> Option<JoinHandle<()>> {
-    tracing::Span::current().record("cold", pipeline.is_none());
-
-    if pipeline.is_none() {
-        debug!("initializing pipeline");
-
-        match get_pipeline().await {
-            Ok(p) => *pipeline = Some(Arc::new(p)),
-            Err(e) => {
-                let _ = cb.send(Err(e));
-                return None;
-            }
+async fn run(config: Config, runtime_affinity: Option<io::Result<()>>) -> anyhow::Result<()> {
+    let command = match cli::parse(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{e}\n\n{}", cli::USAGE);
+            process::exit(2);
         }
+    };
 
-        debug!("initialized pipeline");
-    }
+    let model = command.model().map(ToOwned::to_owned);
+    let model = model.as_deref();
+    let self_test = match command {
+        Command::Serve { self_test } => self_test,
+        Command::Loadtest(args) => {
+            telemetry::init_cli();
+            return loadtest::run(args).await;
+        }
+        Command::Warmup(args) => {
+            telemetry::init_cli();
+            return warmup::run(args).await;
+        }
+        Command::Config(args) => {
+            telemetry::init_cli();
+            let config = trast_client::Client::new(args.target)?.config().await?;
+            println!("{config}");
+            return Ok(());
+        }
+        Command::ValidateModel(args) => {
+            telemetry::init_cli();
+            return task::spawn_blocking(move || validate_model::run(args)).await?;
+        }
+        Command::Cache(args) => {
+            return offline(&config, model, |registry, _| downloads::run(registry, args)).await;
+        }
+        Command::Batch(args) => {
+            return offline(&config, model, |registry, model| {
+                batch::run(registry, model, args)
+            })
+            .await;
+        }
+        Command::Predict(args) => {
+            return offline(&config, model, |registry, model| {
+                predict::run(registry, model, args)
+            })
+            .await;
+        }
+        Command::Replay(args) => {
+            return offline(&config, model, |registry, model| {
+                replay::run(registry, model, args)
+            })
+            .await;
+        }
+        Command::Bench(args) => {
+            return offline(&config, model, |registry, model| {
+                bench::run(registry, model, args)
+            })
+            .await;
+        }
+    };
 
-    let pipeline = Arc::clone(pipeline.as_ref().unwrap());
-    let threadpool = threadpool.clone();
+    telemetry::init(&config)?;
+    if let Some(Err(e)) = runtime_affinity {
+        warn!(?e, "failed to keep the runtime off the inference cores");
+    }
 
-    debug!("recognizing entities");
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter
+        .set_not_serving::<TrastServer<TrastService>>()
+        .await;
 
-    let handle = tokio::spawn(
-        async move {
-            let span = Span::current();
-            match threadpool
-                .spawn_fifo_async(move || span.in_scope(|| pipeline.predict(sentence)))
-                .await
-            {
-                Ok(entities) => {
-                    let _ = cb.send(Ok(entities));
-                }
-                Err(e) => {
-                    error!(?e);
-                    let _ = cb.send(Err(e.into()));
-                }
-            };
-        }
-        .in_current_span(),
-    );
+    let threadpool = pinned(ThreadPoolBuilder::new(), &config)
+        .num_threads(config.num_worker_threads)
+        .build()?;
+    let threadpool = Arc::new(threadpool);
 
-    Some(handle)
-}
+    let metrics = Metrics::new().context("failed to register the metrics")?;
+    let registry = Registry::open(&config);
+    for model in [
+        &config.fallback_model,
+        &config.canary_model,
+        &config.shadow_model,
+    ]
+    .into_iter()
+    .flatten()
+    .chain(config.language_models.values())
+    .chain([&config.model])
+    {
+        registry.register(model);
+    }
+    if self_test {
+        self_test::run(registry, config.model).await;
+    }
 
-async fn wait(handles: &mut Handles) {
-    while handles.next().await.is_some() {}
-    sleep(PIPELINE_TTL).await;
-}
+    if let Some(period) = config.model_refresh_interval {
+        tokio::spawn(registry::refresh_periodically(
+            Arc::clone(&registry),
+            config.model.clone(),
+            period,
+        ));
+    }
 
-fn act(threadpool: ThreadPool) -> mpsc::Sender<Message> {
-    let (tx, mut rx) = mpsc::channel::<Message>(16);
-    let threadpool = Arc::new(threadpool);
-    let mut pipeline = None;
-    let mut handles = FuturesUnordered::new();
+    let mut prefetched = Vec::new();
+    let enabled = config.prefetch;
+    let mut prefetch = || {
+        enabled.then(|| {
+            let (tx, rx) = oneshot::channel();
+            prefetched.push(rx);
+            tx
+        })
+    };
 
-    tokio::spawn(async move {
-        loop {
-            select! {
-                Some(Message { sentence, tx, span }) = rx.recv() => {
-                    if let Some(handle) = spawn_ner_task(sentence, tx, &mut pipeline, &threadpool).instrument(span).await {
-                        handles.push(handle);
-                    }
-                }
-                _ = wait(&mut handles) => if pipeline.take().is_some() {
-                    info!("dropped pipeline");
-                }
+    let canary = match config.canary_model.clone() {
+        Some(model) => {
+            let canary_config = Config {
+                model: model.clone(),
+                fallback_model: None,
+                ..config.clone()
+            };
+            let tx = act(
+                threadpool_for(&config, &model, &threadpool)?,
+                Arc::clone(&metrics),
+                Arc::clone(&registry),
+                canary_config,
+                prefetch(),
+            )?;
+            Some(Canary::new(model, config.canary_percent, tx))
+        }
+        None => None,
+    };
+    let shadow = match config.shadow_model.clone() {
+        Some(model) => {
+            let shadow_config = Config {
+                model: model.clone(),
+                fallback_model: None,
+                pipeline_replicas: 1,
+                max_pipeline_replicas: 1,
+                ..config.clone()
+            };
+            let tx = act(
+                threadpool_for(&config, &model, &threadpool)?,
+                Arc::clone(&metrics),
+                Arc::clone(&registry),
+                shadow_config,
+                None,
+            )?;
+            Some(Shadow::new(
+                model,
+                config.shadow_fraction,
+                tx,
+                Arc::clone(&metrics),
+            ))
+        }
+        None => None,
+    };
+    let languages = if config.language_models.is_empty() {
+        None
+    } else {
+        let mut actors = HashMap::new();
+        for model in config.language_models.values() {
+            if *model == config.model || actors.contains_key(model) {
+                continue;
             }
+            let language_config = Config {
+                model: model.clone(),
+                fallback_model: None,
+                ..config.clone()
+            };
+            let tx = act(
+                threadpool_for(&config, model, &threadpool)?,
+                Arc::clone(&metrics),
+                Arc::clone(&registry),
+                language_config,
+                prefetch(),
+            )?;
+            actors.insert(model.clone(), tx);
         }
+        Some(LanguageRouter::new(&config.language_models, actors))
+    };
+    let threadpool = threadpool_for(&config, &config.model, &threadpool)?;
+    let draining = Arc::new(watch::channel(false).0);
+    let grace_period = config.drain_grace_period;
+    let baggage_attributes = config.baggage_attributes.clone();
+    let server = Server::builder()
+        .http2_keepalive_interval(config.http2_keepalive_interval)
+        .http2_keepalive_timeout(config.http2_keepalive_timeout)
+        .max_concurrent_streams(config.max_concurrent_streams)
+        .initial_stream_window_size(config.initial_stream_window_size)
+        .initial_connection_window_size(config.initial_connection_window_size)
+        .tcp_nodelay(config.tcp_nodelay);
+    #[cfg(feature = "nats")]
+    let nats = config.nats_url.clone().map(|url| {
+        (
+            url,
+            config.nats_subject.clone(),
+            config.nats_queue_group.clone(),
+        )
+    });
+    #[cfg(feature = "redis")]
+    let redis = config.redis_url.clone().map(|url| {
+        let worker_config = redis::WorkerConfig {
+            url,
+            queue: config.redis_queue.clone(),
+            processing: config.redis_processing_queue.clone(),
+            result_prefix: config.redis_result_prefix.clone(),
+            result_ttl: config.redis_result_ttl,
+            visibility_timeout: config.redis_visibility_timeout,
+        };
+        (worker_config, config.redis_workers)
     });
+    #[cfg(feature = "kafka")]
+    let kafka = config
+        .kafka_brokers
+        .clone()
+        .map(|brokers| kafka::ConsumerConfig {
+            brokers,
+            group_id: config.kafka_group_id.clone(),
+            input_topic: config.kafka_input_topic.clone(),
+            output_topic: config.kafka_output_topic.clone(),
+            concurrency: config.kafka_concurrency.unwrap_or(config.batch_max_size),
+        });
+    let gateway = config.http_addr.map(|addr| http::Gateway {
+        addr,
+        max_upload_bytes: config.max_upload_bytes,
+        concurrency: config
+            .document_concurrency
+            .unwrap_or(config.batch_max_size * threadpool.current_num_threads()),
+    });
+    let (jobs, job_queue) = Jobs::new(config.job_retention);
+    let job_concurrency = config.batch_max_size;
+    let trast = TrastService {
+        model: config.model.clone(),
+        quotas: Quotas::new(&config),
+        metrics: Arc::clone(&metrics),
+        cache: ResponseCache::new(config.cache_size),
+        registry: Arc::clone(&registry),
+        canary,
+        languages,
+        shadow,
+        audit: config
+            .audit_sink
+            .as_ref()
+            .map(|sink| Audit::new(sink, config.audit_input)),
+        request_log_sample_rate: config.request_log_sample_rate,
+        max_input_chars: config.max_input_chars,
+        draining: Arc::clone(&draining),
+        drain_grace_period: config.drain_grace_period,
+        jobs,
+        config: serde_json::to_string_pretty(&config)?,
+        actor_tx: act(threadpool, metrics, registry, config, prefetch())?,
+    };
+    let trast = Arc::new(trast);
 
-    tx
-}
+    #[cfg(feature = "nats")]
+    if let Some((url, subject, queue_group)) = nats {
+        tokio::spawn(nats::serve(Arc::clone(&trast), url, subject, queue_group));
+    }
+    if let Some(gateway) = gateway {
+        tokio::spawn(http::serve(Arc::clone(&trast), gateway));
+    }
+    tokio::spawn(jobs::run(Arc::clone(&trast), job_queue, job_concurrency));
+    #[cfg(feature = "redis")]
+    if let Some((worker_config, workers)) = redis {
+        tokio::spawn(redis::reap(worker_config.clone()));
+        for _ in 0..workers {
+            tokio::spawn(redis::work(Arc::clone(&trast), worker_config.clone()));
+        }
+    }
+    #[cfg(feature = "kafka")]
+    if let Some(consumer_config) = kafka {
+        tokio::spawn(kafka::consume(Arc::clone(&trast), consumer_config));
+    }
 
-fn init_telemetry(otlp_endpoint: impl Into<String>) -> anyhow::Result<()> {
-    let exporter = opentelemetry_otlp::new_exporter()
-        .tonic()
-        .with_endpoint(otlp_endpoint);
-
-    let tracer = opentelemetry_otlp::new_pipeline()
-        .tracing()
-        .with_exporter(exporter)
-        .with_trace_config(
-            opentelemetry::sdk::trace::config()
-                .with_sampler(Sampler::ParentBased(Box::new(Sampler::AlwaysOn)))
-                .with_resource(Resource::new(vec![
-                    KeyValue::new(
-                        opentelemetry_semantic_conventions::resource::SERVICE_NAME,
-                        env!("CARGO_PKG_NAME"),
-                    ),
-                    KeyValue::new(
-                        opentelemetry_semantic_conventions::resource::SERVICE_VERSION,
-                        env!("CARGO_PKG_VERSION"),
-                    ),
-                ])),
-        )
-        .install_batch(opentelemetry::runtime::Tokio)?;
+    let shutdown = drained(Arc::clone(&draining), health_reporter.clone(), grace_period);
+
+    // report ready once the pipelines are loaded, unless already draining
+    tokio::spawn(async move {
+        future::join_all(prefetched).await;
+        if !*draining.borrow() {
+            health_reporter
+                .set_serving::<TrastServer<TrastService>>()
+                .await;
+        }
+    });
 
-    let otel_layer = tracing_opentelemetry::layer().with_tracer(tracer);
+    let addr = SocketAddr::from(([0, 0, 0, 0], 8000));
 
-    tracing_subscriber::registry()
-        .with(
-            EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env_lossy(),
-        )
-        .with(fmt::layer())
-        .with(otel_layer)
-        .init();
+    info!("listening on {addr}");
 
-    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    let trace_layer = tower::ServiceBuilder::new()
+        .layer(TraceLayer::new(baggage_attributes))
+        .into_inner();
 
+    server
+        .layer(trace_layer)
+        .add_service(health_service)
+        .add_service(TrastServer::from_arc(trast))
+        .serve_with_shutdown(addr, shutdown)
+        .await?;
     Ok(())
 }
 
-#[tokio::main]
-async fn main() {
-    let _ = dotenv::dotenv();
-    let otlp_endpoint =
-        env::var("OTLP_ENDPOINT").unwrap_or_else(|_| "http://localhost:4317".to_owned());
-    let num_threads = env::var("NUM_WORKER_THREADS")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(0);
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
 
-    init_telemetry(otlp_endpoint).unwrap();
+    use super::*;
+    use crate::{config::OverflowPolicy, mailbox::Inbox};
 
-    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
-    health_reporter
-        .set_serving::<TrastServer<TrastService>>()
-        .await;
+    pub(crate) fn service() -> (TrastService, Inbox) {
+        let config = Config {
+            registry_path: None,
+            ..Config::from_env().unwrap()
+        };
+        let metrics = Metrics::new().unwrap();
+        let (actor_tx, inbox) = mailbox::mailbox(
+            config.model.clone(),
+            1,
+            OverflowPolicy::RejectNew,
+            Arc::clone(&metrics),
+        );
+        let (jobs, _) = Jobs::new(config.job_retention);
+        let service = TrastService {
+            actor_tx,
+            model: config.model.clone(),
+            quotas: Quotas::new(&config),
+            metrics,
+            cache: ResponseCache::new(0),
+            registry: Registry::open(&config),
+            canary: None,
+            languages: None,
+            shadow: None,
+            audit: None,
+            request_log_sample_rate: 0.,
+            max_input_chars: config.max_input_chars,
+            draining: Arc::new(watch::channel(false).0),
+            drain_grace_period: Duration::ZERO,
+            jobs,
+            config: String::new(),
+        };
+        (service, inbox)
+    }
 
-    let threadpool = ThreadPoolBuilder::new()
-        .num_threads(num_threads)
-        .build()
-        .unwrap();
+    fn request() -> Request<NerInput> {
+        Request::new(NerInput {
+            sentence: "Ada Lovelace".to_owned(),
+            ..Default::default()
+        })
+    }
 
-    let trast = TrastService {
-        actor_tx: act(threadpool),
-    };
+    #[tokio::test]
+    async fn dead_actors_are_unavailable() {
+        let (service, inbox) = service();
+        drop(inbox);
 
-    let addr = "0.0.0.0:8000".parse().unwrap();
+        let status = service.ner(request()).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+    }
 
-    info!("listening on {addr}");
+    #[tokio::test]
+    async fn actors_dying_mid_request_fail_it() {
+        let (service, mut inbox) = service();
+        // take the message and drop it without replying, as a panic would
+        tokio::spawn(async move { drop(inbox.recv().await) });
 
-    let trace_layer = tower::ServiceBuilder::new()
-        .layer(TraceLayer::default())
-        .into_inner();
+        let status = service.ner(request()).await.unwrap_err();
+        assert_eq!(status.code(), Code::Internal);
+    }
 
-    Server::builder()
-        .layer(trace_layer)
-        .add_service(health_service)
-        .add_service(TrastServer::new(trast))
-        .serve(addr)
-        .await
-        .unwrap();
+    #[tokio::test]
+    async fn offsets_beyond_u32_are_out_of_range() {
+        let (service, mut inbox) = service();
+        tokio::spawn(async move {
+            let message = inbox.recv().await.unwrap().into_message();
+            let start = u32::MAX as usize + 1;
+            let prediction = Prediction {
+                entities: vec![onnx_bert::Entity {
+                    label: "PER".to_owned(),
+                    score: 1.,
+                    word: "Ada".to_owned(),
+                    start,
+                    end: start + 3,
+                    start_word: 0,
+                    end_word: 1,
+                    candidates: Vec::new(),
+                }],
+                tokens: 3,
+                truncated: false,
+                words: 2,
+                model: "ner".to_owned(),
+                batch_size: 1,
+            };
+            let _ = message.tx.send(Ok(prediction));
+        });
+
+        let status = service.ner(request()).await.unwrap_err();
+        assert_eq!(status.code(), Code::OutOfRange);
+    }
 }
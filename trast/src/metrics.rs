@@ -0,0 +1,285 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use onnx_bert::{Entity, Pipeline};
+use opentelemetry::{
+    global,
+    metrics::{Counter, Histogram, MetricsError, Unit},
+    Context, KeyValue,
+};
+
+use crate::config::OverflowPolicy;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct ModelState {
+    weights: u64,
+    tokenizer: u64,
+    replicas: u64,
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    models: Mutex<HashMap<String, ModelState>>,
+    loads: Counter<u64>,
+    unloads: Counter<u64>,
+    cold_requests: Counter<u64>,
+    client_requests: Counter<u64>,
+    client_tokens: Counter<u64>,
+    quota_rejections: Counter<u64>,
+    model_requests: Counter<u64>,
+    model_latency: Histogram<f64>,
+    model_entities: Counter<u64>,
+    dead_letters: Counter<u64>,
+    queue_overflows: Counter<u64>,
+    shadow_comparisons: Counter<u64>,
+    shadow_agreement: Histogram<f64>,
+}
+
+impl Metrics {
+    /// The instruments of the service, failing if the callback observing
+    /// memory and replicas can't be registered with the meter provider.
+    pub fn new() -> Result<Arc<Self>, MetricsError> {
+        let meter = global::meter(env!("CARGO_PKG_NAME"));
+        let metrics = Arc::new(Self {
+            models: Mutex::default(),
+            loads: meter
+                .u64_counter("pipeline.loads")
+                .with_description("Number of pipeline initializations")
+                .init(),
+            unloads: meter
+                .u64_counter("pipeline.unloads")
+                .with_description("Number of pipelines dropped after the TTL expired")
+                .init(),
+            cold_requests: meter
+                .u64_counter("requests.cold")
+                .with_description("Number of requests that had to wait for the pipeline to load")
+                .init(),
+            client_requests: meter
+                .u64_counter("client.requests")
+                .with_description("Number of requests served per client")
+                .init(),
+            client_tokens: meter
+                .u64_counter("client.tokens")
+                .with_description("Number of tokens processed per client")
+                .init(),
+            quota_rejections: meter
+                .u64_counter("quota.rejections")
+                .with_description("Number of requests rejected because of an exhausted quota")
+                .init(),
+            model_requests: meter
+                .u64_counter("model.requests")
+                .with_description("Number of requests routed to each model")
+                .init(),
+            model_latency: meter
+                .f64_histogram("model.latency")
+                .with_description("Time from enqueueing a request to receiving its prediction")
+                .with_unit(Unit::new("ms"))
+                .init(),
+            model_entities: meter
+                .u64_counter("model.entities")
+                .with_description("Number of entities returned by each model, per label")
+                .init(),
+            dead_letters: meter
+                .u64_counter("inference.dead_letters")
+                .with_description("Number of sentences whose inference failed for good")
+                .init(),
+            queue_overflows: meter
+                .u64_counter("actor.queue.overflows")
+                .with_description(
+                    "Number of requests rejected because the queue of a model was full",
+                )
+                .init(),
+            shadow_comparisons: meter
+                .u64_counter("shadow.comparisons")
+                .with_description("Number of predictions compared with the shadow model")
+                .init(),
+            shadow_agreement: meter
+                .f64_histogram("shadow.agreement")
+                .with_description("Overlap between the entities of the primary and shadow models")
+                .init(),
+        });
+
+        let rss = meter
+            .u64_observable_gauge("process.memory.rss")
+            .with_description("Resident set size of the process")
+            .with_unit(Unit::new("By"))
+            .init();
+        let weights = meter
+            .u64_observable_gauge("model.weights.size")
+            .with_description("Size of the loaded model weights")
+            .with_unit(Unit::new("By"))
+            .init();
+        let tokenizer = meter
+            .u64_observable_gauge("model.tokenizer.size")
+            .with_description("Approximate memory used by the tokenizer")
+            .with_unit(Unit::new("By"))
+            .init();
+        let replicas = meter
+            .u64_observable_gauge("pipeline.replicas")
+            .with_description("Number of loaded pipeline replicas")
+            .init();
+
+        let state = Arc::clone(&metrics);
+        meter.register_callback(move |cx| {
+            if let Some(usage) = memory_stats::memory_stats() {
+                rss.observe(cx, usage.physical_mem as u64, &[]);
+            }
+
+            for (model, state) in state.models.lock().unwrap().iter() {
+                let attributes = [KeyValue::new("model", model.clone())];
+                weights.observe(cx, state.weights, &attributes);
+                tokenizer.observe(cx, state.tokenizer, &attributes);
+                replicas.observe(cx, state.replicas, &attributes);
+            }
+        })?;
+
+        Ok(metrics)
+    }
+
+    pub fn pipeline_loaded(&self, model: &str, pipeline: &Pipeline, replicas: usize) {
+        self.loads.add(
+            &Context::current(),
+            1,
+            &[KeyValue::new("model", model.to_owned())],
+        );
+        self.set_replicas(model, pipeline, replicas);
+    }
+
+    pub fn set_replicas(&self, model: &str, pipeline: &Pipeline, replicas: usize) {
+        let replicas = replicas as u64;
+        self.models.lock().unwrap().insert(
+            model.to_owned(),
+            ModelState {
+                weights: pipeline.model_size() * replicas,
+                tokenizer: pipeline.tokenizer_size() * replicas,
+                replicas,
+            },
+        );
+    }
+
+    pub fn pipeline_unloaded(&self, model: &str) {
+        self.unloads.add(
+            &Context::current(),
+            1,
+            &[KeyValue::new("model", model.to_owned())],
+        );
+        self.models
+            .lock()
+            .unwrap()
+            .insert(model.to_owned(), ModelState::default());
+    }
+
+    /// Like [`Metrics::pipeline_unloaded`], but the tokenizer of a single
+    /// replica stays in memory.
+    pub fn weights_unloaded(&self, model: &str) {
+        self.unloads.add(
+            &Context::current(),
+            1,
+            &[KeyValue::new("model", model.to_owned())],
+        );
+        if let Some(state) = self.models.lock().unwrap().get_mut(model) {
+            state.tokenizer /= state.replicas.max(1);
+            state.weights = 0;
+            state.replicas = 0;
+        }
+    }
+
+    pub fn cold_request(&self, model: &str) {
+        self.cold_requests.add(
+            &Context::current(),
+            1,
+            &[KeyValue::new("model", model.to_owned())],
+        );
+    }
+
+    pub fn client_usage(&self, client: &str, tokens: u64) {
+        let cx = Context::current();
+        let attributes = [KeyValue::new("client", client.to_owned())];
+        self.client_requests.add(&cx, 1, &attributes);
+        self.client_tokens.add(&cx, tokens, &attributes);
+    }
+
+    pub fn quota_rejected(&self, client: &str) {
+        self.quota_rejections.add(
+            &Context::current(),
+            1,
+            &[KeyValue::new("client", client.to_owned())],
+        );
+    }
+
+    /// Record a request to the model, with the attributes taken from the
+    /// baggage of the request.
+    pub fn model_request(
+        &self,
+        model: &str,
+        latency: Duration,
+        success: bool,
+        baggage: &[KeyValue],
+    ) {
+        let cx = Context::current();
+        let mut attributes = vec![KeyValue::new("model", model.to_owned())];
+        attributes.extend_from_slice(baggage);
+        self.model_latency
+            .record(&cx, latency.as_secs_f64() * 1000., &attributes);
+        attributes.push(KeyValue::new("success", success));
+        self.model_requests.add(&cx, 1, &attributes);
+    }
+
+    /// Count the entities returned by the model per label, to watch the mix
+    /// of labels for regressions.
+    pub fn entities_returned(&self, model: &str, entities: &[Entity], baggage: &[KeyValue]) {
+        let mut labels = HashMap::<&str, u64>::new();
+        for entity in entities {
+            *labels.entry(&entity.label).or_default() += 1;
+        }
+
+        let cx = Context::current();
+        for (label, count) in labels {
+            let mut attributes = vec![
+                KeyValue::new("model", model.to_owned()),
+                KeyValue::new("label", label.to_owned()),
+            ];
+            attributes.extend_from_slice(baggage);
+            self.model_entities.add(&cx, count, &attributes);
+        }
+    }
+
+    pub fn dead_letter(&self, model: &str, reason: &'static str, sentences: usize) {
+        self.dead_letters.add(
+            &Context::current(),
+            sentences as u64,
+            &[
+                KeyValue::new("model", model.to_owned()),
+                KeyValue::new("reason", reason),
+            ],
+        );
+    }
+
+    pub fn queue_overflowed(&self, model: &str, policy: OverflowPolicy) {
+        self.queue_overflows.add(
+            &Context::current(),
+            1,
+            &[
+                KeyValue::new("model", model.to_owned()),
+                KeyValue::new("policy", policy.as_str()),
+            ],
+        );
+    }
+
+    pub fn shadow_compared(&self, model: &str, agreement: f64) {
+        let cx = Context::current();
+        self.shadow_comparisons.add(
+            &cx,
+            1,
+            &[
+                KeyValue::new("model", model.to_owned()),
+                KeyValue::new("agree", agreement >= 1.),
+            ],
+        );
+        self.shadow_agreement
+            .record(&cx, agreement, &[KeyValue::new("model", model.to_owned())]);
+    }
+}
@@ -0,0 +1,129 @@
+//! Serving requests from a NATS subject: messages carry an encoded
+//! `NerInput` and are answered with an encoded `NerOutput`. Failures are
+//! answered with an empty message with the `Grpc-Status` and `Grpc-Message`
+//! headers set.
+//!
+//! The URL is `nats://[user[:password]@]host[:port]`, or `tls://…` to
+//! require TLS. A user without a password is taken as a token.
+
+use std::{sync::Arc, time::Duration};
+
+use async_nats::{Client, ConnectOptions, HeaderMap, Message, Subject};
+use futures::StreamExt;
+use percent_encoding::percent_decode_str;
+use prost::Message as _;
+use tokio::time;
+use tonic::Status;
+use tracing::{info, info_span, warn, Instrument};
+use trast_proto::NerInput;
+use url::Url;
+
+use crate::{trace, TrastService};
+
+/// How long to wait before connecting again if the connection can't be
+/// established. Once it is, the client reconnects by itself.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Serve requests published to the subject, reconnecting whenever the
+/// connection is lost. Instances in the same queue group share the load.
+pub async fn serve(trast: Arc<TrastService>, url: String, subject: String, queue_group: String) {
+    loop {
+        match connect_and_serve(&trast, &url, &subject, &queue_group).await {
+            Ok(()) => warn!(subject, "nats subscription ended"),
+            Err(e) => warn!(?e, subject, "nats connection failed"),
+        }
+        time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// The options of the connection, with the credentials of the URL.
+fn connect_options(url: &str) -> Result<ConnectOptions, async_nats::Error> {
+    let url = Url::parse(url)?;
+    let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().into_owned();
+
+    let options = match (url.username(), url.password()) {
+        ("", None) => ConnectOptions::new(),
+        (token, None) => ConnectOptions::with_token(decode(token)),
+        (user, Some(password)) => {
+            ConnectOptions::with_user_and_password(decode(user), decode(password))
+        }
+    };
+    Ok(options.name(env!("CARGO_PKG_NAME")))
+}
+
+async fn connect_and_serve(
+    trast: &Arc<TrastService>,
+    url: &str,
+    subject: &str,
+    queue_group: &str,
+) -> Result<(), async_nats::Error> {
+    let client = connect_options(url)?.connect(url).await?;
+    let mut messages = client
+        .queue_subscribe(subject.to_owned(), queue_group.to_owned())
+        .await?;
+    info!(subject, queue_group, "subscribed to nats subject");
+
+    while let Some(msg) = messages.next().await {
+        let Some(reply_to) = msg.reply.clone() else {
+            continue;
+        };
+        let trast = Arc::clone(trast);
+        let client = client.clone();
+        let request_id = header(&msg, "x-request-id")
+            .map(ToOwned::to_owned)
+            .unwrap_or_else(trace::generate_request_id);
+        let span = info_span!("nats", subject, request_id);
+
+        tokio::spawn(
+            async move {
+                let client_id = header(&msg, "x-client-id").unwrap_or("nats").to_owned();
+                let result = match NerInput::decode(msg.payload.as_ref()) {
+                    Ok(input) => trast.predict(input, &client_id, &request_id).await,
+                    Err(e) => Err(Status::invalid_argument(e.to_string())),
+                };
+                if let Err(e) = reply(&client, reply_to, result).await {
+                    warn!(?e, "failed to reply to nats request");
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    Ok(())
+}
+
+/// The value of the header of the message, whatever the case of its name.
+fn header<'a>(msg: &'a Message, key: &str) -> Option<&'a str> {
+    msg.headers
+        .as_ref()?
+        .iter()
+        .find(|(name, _)| AsRef::<str>::as_ref(name).eq_ignore_ascii_case(key))
+        .and_then(|(_, values)| values.first())
+        .map(|value| value.as_str())
+}
+
+async fn reply(
+    client: &Client,
+    subject: Subject,
+    result: Result<trast_proto::NerOutput, Status>,
+) -> Result<(), async_nats::Error> {
+    match result {
+        Ok(output) => {
+            client
+                .publish(subject, output.encode_to_vec().into())
+                .await?
+        }
+        Err(status) => {
+            let mut headers = HeaderMap::new();
+            headers.insert("Grpc-Status", (status.code() as i32).to_string().as_str());
+            headers.insert(
+                "Grpc-Message",
+                status.message().replace(['\r', '\n'], " ").as_str(),
+            );
+            client
+                .publish_with_headers(subject, headers, Vec::new().into())
+                .await?
+        }
+    }
+    Ok(())
+}
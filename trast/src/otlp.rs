@@ -0,0 +1,77 @@
+//! Exporting spans as OTLP over HTTP with protobuf bodies, for collectors
+//! that can't be reached over gRPC. The version of `opentelemetry-otlp` in
+//! use only implements this on top of clients we don't otherwise depend on.
+
+use std::collections::HashMap;
+
+use futures::future::BoxFuture;
+use opentelemetry::{
+    sdk::export::trace::{ExportResult, SpanData, SpanExporter},
+    trace::TraceError,
+};
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use prost::Message;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+
+/// Path that spans are posted to, relative to the endpoint of the collector.
+const TRACES_PATH: &str = "/v1/traces";
+
+#[derive(Debug)]
+pub struct HttpExporter {
+    client: reqwest::Client,
+    url: String,
+    headers: HeaderMap,
+}
+
+impl HttpExporter {
+    /// Post spans to the collector at the endpoint, such as
+    /// `http://localhost:4318`, with the headers.
+    pub fn new(endpoint: &str, headers: &HashMap<String, String>) -> anyhow::Result<Self> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let url = if endpoint.ends_with(TRACES_PATH) {
+            endpoint.to_owned()
+        } else {
+            format!("{endpoint}{TRACES_PATH}")
+        };
+
+        let mut header_map = HeaderMap::new();
+        header_map.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-protobuf"),
+        );
+        for (name, value) in headers {
+            header_map.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            url,
+            headers: header_map,
+        })
+    }
+}
+
+impl SpanExporter for HttpExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let request = ExportTraceServiceRequest {
+            resource_spans: batch.into_iter().map(Into::into).collect(),
+        };
+        let request = self
+            .client
+            .post(&self.url)
+            .headers(self.headers.clone())
+            .body(request.encode_to_vec());
+
+        Box::pin(async move {
+            request
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .map_err(|e| TraceError::Other(Box::new(e)))?;
+            Ok(())
+        })
+    }
+}
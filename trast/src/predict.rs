@@ -0,0 +1,48 @@
+use std::io::{self, Write};
+
+use crate::{cli::PredictArgs, registry::Registry};
+
+/// Print the entities found in the sentences, as a table, as JSON or as the
+/// tags of their words. This blocks.
+pub fn run(registry: &Registry, model: &str, args: PredictArgs) -> anyhow::Result<()> {
+    let pipeline = registry.load(model, None)?;
+    let mut stdout = io::stdout().lock();
+
+    if args.tags {
+        // a word and its tag per line, and a blank line after every sentence
+        for sentence in &args.sentences {
+            for word in pipeline.predict_tags(sentence)? {
+                writeln!(stdout, "{}\t{}", word.word, word.tag)?;
+            }
+            writeln!(stdout)?;
+        }
+        return Ok(());
+    }
+
+    let entities = pipeline.predict_batch(&args.sentences)?;
+    if args.json {
+        for entities in entities {
+            writeln!(stdout, "{}", serde_json::to_string(&entities)?)?;
+        }
+        return Ok(());
+    }
+
+    for (sentence, entities) in args.sentences.iter().zip(entities) {
+        writeln!(stdout, "{sentence}")?;
+        if entities.is_empty() {
+            writeln!(stdout, "  (no entities)")?;
+            continue;
+        }
+
+        let width = entities.iter().map(|e| e.label.len()).max().unwrap_or(0);
+        for entity in entities {
+            writeln!(
+                stdout,
+                "  {:width$}  {:.3}  {:>4}..{:<4}  {}",
+                entity.label, entity.score, entity.start, entity.end, entity.word
+            )?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,128 @@
+use std::collections::{HashMap, VecDeque};
+
+/// A queue that dequeues round-robin across clients, so that a single client
+/// with many queued items can't starve the others.
+#[derive(Debug)]
+pub struct FairQueue<T> {
+    queues: HashMap<String, VecDeque<T>>,
+    /// Clients with queued items, in the order they will be served.
+    order: VecDeque<String>,
+    len: usize,
+}
+
+impl<T> Default for FairQueue<T> {
+    fn default() -> Self {
+        Self {
+            queues: HashMap::new(),
+            order: VecDeque::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> FairQueue<T> {
+    pub fn push(&mut self, client: String, item: T) {
+        let queue = self.queues.entry(client.clone()).or_default();
+        if queue.is_empty() {
+            self.order.push_back(client);
+        }
+        queue.push_back(item);
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        let client = self.order.pop_front()?;
+        let queue = self.queues.get_mut(&client)?;
+        let item = queue.pop_front();
+
+        if queue.is_empty() {
+            self.queues.remove(&client);
+        } else {
+            self.order.push_back(client);
+        }
+
+        self.len -= 1;
+        item
+    }
+
+    /// Dequeue the item with the smallest key of those first in line for
+    /// their client, such as the oldest if they're queued in order.
+    pub fn pop_min_by_key<K: Ord>(&mut self, key: impl Fn(&T) -> K) -> Option<T> {
+        let client = self
+            .queues
+            .iter()
+            .filter_map(|(client, queue)| Some((client, key(queue.front()?))))
+            .min_by(|(_, a), (_, b)| a.cmp(b))
+            .map(|(client, _)| client.clone())?;
+        let queue = self.queues.get_mut(&client)?;
+        let item = queue.pop_front();
+
+        if queue.is_empty() {
+            self.queues.remove(&client);
+            self.order.retain(|c| *c != client);
+        }
+
+        self.len -= 1;
+        item
+    }
+
+    /// Dequeue up to `n` items.
+    pub fn take(&mut self, n: usize) -> Vec<T> {
+        std::iter::from_fn(|| self.pop()).take(n).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(items: &[(&str, u32)]) -> FairQueue<u32> {
+        let mut queue = FairQueue::default();
+        for &(client, item) in items {
+            queue.push(client.to_owned(), item);
+        }
+        queue
+    }
+
+    #[test]
+    fn clients_are_served_round_robin() {
+        let mut queue = queue(&[("a", 1), ("a", 2), ("a", 3), ("b", 4), ("c", 5), ("b", 6)]);
+        assert_eq!(queue.take(10), [1, 4, 5, 2, 6, 3]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn client_rejoins_at_the_back() {
+        let mut queue = queue(&[("a", 1), ("b", 2)]);
+        assert_eq!(queue.pop(), Some(1));
+        queue.push("a".to_owned(), 3);
+        queue.push("c".to_owned(), 4);
+        assert_eq!(queue.take(10), [2, 3, 4]);
+    }
+
+    #[test]
+    fn take_stops_at_n() {
+        let mut queue = queue(&[("a", 1), ("b", 2), ("a", 3)]);
+        assert_eq!(queue.take(2), [1, 2]);
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn pop_min_by_key_looks_at_the_front_of_every_client() {
+        let mut queue = queue(&[("a", 5), ("a", 1), ("b", 3), ("c", 4)]);
+        // the 1 of a is behind its 5
+        assert_eq!(queue.pop_min_by_key(|&item| item), Some(3));
+        assert_eq!(queue.pop_min_by_key(|&item| item), Some(4));
+        assert_eq!(queue.len(), 2);
+        // b and c are gone from the round-robin order too
+        assert_eq!(queue.take(10), [5, 1]);
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{config::Config, Error, Result};
+
+#[derive(Debug)]
+struct Usage {
+    window_start: Instant,
+    requests: u64,
+    tokens: u64,
+}
+
+#[derive(Debug)]
+struct Clients {
+    usage: HashMap<String, Usage>,
+    /// When clients whose window has expired were last forgotten.
+    pruned: Instant,
+}
+
+/// Per-client request and token quotas over a fixed window.
+#[derive(Debug)]
+pub struct Quotas {
+    max_requests: Option<u64>,
+    max_tokens: Option<u64>,
+    window: Duration,
+    clients: Mutex<Clients>,
+}
+
+impl Quotas {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            max_requests: config.quota_requests,
+            max_tokens: config.quota_tokens,
+            window: config.quota_window,
+            clients: Mutex::new(Clients {
+                usage: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.max_requests.is_some() || self.max_tokens.is_some()
+    }
+
+    /// Count a request against the quota of the client, failing with
+    /// [`Error::QuotaExceeded`] if the quota is used up.
+    pub fn acquire(&self, client: &str) -> Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        let mut clients = self.clients.lock().unwrap();
+        let now = Instant::now();
+
+        // clients that have gone quiet would otherwise be kept forever, and
+        // a client is as good as new once its window has expired
+        if now.duration_since(clients.pruned) >= self.window {
+            let window = self.window;
+            clients
+                .usage
+                .retain(|_, usage| now.duration_since(usage.window_start) < window);
+            clients.pruned = now;
+        }
+
+        let usage = clients.usage.entry(client.to_owned()).or_insert(Usage {
+            window_start: now,
+            requests: 0,
+            tokens: 0,
+        });
+
+        if now.duration_since(usage.window_start) >= self.window {
+            *usage = Usage {
+                window_start: now,
+                requests: 0,
+                tokens: 0,
+            };
+        }
+
+        let resets_in = (usage.window_start + self.window).duration_since(now);
+
+        if let Some(max) = self.max_requests.filter(|&max| usage.requests >= max) {
+            return Err(Error::QuotaExceeded {
+                kind: "request",
+                max,
+                resets_in,
+            });
+        }
+
+        if let Some(max) = self.max_tokens.filter(|&max| usage.tokens >= max) {
+            return Err(Error::QuotaExceeded {
+                kind: "token",
+                max,
+                resets_in,
+            });
+        }
+
+        usage.requests += 1;
+        Ok(())
+    }
+
+    /// Charge the tokens processed for a request to the client.
+    pub fn record_tokens(&self, client: &str, tokens: u64) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        if let Some(usage) = self.clients.lock().unwrap().usage.get_mut(client) {
+            usage.tokens += tokens;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quotas(max_requests: Option<u64>, max_tokens: Option<u64>, window: Duration) -> Quotas {
+        Quotas {
+            max_requests,
+            max_tokens,
+            window,
+            clients: Mutex::new(Clients {
+                usage: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    #[test]
+    fn requests_beyond_the_quota_are_rejected() {
+        let quotas = quotas(Some(2), None, Duration::from_secs(60));
+        assert!(quotas.acquire("a").is_ok());
+        assert!(quotas.acquire("a").is_ok());
+        assert!(matches!(
+            quotas.acquire("a"),
+            Err(Error::QuotaExceeded {
+                kind: "request",
+                max: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn clients_have_quotas_of_their_own() {
+        let quotas = quotas(Some(1), None, Duration::from_secs(60));
+        assert!(quotas.acquire("a").is_ok());
+        assert!(quotas.acquire("a").is_err());
+        assert!(quotas.acquire("b").is_ok());
+    }
+
+    #[test]
+    fn tokens_are_charged_to_later_requests() {
+        let quotas = quotas(None, Some(10), Duration::from_secs(60));
+        assert!(quotas.acquire("a").is_ok());
+        quotas.record_tokens("a", 10);
+        assert!(matches!(
+            quotas.acquire("a"),
+            Err(Error::QuotaExceeded { kind: "token", .. })
+        ));
+    }
+
+    #[test]
+    fn quota_resets_with_the_window() {
+        let quotas = quotas(Some(1), Some(1), Duration::ZERO);
+        for _ in 0..3 {
+            assert!(quotas.acquire("a").is_ok());
+            quotas.record_tokens("a", 1);
+        }
+    }
+
+    #[test]
+    fn expired_clients_are_forgotten() {
+        let quotas = quotas(Some(1), None, Duration::ZERO);
+        quotas.acquire("a").unwrap();
+        quotas.acquire("b").unwrap();
+        let clients = quotas.clients.lock().unwrap();
+        assert_eq!(clients.usage.keys().collect::<Vec<_>>(), ["b"]);
+    }
+
+    #[test]
+    fn disabled_quotas_count_nothing() {
+        let quotas = quotas(None, None, Duration::from_secs(60));
+        for _ in 0..3 {
+            assert!(quotas.acquire("a").is_ok());
+        }
+        quotas.record_tokens("a", 100);
+        assert!(quotas.clients.lock().unwrap().usage.is_empty());
+    }
+}
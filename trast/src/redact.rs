@@ -0,0 +1,101 @@
+use onnx_bert::Entity;
+
+/// Mask the entities found in the text with their labels, e.g. turning
+/// "Kalle bor i Ankeborg" into "[PER] bor i [LOC]", so that it can be logged.
+pub fn redact(text: &str, entities: &[Entity]) -> String {
+    let mut spans = entities
+        .iter()
+        .filter(|e| text.is_char_boundary(e.start) && text.is_char_boundary(e.end))
+        .collect::<Vec<_>>();
+    spans.sort_by_key(|e| e.start);
+
+    let mut redacted = String::with_capacity(text.len());
+    let mut pos = 0;
+    for entity in spans {
+        // overlapping entities are covered by the previous mask, which is
+        // extended over the rest of them
+        if entity.start < pos {
+            pos = pos.max(entity.end);
+            continue;
+        }
+        redacted.push_str(&text[pos..entity.start]);
+        redacted.push('[');
+        redacted.push_str(&entity.label);
+        redacted.push(']');
+        pos = entity.end;
+    }
+    redacted.push_str(&text[pos..]);
+
+    redacted
+}
+
+/// The entities with their words masked by [`redact`].
+pub fn redact_entities(entities: &[Entity]) -> Vec<Entity> {
+    entities
+        .iter()
+        .map(|e| Entity {
+            word: format!("[{}]", e.label),
+            candidates: vec![],
+            ..e.clone()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use onnx_bert::Candidate;
+
+    use super::*;
+
+    fn entity(label: &str, start: usize, end: usize) -> Entity {
+        Entity {
+            label: label.to_owned(),
+            score: 1.,
+            word: String::new(),
+            start,
+            end,
+            start_word: 0,
+            end_word: 0,
+            candidates: vec![],
+        }
+    }
+
+    #[test]
+    fn entities_are_masked_by_their_labels() {
+        let text = "Kalle bor i Ankeborg";
+        let entities = [entity("LOC", 12, 20), entity("PER", 0, 5)];
+        assert_eq!(redact(text, &entities), "[PER] bor i [LOC]");
+    }
+
+    #[test]
+    fn overlapping_entities_are_masked_once() {
+        let text = "Ada Lovelace wrote";
+        let entities = [entity("PER", 0, 3), entity("PER", 0, 12)];
+        assert_eq!(redact(text, &entities), "[PER] wrote");
+
+        let entities = [entity("PER", 0, 8), entity("ORG", 4, 12)];
+        assert_eq!(redact(text, &entities), "[PER] wrote");
+    }
+
+    #[test]
+    fn entities_off_the_text_are_skipped() {
+        let text = "Malmö är fin";
+        // inside "ö", and past the end
+        let entities = [entity("LOC", 0, 5), entity("LOC", 10, 40)];
+        assert_eq!(redact(text, &entities), text);
+    }
+
+    #[test]
+    fn words_and_candidates_of_entities_are_masked() {
+        let mut ada = entity("PER", 0, 3);
+        ada.word = "Ada".to_owned();
+        ada.candidates = vec![Candidate {
+            id: "Q7259".to_owned(),
+            score: 1.,
+        }];
+        let redacted = redact_entities(&[ada]);
+        assert_eq!(redacted[0].word, "[PER]");
+        assert!(redacted[0].candidates.is_empty());
+        assert_eq!((redacted[0].start, redacted[0].end), (0, 3));
+    }
+}
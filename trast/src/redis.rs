@@ -0,0 +1,256 @@
+//! Worker mode for offline NER: jobs are popped from a Redis list and their
+//! results are written back keyed by job id, so that any number of instances
+//! can share the work. Jobs look like `{"id": "…", "sentence": "…"}`, with
+//! an optional `"client"` that quotas are counted against, and
+//! results are the `NerOutput` as JSON, or `{"error": {"code": …,
+//! "message": "…"}}` with the gRPC status code if the job failed.
+//!
+//! A job is moved to a processing list while it's worked on, and removed
+//! from it once its result is written. The worker holds a lease on the job,
+//! which it renews until then; jobs whose lease has run out are pushed back
+//! onto the queue by [`reap`], so that the jobs of a worker that dies aren't
+//! lost. Jobs that fail for transient reasons are returned to the queue
+//! as well, instead of a result being written.
+
+use std::{collections::HashSet, io, sync::Arc, time::Duration};
+
+use redis::{aio::Connection, AsyncCommands, Client, Direction, RedisResult};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use tonic::Code;
+use tracing::{info, info_span, warn, Instrument};
+use trast_proto::{NerInput, NerOutput, Priority};
+
+use crate::TrastService;
+
+/// How long to wait before reconnecting to the server.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How long to wait after returning a job to the queue, so that a worker
+/// doesn't spin on jobs that can't be run at the moment.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Puts a job back onto the queue, unless its lease has been renewed or
+/// another instance already requeued it.
+const REQUEUE: &str = r#"
+if redis.call("EXISTS", KEYS[3]) == 0 and redis.call("LREM", KEYS[1], 1, ARGV[1]) == 1 then
+    redis.call("LPUSH", KEYS[2], ARGV[1])
+    return 1
+end
+return 0
+"#;
+
+/// Returns a job that its worker gave up on to the back of the queue.
+const RELEASE: &str = r#"
+redis.call("DEL", KEYS[3])
+if redis.call("LREM", KEYS[1], 1, ARGV[1]) == 1 then
+    redis.call("RPUSH", KEYS[2], ARGV[1])
+end
+"#;
+
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    /// `redis://` or, for TLS, `rediss://`, with the user, password and
+    /// database if any.
+    pub url: String,
+    /// List that jobs are pushed to.
+    pub queue: String,
+    /// List that jobs are kept in while they're processed.
+    pub processing: String,
+    /// Prepended to the job id to form the key of the result.
+    pub result_prefix: String,
+    pub result_ttl: Duration,
+    /// How long a job may go without its worker renewing the lease on it,
+    /// before it's assumed to be lost and put back onto the queue.
+    pub visibility_timeout: Duration,
+}
+
+impl WorkerConfig {
+    /// Key of the lease on a job in the processing list.
+    fn lease(&self, job: &[u8]) -> String {
+        let id = serde_json::from_slice::<Job>(job)
+            .map(|job| job.id)
+            .unwrap_or_else(|_| String::from_utf8_lossy(job).into_owned());
+        format!("{}:lease:{id}", self.processing)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Job {
+    id: String,
+    sentence: String,
+    /// Identity of the client that the job is run on behalf of, or else
+    /// the queue.
+    client: Option<String>,
+}
+
+/// What is written back for a job: the `NerOutput` as is, or the status it
+/// failed with.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum JobResult<'a> {
+    Output(&'a NerOutput),
+    Error { error: JobError<'a> },
+}
+
+#[derive(Debug, Serialize)]
+struct JobError<'a> {
+    code: i32,
+    message: &'a str,
+}
+
+/// Process jobs from the queue one at a time, reconnecting whenever the
+/// connection is lost.
+pub async fn work(trast: Arc<TrastService>, config: WorkerConfig) {
+    loop {
+        match process_jobs(&trast, &config).await {
+            Ok(()) => {}
+            Err(e) => warn!(?e, url = config.url, "redis worker failed"),
+        }
+        time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn process_jobs(trast: &TrastService, config: &WorkerConfig) -> RedisResult<()> {
+    let mut conn = Client::open(config.url.as_str())?
+        .get_async_connection()
+        .await?;
+    info!(queue = config.queue, "waiting for redis jobs");
+
+    loop {
+        // time out now and then, so that a dead connection is noticed
+        let moved: Option<Vec<u8>> = conn
+            .blmove(
+                &config.queue,
+                &config.processing,
+                Direction::Left,
+                Direction::Right,
+                5.,
+            )
+            .await?;
+        let Some(bytes) = moved else {
+            continue;
+        };
+
+        let lease = config.lease(&bytes);
+        let lease_ms = config.visibility_timeout.as_millis() as usize;
+        conn.pset_ex::<_, _, ()>(&lease, 1, lease_ms).await?;
+
+        let job: Job = match serde_json::from_slice(&bytes) {
+            Ok(job) => job,
+            Err(e) => {
+                warn!(?e, queue = config.queue, "dropping malformed job");
+                done(&mut conn, config, &bytes).await?;
+                continue;
+            }
+        };
+
+        let span = info_span!("redis", job = job.id);
+        let input = NerInput {
+            sentence: job.sentence,
+            priority: Priority::Batch as i32,
+            ..Default::default()
+        };
+        let predict = trast
+            .predict(input, job.client.as_ref().unwrap_or(&config.queue), &job.id)
+            .instrument(span);
+        tokio::pin!(predict);
+        let mut renew = time::interval(config.visibility_timeout / 3);
+        renew.tick().await;
+        let output = loop {
+            tokio::select! {
+                output = &mut predict => break output,
+                _ = renew.tick() => conn.pexpire::<_, ()>(&lease, lease_ms).await?,
+            }
+        };
+
+        if let Err(status) = &output {
+            if matches!(status.code(), Code::Unavailable | Code::ResourceExhausted) {
+                info!(job = job.id, code = ?status.code(), "returning job to the queue");
+                redis::cmd("EVAL")
+                    .arg(RELEASE)
+                    .arg(3)
+                    .arg(&config.processing)
+                    .arg(&config.queue)
+                    .arg(&lease)
+                    .arg(&bytes)
+                    .query_async::<_, ()>(&mut conn)
+                    .await?;
+                time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        }
+
+        let result = match &output {
+            Ok(output) => JobResult::Output(output),
+            Err(status) => JobResult::Error {
+                error: JobError {
+                    code: status.code() as i32,
+                    message: status.message(),
+                },
+            },
+        };
+
+        let key = format!("{}{}", config.result_prefix, job.id);
+        let ttl = config.result_ttl.as_secs().max(1) as usize;
+        let result = serde_json::to_string(&result).map_err(io::Error::from)?;
+        conn.set_ex::<_, _, ()>(key, result, ttl).await?;
+        done(&mut conn, config, &bytes).await?;
+    }
+}
+
+/// Remove the job from the processing list, now that it's been dealt with.
+async fn done(conn: &mut Connection, config: &WorkerConfig, job: &[u8]) -> RedisResult<()> {
+    conn.lrem::<_, _, ()>(&config.processing, 1, job).await?;
+    conn.del(config.lease(job)).await
+}
+
+/// Push jobs whose lease has run out back onto the queue, checking once per
+/// visibility timeout. A job is only requeued once its lease has been found
+/// missing twice in a row, as a worker takes it before it sets the lease.
+pub async fn reap(config: WorkerConfig) {
+    loop {
+        match reap_jobs(&config).await {
+            Ok(()) => {}
+            Err(e) => warn!(?e, url = config.url, "redis reaper failed"),
+        }
+        time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn reap_jobs(config: &WorkerConfig) -> RedisResult<()> {
+    let mut conn = Client::open(config.url.as_str())?
+        .get_async_connection()
+        .await?;
+    let mut expired = HashSet::new();
+
+    loop {
+        time::sleep(config.visibility_timeout).await;
+
+        let jobs: Vec<Vec<u8>> = conn.lrange(&config.processing, 0, -1).await?;
+        let mut still_expired = HashSet::new();
+        for job in jobs {
+            let lease = config.lease(&job);
+            if conn.exists(&lease).await? {
+                continue;
+            }
+            if !expired.contains(&job) {
+                still_expired.insert(job);
+                continue;
+            }
+            let requeued: bool = redis::cmd("EVAL")
+                .arg(REQUEUE)
+                .arg(3)
+                .arg(&config.processing)
+                .arg(&config.queue)
+                .arg(&lease)
+                .arg(&job)
+                .query_async(&mut conn)
+                .await?;
+            if requeued {
+                warn!(lease, queue = config.queue, "requeued lost job");
+            }
+        }
+        expired = still_expired;
+    }
+}
@@ -0,0 +1,572 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use onnx_bert::{
+    AggregationStrategy, EncodingCache, KnowledgeBase, ModelCard, Pipeline, PretrainedFiles,
+    Resident, ScoreAggregation, TokenizerOptions, WeightPrecision, WordSegmentation,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Notify, task::spawn_blocking, time};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{config::Config, Result};
+
+/// Sentence run through a new revision before it's swapped in.
+pub const WARMUP_SENTENCE: &str = "Kalle Anka bor i Ankeborg.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelState {
+    /// Known, but never downloaded.
+    Available,
+    Downloading,
+    /// The files are on disk, but the model isn't loaded.
+    Downloaded,
+    Loading,
+    Loaded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelEntry {
+    pub id: String,
+    /// Where the model is downloaded from.
+    pub source: String,
+    /// Branch or tag that is tracked.
+    pub revision: String,
+    /// Commit that the downloaded files belong to.
+    #[serde(default)]
+    pub sha: Option<String>,
+    /// Local path of the downloaded weights.
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    files: Option<PretrainedFiles>,
+    pub state: ModelState,
+    pub loads: u64,
+    pub requests: u64,
+    /// Requests that the model failed.
+    #[serde(default)]
+    pub errors: u64,
+    /// Total time spent waiting for the model to reply, since the server
+    /// started.
+    #[serde(skip)]
+    pub latency: Duration,
+    /// Replies included in [`ModelEntry::latency`].
+    #[serde(skip)]
+    pub replies: u64,
+    /// Unix timestamp of the last request served by the model.
+    pub last_used: Option<u64>,
+    /// Bytes used by the loaded replicas of the model.
+    #[serde(skip)]
+    pub memory: u64,
+    /// What the model card declares, as of when the model was last loaded.
+    #[serde(default)]
+    pub card: Option<ModelCard>,
+}
+
+impl ModelEntry {
+    fn new(id: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            source: "huggingface".to_owned(),
+            revision: "main".to_owned(),
+            sha: None,
+            path: None,
+            files: None,
+            state: ModelState::Available,
+            loads: 0,
+            requests: 0,
+            errors: 0,
+            latency: Duration::ZERO,
+            replies: 0,
+            last_used: None,
+            memory: 0,
+            card: None,
+        }
+    }
+}
+
+/// A new revision of a model, which becomes the revision of the entry once
+/// it's swapped in.
+struct Staged {
+    pipeline: Arc<Pipeline>,
+    sha: String,
+    files: PretrainedFiles,
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Keeps track of the models known to the server and drives their download
+/// and loading. The state is persisted to disk, if a path is configured, so
+/// that it survives restarts.
+pub struct Registry {
+    /// Snapshots of the state to persist, if a path is configured.
+    writer: Option<mpsc::Sender<Vec<u8>>>,
+    models: Mutex<BTreeMap<String, ModelEntry>>,
+    /// New revisions that have been loaded and warmed up, waiting to be
+    /// swapped in.
+    staged: Mutex<HashMap<String, Staged>>,
+    memory_budget: Option<u64>,
+    pinned: HashSet<String>,
+    tokenizer_options: TokenizerOptions,
+    aggregation_strategy: AggregationStrategy,
+    score_aggregation: ScoreAggregation,
+    weight_precision: WeightPrecision,
+    snap_to_words: bool,
+    label_thresholds: HashMap<String, f32>,
+    min_score: f32,
+    chunking: bool,
+    word_segmentation: WordSegmentation,
+    /// Shared by all pipelines, so that a sentence predicted by several
+    /// models is only tokenized once per tokenizer.
+    encoding_cache: Option<Arc<EncodingCache>>,
+    /// Shared by all pipelines, which link their entities to it.
+    knowledge_base: Option<Arc<KnowledgeBase>>,
+    /// Notified when a model should be unloaded to free up memory.
+    evictions: Mutex<HashMap<String, Arc<Notify>>>,
+}
+
+impl Registry {
+    pub fn open(config: &Config) -> Arc<Self> {
+        let path = config.registry_path.clone();
+        let mut models: BTreeMap<String, ModelEntry> = path
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|bytes| match serde_json::from_slice(&bytes) {
+                Ok(models) => Some(models),
+                Err(e) => {
+                    warn!(?e, "ignoring corrupt model registry");
+                    None
+                }
+            })
+            .unwrap_or_default();
+
+        // nothing is loaded in a fresh process
+        for entry in models.values_mut() {
+            entry.state = match entry.path {
+                Some(_) => ModelState::Downloaded,
+                None => ModelState::Available,
+            };
+        }
+
+        Arc::new(Self {
+            writer: path.map(spawn_writer),
+            models: Mutex::new(models),
+            staged: Mutex::default(),
+            memory_budget: config.memory_budget,
+            pinned: config.pinned_models.iter().cloned().collect(),
+            tokenizer_options: TokenizerOptions {
+                lowercase: config.lowercase,
+                strip_accents: config.strip_accents,
+                unicode_normalization: config.unicode_normalization,
+                max_length: config.max_sequence_length,
+                stride: config.stride,
+                padding: config.padding,
+                ..Default::default()
+            },
+            aggregation_strategy: config.aggregation_strategy,
+            score_aggregation: config.score_aggregation,
+            weight_precision: config.weight_precision,
+            snap_to_words: config.snap_to_words,
+            label_thresholds: config.label_thresholds.clone(),
+            min_score: config.min_score,
+            chunking: config.chunk_long_inputs,
+            word_segmentation: config.word_segmentation,
+            encoding_cache: NonZeroUsize::new(config.tokenization_cache_size)
+                .map(EncodingCache::new),
+            knowledge_base: config.knowledge_base.as_ref().and_then(
+                |path| match KnowledgeBase::open(path) {
+                    Ok(knowledge_base) => {
+                        info!(aliases = knowledge_base.len(), "loaded knowledge base");
+                        Some(Arc::new(
+                            knowledge_base.with_max_candidates(config.max_candidates),
+                        ))
+                    }
+                    Err(e) => {
+                        error!(
+                            ?e,
+                            ?path,
+                            "failed to load knowledge base, not linking entities"
+                        );
+                        None
+                    }
+                },
+            ),
+            evictions: Mutex::default(),
+        })
+    }
+
+    pub fn register(&self, id: &str) {
+        let mut models = self.models.lock().unwrap();
+        if !models.contains_key(id) {
+            models.insert(id.to_owned(), ModelEntry::new(id));
+            self.persist(&models);
+        }
+    }
+
+    pub fn list(&self) -> Vec<ModelEntry> {
+        self.models.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<ModelEntry> {
+        self.models.lock().unwrap().get(id).cloned()
+    }
+
+    /// Commit of the model that is downloaded, if any.
+    pub fn sha(&self, id: &str) -> Option<String> {
+        self.models.lock().unwrap().get(id)?.sha.clone()
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut ModelEntry)) -> ModelEntry {
+        let mut models = self.models.lock().unwrap();
+        let entry = models
+            .entry(id.to_owned())
+            .or_insert_with(|| ModelEntry::new(id));
+        f(entry);
+        let entry = entry.clone();
+        self.persist(&models);
+        entry
+    }
+
+    fn set_state(&self, id: &str, state: ModelState) {
+        self.update(id, |entry| entry.state = state);
+    }
+
+    /// Download the model unless its files are already known, then load it,
+    /// only reading the weights if the rest of the pipeline is still
+    /// `resident`. This blocks and should be run off the async runtime.
+    #[instrument(skip(self, resident))]
+    pub fn load(&self, id: &str, resident: Option<Resident>) -> Result<Pipeline> {
+        let entry = self.update(id, |_| {});
+
+        let result = self.files(entry).and_then(|files| {
+            self.set_state(id, ModelState::Loading);
+            let pipeline = match resident {
+                Some(resident) => resident.load_weights(&files.model)?,
+                None => self.pipeline(&files)?,
+            };
+            Ok(pipeline)
+        });
+
+        match &result {
+            Ok(pipeline) => {
+                self.update(id, |entry| {
+                    entry.state = ModelState::Loaded;
+                    entry.loads += 1;
+                    entry.card = Some(pipeline.model_card().clone());
+                });
+            }
+            Err(_) => self.set_state(id, ModelState::Failed),
+        }
+
+        result
+    }
+
+    fn pipeline(&self, files: &PretrainedFiles) -> Result<Pipeline> {
+        let pipeline = Resident::from_pretrained_files(files, &self.tokenizer_options)?
+            .with_weight_precision(self.weight_precision)
+            .load_weights(&files.model)?
+            .with_aggregation_strategy(self.aggregation_strategy)
+            .with_score_aggregation(self.score_aggregation)
+            .with_snap_to_words(self.snap_to_words)
+            .with_label_thresholds(self.label_thresholds.clone())
+            .with_min_score(self.min_score)
+            .with_chunking(self.chunking)
+            .with_word_segmentation(self.word_segmentation);
+        let pipeline = match &self.encoding_cache {
+            Some(cache) => pipeline.with_encoding_cache(Arc::clone(cache))?,
+            None => pipeline,
+        };
+        Ok(match &self.knowledge_base {
+            Some(knowledge_base) => pipeline.with_knowledge_base(Arc::clone(knowledge_base)),
+            None => pipeline,
+        })
+    }
+
+    fn files(&self, entry: ModelEntry) -> Result<PretrainedFiles> {
+        if let Some(files) = entry.files.filter(|files| files.model.exists()) {
+            return Ok(files);
+        }
+
+        self.set_state(&entry.id, ModelState::Downloading);
+        let sha = onnx_bert::resolve_revision(&entry.id, &entry.revision)?;
+        let files = onnx_bert::download_pretrained(&entry.id, &sha)?;
+        self.update(&entry.id, |entry| {
+            entry.sha = Some(sha);
+            entry.path = Some(files.model.clone());
+            entry.files = Some(files.clone());
+            entry.state = ModelState::Downloaded;
+        });
+
+        Ok(files)
+    }
+
+    /// Check whether the tracked revision of the model has moved upstream,
+    /// and if so download, verify and stage the new version. Returns the new
+    /// commit, if any. This blocks and should be run off the async runtime.
+    #[instrument(skip(self))]
+    pub fn refresh(&self, id: &str) -> Result<Option<String>> {
+        let entry = self.update(id, |_| {});
+        let sha = onnx_bert::resolve_revision(id, &entry.revision)?;
+        let staged = self
+            .staged
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|staged| staged.sha.clone());
+        if entry.sha.as_ref() == Some(&sha) || staged.as_ref() == Some(&sha) {
+            return Ok(None);
+        }
+
+        let files = onnx_bert::download_pretrained(id, &sha)?;
+        let pipeline = self.pipeline(&files)?;
+        pipeline.predict(WARMUP_SENTENCE)?;
+
+        self.staged.lock().unwrap().insert(
+            id.to_owned(),
+            Staged {
+                pipeline: Arc::new(pipeline),
+                sha: sha.clone(),
+                files,
+            },
+        );
+
+        Ok(Some(sha))
+    }
+
+    /// Take the new revision of the model staged by [`Registry::refresh`],
+    /// which from then on is the one that the model is loaded from.
+    pub fn take_staged(&self, id: &str) -> Option<Arc<Pipeline>> {
+        let staged = self.staged.lock().unwrap().remove(id)?;
+        let card = staged.pipeline.model_card().clone();
+        self.update(id, |entry| {
+            entry.sha = Some(staged.sha);
+            entry.path = Some(staged.files.model.clone());
+            entry.files = Some(staged.files);
+            entry.state = ModelState::Loaded;
+            entry.loads += 1;
+            entry.card = Some(card);
+        });
+        Some(staged.pipeline)
+    }
+
+    /// Forget the downloaded files of the model, after they have been
+    /// deleted, so that they're downloaded again when it's next loaded.
+    pub fn forget_download(&self, id: &str) {
+        let mut models = self.models.lock().unwrap();
+        if let Some(entry) = models.get_mut(id) {
+            entry.sha = None;
+            entry.path = None;
+            entry.files = None;
+            entry.state = ModelState::Available;
+            self.persist(&models);
+        }
+    }
+
+    pub fn unloaded(&self, id: &str) {
+        self.update(id, |entry| {
+            if entry.state == ModelState::Loaded {
+                entry.state = ModelState::Downloaded;
+            }
+        });
+    }
+
+    /// Notified when the model should be unloaded because the memory budget
+    /// is exceeded.
+    pub fn eviction(&self, id: &str) -> Arc<Notify> {
+        let mut evictions = self.evictions.lock().unwrap();
+        Arc::clone(evictions.entry(id.to_owned()).or_default())
+    }
+
+    /// Update the memory used by the model, evicting the least recently used
+    /// other models if that exceeds the memory budget.
+    pub fn set_memory(&self, id: &str, bytes: u64) {
+        let mut models = self.models.lock().unwrap();
+        let entry = models
+            .entry(id.to_owned())
+            .or_insert_with(|| ModelEntry::new(id));
+        let grew = bytes > entry.memory;
+        entry.memory = bytes;
+
+        let Some(budget) = self.memory_budget.filter(|_| grew) else {
+            return;
+        };
+
+        let mut total = models.values().map(|entry| entry.memory).sum::<u64>();
+        let mut candidates = models
+            .values()
+            .filter(|entry| entry.memory > 0 && entry.id != id && !self.pinned.contains(&entry.id))
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|entry| entry.last_used);
+
+        for entry in candidates {
+            if total <= budget {
+                break;
+            }
+            warn!(
+                model = entry.id,
+                "evicting model to stay within the memory budget"
+            );
+            total -= entry.memory;
+            self.eviction(&entry.id).notify_one();
+        }
+    }
+
+    /// Count requests served by the model. Usage is only written to disk
+    /// along with the next state change.
+    pub fn record_requests(&self, id: &str, requests: u64) {
+        if let Some(entry) = self.models.lock().unwrap().get_mut(id) {
+            entry.requests += requests;
+            entry.last_used = Some(unix_now());
+        }
+    }
+
+    /// Record a reply of the model to a request, successful or not.
+    pub fn record_reply(&self, id: &str, latency: Duration, success: bool) {
+        if let Some(entry) = self.models.lock().unwrap().get_mut(id) {
+            entry.latency += latency;
+            entry.replies += 1;
+            if !success {
+                entry.errors += 1;
+            }
+        }
+    }
+
+    /// Persist a snapshot of the state, which is written off the lock.
+    fn persist(&self, models: &BTreeMap<String, ModelEntry>) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+
+        match serde_json::to_vec_pretty(models) {
+            Ok(bytes) => {
+                if writer.send(bytes).is_err() {
+                    warn!("model registry writer is gone, not persisting");
+                }
+            }
+            Err(e) => warn!(?e, "failed to serialize model registry"),
+        }
+    }
+}
+
+/// Write the snapshots of the registry to the file on a thread of its own,
+/// so that a slow disk holds up neither the actors nor the requests. Only
+/// the latest of the snapshots waiting is written.
+fn spawn_writer(path: PathBuf) -> mpsc::Sender<Vec<u8>> {
+    let (tx, rx) = mpsc::channel::<Vec<u8>>();
+
+    thread::Builder::new()
+        .name("registry".to_owned())
+        .spawn(move || {
+            while let Ok(mut bytes) = rx.recv() {
+                bytes = rx.try_iter().last().unwrap_or(bytes);
+
+                let result = path
+                    .parent()
+                    .map_or(Ok(()), fs::create_dir_all)
+                    .and_then(|()| {
+                        // write to a temporary file first, so that a crash
+                        // can't leave a truncated registry behind
+                        let tmp = path.with_extension("tmp");
+                        fs::write(&tmp, bytes)?;
+                        fs::rename(tmp, &path)
+                    });
+                if let Err(e) = result {
+                    warn!(?e, path = %path.display(), "failed to persist model registry");
+                }
+            }
+        })
+        .unwrap();
+
+    tx
+}
+
+/// Periodically refresh the model, so that long-lived deployments track its
+/// upstream revision.
+pub async fn refresh_periodically(registry: Arc<Registry>, model: String, period: Duration) {
+    let mut interval = time::interval(period);
+    // the first tick completes immediately
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let registry = Arc::clone(&registry);
+        let id = model.clone();
+        match spawn_blocking(move || registry.refresh(&id)).await {
+            Ok(Ok(Some(sha))) => info!(model, sha, "staged new model revision"),
+            Ok(Ok(None)) => debug!(model, "model is up to date"),
+            Ok(Err(e)) => warn!(?e, model, "failed to refresh model"),
+            Err(e) => warn!(?e, model, "failed to refresh model"),
+        }
+    }
+}
+
+impl From<ModelState> for trast_proto::ModelState {
+    fn from(state: ModelState) -> Self {
+        match state {
+            ModelState::Available => Self::Available,
+            ModelState::Downloading => Self::Downloading,
+            ModelState::Downloaded => Self::Downloaded,
+            ModelState::Loading => Self::Loading,
+            ModelState::Loaded => Self::Loaded,
+            ModelState::Failed => Self::Failed,
+        }
+    }
+}
+
+impl From<ModelEntry> for trast_proto::ModelStats {
+    fn from(entry: ModelEntry) -> Self {
+        let average_latency_ms = match entry.replies {
+            0 => 0.,
+            n => entry.latency.as_secs_f64() * 1000. / n as f64,
+        };
+
+        Self {
+            id: entry.id,
+            state: trast_proto::ModelState::from(entry.state) as i32,
+            requests: entry.requests,
+            errors: entry.errors,
+            average_latency_ms,
+            last_used: entry.last_used.unwrap_or_default(),
+            memory: entry.memory,
+        }
+    }
+}
+
+impl From<ModelEntry> for trast_proto::ModelInfo {
+    fn from(entry: ModelEntry) -> Self {
+        Self {
+            id: entry.id,
+            source: entry.source,
+            revision: entry.revision,
+            path: entry
+                .path
+                .map(|path| path.display().to_string())
+                .unwrap_or_default(),
+            state: trast_proto::ModelState::from(entry.state) as i32,
+            loads: entry.loads,
+            requests: entry.requests,
+            last_used: entry.last_used.unwrap_or_default(),
+            memory: entry.memory,
+            card: entry.card.map(|card| trast_proto::ModelCard {
+                languages: card.languages,
+                license: card.license.unwrap_or_default(),
+                datasets: card.datasets,
+                metrics: card.metrics,
+            }),
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+};
+
+use onnx_bert::Entity;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{cli::ReplayArgs, registry::Registry, shadow};
+
+/// The parts of an audit record that are replayed.
+#[derive(Debug, Deserialize)]
+struct Record {
+    request_id: String,
+    /// Missing unless the audit log records the full text.
+    input: Option<String>,
+    model: String,
+    revision: Option<String>,
+    entities: Vec<Entity>,
+}
+
+/// A line of the output, for every record that the model predicts
+/// differently.
+#[derive(Debug, Serialize)]
+struct Difference<'a> {
+    request_id: &'a str,
+    /// The model that made the recorded prediction, and its commit.
+    model: &'a str,
+    revision: Option<&'a str>,
+    agreement: f64,
+    /// Recorded entities that the model no longer finds.
+    removed: Vec<&'a Entity>,
+    /// Entities that the model finds but weren't recorded.
+    added: Vec<&'a Entity>,
+}
+
+/// What a replay came to, reported on stderr once it's done.
+#[derive(Debug, Default)]
+struct Summary {
+    replayed: usize,
+    changed: usize,
+    skipped: usize,
+    /// Records that the model failed to predict.
+    failed: usize,
+    total_agreement: f64,
+}
+
+impl Summary {
+    fn mean_agreement(&self) -> f64 {
+        if self.replayed > 0 {
+            self.total_agreement / self.replayed as f64
+        } else {
+            1.
+        }
+    }
+}
+
+/// Run the inputs recorded in the audit log through the model, writing a
+/// line for every record whose entities differ from the recorded ones and
+/// a summary on stderr. Records without the text of the input are skipped,
+/// as are lines that aren't records. This blocks.
+pub fn run(registry: &Registry, model: &str, args: ReplayArgs) -> anyhow::Result<()> {
+    let pipeline = registry.load(model, None)?;
+    let lines = BufReader::new(File::open(&args.input)?);
+    let mut writer: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+
+    let summary = replay(lines, &mut writer, args.batch_size, |inputs| {
+        pipeline.predict_batch(inputs)
+    })?;
+    writer.flush()?;
+
+    let Summary {
+        replayed,
+        changed,
+        skipped,
+        failed,
+        ..
+    } = summary;
+    eprintln!(
+        "{replayed} records replayed against {model}, {changed} changed, \
+         {failed} failed, {skipped} lines skipped, mean agreement {:.4}",
+        summary.mean_agreement()
+    );
+
+    Ok(())
+}
+
+/// Replay the records of the lines in batches, writing the differences to
+/// the writer. A batch that fails is predicted a record at a time, so that
+/// only the records that fail are left out.
+fn replay(
+    lines: impl BufRead,
+    writer: &mut impl Write,
+    batch_size: usize,
+    predict: impl Fn(&[&str]) -> onnx_bert::Result<Vec<Vec<Entity>>>,
+) -> anyhow::Result<Summary> {
+    let mut lines = lines.lines();
+    let mut summary = Summary::default();
+
+    loop {
+        let chunk = lines
+            .by_ref()
+            .take(batch_size.max(1))
+            .collect::<io::Result<Vec<_>>>()?;
+        if chunk.is_empty() {
+            break;
+        }
+
+        let records = chunk
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| serde_json::from_str::<Record>(line).ok())
+            .filter(|record| record.input.is_some())
+            .collect::<Vec<_>>();
+        summary.skipped += chunk.len() - records.len();
+
+        let inputs = records
+            .iter()
+            .filter_map(|record| record.input.as_deref())
+            .collect::<Vec<_>>();
+        let predictions = match predict(&inputs) {
+            Ok(predictions) => predictions.into_iter().map(Ok).collect(),
+            Err(_) => inputs
+                .iter()
+                .map(|&input| {
+                    predict(&[input]).map(|mut entities| entities.pop().unwrap_or_default())
+                })
+                .collect::<Vec<_>>(),
+        };
+
+        for (record, entities) in records.iter().zip(&predictions) {
+            let entities = match entities {
+                Ok(entities) => entities,
+                Err(e) => {
+                    warn!(
+                        request_id = record.request_id,
+                        ?e,
+                        "failed to replay record"
+                    );
+                    summary.failed += 1;
+                    continue;
+                }
+            };
+
+            summary.replayed += 1;
+            let agreement = shadow::agreement(&record.entities, entities);
+            summary.total_agreement += agreement;
+            if agreement >= 1. {
+                continue;
+            }
+
+            summary.changed += 1;
+            let difference = Difference {
+                request_id: &record.request_id,
+                model: &record.model,
+                revision: record.revision.as_deref(),
+                agreement,
+                removed: missing(&record.entities, entities),
+                added: missing(entities, &record.entities),
+            };
+            serde_json::to_writer(&mut *writer, &difference)?;
+            writeln!(writer)?;
+        }
+    }
+
+    Ok(summary)
+}
+
+/// The entities of `a` whose labelled spans aren't in `b`.
+fn missing<'a>(a: &'a [Entity], b: &[Entity]) -> Vec<&'a Entity> {
+    let b = b
+        .iter()
+        .map(|e| (&e.label, e.start, e.end))
+        .collect::<HashSet<_>>();
+    a.iter()
+        .filter(|e| !b.contains(&(&e.label, e.start, e.end)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn entity(label: &str, start: usize, end: usize) -> Entity {
+        Entity {
+            label: label.to_owned(),
+            score: 1.,
+            word: String::new(),
+            start,
+            end,
+            start_word: 0,
+            end_word: 0,
+            candidates: vec![],
+        }
+    }
+
+    fn record(request_id: &str, input: Option<&str>, entities: &[Entity]) -> String {
+        json!({
+            "request_id": request_id,
+            "input": input,
+            "model": "model",
+            "revision": "abc",
+            "entities": entities,
+        })
+        .to_string()
+    }
+
+    /// Finds a `PER` over the first word of every input, and fails on those
+    /// starting with "fail".
+    fn predict(inputs: &[&str]) -> onnx_bert::Result<Vec<Vec<Entity>>> {
+        inputs
+            .iter()
+            .map(|input| {
+                if input.starts_with("fail") {
+                    return Err(onnx_bert::Error::Tokenizer);
+                }
+                let end = input.find(' ').unwrap_or(input.len());
+                Ok(vec![entity("PER", 0, end)])
+            })
+            .collect()
+    }
+
+    fn replay(lines: &[String], batch_size: usize) -> (Summary, Vec<Value>) {
+        let mut output = vec![];
+        let summary = super::replay(
+            lines.join("\n").as_bytes(),
+            &mut output,
+            batch_size,
+            predict,
+        )
+        .unwrap();
+        let differences = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        (summary, differences)
+    }
+
+    #[test]
+    fn missing_compares_labelled_spans() {
+        let a = [
+            entity("PER", 0, 3),
+            entity("LOC", 4, 9),
+            entity("ORG", 10, 12),
+        ];
+        let b = [entity("PER", 0, 3), entity("ORG", 4, 9)];
+        let missing = missing(&a, &b)
+            .into_iter()
+            .map(|e| (e.label.as_str(), e.start, e.end))
+            .collect::<Vec<_>>();
+        assert_eq!(missing, [("LOC", 4, 9), ("ORG", 10, 12)]);
+    }
+
+    #[test]
+    fn only_differences_are_written() {
+        let lines = [
+            record("same", Some("Ada wrote"), &[entity("PER", 0, 3)]),
+            record("changed", Some("Ada wrote"), &[entity("LOC", 4, 9)]),
+        ];
+        let (summary, differences) = replay(&lines, 8);
+        assert_eq!((summary.replayed, summary.changed), (2, 1));
+        assert_eq!(summary.mean_agreement(), 0.5);
+
+        let expected = json!({
+            "request_id": "changed",
+            "model": "model",
+            "revision": "abc",
+            "agreement": 0.,
+            "removed": [entity("LOC", 4, 9)],
+            "added": [entity("PER", 0, 3)],
+        });
+        assert_eq!(differences, [expected]);
+    }
+
+    #[test]
+    fn records_without_input_are_skipped() {
+        let lines = [
+            record("redacted", None, &[]),
+            "not a record".to_owned(),
+            record("kept", Some("Ada"), &[entity("PER", 0, 3)]),
+        ];
+        let (summary, differences) = replay(&lines, 8);
+        assert_eq!((summary.replayed, summary.skipped), (1, 2));
+        assert!(differences.is_empty());
+    }
+
+    #[test]
+    fn failed_records_fail_alone() {
+        let lines = [
+            record("a", Some("Ada"), &[entity("PER", 0, 3)]),
+            record("b", Some("fail"), &[]),
+            record("c", Some("Grace"), &[]),
+            record("d", Some("Alan"), &[entity("PER", 0, 4)]),
+        ];
+        let (summary, differences) = replay(&lines, 2);
+        assert_eq!((summary.replayed, summary.failed), (3, 1));
+        assert_eq!(differences.len(), 1);
+        assert_eq!(differences[0]["request_id"], "c");
+    }
+}
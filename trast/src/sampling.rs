@@ -0,0 +1,82 @@
+//! Sampling a bounded number of traces per second, however many requests
+//! there are, so that the trace backend isn't overwhelmed at high QPS.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use opentelemetry::{
+    sdk::{trace::ShouldSample, InstrumentationLibrary},
+    trace::{Link, OrderMap, SamplingDecision, SamplingResult, SpanKind, TraceContextExt, TraceId},
+    Context, Key, Value,
+};
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Samples up to `per_second` root traces every second, allowing bursts of
+/// as many.
+#[derive(Debug, Clone)]
+pub struct RateLimited {
+    per_second: f64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimited {
+    pub fn new(per_second: f64) -> Self {
+        let per_second = per_second.max(0.);
+        Self {
+            per_second,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: per_second.max(1.),
+                refilled: Instant::now(),
+            })),
+        }
+    }
+
+    fn take(&self) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.per_second.max(1.));
+        bucket.refilled = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl ShouldSample for RateLimited {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        _trace_id: TraceId,
+        _name: &str,
+        _span_kind: &SpanKind,
+        _attributes: &OrderMap<Key, Value>,
+        _links: &[Link],
+        _instrumentation_library: &InstrumentationLibrary,
+    ) -> SamplingResult {
+        let decision = if self.per_second > 0. && self.take() {
+            SamplingDecision::RecordAndSample
+        } else {
+            SamplingDecision::Drop
+        };
+
+        SamplingResult {
+            decision,
+            attributes: Vec::new(),
+            trace_state: parent_context
+                .map(|cx| cx.span().span_context().trace_state().clone())
+                .unwrap_or_default(),
+        }
+    }
+}
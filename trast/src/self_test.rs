@@ -0,0 +1,44 @@
+use std::{process, sync::Arc};
+
+use tokio::task::spawn_blocking;
+
+use crate::registry::{Registry, WARMUP_SENTENCE};
+
+/// Load the model and run a sample sentence through it, exiting with a
+/// non-zero status unless at least one entity is found. This smoke tests the
+/// combination of the image and the model before it's deployed.
+pub async fn run(registry: Arc<Registry>, model: String) -> ! {
+    let result = spawn_blocking(move || {
+        let pipeline = registry.load(&model, None)?;
+        Ok::<_, crate::Error>(pipeline.predict(WARMUP_SENTENCE)?)
+    })
+    .await;
+
+    let entities = match result {
+        Ok(Ok(entities)) => entities,
+        Ok(Err(e)) => {
+            eprintln!("self-test failed: {e}");
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("self-test failed: {e}");
+            process::exit(1);
+        }
+    };
+
+    println!("{WARMUP_SENTENCE}");
+    for entity in &entities {
+        println!(
+            "  {} {:?} ({}..{}, score {:.3})",
+            entity.label, entity.word, entity.start, entity.end, entity.score
+        );
+    }
+
+    if entities.is_empty() {
+        eprintln!("self-test failed: no entities found");
+        process::exit(1);
+    }
+
+    println!("self-test passed");
+    process::exit(0);
+}
@@ -0,0 +1,112 @@
+use std::{collections::HashSet, sync::Arc, time::Instant};
+
+use onnx_bert::Entity;
+use rand::Rng;
+use tokio::sync::oneshot;
+use tracing::{debug, info, Instrument, Span};
+use trast_proto::Priority;
+
+use crate::{
+    actor::{Message, Prediction},
+    mailbox::Mailbox,
+    metrics::Metrics,
+    trace::BaggageAttributes,
+};
+
+/// Mirrors a fraction of the traffic to a second model and compares its
+/// predictions with those of the primary model. The shadow predictions are
+/// never returned to clients.
+#[derive(Debug)]
+pub struct Shadow {
+    model: String,
+    fraction: f64,
+    tx: Mailbox,
+    metrics: Arc<Metrics>,
+}
+
+impl Shadow {
+    pub fn new(model: String, fraction: f64, tx: Mailbox, metrics: Arc<Metrics>) -> Self {
+        Self {
+            model,
+            fraction,
+            tx,
+            metrics,
+        }
+    }
+
+    /// Run the sentence through the shadow model in the background, if it's
+    /// sampled.
+    pub fn mirror(&self, sentence: &str, client: &str, primary: &Prediction) {
+        if rand::thread_rng().gen::<f64>() >= self.fraction {
+            return;
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let message = Message {
+            sentence: sentence.to_owned(),
+            tx,
+            // shadow traffic must never delay real requests
+            priority: Priority::Batch,
+            client: client.to_owned(),
+            span: Span::current(),
+            baggage: BaggageAttributes::current().0,
+            received: Instant::now(),
+            windowed: false,
+        };
+        let actor_tx = self.tx.clone();
+        let model = self.model.clone();
+        let metrics = Arc::clone(&self.metrics);
+        let primary = primary.entities.clone();
+
+        tokio::spawn(
+            async move {
+                if actor_tx.send(message).await.is_err() {
+                    return;
+                }
+
+                let shadow = match rx.await {
+                    Ok(Ok(prediction)) => prediction.entities,
+                    Ok(Err(status)) => {
+                        debug!(model, ?status, "shadow prediction failed");
+                        return;
+                    }
+                    Err(_) => return,
+                };
+
+                let agreement = agreement(&primary, &shadow);
+                metrics.shadow_compared(&model, agreement);
+
+                if agreement < 1. {
+                    info!(
+                        model,
+                        agreement,
+                        ?primary,
+                        ?shadow,
+                        "shadow model disagrees"
+                    );
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+/// Jaccard index of the labelled spans found by the two models, one if both
+/// found nothing.
+pub fn agreement(a: &[Entity], b: &[Entity]) -> f64 {
+    let a = a
+        .iter()
+        .map(|e| (&e.label, e.start, e.end))
+        .collect::<HashSet<_>>();
+    let b = b
+        .iter()
+        .map(|e| (&e.label, e.start, e.end))
+        .collect::<HashSet<_>>();
+
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 1.;
+    }
+
+    a.intersection(&b).count() as f64 / union as f64
+}
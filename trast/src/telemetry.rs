@@ -0,0 +1,144 @@
+use opentelemetry::{
+    sdk::{
+        export::{metrics::aggregation::cumulative_temporality_selector, trace::stdout},
+        metrics::selectors,
+        propagation::{BaggagePropagator, TextMapCompositePropagator, TraceContextPropagator},
+        trace::{self, Sampler, ShouldSample, Tracer, TracerProvider},
+        Resource,
+    },
+    trace::TracerProvider as _,
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tracing::metadata::LevelFilter;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::{
+    config::{Config, LogFormat, OtlpProtocol, TraceExporter, TraceSampler},
+    otlp::HttpExporter,
+    sampling::RateLimited,
+};
+
+fn resource() -> Resource {
+    Resource::new(vec![
+        KeyValue::new(
+            opentelemetry_semantic_conventions::resource::SERVICE_NAME,
+            env!("CARGO_PKG_NAME"),
+        ),
+        KeyValue::new(
+            opentelemetry_semantic_conventions::resource::SERVICE_VERSION,
+            env!("CARGO_PKG_VERSION"),
+        ),
+    ])
+}
+
+fn init_tracer(config: &Config) -> anyhow::Result<Option<Tracer>> {
+    let root_sampler: Box<dyn ShouldSample> = match config.trace_sampler {
+        TraceSampler::AlwaysOn => Box::new(Sampler::AlwaysOn),
+        TraceSampler::AlwaysOff => Box::new(Sampler::AlwaysOff),
+        TraceSampler::Ratio => Box::new(Sampler::TraceIdRatioBased(config.trace_sampling_ratio)),
+        TraceSampler::RateLimited => Box::new(RateLimited::new(config.trace_rate_limit)),
+    };
+    let trace_config = trace::config()
+        .with_sampler(Sampler::ParentBased(root_sampler))
+        .with_resource(resource());
+
+    let tracer = match config.trace_exporter {
+        TraceExporter::Otlp if config.otlp_protocol == OtlpProtocol::HttpProtobuf => {
+            let exporter = HttpExporter::new(&config.otlp_endpoint, &config.otlp_headers)?;
+            let provider = TracerProvider::builder()
+                .with_batch_exporter(exporter, opentelemetry::runtime::Tokio)
+                .with_config(trace_config)
+                .build();
+            let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+            opentelemetry::global::set_tracer_provider(provider);
+            tracer
+        }
+        TraceExporter::Otlp => {
+            let mut metadata = MetadataMap::new();
+            for (name, value) in &config.otlp_headers {
+                metadata.insert(
+                    MetadataKey::from_bytes(name.as_bytes())?,
+                    MetadataValue::try_from(value.as_str())?,
+                );
+            }
+
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(&config.otlp_endpoint)
+                        .with_metadata(metadata.clone()),
+                )
+                .with_trace_config(trace_config)
+                .install_batch(opentelemetry::runtime::Tokio)?;
+
+            // the otlp metrics exporter only speaks grpc
+            opentelemetry_otlp::new_pipeline()
+                .metrics(
+                    selectors::simple::inexpensive(),
+                    cumulative_temporality_selector(),
+                    opentelemetry::runtime::Tokio,
+                )
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(&config.otlp_endpoint)
+                        .with_metadata(metadata),
+                )
+                .with_resource(resource())
+                .build()?;
+
+            tracer
+        }
+        TraceExporter::Stdout => stdout::new_pipeline()
+            .with_trace_config(trace_config)
+            .install_simple(),
+        TraceExporter::None => return Ok(None),
+    };
+
+    Ok(Some(tracer))
+}
+
+pub fn init(config: &Config) -> anyhow::Result<()> {
+    let otel_layer =
+        init_tracer(config)?.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+
+    tracing_subscriber::registry()
+        .with(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::INFO.into())
+                .from_env_lossy(),
+        )
+        .with(matches!(config.log_format, LogFormat::Text).then(fmt::layer))
+        .with(matches!(config.log_format, LogFormat::Json).then(|| {
+            fmt::layer()
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+        }))
+        .with(otel_layer)
+        .init();
+
+    opentelemetry::global::set_text_map_propagator(TextMapCompositePropagator::new(vec![
+        Box::new(TraceContextPropagator::new()),
+        Box::new(BaggagePropagator::new()),
+    ]));
+
+    Ok(())
+}
+
+/// Log warnings to stderr, leaving stdout to the output of the command line
+/// tools.
+pub fn init_cli() {
+    tracing_subscriber::registry()
+        .with(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::WARN.into())
+                .from_env_lossy(),
+        )
+        .with(fmt::layer().with_writer(std::io::stderr))
+        .init();
+}
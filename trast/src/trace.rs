@@ -1,26 +1,97 @@
-use std::task::{Context, Poll};
-
-use hyper::{Body, HeaderMap};
-use opentelemetry::propagation::Extractor;
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use hyper::{header::HeaderValue, Body, HeaderMap};
+use opentelemetry::{
+    baggage::BaggageExt,
+    propagation::Extractor,
+    trace::{FutureExt, TraceContextExt},
+    KeyValue,
+};
 use tonic::body::BoxBody;
 use tower::{Layer, Service};
 use tracing::{field, info_span, Instrument, Span};
-use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_opentelemetry::{OpenTelemetrySpanExt, OtelData};
+use tracing_subscriber::{registry::LookupSpan, Registry};
+
+/// Metadata correlating the logs of a client with those of the server. It's
+/// generated if the client doesn't send one, and always echoed back.
+pub const REQUEST_ID: &str = "x-request-id";
 
+pub fn generate_request_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// The entries of the W3C baggage of a request that are copied onto its
+/// spans and metrics, as attributes named `baggage.<key>`. They're part of
+/// the OpenTelemetry context that the request is handled in.
 #[derive(Debug, Clone, Default)]
-pub struct TraceLayer;
+pub struct BaggageAttributes(pub Vec<KeyValue>);
+
+impl BaggageAttributes {
+    /// The attributes of the request being handled, if any.
+    pub fn current() -> Self {
+        opentelemetry::Context::current()
+            .get::<Self>()
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// Add the attributes to the OpenTelemetry span of the tracing span, which
+/// unlike fields needn't be declared when the span is created.
+pub fn set_attributes(span: &Span, attributes: &[KeyValue]) {
+    if attributes.is_empty() {
+        return;
+    }
+    span.with_subscriber(|(id, dispatch)| {
+        let Some(span) = dispatch
+            .downcast_ref::<Registry>()
+            .and_then(|registry| registry.span(id))
+        else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<OtelData>() {
+            let span_attributes = data.builder.attributes.get_or_insert_with(Default::default);
+            for attribute in attributes {
+                span_attributes.insert(attribute.key.clone(), attribute.value.clone());
+            }
+        }
+    });
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TraceLayer {
+    /// Keys of the baggage entries to copy onto spans and metrics.
+    baggage_keys: Arc<[String]>,
+}
+
+impl TraceLayer {
+    pub fn new(baggage_keys: Vec<String>) -> Self {
+        Self {
+            baggage_keys: baggage_keys.into(),
+        }
+    }
+}
 
 impl<S> Layer<S> for TraceLayer {
     type Service = TraceMiddleware<S>;
 
     fn layer(&self, service: S) -> Self::Service {
-        TraceMiddleware { inner: service }
+        TraceMiddleware {
+            inner: service,
+            baggage_keys: Arc::clone(&self.baggage_keys),
+        }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct TraceMiddleware<S> {
     inner: S,
+    baggage_keys: Arc<[String]>,
 }
 
 impl<S> Service<hyper::Request<Body>> for TraceMiddleware<S>
@@ -36,15 +107,24 @@ where
         self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, req: hyper::Request<Body>) -> Self::Future {
+    fn call(&mut self, mut req: hyper::Request<Body>) -> Self::Future {
         // This is necessary because tonic internally uses `tower::buffer::Buffer`.
         // See https://github.com/tower-rs/tower/issues/547#issuecomment-767629149
         // for details on why this is necessary
         let clone = self.inner.clone();
         let mut inner = std::mem::replace(&mut self.inner, clone);
 
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID)
+            .filter(|v| !v.is_empty() && v.to_str().is_ok())
+            .cloned()
+            .unwrap_or_else(|| HeaderValue::from_str(&generate_request_id()).unwrap());
+        req.headers_mut().insert(REQUEST_ID, request_id.clone());
+
         let path = req.uri().path().trim_start_matches('/');
-        let (service, method) = path.split_once('/').unwrap();
+        // anything but a gRPC call is passed on to be rejected by tonic
+        let (service, method) = path.split_once('/').unwrap_or((path, ""));
 
         let parent_context = opentelemetry::global::get_text_map_propagator(|propagator| {
             propagator.extract(&RequestHeaderCarrier::new(req.headers()))
@@ -62,14 +142,41 @@ where
                 "otel.kind" = "server",
                 "rpc.grpc.status_code" = field::Empty,
                 "otel.status_code" = field::Empty,
+                "trace_id" = field::Empty,
+                "request_id" = request_id.to_str().unwrap_or_default(),
+                "model" = field::Empty,
+                "model_revision" = field::Empty,
+                "language" = field::Empty,
+                "cache_hit" = field::Empty,
+                "batch_size" = field::Empty,
+                "tokens" = field::Empty,
+                "entities" = field::Empty,
             )
         };
 
+        let baggage = parent_context.baggage();
+        let attributes = self
+            .baggage_keys
+            .iter()
+            .filter_map(|key| {
+                let value = baggage.get(key.clone())?;
+                Some(KeyValue::new(format!("baggage.{key}"), value.clone()))
+            })
+            .collect::<Vec<_>>();
+        set_attributes(&span, &attributes);
+        let cx = parent_context.with_value(BaggageAttributes(attributes));
+
         span.set_parent(parent_context);
+        // makes the trace id visible in the (JSON) logs
+        span.record(
+            "trace_id",
+            span.context().span().span_context().trace_id().to_string(),
+        );
 
         Box::pin(
             async move {
-                let response = inner.call(req).await?;
+                let mut response = inner.call(req).await?;
+                response.headers_mut().insert(REQUEST_ID, request_id);
 
                 let grpc_status = response
                     .headers()
@@ -85,7 +192,8 @@ where
 
                 Ok(response)
             }
-            .instrument(span),
+            .instrument(span)
+            .with_context(cx),
         )
     }
 }
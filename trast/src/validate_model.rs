@@ -0,0 +1,45 @@
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+
+use anyhow::bail;
+use onnx_bert::PretrainedFiles;
+
+use crate::cli::ValidateModelArgs;
+
+/// Check that the model, in a local directory or on the Hub, is compatible
+/// with the pipeline and print a report. Fails if any check does. This
+/// blocks.
+pub fn run(args: ValidateModelArgs) -> anyhow::Result<()> {
+    let dir = Path::new(&args.model);
+    let files = if dir.is_dir() {
+        PretrainedFiles::from_dir(dir)
+    } else {
+        onnx_bert::download_pretrained(&args.model, &args.revision)?
+    };
+
+    let checks = onnx_bert::validate(&files);
+
+    let mut stdout = io::stdout().lock();
+    let width = checks
+        .iter()
+        .map(|check| check.name.len())
+        .max()
+        .unwrap_or(0);
+    for check in &checks {
+        let status = if check.passed { "ok" } else { "FAIL" };
+        writeln!(
+            stdout,
+            "{status:4}  {:width$}  {}",
+            check.name, check.detail
+        )?;
+    }
+
+    let failed = checks.iter().filter(|check| !check.passed).count();
+    if failed > 0 {
+        bail!("{failed} of {} checks failed", checks.len());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,14 @@
+use trast_client::Client;
+
+use crate::cli::WarmupArgs;
+
+/// Ask the server to warm up its models, and wait until it has.
+pub async fn run(args: WarmupArgs) -> anyhow::Result<()> {
+    let client = Client::new(args.target)?;
+
+    for model in client.warmup(args.model.as_deref()).await? {
+        println!("{}  {:.1} ms", model.id, model.latency_ms);
+    }
+
+    Ok(())
+}